@@ -0,0 +1,98 @@
+use std::io::{IsTerminal, Read};
+use std::sync::mpsc::{channel, Receiver};
+use emulator_6502_core::{BasicMemory, Bus, ConsoleAddresses, ConsoleDevice};
+use log::warn;
+use structopt::StructOpt;
+use crate::parse::{parse_byte, parse_console};
+
+#[derive(StructOpt)]
+pub struct ConsoleOpts {
+    /// Map a console reading stdin and writing stdout: `py65` for output at $F001 and input at $F004,
+    /// `kowalski` for $E001 and $E004, or the output and input addresses as `OUT:IN`
+    #[structopt(long, parse(try_from_str = parse_console))]
+    pub console: Option<ConsoleAddresses>,
+    /// The value the console's input register reads when no input is available
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_byte))]
+    pub console_empty: u8,
+    /// Pass keys to the console as they are pressed, without echoing them, instead of a line at a time.
+    /// Ctrl-C still interrupts the run
+    #[structopt(long)]
+    pub raw: bool,
+}
+
+impl ConsoleOpts {
+    /// Map the console on the bus if it was requested. Raw mode lasts as long as the returned guard
+    pub fn install(&self, bus: &mut Bus<BasicMemory>) -> Option<RawMode> {
+        let addresses = self.console?;
+        let raw_mode = if self.raw { RawMode::enable() } else { None };
+        let input = stdin_bytes();
+        ConsoleDevice::new(addresses, crate::run::write_stdout, move || input.try_recv().ok())
+            .with_empty_status(self.console_empty)
+            .map(bus);
+        raw_mode
+    }
+}
+
+/// Read stdin on a thread of its own, so the bytes can be taken without blocking as they arrive
+fn stdin_bytes() -> Receiver<u8> {
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        for byte in std::io::stdin().lock().bytes() {
+            match byte {
+                Ok(byte) if sender.send(byte).is_ok() => {},
+                _ => break,
+            }
+        }
+    });
+    receiver
+}
+
+/// A terminal on stdin switched to passing every key as it's pressed, without echo, until dropped
+pub struct RawMode {
+    #[cfg(unix)]
+    original: nix::sys::termios::Termios,
+}
+
+impl RawMode {
+    /// Switch the terminal to raw mode. Returns `None`, with a warning, if stdin isn't a terminal or it can't be switched
+    pub fn enable() -> Option<Self> {
+        if !std::io::stdin().is_terminal() {
+            warn!("stdin isn't a terminal, so it's read as it is instead of in raw mode");
+            return None;
+        }
+        Self::switch()
+    }
+
+    #[cfg(unix)]
+    fn switch() -> Option<Self> {
+        use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+
+        let original = match tcgetattr(std::io::stdin()) {
+            Ok(termios) => termios,
+            Err(e) => {
+                warn!("Failed to read the terminal settings: {}", e);
+                return None;
+            },
+        };
+        let mut raw = original.clone();
+        raw.local_flags.remove(LocalFlags::ICANON | LocalFlags::ECHO);
+        if let Err(e) = tcsetattr(std::io::stdin(), SetArg::TCSANOW, &raw) {
+            warn!("Failed to switch the terminal to raw mode: {}", e);
+            return None;
+        }
+        Some(Self { original })
+    }
+
+    #[cfg(not(unix))]
+    fn switch() -> Option<Self> {
+        warn!("Raw mode is only supported on Unix");
+        None
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = nix::sys::termios::tcsetattr(std::io::stdin(), nix::sys::termios::SetArg::TCSANOW, &self.original);
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use emulator_6502_core::{Coverage, Cpu, Instruction, Memory, SourceLine, SourceMap, MAX_MEMORY};
+use log::info;
+use structopt::StructOpt;
+use crate::error::{Error, Result};
+use crate::lcov::Lcov;
+
+/// Executed instructions further apart than this are listed in separate blocks when no debug information is available
+const LISTING_GAP: u32 = 16;
+
+#[derive(StructOpt)]
+pub struct CoverageOpts {
+    /// Record which instructions and branches are executed and write them to this file in lcov format.
+    /// Without debug information, coverage refers to a disassembly listing written next to it
+    #[structopt(parse(from_os_str), long)]
+    pub lcov: Option<PathBuf>,
+}
+
+impl CoverageOpts {
+    /// Enable coverage on the CPU if it was requested
+    pub fn install(&self, cpu: &mut Cpu) {
+        if self.lcov.is_some() {
+            cpu.enable_coverage();
+        }
+    }
+
+    /// Write the lcov tracefile, mapping coverage to lines through `source_map` or, if it's empty,
+    /// to a listing named after the `image`
+    pub fn write_report(&self, cpu: &Cpu, memory: &dyn Memory<MAX_MEMORY>, image: &Path, source_map: &SourceMap) -> Result<()> {
+        let (path, coverage) = match (&self.lcov, cpu.coverage()) {
+            (Some(path), Some(coverage)) => (path, coverage),
+            _ => return Ok(()),
+        };
+
+        let lcov = if source_map.is_empty() {
+            let file_name = format!("{}.lst", image.file_name().unwrap_or_default().to_string_lossy());
+            let listing_path = path.with_file_name(file_name);
+            let (listing, listing_map) = disassembly_listing(coverage, memory, &listing_path.to_string_lossy());
+            std::fs::write(&listing_path, listing)?;
+            info!("No debug information, coverage refers to the listing in {:?}", listing_path);
+
+            Lcov::from_coverage(coverage, &listing_map, memory)
+        } else {
+            Lcov::from_coverage(coverage, source_map, memory)
+        };
+
+        std::fs::write(path, lcov.to_string())?;
+        Ok(())
+    }
+}
+
+/// Disassemble the code around all executed instructions, one instruction per line.
+/// Returns the listing and the map from addresses to its lines, which are attributed to `file`
+pub fn disassembly_listing(coverage: &Coverage, memory: &dyn Memory<MAX_MEMORY>, file: &str) -> (String, SourceMap) {
+    let mut source_map = SourceMap::new();
+    let file = source_map.add_file(file);
+    let mut listing = String::new();
+    let mut line = 0;
+
+    let executed: Vec<u16> = coverage.executed().collect();
+    let mut index = 0;
+    while index < executed.len() {
+        // A block ends at the last executed instruction before a gap
+        let start = executed[index];
+        while index + 1 < executed.len() && (executed[index + 1] - executed[index]) as u32 <= LISTING_GAP {
+            index += 1;
+        }
+        let end = executed[index] as u32;
+        index += 1;
+
+        if line > 0 {
+            listing.push('\n');
+            line += 1;
+        }
+
+        let mut address = start as u32;
+        while address <= end {
+            let instruction = Instruction::decode(memory, address as u16);
+            // Resynchronize on executed instructions in case the bytes before them are data
+            let next = (address + 1..address + instruction.length() as u32)
+                .find(|a| *a <= end && coverage.is_executed(*a as u16))
+                .unwrap_or(address + instruction.length() as u32);
+
+            line += 1;
+            let _ = writeln!(listing, "{:04X}  {}", address, instruction);
+            source_map.add_span(address as u16, (next - address) as u16, SourceLine { file, line });
+            address = next;
+        }
+    }
+
+    (listing, source_map)
+}
+
+/// Merge lcov tracefiles of several runs into one
+pub fn merge(inputs: &[PathBuf], output: &Path) -> Result<()> {
+    let mut merged = Lcov::default();
+    for input in inputs {
+        let text = std::fs::read_to_string(input)?;
+        let lcov = Lcov::parse(&text).map_err(|message| Error::InvalidLcov { path: input.clone(), message })?;
+        merged.merge(&lcov);
+    }
+
+    std::fs::write(output, merged.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use emulator_6502_core::{BasicMemory, Cpu, Memory, SourceLine, BNE_RELATIVE, INX_IMPLIED, JMP_ABSOLUTE, NOP_IMPLIED};
+    use super::disassembly_listing;
+
+    #[test]
+    fn listing() {
+        let mut memory = BasicMemory::default();
+        // The branch is taken, so the NOP after it is not executed
+        for (offset, byte) in [INX_IMPLIED, BNE_RELATIVE, 0x01, NOP_IMPLIED, JMP_ABSOLUTE, 0x00, 0x03].iter().enumerate() {
+            memory.write(0x0200 + offset as u16, *byte);
+        }
+        memory.write(0x0300, NOP_IMPLIED);
+        memory.write(0x0301, JMP_ABSOLUTE);
+        memory.write(0x0302, 0x01);
+        memory.write(0x0303, 0x03);
+
+        let mut cpu = Cpu::default();
+        cpu.set_program_counter(0x0200);
+        cpu.enable_coverage();
+        for _ in 0..5 {
+            cpu.step(&mut memory);
+        }
+
+        let (listing, source_map) = disassembly_listing(cpu.coverage().unwrap(), &memory, "image.lst");
+        assert_eq!(listing, "0200  INX\n0201  BNE $0204\n0203  NOP\n0204  JMP $0300\n\n0300  NOP\n0301  JMP $0301\n");
+        assert_eq!(source_map.line_at(0x0203), Some(SourceLine { file: 0, line: 3 }));
+        assert_eq!(source_map.line_at(0x0301), Some(SourceLine { file: 0, line: 7 }));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,51 @@
+//! A Debug Adapter Protocol server, for debugging from editors like VS Code.
+//!
+//! Messages are exchanged over stdio. Reading and writing happen on their own threads,
+//! so a [Session] can keep running the program while it waits for requests like `pause`.
+
+use std::io;
+use std::sync::mpsc::channel;
+use log::warn;
+use crate::error::Result;
+
+mod protocol;
+mod session;
+pub use session::Session;
+
+/// Serve a single client on stdin and stdout until it disconnects
+pub fn serve_stdio() -> Result<()> {
+    let (request_tx, request_rx) = channel();
+    let (output_tx, output_rx) = channel();
+
+    std::thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        loop {
+            match protocol::read_message(&mut stdin) {
+                Ok(Some(message)) => {
+                    if request_tx.send(message).is_err() {
+                        break;
+                    }
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Failed to read DAP message: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    let writer = std::thread::spawn(move || -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        for message in output_rx {
+            protocol::write_message(&mut stdout, &message)?;
+        }
+        Ok(())
+    });
+
+    Session::new(output_tx).run(request_rx);
+    writer.join().expect("DAP writer thread panicked")?;
+    Ok(())
+}
\ No newline at end of file
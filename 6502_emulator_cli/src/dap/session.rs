@@ -0,0 +1,1133 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use emulator_6502_core::{BasicMemory, Cpu, CpuStatusFlags, Instruction, Memory, SourceLine, SourceMap, SymbolTable, DEFAULT_MAX_CALL_DEPTH, JSR_ABSOLUTE, MAX_MEMORY, RTI_IMPLIED, RTS_IMPLIED, BRK_IMPLIED};
+use log::debug;
+use serde_json::{json, Value};
+use crate::dap::protocol::{decode_base64, encode_base64};
+use crate::image::{load_image, ImageOpts};
+use crate::parse::parse_number;
+use crate::dbgfile::load_debug_info;
+
+/// The 6502 has a single thread of execution
+const THREAD_ID: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+const FLAGS_REFERENCE: i64 = 2;
+/// Instructions executed between checks for new requests while running
+const BATCH_SIZE: u32 = 10_000;
+
+/// Flags shown in the variables view, in the order of the status register
+const FLAGS: [(&str, CpuStatusFlags); 7] = [
+    ("N", CpuStatusFlags::NEGATIVE),
+    ("V", CpuStatusFlags::OVERFLOW),
+    ("B", CpuStatusFlags::BREAK_COMMAND),
+    ("D", CpuStatusFlags::DECIMAL_MODE),
+    ("I", CpuStatusFlags::IRQ_DISABLE),
+    ("Z", CpuStatusFlags::ZERO),
+    ("C", CpuStatusFlags::CARRY),
+];
+
+type RequestResult = std::result::Result<Value, String>;
+
+struct Machine {
+    cpu: Cpu,
+    memory: BasicMemory,
+}
+
+/// How far to run before reporting a stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StepKind {
+    Continue,
+    /// Stop after every instruction
+    Instruction,
+    /// Stop once a called subroutine has returned
+    InstructionOver,
+    /// Stop once the current subroutine has returned
+    Out,
+    /// Stop at the start of a different source line, stepping into subroutines
+    Line,
+    /// Stop at the start of a different source line in the current subroutine
+    LineOver,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Stepping {
+    kind: StepKind,
+    /// Subroutine nesting relative to where stepping started
+    depth: i32,
+    /// The source line at which stepping started
+    start: Option<SourceLine>,
+}
+
+/// A Debug Adapter Protocol session. Requests are read from a channel,
+/// responses and events are sent to another, so the session can keep executing while waiting for requests.
+pub struct Session {
+    output: Sender<Value>,
+    seq: i64,
+    machine: Option<Machine>,
+    source_map: SourceMap,
+    symbols: SymbolTable,
+    /// Breakpoints set through `setBreakpoints`, per source path
+    source_breakpoints: BTreeMap<String, Vec<u16>>,
+    instruction_breakpoints: Vec<u16>,
+    function_breakpoints: Vec<u16>,
+    /// Union of all breakpoint kinds
+    breakpoints: BTreeSet<u16>,
+    stop_on_entry: bool,
+    running: Option<Stepping>,
+    /// Events to send after the response to the current request
+    pending_events: Vec<Value>,
+    terminated: bool,
+}
+
+impl Session {
+    pub fn new(output: Sender<Value>) -> Self {
+        Self {
+            output,
+            seq: 0,
+            machine: None,
+            source_map: SourceMap::new(),
+            symbols: SymbolTable::new(),
+            source_breakpoints: BTreeMap::new(),
+            instruction_breakpoints: Vec::new(),
+            function_breakpoints: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            stop_on_entry: false,
+            running: None,
+            pending_events: Vec::new(),
+            terminated: false,
+        }
+    }
+
+    /// Handle requests until the client disconnects or the session is terminated
+    pub fn run(mut self, requests: Receiver<Value>) {
+        while !self.terminated {
+            if self.running.is_some() {
+                self.run_batch();
+
+                loop {
+                    match requests.try_recv() {
+                        Ok(request) => self.handle(request),
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => return,
+                    }
+                }
+            } else {
+                match requests.recv() {
+                    Ok(request) => self.handle(request),
+                    Err(_) => return,
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, request: Value) {
+        let command = request["command"].as_str().unwrap_or_default().to_string();
+        let args = &request["arguments"];
+        debug!("DAP request: {}", command);
+
+        let result = match command.as_str() {
+            "initialize" => self.initialize(),
+            "launch" => self.launch(args, false),
+            "attach" => self.launch(args, true),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setFunctionBreakpoints" => self.set_function_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => self.configuration_done(),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "6502" }] })),
+            "stackTrace" => self.stack_trace(args),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                { "name": "Flags", "variablesReference": FLAGS_REFERENCE, "expensive": false },
+            ]})),
+            "variables" => self.variables(args),
+            "setVariable" => self.set_variable(args),
+            "continue" => self.resume(StepKind::Continue, args),
+            "next" => self.resume(StepKind::LineOver, args),
+            "stepIn" => self.resume(StepKind::Line, args),
+            "stepOut" => self.resume(StepKind::Out, args),
+            "stepBack" => self.reverse(false, args),
+            "reverseContinue" => self.reverse(true, args),
+            "evaluate" => self.evaluate(args),
+            "pause" => self.pause(),
+            "disassemble" => self.disassemble(args),
+            "readMemory" => self.read_memory(args),
+            "writeMemory" => self.write_memory(args),
+            "disconnect" | "terminate" => {
+                self.terminated = true;
+                self.pending_events.push(event("terminated", json!({})));
+                Ok(json!({}))
+            },
+            _ => Err(format!("Unsupported request '{}'", command)),
+        };
+
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = Value::String(message),
+        }
+
+        self.send(response);
+        for event in std::mem::take(&mut self.pending_events) {
+            self.send(event);
+        }
+    }
+
+    fn initialize(&mut self) -> RequestResult {
+        Ok(json!({
+            "supportsConfigurationDoneRequest": true,
+            "supportsFunctionBreakpoints": true,
+            "supportsInstructionBreakpoints": true,
+            "supportsSetVariable": true,
+            "supportsReadMemoryRequest": true,
+            "supportsWriteMemoryRequest": true,
+            "supportsSteppingGranularity": true,
+            "supportsTerminateRequest": true,
+            "supportsDisassembleRequest": true,
+        }))
+    }
+
+    /// Load the program and its debug information. Attaching starts halted, as the emulator runs in-process.
+    /// Breakpoints are only accepted after this, as source breakpoints need the debug information
+    fn launch(&mut self, args: &Value, attach: bool) -> RequestResult {
+        let program = args["program"].as_str().ok_or("Missing 'program' argument")?;
+        let address = |name: &str| args[name].as_u64().and_then(|address| u16::try_from(address).ok());
+        let opts = ImageOpts {
+            input: PathBuf::from(program),
+            load_address: address("loadAddress"),
+            skip: args["skip"].as_u64().unwrap_or_default() as usize,
+            reset_vector: address("resetVector"),
+            ..Default::default()
+        };
+        let image = load_image(&opts).map_err(|e| e.to_string())?;
+
+        let mut cpu = Cpu::default();
+        image.start(&mut cpu, args["useEntry"].as_bool().unwrap_or(false));
+        cpu.enable_call_graph(DEFAULT_MAX_CALL_DEPTH);
+        // Stepping back needs the history, so it's only offered once the launch enabled it
+        if let Some(capacity) = args["history"].as_u64() {
+            cpu.enable_history(capacity as usize);
+            self.pending_events.push(event("capabilities", json!({ "capabilities": { "supportsStepBack": true } })));
+        }
+        if let Some(depth) = args["provenance"].as_u64() {
+            cpu.enable_provenance(depth as usize);
+        }
+
+        // Label files are given as a single path or a list of paths
+        let symbol_files: Vec<PathBuf> = match &args["symbols"] {
+            Value::String(path) => vec![PathBuf::from(path)],
+            Value::Array(paths) => paths.iter().filter_map(Value::as_str).map(PathBuf::from).collect(),
+            _ => Vec::new(),
+        };
+        let dbgfile = args["dbgFile"].as_str().map(Path::new);
+        let (source_map, symbols) = load_debug_info(&image, dbgfile, &symbol_files).map_err(|e| e.to_string())?;
+        if dbgfile.is_some() || !image.source_map.is_empty() {
+            self.source_map = source_map;
+        }
+        self.symbols = symbols;
+
+        self.machine = Some(Machine { cpu, memory: image.memory });
+        self.stop_on_entry = attach || args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.pending_events.push(event("initialized", json!({})));
+        Ok(json!({}))
+    }
+
+    fn configuration_done(&mut self) -> RequestResult {
+        if self.machine.is_none() {
+            return Err("No program loaded".to_string());
+        }
+
+        if self.stop_on_entry {
+            self.pending_events.push(stopped_event("entry"));
+        } else {
+            self.running = Some(Stepping { kind: StepKind::Continue, depth: 0, start: None });
+        }
+
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> RequestResult {
+        let path = args["source"]["path"].as_str().ok_or("Missing source path")?.to_string();
+        let file = self.source_map.find_file(&path);
+
+        let mut addresses = Vec::new();
+        let breakpoints: Vec<Value> = args["breakpoints"].as_array().cloned().unwrap_or_default().iter()
+            .map(|bp| {
+                let line = bp["line"].as_u64().unwrap_or_default() as u32;
+                let source = file.and_then(|f| self.source_map.nearest_line(f, line));
+
+                match source {
+                    Some(source) => {
+                        let address = self.source_map.addresses_of(source)[0];
+                        addresses.push(address);
+                        json!({ "verified": true, "line": source.line, "instructionReference": format_address(address) })
+                    },
+                    None if file.is_none() => json!({ "verified": false, "line": line, "message": "No debug information for this file" }),
+                    None => json!({ "verified": false, "line": line, "message": "No code at or after this line" }),
+                }
+            })
+            .collect();
+
+        self.source_breakpoints.insert(path, addresses);
+        self.rebuild_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> RequestResult {
+        self.instruction_breakpoints.clear();
+        let breakpoints: Vec<Value> = args["breakpoints"].as_array().cloned().unwrap_or_default().iter()
+            .map(|bp| {
+                let address = bp["instructionReference"].as_str()
+                    .and_then(parse_number)
+                    .map(|a| (a as i64 + bp["offset"].as_i64().unwrap_or_default()) as u16);
+
+                match address {
+                    Some(address) => {
+                        self.instruction_breakpoints.push(address);
+                        json!({ "verified": true, "instructionReference": format_address(address) })
+                    },
+                    None => json!({ "verified": false, "message": "Invalid instruction reference" }),
+                }
+            })
+            .collect();
+
+        self.rebuild_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    /// Function breakpoints are given as a symbol or an address, e.g. `main`, `02:reset`, `$C000` or `0xC000`
+    fn set_function_breakpoints(&mut self, args: &Value) -> RequestResult {
+        self.function_breakpoints.clear();
+        let breakpoints: Vec<Value> = args["breakpoints"].as_array().cloned().unwrap_or_default().iter()
+            .map(|bp| match bp["name"].as_str().and_then(|name| self.address(name)) {
+                Some(address) => {
+                    self.function_breakpoints.push(address);
+                    json!({ "verified": true, "instructionReference": format_address(address) })
+                },
+                None => json!({ "verified": false, "message": "Unknown function" }),
+            })
+            .collect();
+
+        self.rebuild_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn rebuild_breakpoints(&mut self) {
+        self.breakpoints = self.source_breakpoints.values().flatten()
+            .chain(self.instruction_breakpoints.iter())
+            .chain(self.function_breakpoints.iter())
+            .copied()
+            .collect();
+    }
+
+    /// The current instruction followed by the calls leading to it, as tracked by the call graph.
+    /// Stepping back doesn't rewind the call graph, so the calls are those of the furthest point executed
+    fn stack_trace(&self, args: &Value) -> RequestResult {
+        let machine = self.machine()?;
+        let call_sites: Vec<u16> = machine.cpu.call_graph().map(|c| c.call_sites().rev().collect()).unwrap_or_default();
+        let addresses: Vec<u16> = std::iter::once(machine.cpu.program_counter()).chain(call_sites).collect();
+
+        let start = args["startFrame"].as_u64().unwrap_or_default() as usize;
+        let levels = match args["levels"].as_u64() {
+            Some(levels) if levels > 0 => levels as usize,
+            _ => addresses.len(),
+        };
+        let frames: Vec<Value> = addresses.iter().enumerate()
+            .skip(start)
+            .take(levels)
+            .map(|(id, address)| self.frame(id, *address))
+            .collect();
+
+        Ok(json!({ "stackFrames": frames, "totalFrames": addresses.len() }))
+    }
+
+    fn frame(&self, id: usize, address: u16) -> Value {
+        let name = match self.symbols.nearest(address) {
+            Some((symbol, 0)) => symbol.to_string(),
+            Some((symbol, offset)) => format!("{}+{}", symbol, offset),
+            None => format!("${:04X}", address),
+        };
+        let mut frame = json!({
+            "id": id,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format_address(address),
+        });
+
+        if let Some(source) = self.source_map.line_at(address) {
+            let path = &self.source_map.files()[source.file];
+            frame["source"] = json!({ "path": path });
+            frame["line"] = json!(source.line);
+            frame["column"] = json!(1);
+        }
+
+        frame
+    }
+
+    /// Disassemble instructions with their symbols and source lines, relative to the instruction at the memory reference
+    fn disassemble(&self, args: &Value) -> RequestResult {
+        let memory = &self.machine()?.memory;
+        let base = memory_address(args)?;
+        let offset = args["instructionOffset"].as_i64().unwrap_or_default();
+        let count = args["instructionCount"].as_u64().ok_or("Missing instruction count")? as usize;
+
+        let mut address = if offset < 0 {
+            instructions_before(memory, base, offset.unsigned_abs() as usize)
+        } else {
+            (0..offset).fold(base, |address, _| Instruction::decode(memory, address).next_address())
+        };
+
+        let mut instructions = Vec::with_capacity(count);
+        for _ in 0..count {
+            let instruction = Instruction::decode(memory, address);
+            let bytes = &instruction.bytes()[..instruction.length() as usize];
+            let mut disassembled = json!({
+                "address": format_address(address),
+                "instructionBytes": bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" "),
+                "instruction": instruction.with_symbols(&self.symbols).to_string(),
+            });
+
+            if let Some(symbol) = self.symbols.symbol_at(address, None) {
+                disassembled["symbol"] = json!(symbol.to_string());
+            }
+            if let Some(source) = self.source_map.line_at(address) {
+                disassembled["location"] = json!({ "path": self.source_map.files()[source.file] });
+                disassembled["line"] = json!(source.line);
+            }
+
+            instructions.push(disassembled);
+            address = instruction.next_address();
+        }
+
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    fn variables(&self, args: &Value) -> RequestResult {
+        let registers = self.machine()?.cpu.registers();
+
+        let variables = match args["variablesReference"].as_i64() {
+            Some(REGISTERS_REFERENCE) => json!([
+                variable("A", format!("${:02X}", registers.accumulator)),
+                variable("X", format!("${:02X}", registers.x)),
+                variable("Y", format!("${:02X}", registers.y)),
+                variable("SP", format!("${:02X}", registers.stack_pointer)),
+                {
+                    "name": "PC",
+                    "value": format!("${:04X}", registers.program_counter),
+                    "variablesReference": 0,
+                    "memoryReference": format_address(registers.program_counter),
+                },
+                variable("P", format!("${:02X}", registers.flags.bits())),
+            ]),
+            Some(FLAGS_REFERENCE) => FLAGS.iter()
+                .map(|(name, flag)| variable(name, (registers.flags.contains(*flag) as u8).to_string()))
+                .collect(),
+            _ => return Err("Unknown variables reference".to_string()),
+        };
+
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn set_variable(&mut self, args: &Value) -> RequestResult {
+        let name = args["name"].as_str().unwrap_or_default();
+        let value = args["value"].as_str().and_then(parse_number).ok_or("Invalid value")?;
+        let reference = args["variablesReference"].as_i64();
+
+        let cpu = &mut self.machine_mut()?.cpu;
+        let mut registers = cpu.registers();
+        let byte = || u8::try_from(value).map_err(|_| "Value does not fit in a byte".to_string());
+
+        let display = match (reference, name) {
+            (Some(REGISTERS_REFERENCE), "A") => { registers.accumulator = byte()?; format!("${:02X}", value) },
+            (Some(REGISTERS_REFERENCE), "X") => { registers.x = byte()?; format!("${:02X}", value) },
+            (Some(REGISTERS_REFERENCE), "Y") => { registers.y = byte()?; format!("${:02X}", value) },
+            (Some(REGISTERS_REFERENCE), "SP") => { registers.stack_pointer = byte()?; format!("${:02X}", value) },
+            (Some(REGISTERS_REFERENCE), "P") => {
+                registers.flags = CpuStatusFlags::from_bits_truncate(byte()?);
+                format!("${:02X}", registers.flags.bits())
+            },
+            (Some(REGISTERS_REFERENCE), "PC") => {
+                registers.program_counter = u16::try_from(value).map_err(|_| "Value does not fit in a word".to_string())?;
+                format!("${:04X}", value)
+            },
+            (Some(FLAGS_REFERENCE), name) => {
+                let (_, flag) = FLAGS.iter().find(|(n, _)| *n == name).ok_or("Unknown flag")?;
+                registers.flags.set(*flag, value != 0);
+                ((value != 0) as u8).to_string()
+            },
+            _ => return Err(format!("Unknown variable '{}'", name)),
+        };
+
+        cpu.set_registers(&registers);
+        Ok(json!({ "value": display }))
+    }
+
+    fn resume(&mut self, kind: StepKind, args: &Value) -> RequestResult {
+        let pc = self.machine()?.cpu.program_counter();
+        let start = self.source_map.line_at(pc);
+
+        // Step by instruction when asked to, or when there is no source line to step from
+        let by_instruction = args["granularity"].as_str() == Some("instruction") || start.is_none();
+        let kind = match kind {
+            StepKind::Line if by_instruction => StepKind::Instruction,
+            StepKind::LineOver if by_instruction => StepKind::InstructionOver,
+            kind => kind,
+        };
+
+        self.running = Some(Stepping { kind, depth: 0, start });
+        Ok(json!({ "allThreadsContinued": true }))
+    }
+
+    /// Undo instructions from the history, a single line or instruction, or until a breakpoint if `to_breakpoint` is set
+    fn reverse(&mut self, to_breakpoint: bool, args: &Value) -> RequestResult {
+        let by_instruction = args["granularity"].as_str() == Some("instruction");
+        let machine = self.machine.as_mut().ok_or("No program loaded")?;
+        if machine.cpu.history().is_none() {
+            return Err("Stepping back requires the 'history' launch argument".to_string());
+        }
+
+        let start = self.source_map.line_at(machine.cpu.program_counter());
+        let reason = loop {
+            if !machine.cpu.step_back(&mut machine.memory) {
+                break "entry";
+            }
+
+            let pc = machine.cpu.program_counter();
+            if self.breakpoints.contains(&pc) {
+                break "breakpoint";
+            }
+
+            let line_changed = start.is_none() || by_instruction
+                || (self.source_map.is_line_start(pc) && self.source_map.line_at(pc) != start);
+            if !to_breakpoint && line_changed {
+                break "step";
+            }
+        };
+
+        self.running = None;
+        self.pending_events.push(stopped_event(reason));
+        Ok(json!({}))
+    }
+
+    /// Run a command typed in the debug console
+    fn evaluate(&self, args: &Value) -> RequestResult {
+        let expression = args["expression"].as_str().unwrap_or_default();
+        let mut words = expression.split_whitespace();
+
+        let result = match words.next() {
+            Some("history") => {
+                let count = match words.next().map(str::parse) {
+                    None => 20,
+                    Some(Ok(count)) => count,
+                    Some(Err(_)) => return Err("Usage: history [count]".to_string()),
+                };
+
+                let history = self.machine()?.cpu.history().ok_or("History is not enabled, launch with the 'history' argument")?;
+                history.iter()
+                    .skip(history.len().saturating_sub(count))
+                    .map(|entry| entry.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            },
+            Some("whowrote") => {
+                let address = words.next()
+                    .and_then(|word| self.address(word))
+                    .ok_or("Usage: whowrote <address>")?;
+
+                let machine = self.machine()?;
+                let provenance = machine.cpu.provenance().ok_or("Write provenance is not enabled, launch with the 'provenance' argument")?;
+                let writes: Vec<String> = provenance.writes(address)
+                    .map(|record| {
+                        let instruction = Instruction::decode(&machine.memory, record.program_counter);
+                        format!("{}  {}", record, instruction.with_symbols(&self.symbols))
+                    })
+                    .collect();
+
+                if writes.is_empty() {
+                    format!("${:04X} was not written", address)
+                } else {
+                    writes.join("\n")
+                }
+            },
+            Some("symbol") => {
+                let name = words.next().ok_or("Usage: symbol <name>")?;
+                let address = self.symbols.address_of(name).ok_or_else(|| format!("Unknown symbol '{}'", name))?;
+                format_address(address)
+            },
+            _ => return Err(format!(
+                "Unknown command '{}', available commands: history [count], whowrote <address>, symbol <name>",
+                expression,
+            )),
+        };
+
+        Ok(json!({ "result": result, "variablesReference": 0 }))
+    }
+
+    fn pause(&mut self) -> RequestResult {
+        if self.running.take().is_some() {
+            self.pending_events.push(stopped_event("pause"));
+        }
+
+        Ok(json!({}))
+    }
+
+    fn read_memory(&self, args: &Value) -> RequestResult {
+        let machine = self.machine()?;
+        let address = memory_address(args)?;
+        let count = args["count"].as_u64().unwrap_or_default() as usize;
+        let readable = count.min(MAX_MEMORY - address as usize);
+
+        let bytes: Vec<u8> = (0..readable)
+            .map(|offset| machine.memory.peek(address + offset as u16))
+            .collect();
+
+        Ok(json!({
+            "address": format_address(address),
+            "data": encode_base64(&bytes),
+            "unreadableBytes": count - readable,
+        }))
+    }
+
+    fn write_memory(&mut self, args: &Value) -> RequestResult {
+        let address = memory_address(args)?;
+        let data = args["data"].as_str().and_then(decode_base64).ok_or("Invalid data")?;
+        let writable = data.len().min(MAX_MEMORY - address as usize);
+
+        let machine = self.machine_mut()?;
+        for (offset, byte) in data.iter().take(writable).enumerate() {
+            machine.memory.write(address + offset as u16, *byte);
+        }
+
+        Ok(json!({ "bytesWritten": writable }))
+    }
+
+    /// Execute up to [BATCH_SIZE] instructions, reporting a stop if one is reached
+    fn run_batch(&mut self) {
+        let mut stepping = match self.running {
+            Some(s) => s,
+            None => return,
+        };
+
+        let machine = match self.machine.as_mut() {
+            Some(m) => m,
+            None => {
+                self.running = None;
+                return;
+            }
+        };
+
+        for _ in 0..BATCH_SIZE {
+            let address = machine.cpu.program_counter();
+            let opcode = machine.memory.peek(address);
+            machine.cpu.step(&mut machine.memory);
+
+            match opcode {
+                JSR_ABSOLUTE | BRK_IMPLIED => stepping.depth += 1,
+                RTS_IMPLIED | RTI_IMPLIED => stepping.depth -= 1,
+                _ => {}
+            }
+
+            let pc = machine.cpu.program_counter();
+            if self.breakpoints.contains(&pc) {
+                self.stop("breakpoint");
+                return;
+            }
+            // An instruction jumping to itself, like `JMP *`, would never stop otherwise. `run` halts there too
+            if pc == address {
+                self.running = None;
+                let mut stopped = stopped_event("pause");
+                stopped["body"]["description"] = json!(format!("Halted at ${:04X}", pc));
+                self.send(stopped);
+                return;
+            }
+
+            let line_changed = || {
+                self.source_map.is_line_start(pc) && self.source_map.line_at(pc) != stepping.start
+            };
+
+            let done = match stepping.kind {
+                StepKind::Continue => false,
+                StepKind::Instruction => true,
+                StepKind::InstructionOver => stepping.depth <= 0,
+                StepKind::Out => stepping.depth < 0,
+                StepKind::Line => stepping.depth < 0 || line_changed(),
+                StepKind::LineOver => stepping.depth < 0 || (stepping.depth == 0 && line_changed()),
+            };
+
+            if done {
+                self.stop("step");
+                return;
+            }
+        }
+
+        self.running = Some(stepping);
+    }
+
+    fn stop(&mut self, reason: &str) {
+        self.running = None;
+        self.send(stopped_event(reason));
+    }
+
+    /// Parse an address typed by the user, written as a number or the name of a symbol
+    fn address(&self, s: &str) -> Option<u16> {
+        match parse_number(s) {
+            Some(address) => u16::try_from(address).ok(),
+            None => self.symbols.address_of(s),
+        }
+    }
+
+    fn machine(&self) -> std::result::Result<&Machine, String> {
+        self.machine.as_ref().ok_or_else(|| "No program loaded".to_string())
+    }
+
+    fn machine_mut(&mut self) -> std::result::Result<&mut Machine, String> {
+        self.machine.as_mut().ok_or_else(|| "No program loaded".to_string())
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        // The client going away is noticed when reading the next request
+        let _ = self.output.send(message);
+    }
+}
+
+fn event(name: &str, body: Value) -> Value {
+    json!({ "type": "event", "event": name, "body": body })
+}
+
+fn stopped_event(reason: &str) -> Value {
+    event("stopped", json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }))
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+fn format_address(address: u16) -> String {
+    format!("0x{:04X}", address)
+}
+
+/// The address `count` instructions before `address`. As instructions have different lengths, this is the furthest address
+/// from which decoding lands on `address` after `count` instructions, preferring ones which only decode supported opcodes
+fn instructions_before(memory: &dyn Memory<MAX_MEMORY>, address: u16, count: usize) -> u16 {
+    let lands = |start: u16, known_only: bool| {
+        let mut current = start;
+        for _ in 0..count {
+            let instruction = Instruction::decode(memory, current);
+            if known_only && !instruction.is_known() {
+                return false;
+            }
+            current = instruction.next_address();
+        }
+        current == address
+    };
+
+    let candidates = || (count..=count * 3).rev().map(|distance| address.wrapping_sub(distance as u16));
+    candidates().find(|start| lands(*start, true))
+        .or_else(|| candidates().find(|start| lands(*start, false)))
+        .unwrap_or_else(|| address.wrapping_sub(count as u16))
+}
+
+/// The address referred to by the `memoryReference` and `offset` arguments
+fn memory_address(args: &Value) -> std::result::Result<u16, String> {
+    let base = args["memoryReference"].as_str().and_then(parse_number).ok_or("Invalid memory reference")?;
+    let address = base as i64 + args["offset"].as_i64().unwrap_or_default();
+    u16::try_from(address).map_err(|_| "Address out of range".to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread::JoinHandle;
+    use emulator_6502_core::{SourceLine, MAX_MEMORY};
+    use serde_json::{json, Value};
+    use tempfile::NamedTempFile;
+    use super::Session;
+
+    /// ```text
+    /// 0200  LDA #$42
+    /// 0202  STA $10
+    /// 0204  JSR $020A
+    /// 0207  JMP $0207
+    /// 020A  INX
+    /// 020B  RTS
+    /// ```
+    const PROGRAM: [u8; 12] = [0xA9, 0x42, 0x85, 0x10, 0x20, 0x0A, 0x02, 0x4C, 0x07, 0x02, 0xE8, 0x60];
+
+    fn image() -> NamedTempFile {
+        let mut image = vec![0u8; MAX_MEMORY];
+        image[0x0200..0x0200 + PROGRAM.len()].copy_from_slice(&PROGRAM);
+        image[0xFFFC] = 0x00;
+        image[0xFFFD] = 0x02;
+
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&image).unwrap();
+        file
+    }
+
+    /// Drives a session on a background thread with scripted requests
+    struct Client {
+        requests: Sender<Value>,
+        output: Receiver<Value>,
+        events: Vec<Value>,
+        seq: i64,
+        handle: Option<JoinHandle<()>>,
+    }
+
+    impl Client {
+        fn new(session: impl FnOnce(Sender<Value>) -> Session) -> Self {
+            let (requests, request_rx) = channel();
+            let (output_tx, output) = channel();
+            let session = session(output_tx);
+            let handle = std::thread::spawn(move || session.run(request_rx));
+
+            Self { requests, output, events: Vec::new(), seq: 0, handle: Some(handle) }
+        }
+
+        /// Send a request and wait for its response, queueing events received in the meantime
+        fn request(&mut self, command: &str, arguments: Value) -> Value {
+            self.seq += 1;
+            self.requests.send(json!({ "seq": self.seq, "type": "request", "command": command, "arguments": arguments })).unwrap();
+
+            loop {
+                let message = self.output.recv().expect("Session ended");
+                if message["type"] == "response" && message["request_seq"] == self.seq {
+                    assert_eq!(message["command"], command);
+                    return message;
+                }
+                self.events.push(message);
+            }
+        }
+
+        fn event(&mut self, name: &str) -> Value {
+            if let Some(index) = self.events.iter().position(|e| e["event"] == name) {
+                return self.events.remove(index);
+            }
+
+            loop {
+                let message = self.output.recv().expect("Session ended");
+                if message["event"] == name {
+                    return message;
+                }
+                self.events.push(message);
+            }
+        }
+
+        fn register(&mut self, name: &str) -> String {
+            let variables = self.request("variables", json!({ "variablesReference": 1 }));
+            variables["body"]["variables"].as_array().unwrap().iter()
+                .find(|v| v["name"] == name)
+                .map(|v| v["value"].as_str().unwrap().to_string())
+                .unwrap()
+        }
+
+        fn pc(&mut self) -> String {
+            let trace = self.request("stackTrace", json!({ "threadId": 1 }));
+            trace["body"]["stackFrames"][0]["instructionPointerReference"].as_str().unwrap().to_string()
+        }
+
+        fn finish(mut self) {
+            assert_eq!(self.request("disconnect", json!({}))["success"], true);
+            self.event("terminated");
+            self.handle.take().unwrap().join().unwrap();
+        }
+    }
+
+    fn launch(client: &mut Client, image: &NamedTempFile) {
+        let initialize = client.request("initialize", json!({ "adapterID": "6502" }));
+        assert_eq!(initialize["body"]["supportsInstructionBreakpoints"], true);
+
+        let launch = client.request("launch", json!({ "program": image.path(), "stopOnEntry": true }));
+        assert_eq!(launch["success"], true);
+        client.event("initialized");
+    }
+
+    #[test]
+    fn stepping_and_breakpoints() {
+        let image = image();
+        let mut client = Client::new(Session::new);
+        launch(&mut client, &image);
+
+        let breakpoints = client.request("setInstructionBreakpoints", json!({ "breakpoints": [{ "instructionReference": "0x0207" }] }));
+        assert_eq!(breakpoints["body"]["breakpoints"][0]["verified"], true);
+
+        client.request("configurationDone", json!({}));
+        assert_eq!(client.event("stopped")["body"]["reason"], "entry");
+        assert_eq!(client.pc(), "0x0200");
+
+        client.request("stepIn", json!({ "threadId": 1 }));
+        assert_eq!(client.event("stopped")["body"]["reason"], "step");
+        assert_eq!(client.pc(), "0x0202");
+        assert_eq!(client.register("A"), "$42");
+
+        // Step over the store, then over the subroutine
+        client.request("next", json!({ "threadId": 1 }));
+        client.event("stopped");
+        client.request("next", json!({ "threadId": 1 }));
+        assert_eq!(client.event("stopped")["body"]["reason"], "breakpoint");
+        assert_eq!(client.pc(), "0x0207");
+        assert_eq!(client.register("X"), "$01");
+
+        let memory = client.request("readMemory", json!({ "memoryReference": "0x0010", "count": 1 }));
+        assert_eq!(memory["body"]["data"], "Qg==");
+
+        client.finish();
+    }
+
+    #[test]
+    fn step_out_and_variables() {
+        let image = image();
+        let mut client = Client::new(Session::new);
+        launch(&mut client, &image);
+
+        client.request("setFunctionBreakpoints", json!({ "breakpoints": [{ "name": "$020A" }] }));
+        client.request("configurationDone", json!({}));
+        client.event("stopped");
+
+        client.request("continue", json!({ "threadId": 1 }));
+        assert_eq!(client.event("stopped")["body"]["reason"], "breakpoint");
+        assert_eq!(client.pc(), "0x020A");
+
+        let set = client.request("setVariable", json!({ "variablesReference": 1, "name": "X", "value": "$10" }));
+        assert_eq!(set["body"]["value"], "$10");
+        let set = client.request("setVariable", json!({ "variablesReference": 2, "name": "C", "value": "1" }));
+        assert_eq!(set["body"]["value"], "1");
+
+        client.request("stepOut", json!({ "threadId": 1 }));
+        client.event("stopped");
+        assert_eq!(client.pc(), "0x0207");
+        assert_eq!(client.register("X"), "$11");
+        assert_eq!(client.register("P"), "$01");
+
+        client.request("writeMemory", json!({ "memoryReference": "0x0300", "data": "AQI=" }));
+        let memory = client.request("readMemory", json!({ "memoryReference": "0x0300", "offset": 1, "count": 1 }));
+        assert_eq!(memory["body"]["data"], "Ag==");
+
+        client.finish();
+    }
+
+    #[test]
+    fn pause() {
+        let image = image();
+        let mut client = Client::new(Session::new);
+        launch(&mut client, &image);
+
+        client.request("configurationDone", json!({}));
+        client.event("stopped");
+        // `JMP $0204`, calling the subroutine forever
+        client.request("writeMemory", json!({ "memoryReference": "0x0208", "data": "BA==" }));
+        client.request("continue", json!({ "threadId": 1 }));
+        client.request("pause", json!({ "threadId": 1 }));
+        assert_eq!(client.event("stopped")["body"]["reason"], "pause");
+
+        client.finish();
+    }
+
+    #[test]
+    fn halt() {
+        let image = image();
+        let mut client = Client::new(Session::new);
+        launch(&mut client, &image);
+
+        client.request("configurationDone", json!({}));
+        client.event("stopped");
+        client.request("continue", json!({ "threadId": 1 }));
+        let stopped = client.event("stopped");
+        assert_eq!((stopped["body"]["reason"].as_str(), stopped["body"]["description"].as_str()), (Some("pause"), Some("Halted at $0207")));
+        assert_eq!(client.pc(), "0x0207");
+        assert!(client.events.iter().all(|event| event["event"] != "capabilities"));
+
+        client.finish();
+    }
+
+    #[test]
+    fn source_lines() {
+        let image = image();
+        let mut client = Client::new(|output| {
+            let mut session = Session::new(output);
+            let file = session.source_map.add_file("src/main.s");
+            for (address, length, line) in [(0x0200, 2, 3), (0x0202, 2, 4), (0x0204, 3, 5), (0x0207, 3, 6), (0x020A, 1, 9), (0x020B, 1, 10)] {
+                session.source_map.add_span(address, length, SourceLine { file, line });
+            }
+            session
+        });
+        launch(&mut client, &image);
+
+        let breakpoints = client.request("setBreakpoints", json!({
+            "source": { "path": "/home/user/project/src/main.s" },
+            "breakpoints": [{ "line": 8 }, { "line": 20 }],
+        }));
+        assert_eq!(breakpoints["body"]["breakpoints"][0]["verified"], true);
+        assert_eq!(breakpoints["body"]["breakpoints"][0]["line"], 9);
+        assert_eq!(breakpoints["body"]["breakpoints"][1]["verified"], false);
+
+        let unknown = client.request("setBreakpoints", json!({ "source": { "path": "other.s" }, "breakpoints": [{ "line": 1 }] }));
+        assert_eq!(unknown["body"]["breakpoints"][0]["verified"], false);
+
+        client.request("configurationDone", json!({}));
+        client.event("stopped");
+        client.request("next", json!({ "threadId": 1 }));
+        client.event("stopped");
+
+        let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(trace["body"]["stackFrames"][0]["line"], 4);
+        assert_eq!(trace["body"]["stackFrames"][0]["source"]["path"], "src/main.s");
+
+        client.request("continue", json!({ "threadId": 1 }));
+        assert_eq!(client.event("stopped")["body"]["reason"], "breakpoint");
+        let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(trace["body"]["stackFrames"][0]["line"], 9);
+
+        client.finish();
+    }
+
+    #[test]
+    fn step_back() {
+        let image = image();
+        let mut client = Client::new(Session::new);
+        assert_eq!(client.request("initialize", json!({ "adapterID": "6502" }))["body"]["supportsStepBack"], Value::Null);
+        client.request("launch", json!({ "program": image.path(), "stopOnEntry": true, "history": 100, "provenance": 1 }));
+        assert_eq!(client.event("capabilities")["body"]["capabilities"]["supportsStepBack"], true);
+        client.request("setInstructionBreakpoints", json!({ "breakpoints": [{ "instructionReference": "0x0207" }] }));
+        client.request("configurationDone", json!({}));
+        client.event("stopped");
+
+        client.request("continue", json!({ "threadId": 1 }));
+        assert_eq!(client.event("stopped")["body"]["reason"], "breakpoint");
+
+        let history = client.request("evaluate", json!({ "expression": "history 2", "context": "repl" }));
+        let history = history["body"]["result"].as_str().unwrap().to_string();
+        let lines: Vec<&str> = history.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("020A  INX"));
+        assert!(lines[1].starts_with("020B  RTS"));
+
+        client.request("stepBack", json!({ "threadId": 1 }));
+        assert_eq!(client.event("stopped")["body"]["reason"], "step");
+        assert_eq!(client.pc(), "0x020B");
+        client.request("stepBack", json!({ "threadId": 1 }));
+        client.event("stopped");
+        assert_eq!(client.pc(), "0x020A");
+        assert_eq!(client.register("X"), "$00");
+
+        let who_wrote = client.request("evaluate", json!({ "expression": "whowrote $10", "context": "repl" }));
+        assert_eq!(who_wrote["body"]["result"], "$42 written by $0202 at cycle 2 (instruction 1)  STA $10");
+
+        client.request("reverseContinue", json!({ "threadId": 1 }));
+        assert_eq!(client.event("stopped")["body"]["reason"], "entry");
+        assert_eq!(client.pc(), "0x0200");
+        let memory = client.request("readMemory", json!({ "memoryReference": "0x0010", "count": 1 }));
+        assert_eq!(memory["body"]["data"], "AA==");
+        let who_wrote = client.request("evaluate", json!({ "expression": "whowrote $10", "context": "repl" }));
+        assert_eq!(who_wrote["body"]["result"], "$0010 was not written");
+
+        client.finish();
+    }
+
+    #[test]
+    fn symbols() {
+        let image = image();
+        let mut labels = NamedTempFile::new().unwrap();
+        labels.write_all(b"al C:0200 .start\nal C:020A .increment\nal C:0010 .result\n").unwrap();
+
+        let mut client = Client::new(Session::new);
+        client.request("initialize", json!({ "adapterID": "6502" }));
+        let launch = client.request("launch", json!({ "program": image.path(), "symbols": [labels.path()], "provenance": 1 }));
+        assert_eq!(launch["success"], true);
+
+        let breakpoints = client.request("setFunctionBreakpoints", json!({ "breakpoints": [{ "name": "increment" }, { "name": "missing" }] }));
+        assert_eq!(breakpoints["body"]["breakpoints"][0]["instructionReference"], "0x020A");
+        assert_eq!(breakpoints["body"]["breakpoints"][1]["verified"], false);
+
+        client.request("configurationDone", json!({}));
+        assert_eq!(client.event("stopped")["body"]["reason"], "breakpoint");
+        let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(trace["body"]["stackFrames"][0]["name"], "increment");
+
+        let symbol = client.request("evaluate", json!({ "expression": "symbol start", "context": "repl" }));
+        assert_eq!(symbol["body"]["result"], "0x0200");
+        let who_wrote = client.request("evaluate", json!({ "expression": "whowrote result", "context": "repl" }));
+        assert_eq!(who_wrote["body"]["result"], "$42 written by $0202 at cycle 2 (instruction 1)  STA result");
+
+        client.request("next", json!({ "threadId": 1 }));
+        client.event("stopped");
+        let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(trace["body"]["stackFrames"][0]["name"], "increment+1");
+
+        let response = client.request("launch", json!({ "program": image.path(), "symbols": "missing.lbl" }));
+        assert_eq!(response["success"], false);
+
+        client.finish();
+    }
+
+    #[test]
+    fn debug_file() {
+        let image = image();
+        let mut dbg = NamedTempFile::new().unwrap();
+        let spans: Vec<String> = [(0, 2), (2, 2), (4, 3), (7, 3), (10, 1), (11, 1)].iter().enumerate()
+            .map(|(id, (start, size))| format!("span\tid={},seg=0,start={},size={}\n", id, start, size))
+            .collect();
+        let lines: Vec<String> = [3, 4, 5, 6, 9, 10].iter().enumerate()
+            .map(|(id, line)| format!("line\tid={},file=0,line={},span={}\n", id, line, id))
+            .collect();
+        write!(
+            dbg,
+            "version\tmajor=2,minor=0\nfile\tid=0,name=\"src/main.s\",size=1,mtime=0,mod=0\n\
+             seg\tid=0,name=\"CODE\",start=0x000200,size=0x00000C,addrsize=absolute,type=ro\n{}{}\
+             sym\tid=0,name=\"main\",addrsize=absolute,scope=0,def=0,val=0x200,seg=0,type=lab\n\
+             sym\tid=1,name=\"increment\",addrsize=absolute,scope=0,def=4,val=0x20A,seg=0,type=lab\n",
+            spans.concat(),
+            lines.concat(),
+        ).unwrap();
+
+        let mut client = Client::new(Session::new);
+        assert_eq!(client.request("initialize", json!({ "adapterID": "6502" }))["body"]["supportsDisassembleRequest"], true);
+        client.request("launch", json!({ "program": image.path(), "dbgFile": dbg.path() }));
+        client.event("initialized");
+
+        let breakpoints = client.request("setBreakpoints", json!({ "source": { "path": "/project/src/main.s" }, "breakpoints": [{ "line": 8 }] }));
+        assert_eq!(breakpoints["body"]["breakpoints"][0]["instructionReference"], "0x020A");
+        client.request("configurationDone", json!({}));
+        assert_eq!(client.event("stopped")["body"]["reason"], "breakpoint");
+
+        let trace = client.request("stackTrace", json!({ "threadId": 1 }));
+        let frames = trace["body"]["stackFrames"].as_array().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0]["name"].as_str(), frames[0]["line"].as_u64()), (Some("increment"), Some(9)));
+        assert_eq!((frames[1]["name"].as_str(), frames[1]["line"].as_u64()), (Some("main+4"), Some(5)));
+        assert_eq!(frames[1]["instructionPointerReference"], "0x0204");
+
+        let disassembly = client.request("disassemble", json!({ "memoryReference": "0x020A", "instructionOffset": -2, "instructionCount": 4 }));
+        let instructions = disassembly["body"]["instructions"].as_array().unwrap();
+        let addresses: Vec<&str> = instructions.iter().map(|i| i["address"].as_str().unwrap()).collect();
+        assert_eq!(addresses, ["0x0204", "0x0207", "0x020A", "0x020B"]);
+        assert_eq!(instructions[0]["instruction"], "JSR increment");
+        assert_eq!(instructions[0]["instructionBytes"], "20 0A 02");
+        assert_eq!(instructions[2]["symbol"], "increment");
+        assert_eq!((instructions[2]["location"]["path"].as_str(), instructions[2]["line"].as_u64()), (Some("src/main.s"), Some(9)));
+
+        client.finish();
+    }
+
+    #[test]
+    fn requires_program() {
+        let mut client = Client::new(Session::new);
+        let response = client.request("stackTrace", json!({ "threadId": 1 }));
+        assert_eq!(response["success"], false);
+        assert_eq!(response["message"], "No program loaded");
+
+        let response = client.request("launch", json!({}));
+        assert_eq!(response["success"], false);
+
+        client.finish();
+    }
+}
@@ -0,0 +1,29 @@
+use std::path::{Path, PathBuf};
+use emulator_6502_core::{DbgFile, SourceMap, SymbolTable};
+use log::info;
+use crate::error::{Error, Result};
+use crate::image::Image;
+use crate::symbols::load_symbols;
+
+/// Load a debug file written by ld65 with `--dbgfile`
+pub fn load_dbg_file(path: &Path) -> Result<DbgFile> {
+    let text = std::fs::read_to_string(path)?;
+    let dbg = DbgFile::parse(&text).map_err(|error| Error::InvalidDbgFile { path: path.to_path_buf(), error })?;
+    info!("Loaded {} source files and {} symbols from {:?}", dbg.source_map.files().len(), dbg.symbols.len(), path);
+    Ok(dbg)
+}
+
+/// The source lines and symbols of an optional debug file, or else those of the image itself,
+/// with the symbols of label files added
+pub fn load_debug_info(image: &Image, dbgfile: Option<&Path>, symbol_files: &[PathBuf]) -> Result<(SourceMap, SymbolTable)> {
+    let (source_map, mut symbols) = match dbgfile {
+        Some(path) => {
+            let dbg = load_dbg_file(path)?;
+            (dbg.source_map, dbg.symbols)
+        },
+        None => (image.source_map.clone(), image.symbols.clone()),
+    };
+
+    symbols.extend(load_symbols(symbol_files)?.iter().cloned());
+    Ok((source_map, symbols))
+}
\ No newline at end of file
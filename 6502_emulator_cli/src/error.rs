@@ -0,0 +1,47 @@
+use std::path::PathBuf;
+use emulator_6502_core::{DbgFileError, ElfError, HexFileError, LoadError, Sim65Error, SymbolError, SysStubOverlap, TimingError};
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Failed to install the Ctrl-C handler: {0}")]
+    Signal(#[from] ctrlc::Error),
+    #[error("GDB stub error: {0}")]
+    Gdb(#[from] emulator_6502_gdb::Error),
+    #[error("{path}: memory images must be exactly {expected} bytes, got {actual}. Use --load-address to load a raw binary")]
+    ImageSize { path: PathBuf, expected: usize, actual: usize },
+    #[error("{path}: {error}")]
+    InvalidImage { path: PathBuf, error: HexFileError },
+    #[error("{path}: {error}")]
+    InvalidElf { path: PathBuf, error: ElfError },
+    #[error("{path}: {error}")]
+    InvalidSim65 { path: PathBuf, error: Sim65Error },
+    #[error("{path}: {error}")]
+    InvalidBinary { path: PathBuf, error: LoadError },
+    #[error("{0}: unknown output format, use --format ihex or --format srec")]
+    UnknownFormat(PathBuf),
+    #[error("{0}: no BASIC stub calling SYS to start at, give the address with --sys-address")]
+    NoSysAddress(PathBuf),
+    #[error("{path}: {error}")]
+    SysStubOverlap { path: PathBuf, error: SysStubOverlap },
+    #[error("{path}: line {line}: {message}")]
+    InvalidCases { path: PathBuf, line: usize, message: String },
+    #[error("{path}: {error}")]
+    InvalidManifest { path: PathBuf, error: toml::de::Error },
+    #[error("The routine can't be timed: {0}")]
+    Timing(TimingError),
+    #[error("Execution diverged from the reference trace at instruction {0}")]
+    TraceDiverged(usize),
+    #[error("{path}: {message}")]
+    InvalidLcov { path: PathBuf, message: String },
+    #[error("{path}: {error}")]
+    InvalidSymbols { path: PathBuf, error: SymbolError },
+    #[error("{path}: {error}")]
+    InvalidDbgFile { path: PathBuf, error: DbgFileError },
+}
\ No newline at end of file
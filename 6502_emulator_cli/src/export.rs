@@ -0,0 +1,21 @@
+use std::ops::RangeInclusive;
+use std::path::Path;
+use emulator_6502_core::{to_intel_hex, to_srec};
+use crate::error::{Error, Result};
+use crate::image::{load_image, HexFormat, ImageOpts};
+
+/// Write `ranges` of the program to `output` in the given format, or the one of the output's extension.
+/// Without ranges, everything the input has data for is written. The `entry` defaults to the one of the input
+pub fn export(image: &ImageOpts, output: &Path, format: Option<HexFormat>, ranges: &[RangeInclusive<u16>], entry: Option<u16>) -> Result<()> {
+    let format = format.or_else(|| HexFormat::from_path(output)).ok_or_else(|| Error::UnknownFormat(output.to_path_buf()))?;
+    let image = load_image(image)?;
+    let ranges = if ranges.is_empty() { &image.ranges } else { ranges };
+    let entry = entry.or(image.entry);
+
+    let text = match format {
+        HexFormat::IntelHex => to_intel_hex(&image.memory, ranges, entry),
+        HexFormat::Srec => to_srec(&image.memory, ranges, entry),
+    };
+    std::fs::write(output, text)?;
+    Ok(())
+}
\ No newline at end of file
@@ -0,0 +1,112 @@
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use emulator_6502_core::{Cpu, DEFAULT_MAX_CALL_DEPTH};
+use emulator_6502_gdb::GdbStub;
+use log::info;
+use structopt::StructOpt;
+use crate::dbgfile::load_debug_info;
+use crate::error::Result;
+use crate::image::{load_image, ImageOpts};
+
+#[derive(StructOpt)]
+pub struct GdbOpts {
+    #[structopt(flatten)]
+    pub image: ImageOpts,
+    /// Start at the start address record of an Intel HEX or S-record input, or the address the BASIC stub
+    /// of a `.prg` input calls with `SYS`, instead of the reset vector
+    #[structopt(long)]
+    pub use_entry: bool,
+    /// TCP address to listen on for the GDB client
+    #[structopt(short, long, default_value = "127.0.0.1:1234")]
+    pub listen: SocketAddr,
+    /// Listen on a Unix socket at this path instead of a TCP address
+    #[structopt(parse(from_os_str), long)]
+    pub unix: Option<PathBuf>,
+    /// Record the last N executed instructions, enabling reverse stepping and `monitor history`
+    #[structopt(long)]
+    pub history: Option<usize>,
+    /// Record the last N writes to every address, enabling `monitor whowrote`
+    #[structopt(long)]
+    pub provenance: Option<usize>,
+    /// A label file (VICE or ld65 `-Ln`, vasm listing, FCEUX `.nl`, Mesen `.mlb`) naming addresses in monitor commands.
+    /// May be given several times
+    #[structopt(parse(from_os_str), long)]
+    pub symbols: Vec<PathBuf>,
+    /// The debug file written by ld65 with `--dbgfile`, for source lines in monitor commands
+    #[structopt(parse(from_os_str), long)]
+    pub dbgfile: Option<PathBuf>,
+}
+
+/// Wait for a single GDB client on a TCP address or Unix socket and serve it.
+/// Reverse execution is available if a `history` capacity is given, write provenance if a `provenance` depth is given.
+/// The call graph is always enabled, for tracebacks with `monitor where`
+pub fn serve(opts: &GdbOpts) -> Result<()> {
+    let image = load_image(&opts.image)?;
+    let (source_map, symbols) = load_debug_info(&image, opts.dbgfile.as_deref(), &opts.symbols)?;
+    let mut cpu = Cpu::default();
+    image.start(&mut cpu, opts.use_entry);
+    if let Some(capacity) = opts.history {
+        cpu.enable_history(capacity);
+    }
+    if let Some(depth) = opts.provenance {
+        cpu.enable_provenance(depth);
+    }
+    cpu.enable_call_graph(DEFAULT_MAX_CALL_DEPTH);
+    let mut stub = GdbStub::new(cpu, image.memory);
+    stub.set_symbols(symbols);
+    stub.set_source_map(source_map);
+
+    match &opts.unix {
+        #[cfg(unix)]
+        Some(path) => {
+            let listener = std::os::unix::net::UnixListener::bind(path)?;
+            let _socket = SocketFile(path);
+            info!("Waiting for GDB on {:?}", path);
+            let (connection, _) = listener.accept()?;
+            stub.serve(connection)?;
+        },
+        #[cfg(not(unix))]
+        Some(_) => return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Unix sockets are not supported on this platform").into()),
+        None => {
+            let listener = TcpListener::bind(opts.listen)?;
+            info!("Waiting for GDB on {}", listener.local_addr()?);
+            let (connection, peer) = listener.accept()?;
+            info!("GDB connected from {}", peer);
+            connection.set_nodelay(true)?;
+            stub.serve(connection)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The file of a bound Unix socket, removed when dropped so it's also cleaned up when serving fails
+#[cfg(unix)]
+struct SocketFile<'a>(&'a std::path::Path);
+
+#[cfg(unix)]
+impl Drop for SocketFile<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(self.0) {
+            log::debug!("Failed to remove the socket {:?}: {}", self.0, e);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::os::unix::net::UnixListener;
+    use super::SocketFile;
+
+    #[test]
+    fn socket_file_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gdb.sock");
+        let _listener = UnixListener::bind(&path).unwrap();
+        {
+            let _socket = SocketFile(&path);
+            assert!(path.exists());
+        }
+        assert!(!path.exists());
+    }
+}
\ No newline at end of file
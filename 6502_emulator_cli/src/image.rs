@@ -0,0 +1,342 @@
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use emulator_6502_core::{parse_intel_hex, parse_srec, BasicMemory, Cpu, ElfFile, Prg, Program, Sim65Header, SourceMap, SymbolTable, Vector, MAX_MEMORY, SIM65_MAGIC};
+use log::info;
+use structopt::StructOpt;
+use crate::error::{Error, Result};
+use crate::parse::{parse_address, parse_number};
+
+/// How to build the memory image a program runs in
+#[derive(StructOpt, Default)]
+pub struct ImageOpts {
+    /// The program: an llvm-mos ELF executable, a cc65 sim65 binary, a 64 KiB memory image, an Intel HEX, S-record or Commodore `.prg` file
+    /// by its extension, or a raw binary of any length with `--load-address`
+    #[structopt(parse(from_os_str), short, long)]
+    pub input: PathBuf,
+    /// Load the input as a raw binary at this address
+    #[structopt(long, parse(try_from_str = parse_address))]
+    pub load_address: Option<u16>,
+    /// Leave out a header of this many bytes at the start of a raw binary input
+    #[structopt(long, default_value = "0")]
+    pub skip: usize,
+    /// Another raw binary to load, as `FILE@ADDRESS`, or `FILE@ADDRESS:SKIP` to leave out a header of SKIP bytes.
+    /// May be given several times, later files overwriting earlier ones
+    #[structopt(long = "load")]
+    pub binaries: Vec<Binary>,
+    /// Fill the memory which isn't loaded from a file with these repeating hexadecimal bytes, e.g. EA or 00FF
+    #[structopt(long)]
+    pub fill: Option<Fill>,
+    /// Point the reset vector at $FFFC at this address
+    #[structopt(long, parse(try_from_str = parse_address))]
+    pub reset_vector: Option<u16>,
+    /// Point the IRQ/BRK vector at $FFFE at this address
+    #[structopt(long, parse(try_from_str = parse_address))]
+    pub irq_vector: Option<u16>,
+    /// Point the NMI vector at $FFFA at this address
+    #[structopt(long, parse(try_from_str = parse_address))]
+    pub nmi_vector: Option<u16>,
+}
+
+/// A raw binary to load at an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binary {
+    pub path: PathBuf,
+    pub address: u16,
+    /// The length of a header to leave out
+    pub skip: usize,
+}
+
+impl FromStr for Binary {
+    type Err = String;
+
+    /// Parse `FILE@ADDRESS` or `FILE@ADDRESS:SKIP`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (path, location) = s.rsplit_once('@').ok_or_else(|| format!("'{}' is not a binary to load like file.bin@$C000", s))?;
+        let (address, skip) = match location.split_once(':') {
+            Some((address, skip)) => {
+                let skip = parse_number(skip).ok_or_else(|| format!("'{}' is not a header length", skip))?;
+                (address, skip as usize)
+            },
+            None => (location, 0),
+        };
+
+        Ok(Self { path: PathBuf::from(path), address: parse_address(address)?, skip })
+    }
+}
+
+/// A pattern of bytes to fill memory with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fill(pub Vec<u8>);
+
+impl FromStr for Fill {
+    type Err = String;
+
+    /// Parse hexadecimal bytes like `EA`, `$EA` or `0x00FF`
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let digits = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")).unwrap_or(s);
+        if digits.is_empty() || !digits.len().is_multiple_of(2) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("'{}' is not a pattern of hexadecimal bytes like EA or 00FF", s));
+        }
+
+        Ok(Self((0..digits.len()).step_by(2).map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap_or_default()).collect()))
+    }
+}
+
+/// A file format which places data at addresses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HexFormat {
+    IntelHex,
+    Srec,
+}
+
+impl HexFormat {
+    /// The format of a file by its extension: `.hex`, `.ihex` and `.ihx` for Intel HEX,
+    /// `.srec`, `.s19`, `.s28`, `.s37` and `.mot` for S-records
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "hex" | "ihex" | "ihx" => Some(Self::IntelHex),
+            "srec" | "s19" | "s28" | "s37" | "mot" => Some(Self::Srec),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for HexFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ihex" => Ok(Self::IntelHex),
+            "srec" => Ok(Self::Srec),
+            _ => Err(format!("'{}' is not one of the formats ihex and srec", s)),
+        }
+    }
+}
+
+/// A program loaded into memory
+pub struct Image {
+    pub memory: BasicMemory,
+    /// The start address recorded in the file, if its format has one.
+    /// For a `.prg` file, this is the address its BASIC stub starts with `SYS`
+    pub entry: Option<u16>,
+    /// The address ranges the file contained data for
+    pub ranges: Vec<RangeInclusive<u16>>,
+    /// The source lines of an ELF executable with DWARF line tables
+    pub source_map: SourceMap,
+    /// The symbol table of an ELF executable
+    pub symbols: SymbolTable,
+    /// The header of a binary built with `cl65 -t sim6502`
+    pub sim65: Option<Sim65Header>,
+}
+
+impl Image {
+    fn load_program(&mut self, program: &Program) {
+        program.load_into(&mut self.memory);
+        self.entry = program.entry;
+        self.ranges.extend(program.segments.iter()
+            .filter(|segment| !segment.data.is_empty())
+            .map(|segment| segment.address..=segment.address + (segment.data.len() - 1) as u16));
+    }
+
+    fn load_binary(&mut self, binary: &Binary) -> Result<()> {
+        let bytes = std::fs::read(&binary.path)?;
+        let length = self.memory.load(binary.address, &bytes, binary.skip)
+            .map_err(|error| Error::InvalidBinary { path: binary.path.clone(), error })?;
+        if length > 0 {
+            self.ranges.push(binary.address..=binary.address + (length - 1) as u16);
+        }
+        Ok(())
+    }
+
+    /// Point `cpu` at the start address of the file if `use_entry` is set and the file has one,
+    /// or else at the reset vector
+    pub fn start(&self, cpu: &mut Cpu, use_entry: bool) {
+        match self.entry.filter(|_| use_entry) {
+            Some(entry) => cpu.set_program_counter(entry),
+            None => cpu.load_reset_vector(&self.memory),
+        }
+    }
+}
+
+/// Build the memory image: fill it, load the input and any further binaries, then write the vectors.
+/// The input is loaded as a raw binary if a load address is given, as Intel HEX, S-records or a `.prg` file
+/// by its extension, as an ELF executable or sim65 binary by its contents, or else as a full 64 KiB memory image
+pub fn load_image(opts: &ImageOpts) -> Result<Image> {
+    let fill = opts.fill.as_ref().map(|fill| fill.0.as_slice()).unwrap_or_default();
+    let mut image = Image {
+        memory: BasicMemory::filled(fill),
+        entry: None,
+        ranges: Vec::new(),
+        source_map: SourceMap::new(),
+        symbols: SymbolTable::new(),
+        sim65: None,
+    };
+    let path = opts.input.as_path();
+
+    match (opts.load_address, HexFormat::from_path(path)) {
+        (Some(address), _) => image.load_binary(&Binary { path: path.to_path_buf(), address, skip: opts.skip })?,
+        (None, _) if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("prg")) => {
+            let bytes = std::fs::read(path)?;
+            let prg = Prg::parse(&bytes).map_err(|error| Error::InvalidBinary { path: path.to_path_buf(), error })?;
+            image.load_binary(&Binary { path: path.to_path_buf(), address: prg.load_address, skip: 2 })?;
+            image.entry = prg.sys_address();
+        },
+        (None, Some(format)) => {
+            let text = std::fs::read_to_string(path)?;
+            let program = match format {
+                HexFormat::IntelHex => parse_intel_hex(&text),
+                HexFormat::Srec => parse_srec(&text),
+            };
+            image.load_program(&program.map_err(|error| Error::InvalidImage { path: path.to_path_buf(), error })?);
+        },
+        (None, None) => {
+            let bytes = std::fs::read(path)?;
+            if bytes.starts_with(b"\x7fELF") {
+                let elf = ElfFile::parse(&bytes).map_err(|error| Error::InvalidElf { path: path.to_path_buf(), error })?;
+                info!("Loaded {} source files and {} symbols from {:?}", elf.source_map.files().len(), elf.symbols.len(), path);
+                image.load_program(&elf.program);
+                image.source_map = elf.source_map;
+                image.symbols = elf.symbols;
+            } else if bytes.starts_with(SIM65_MAGIC) {
+                let header = Sim65Header::parse(&bytes).map_err(|error| Error::InvalidSim65 { path: path.to_path_buf(), error })?;
+                image.load_binary(&Binary { path: path.to_path_buf(), address: header.load_address, skip: Sim65Header::SIZE })?;
+                image.memory.write_vector(Vector::Reset, header.reset_address);
+                image.entry = Some(header.reset_address);
+                image.sim65 = Some(header);
+            } else if bytes.len() == MAX_MEMORY {
+                image.memory = BasicMemory::from(bytes.as_slice());
+                image.ranges.push(0x0000..=0xFFFF);
+            } else {
+                return Err(Error::ImageSize { path: path.to_path_buf(), expected: MAX_MEMORY, actual: bytes.len() });
+            }
+        },
+    }
+
+    for binary in &opts.binaries {
+        image.load_binary(binary)?;
+    }
+    for (vector, target) in [(Vector::Reset, opts.reset_vector), (Vector::Irq, opts.irq_vector), (Vector::Nmi, opts.nmi_vector)] {
+        if let Some(target) = target {
+            image.memory.write_vector(vector, target);
+            image.ranges.push(vector.address()..=vector.address() + 1);
+        }
+    }
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+    use emulator_6502_core::{Cpu, Memory};
+    use super::{load_image, Binary, Fill, HexFormat, ImageOpts};
+
+    #[test]
+    fn formats_by_extension() {
+        assert_eq!(HexFormat::from_path(Path::new("rom.HEX")), Some(HexFormat::IntelHex));
+        assert_eq!(HexFormat::from_path(Path::new("rom.s19")), Some(HexFormat::Srec));
+        assert_eq!(HexFormat::from_path(Path::new("rom.bin")), None);
+        assert_eq!("SREC".parse(), Ok(HexFormat::Srec));
+        assert!("bin".parse::<HexFormat>().is_err());
+    }
+
+    #[test]
+    fn entry_point() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("program.hex");
+        std::fs::write(&path, ":03020000A9018AC7\n:02FFFC00000300\n:0400000500000200F5\n:00000001FF\n").unwrap();
+
+        let opts = ImageOpts { input: path.clone(), ..Default::default() };
+        let image = load_image(&opts).unwrap();
+        assert_eq!(image.memory.read(0x0200), 0xA9);
+        assert_eq!(image.entry, Some(0x0200));
+        assert_eq!(image.ranges, [0x0200..=0x0202, 0xFFFC..=0xFFFD]);
+
+        let mut cpu = Cpu::default();
+        image.start(&mut cpu, false);
+        assert_eq!(cpu.program_counter(), 0x0300);
+        image.start(&mut cpu, true);
+        assert_eq!(cpu.program_counter(), 0x0200);
+
+        std::fs::write(&path, ":03020000A9018AC6\n:00000001FF\n").unwrap();
+        let error = load_image(&opts).err().unwrap().to_string();
+        assert!(error.ends_with("program.hex: line 1: checksum is C6, but should be C7"), "{}", error);
+    }
+
+    #[test]
+    fn raw_binaries() {
+        assert_eq!("rom.bin@$E000".parse(), Ok(Binary { path: PathBuf::from("rom.bin"), address: 0xE000, skip: 0 }));
+        assert_eq!("c:/a@b.nes@0x8000:16".parse(), Ok(Binary { path: PathBuf::from("c:/a@b.nes"), address: 0x8000, skip: 16 }));
+        assert!("rom.bin".parse::<Binary>().is_err());
+        assert_eq!("$EA".parse(), Ok(Fill(vec![0xEA])));
+        assert!("EAE".parse::<Fill>().is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let program = dir.path().join("program.prg");
+        let font = dir.path().join("font.bin");
+        std::fs::write(&program, [0x01, 0x08, 0xA9, 0x01]).unwrap();
+        std::fs::write(&font, [0x18; 8]).unwrap();
+
+        let opts = ImageOpts {
+            input: program,
+            load_address: Some(0x0801),
+            skip: 2,
+            binaries: vec![Binary { path: font.clone(), address: 0x2000, skip: 0 }],
+            fill: Some(Fill(vec![0xEA])),
+            reset_vector: Some(0x0801),
+            irq_vector: None,
+            nmi_vector: Some(0x2000),
+        };
+        let image = load_image(&opts).unwrap();
+        assert_eq!((image.memory.read(0x0801), image.memory.read(0x0802)), (0xA9, 0x01));
+        assert_eq!((image.memory.read(0x0803), image.memory.read(0x2007)), (0xEA, 0x18));
+        assert_eq!(image.ranges, [0x0801..=0x0802, 0x2000..=0x2007, 0xFFFC..=0xFFFD, 0xFFFA..=0xFFFB]);
+        let mut cpu = Cpu::default();
+        image.start(&mut cpu, true);
+        assert_eq!(cpu.program_counter(), 0x0801);
+
+        let opts = ImageOpts { input: font.clone(), load_address: Some(0xFFFC), ..Default::default() };
+        let error = load_image(&opts).err().unwrap().to_string();
+        assert!(error.ends_with("font.bin: 8 bytes at $FFFC don't fit in memory, only 4 bytes are left"), "{}", error);
+        let error = load_image(&ImageOpts { input: font, ..Default::default() }).err().unwrap().to_string();
+        assert!(error.contains("--load-address"), "{}", error);
+    }
+
+    #[test]
+    fn prg_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hello.PRG");
+        std::fs::write(&path, [0x01, 0x08, 0x0B, 0x08, 0x0A, 0x00, 0x9E, b'2', b'0', b'6', b'1', 0x00, 0x00, 0x00, 0x60]).unwrap();
+
+        let image = load_image(&ImageOpts { input: path.clone(), ..Default::default() }).unwrap();
+        assert_eq!(image.memory.read(0x0801), 0x0B);
+        assert_eq!(image.memory.read(0x080D), 0x60);
+        assert_eq!(image.entry, Some(2061));
+        assert_eq!(image.ranges, [0x0801..=0x080D]);
+
+        std::fs::write(&path, [0x01]).unwrap();
+        let error = load_image(&ImageOpts { input: path, ..Default::default() }).err().unwrap().to_string();
+        assert!(error.ends_with("hello.PRG: the 2 byte header is longer than the 1 byte binary"), "{}", error);
+    }
+
+    #[test]
+    fn sim65_binaries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hello");
+        std::fs::write(&path, b"sim65\x02\x00\x80\x00\x02\x01\x02\xEA\x60").unwrap();
+
+        let image = load_image(&ImageOpts { input: path.clone(), ..Default::default() }).unwrap();
+        assert_eq!(image.memory.read(0x0200), 0xEA);
+        assert_eq!(image.ranges, [0x0200..=0x0201]);
+        assert_eq!(image.sim65.map(|header| header.stack_pointer), Some(0x80));
+        let mut cpu = Cpu::default();
+        image.start(&mut cpu, false);
+        assert_eq!(cpu.program_counter(), 0x0201);
+
+        std::fs::write(&path, b"sim65\x02\x01\x80\x00\x02\x01\x02").unwrap();
+        let error = load_image(&ImageOpts { input: path, ..Default::default() }).err().unwrap().to_string();
+        assert!(error.ends_with("hello: the binary is built for CPU type 1, only the 6502 (0) is supported"), "{}", error);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use emulator_6502_core::{AddressingMode, Coverage, Instruction, Memory, SourceMap, MAX_MEMORY};
+
+/// Coverage of a single source file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct FileRecord {
+    /// Execution count per line
+    lines: BTreeMap<u32, u64>,
+    /// Count per `(line, block, branch)`, `None` if the branch instruction was never executed
+    branches: BTreeMap<(u32, u32, u32), Option<u64>>,
+}
+
+/// An lcov tracefile, as read by `genhtml` and most coverage viewers.
+/// Every branch instruction is a block with two branches: taken (0) and not taken (1)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lcov {
+    files: BTreeMap<String, FileRecord>,
+}
+
+impl Lcov {
+    /// Map the coverage of a run to the lines in `source_map`. A line counts as executed if any instruction generated from it was.
+    /// Branches are only found in lines executed from their first byte, so never executed code has no branch records
+    pub fn from_coverage(coverage: &Coverage, source_map: &SourceMap, memory: &dyn Memory<MAX_MEMORY>) -> Self {
+        let mut lcov = Self::default();
+        let mut line_spans: BTreeMap<(usize, u32), u32> = BTreeMap::new();
+
+        for (start, length, source) in source_map.spans() {
+            let record = lcov.files.entry(source_map.files()[source.file].clone()).or_default();
+            let end = start as u32 + length as u32;
+
+            let executed = (start as u32..end).any(|address| coverage.is_executed(address as u16));
+            let count = record.lines.entry(source.line).or_default();
+            *count = (*count).max(executed as u64);
+
+            // Every span of a line is a block, numbered whether it ran or not so the numbers are the same across runs
+            let spans = line_spans.entry((source.file, source.line)).or_default();
+            let block = *spans;
+            *spans += 1;
+
+            // A span which wasn't executed from its start may be data, so it isn't decoded
+            if !coverage.is_executed(start) {
+                continue;
+            }
+            let mut address = start as u32;
+            let mut branch = 0;
+            while address < end {
+                let instruction = Instruction::decode(memory, address as u16);
+                if instruction.mode == AddressingMode::Relative {
+                    let counts = coverage.branch(instruction.address);
+                    record.branches.insert((source.line, block, branch), counts.map(|c| c.taken));
+                    record.branches.insert((source.line, block, branch + 1), counts.map(|c| c.not_taken));
+                    branch += 2;
+                }
+                address += instruction.length() as u32;
+            }
+        }
+
+        lcov
+    }
+
+    /// Parse a tracefile. Records other than lines and branches, like functions, are skipped
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lcov = Self::default();
+        let mut current: Option<(String, FileRecord)> = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let invalid = || format!("Invalid record on line {}: '{}'", index + 1, line);
+            let (tag, value) = line.trim().split_once(':').unwrap_or((line.trim(), ""));
+
+            match tag {
+                "SF" => current = Some((value.to_string(), FileRecord::default())),
+                "DA" => {
+                    let record = &mut current.as_mut().ok_or_else(invalid)?.1;
+                    let mut fields = value.split(',');
+                    let line = fields.next().and_then(|f| f.parse().ok()).ok_or_else(invalid)?;
+                    let count: u64 = fields.next().and_then(|f| f.parse().ok()).ok_or_else(invalid)?;
+                    *record.lines.entry(line).or_default() += count;
+                },
+                "BRDA" => {
+                    let record = &mut current.as_mut().ok_or_else(invalid)?.1;
+                    let fields: Vec<&str> = value.split(',').collect();
+                    let number = |i: usize| fields.get(i).and_then(|f| f.parse().ok()).ok_or_else(invalid);
+                    let key = (number(0)?, number(1)?, number(2)?);
+                    let taken = match fields.get(3) {
+                        Some(&"-") => None,
+                        Some(f) => Some(f.parse().map_err(|_| invalid())?),
+                        None => return Err(invalid()),
+                    };
+                    record.branches.insert(key, taken);
+                },
+                "end_of_record" => {
+                    let (path, record) = current.take().ok_or_else(invalid)?;
+                    lcov.merge_file(path, &record);
+                },
+                _ => {}
+            }
+        }
+
+        Ok(lcov)
+    }
+
+    /// Add the counts of another tracefile
+    pub fn merge(&mut self, other: &Lcov) {
+        for (path, record) in &other.files {
+            self.merge_file(path.clone(), record);
+        }
+    }
+
+    fn merge_file(&mut self, path: String, other: &FileRecord) {
+        let record = self.files.entry(path).or_default();
+        for (line, count) in &other.lines {
+            *record.lines.entry(*line).or_default() += count;
+        }
+
+        for (key, taken) in &other.branches {
+            let merged = record.branches.entry(*key).or_default();
+            *merged = match (*merged, taken) {
+                (Some(a), Some(b)) => Some(a + b),
+                (a, b) => a.or(*b),
+            };
+        }
+    }
+}
+
+impl fmt::Display for Lcov {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (path, record) in &self.files {
+            writeln!(f, "TN:")?;
+            writeln!(f, "SF:{}", path)?;
+
+            for ((line, block, branch), taken) in &record.branches {
+                match taken {
+                    Some(count) => writeln!(f, "BRDA:{},{},{},{}", line, block, branch, count)?,
+                    None => writeln!(f, "BRDA:{},{},{},-", line, block, branch)?,
+                }
+            }
+            writeln!(f, "BRF:{}", record.branches.len())?;
+            writeln!(f, "BRH:{}", record.branches.values().filter(|t| t.is_some_and(|c| c > 0)).count())?;
+
+            for (line, count) in &record.lines {
+                writeln!(f, "DA:{},{}", line, count)?;
+            }
+            writeln!(f, "LF:{}", record.lines.len())?;
+            writeln!(f, "LH:{}", record.lines.values().filter(|c| **c > 0).count())?;
+            writeln!(f, "end_of_record")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use emulator_6502_core::{BasicMemory, Cpu, SourceLine, SourceMap, BNE_RELATIVE, DEX_IMPLIED, LDX_IMMEDIATE, NOP_IMPLIED};
+    use super::Lcov;
+
+    /// ```text
+    /// 1  LDX #$02
+    /// 2  loop: DEX
+    /// 3  BNE loop
+    /// 4  NOP
+    /// 5  NOP   ; never reached
+    /// 6  .byte $D0, $00   ; data which decodes as a branch
+    /// ```
+    fn run() -> (Cpu, BasicMemory, SourceMap) {
+        let mut memory = BasicMemory::default();
+        let program = [LDX_IMMEDIATE, 0x02, DEX_IMPLIED, BNE_RELATIVE, 0xFD, NOP_IMPLIED, NOP_IMPLIED, BNE_RELATIVE, 0x00];
+        memory.load(0x0200, &program, 0).unwrap();
+
+        let mut source_map = SourceMap::new();
+        let file = source_map.add_file("main.s");
+        for (address, length, line) in [(0x0200, 2, 1), (0x0202, 1, 2), (0x0203, 2, 3), (0x0205, 1, 4), (0x0206, 1, 5), (0x0207, 2, 6)] {
+            source_map.add_span(address, length, SourceLine { file, line });
+        }
+
+        let mut cpu = Cpu::default();
+        cpu.set_program_counter(0x0200);
+        cpu.enable_coverage();
+        for _ in 0..6 {
+            cpu.step(&mut memory);
+        }
+
+        (cpu, memory, source_map)
+    }
+
+    const EXPECTED: &str = "\
+TN:
+SF:main.s
+BRDA:3,0,0,1
+BRDA:3,0,1,1
+BRF:2
+BRH:2
+DA:1,1
+DA:2,1
+DA:3,1
+DA:4,1
+DA:5,0
+DA:6,0
+LF:6
+LH:4
+end_of_record
+";
+
+    #[test]
+    fn tracefile() {
+        let (cpu, memory, source_map) = run();
+        let lcov = Lcov::from_coverage(cpu.coverage().unwrap(), &source_map, &memory);
+        assert_eq!(lcov.to_string(), EXPECTED);
+        assert_eq!(Lcov::parse(EXPECTED), Ok(lcov));
+    }
+
+    #[test]
+    fn blocks() {
+        // A macro used twice on line 1, expanding to `LDX #$01` and `BNE *+2`. Only the second use runs
+        let mut memory = BasicMemory::default();
+        let program = [LDX_IMMEDIATE, 0x01, BNE_RELATIVE, 0x00, LDX_IMMEDIATE, 0x01, BNE_RELATIVE, 0x00];
+        memory.load(0x0200, &program, 0).unwrap();
+        let mut source_map = SourceMap::new();
+        let file = source_map.add_file("macro.s");
+        source_map.add_span(0x0200, 4, SourceLine { file, line: 1 });
+        source_map.add_span(0x0204, 4, SourceLine { file, line: 1 });
+
+        let mut cpu = Cpu::default();
+        cpu.set_program_counter(0x0204);
+        cpu.enable_coverage();
+        cpu.step(&mut memory);
+        cpu.step(&mut memory);
+
+        // The branch of the second use is in block 1, as it is when both uses run
+        let lcov = Lcov::from_coverage(cpu.coverage().unwrap(), &source_map, &memory).to_string();
+        assert!(lcov.contains("BRDA:1,1,0,1\nBRDA:1,1,1,0\nBRF:2\n"), "{}", lcov);
+    }
+
+    #[test]
+    fn merge() {
+        let mut lcov = Lcov::parse(EXPECTED).unwrap();
+        let other = Lcov::parse("SF:main.s\nDA:5,1\nBRDA:3,0,0,-\nBRDA:7,0,0,-\nend_of_record\n").unwrap();
+        lcov.merge(&other);
+
+        let merged = lcov.to_string();
+        assert!(merged.contains("DA:5,1\n"));
+        assert!(merged.contains("LH:5\n"));
+        assert!(merged.contains("BRDA:3,0,0,1\n"));
+        assert!(merged.contains("BRDA:7,0,0,-\n"));
+
+        assert!(Lcov::parse("DA:1,1").is_err());
+        assert!(Lcov::parse("SF:a.s\nDA:x,1").is_err());
+    }
+}
\ No newline at end of file
@@ -1,7 +1,59 @@
+use emulator_6502_core::TraceDiffOptions;
+use log::error;
+use crate::error::Result;
+use crate::opts::{Command, Opts};
+
+mod console;
+mod coverage;
+mod dap;
+mod dbgfile;
+mod error;
+mod export;
+mod gdb;
+mod image;
+mod lcov;
 mod opts;
+mod parse;
+mod profile;
+mod run;
+mod sim65;
+mod suite;
+mod symbols;
+mod timing;
+mod trace;
+mod tracediff;
 
 fn main() {
     log_init();
+
+    let opts = Opts::new();
+    match run(opts) {
+        Ok(0) => {},
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        },
+    }
+}
+
+/// Run a command, returning the exit code for the process
+fn run(opts: Opts) -> Result<i32> {
+    match opts.command {
+        Command::Run(opts) => return run::run(&opts),
+        Command::Gdb(opts) => gdb::serve(&opts)?,
+        Command::Tracediff { image, reference, entry, ignore, ignore_flags, context } => {
+            let options = TraceDiffOptions { ignored_fields: ignore, ignored_flags: ignore_flags, context };
+            tracediff::run(&image, &reference, entry, &options)?;
+        },
+        Command::Export { image, output, format, ranges, entry } => export::export(&image, &output, format, &ranges, entry)?,
+        Command::Timing(opts) => return timing::run(&opts),
+        Command::Test(opts) => return suite::run(&opts),
+        Command::MergeCoverage { output, inputs } => coverage::merge(&inputs, &output)?,
+        Command::Dap => dap::serve_stdio()?,
+    }
+
+    Ok(0)
 }
 
 fn log_init() {
@@ -1,10 +1,81 @@
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
+use emulator_6502_core::TraceField;
 use structopt::StructOpt;
+use crate::image::{HexFormat, ImageOpts};
+use crate::parse::{parse_address, parse_address_range, parse_flag_letters, parse_trace_field};
+use crate::gdb::GdbOpts;
+use crate::run::RunOpts;
+use crate::suite::TestOpts;
+use crate::timing::TimingOpts;
 
 #[derive(StructOpt)]
 pub struct Opts {
-    #[structopt(parse(from_os_str), short, long)]
-    pub input: PathBuf
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+#[derive(StructOpt)]
+pub enum Command {
+    /// Run a program, starting at the reset vector, until it halts with a jump to itself
+    Run(RunOpts),
+    /// Run a program under control of a GDB client
+    Gdb(GdbOpts),
+    /// Run a 64 KiB memory image in lockstep with a reference trace, like nestest.log, and report the first divergence
+    Tracediff {
+        #[structopt(flatten)]
+        image: ImageOpts,
+        /// The reference trace to compare against
+        #[structopt(parse(from_os_str), short, long)]
+        reference: PathBuf,
+        /// Start executing at this address instead of the reset vector, e.g. $C000 for nestest
+        #[structopt(long, parse(try_from_str = parse_address))]
+        entry: Option<u16>,
+        /// Columns not to compare, e.g. CYC,SP
+        #[structopt(long, use_delimiter = true, parse(try_from_str = parse_trace_field))]
+        ignore: Vec<TraceField>,
+        /// Status register flags not to compare, e.g. BV
+        #[structopt(long, default_value = "", parse(try_from_str = parse_flag_letters))]
+        ignore_flags: u8,
+        /// How many instructions to show around the divergence
+        #[structopt(long, default_value = "5")]
+        context: usize,
+    },
+    /// Write address ranges of a program as Intel HEX or S-records
+    Export {
+        #[structopt(flatten)]
+        image: ImageOpts,
+        /// The file to write
+        #[structopt(parse(from_os_str), short, long)]
+        output: PathBuf,
+        /// `ihex` or `srec`, chosen by the extension of the output file if not given
+        #[structopt(long)]
+        format: Option<HexFormat>,
+        /// An address range to write, e.g. $C000-$FFFF. May be given several times.
+        /// Defaults to the ranges the input has data for, which is all of memory for a memory image
+        #[structopt(long = "range", parse(try_from_str = parse_address_range))]
+        ranges: Vec<RangeInclusive<u16>>,
+        /// The start address to record, defaulting to the one of the input
+        #[structopt(long, parse(try_from_str = parse_address))]
+        entry: Option<u16>,
+    },
+    /// Run a routine over a set of inputs, or all of them, and report the cycles it takes and the branches
+    /// and page crossings making them vary
+    Timing(TimingOpts),
+    /// Run the cases of a TOML manifest in parallel, checking the registers and memory each one ends with.
+    /// Prints a summary and can write JUnit XML and TAP reports for CI
+    Test(TestOpts),
+    /// Merge lcov tracefiles of several runs into one
+    MergeCoverage {
+        /// The merged tracefile to write
+        #[structopt(parse(from_os_str), short, long)]
+        output: PathBuf,
+        /// The tracefiles to merge
+        #[structopt(parse(from_os_str), required = true)]
+        inputs: Vec<PathBuf>,
+    },
+    /// Serve the Debug Adapter Protocol on stdin and stdout, for debugging from an editor
+    Dap,
 }
 
 impl Opts {
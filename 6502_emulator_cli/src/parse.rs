@@ -0,0 +1,130 @@
+use std::ops::RangeInclusive;
+use emulator_6502_core::{flag_bit, ConsoleAddresses, Location, TraceField};
+
+/// Parse a number written as `$C000`, `0xC000` or `49152`
+pub fn parse_number(s: &str) -> Option<u32> {
+    let s = s.trim();
+    if let Some(hex) = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")).or_else(|| s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Parse an address, for use as a command line argument
+pub fn parse_address(s: &str) -> Result<u16, String> {
+    parse_number(s)
+        .and_then(|n| u16::try_from(n).ok())
+        .ok_or_else(|| format!("'{}' is not an address between $0000 and $FFFF", s))
+}
+
+/// Parse a byte, for use as a command line argument
+pub fn parse_byte(s: &str) -> Result<u8, String> {
+    parse_number(s)
+        .and_then(|n| u8::try_from(n).ok())
+        .ok_or_else(|| format!("'{}' is not a byte between $00 and $FF", s))
+}
+
+/// Parse where the registers of a console are: `py65`, `kowalski` or the output and input addresses as `OUT:IN`
+pub fn parse_console(s: &str) -> Result<ConsoleAddresses, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "py65" => Ok(ConsoleAddresses::PY65),
+        "kowalski" => Ok(ConsoleAddresses::KOWALSKI),
+        _ => {
+            let (output, input) = s.split_once(':').ok_or_else(|| format!("'{}' is not py65, kowalski or a pair of addresses like $F001:$F004", s))?;
+            Ok(ConsoleAddresses { output: parse_address(output)?, input: parse_address(input)? })
+        },
+    }
+}
+
+/// Parse an inclusive address range written as `start-end`, e.g. `$C000-$C0FF`
+pub fn parse_address_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = s.split_once('-').ok_or_else(|| format!("'{}' is not a range like $C000-$C0FF", s))?;
+    let (start, end) = (parse_address(start)?, parse_address(end)?);
+    if start > end {
+        return Err(format!("The range '{}' ends before it starts", s));
+    }
+
+    Ok(start..=end)
+}
+
+/// Parse a register, the carry or a memory address holding an input of a routine, e.g. `x`, `c` or `$80`
+pub fn parse_location(s: &str) -> Result<Location, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "a" => Ok(Location::Accumulator),
+        "x" => Ok(Location::X),
+        "y" => Ok(Location::Y),
+        "c" => Ok(Location::Carry),
+        _ => parse_address(s).map(Location::Memory)
+            .map_err(|_| format!("'{}' is not one of the registers A, X and Y, the carry C or a memory address", s)),
+    }
+}
+
+/// Parse the name of a trace column, e.g. `CYC` or `P`
+pub fn parse_trace_field(s: &str) -> Result<TraceField, String> {
+    TraceField::from_name(s).ok_or_else(|| format!("'{}' is not one of the trace columns PC, A, X, Y, P, SP and CYC", s))
+}
+
+/// Parse status register flags written as letters, e.g. `BV`
+pub fn parse_flag_letters(s: &str) -> Result<u8, String> {
+    s.chars().try_fold(0, |acc, c| {
+        flag_bit(c)
+            .map(|bit| acc | bit)
+            .ok_or_else(|| format!("'{}' is not one of the flags N, V, B, D, I, Z and C", c))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use emulator_6502_core::{ConsoleAddresses, Location, TraceField};
+    use super::{parse_address_range, parse_byte, parse_console, parse_flag_letters, parse_location, parse_number, parse_trace_field};
+
+    #[test]
+    fn number_formats() {
+        assert_eq!(parse_number("$C000"), Some(0xC000));
+        assert_eq!(parse_number("0xc000"), Some(0xC000));
+        assert_eq!(parse_number("49152"), Some(49152));
+        assert_eq!(parse_number("C000"), None);
+        assert_eq!(parse_number("$"), None);
+        assert_eq!(parse_byte("$FF"), Ok(0xFF));
+        assert!(parse_byte("256").is_err());
+    }
+
+    #[test]
+    fn address_ranges() {
+        assert_eq!(parse_address_range("$C000-$C0FF"), Ok(0xC000..=0xC0FF));
+        assert_eq!(parse_address_range("0-0x10"), Ok(0x0000..=0x0010));
+        assert!(parse_address_range("$C0FF-$C000").is_err());
+        assert!(parse_address_range("$C000").is_err());
+        assert!(parse_address_range("$C000-$10000").is_err());
+    }
+
+    #[test]
+    fn consoles() {
+        assert_eq!(parse_console("py65"), Ok(ConsoleAddresses::PY65));
+        assert_eq!(parse_console("Kowalski"), Ok(ConsoleAddresses { output: 0xE001, input: 0xE004 }));
+        assert_eq!(parse_console("$D012:$D010"), Ok(ConsoleAddresses { output: 0xD012, input: 0xD010 }));
+        assert_eq!(parse_console("0:$FFFF"), Ok(ConsoleAddresses { output: 0x0000, input: 0xFFFF }));
+        assert!(parse_console("$D012").is_err());
+        assert!(parse_console("$F001:").is_err());
+        assert!(parse_console("$F001:$10000").is_err());
+    }
+
+    #[test]
+    fn trace_columns() {
+        assert_eq!(parse_trace_field("cyc"), Ok(TraceField::Cycles));
+        assert_eq!(parse_trace_field("S"), Ok(TraceField::StackPointer));
+        assert!(parse_trace_field("PPU").is_err());
+        assert_eq!(parse_flag_letters("BV"), Ok(0b0101_0000));
+        assert_eq!(parse_flag_letters(""), Ok(0));
+        assert!(parse_flag_letters("Q").is_err());
+    }
+
+    #[test]
+    fn locations() {
+        assert_eq!(parse_location("A"), Ok(Location::Accumulator));
+        assert_eq!(parse_location("c"), Ok(Location::Carry));
+        assert_eq!(parse_location("$80"), Ok(Location::Memory(0x80)));
+        assert!(parse_location("p").is_err());
+    }
+}
\ No newline at end of file
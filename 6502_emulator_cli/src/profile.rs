@@ -0,0 +1,276 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::path::PathBuf;
+use emulator_6502_core::{Cpu, Instruction, Memory, Profiler, SymbolTable, MAX_MEMORY};
+use serde_json::{json, Value};
+use structopt::StructOpt;
+use crate::error::Result;
+use crate::symbols::address_name;
+
+#[derive(StructOpt)]
+pub struct ProfileOpts {
+    /// Count instructions and cycles per address and print the hot spots when the run ends
+    #[structopt(long)]
+    pub profile: bool,
+    /// How many addresses and routines the hot-spot report lists
+    #[structopt(long, default_value = "20")]
+    pub profile_top: usize,
+    /// Write the counts per address to this file as CSV
+    #[structopt(parse(from_os_str), long)]
+    pub profile_csv: Option<PathBuf>,
+    /// Write the counts per address and per routine to this file as JSON
+    #[structopt(parse(from_os_str), long)]
+    pub profile_json: Option<PathBuf>,
+    /// Write the cycles spent per call path to this file as folded stacks, for flamegraph tools
+    #[structopt(parse(from_os_str), long)]
+    pub flamegraph: Option<PathBuf>,
+    /// How deep calls are followed for the flamegraph
+    #[structopt(long, default_value = "256")]
+    pub flamegraph_depth: usize,
+}
+
+impl ProfileOpts {
+    fn enabled(&self) -> bool {
+        self.profile || self.profile_csv.is_some() || self.profile_json.is_some()
+    }
+
+    /// Enable the profiler and call graph on the CPU if any profiling output was requested
+    pub fn install(&self, cpu: &mut Cpu) {
+        if self.enabled() {
+            cpu.enable_profiler();
+        }
+        if self.flamegraph.is_some() {
+            cpu.enable_call_graph(self.flamegraph_depth);
+        }
+    }
+
+    /// Print the hot-spot report and write the exports which were requested, starting and naming routines at `symbols`
+    pub fn write_reports(&self, cpu: &Cpu, memory: &dyn Memory<MAX_MEMORY>, symbols: &SymbolTable) -> Result<()> {
+        if let (Some(path), Some(call_graph)) = (&self.flamegraph, cpu.call_graph()) {
+            std::fs::write(path, call_graph.folded(|start| address_name(symbols, start)))?;
+        }
+
+        let profiler = match cpu.profiler() {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        if self.profile {
+            print!("{}", hot_spot_report(profiler, memory, symbols, self.profile_top));
+        }
+        if let Some(path) = &self.profile_csv {
+            std::fs::write(path, to_csv(profiler, memory, symbols))?;
+        }
+        if let Some(path) = &self.profile_json {
+            std::fs::write(path, serde_json::to_string_pretty(&to_json(profiler, memory, symbols))?)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The share of `part` in `total` as a percentage
+fn percentage(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+/// The addresses routines start at: those of the symbols if there are any, otherwise the `JSR` targets and interrupt handlers
+/// the profiler found
+fn routine_starts(profiler: &Profiler, symbols: &SymbolTable) -> BTreeSet<u16> {
+    if symbols.is_empty() {
+        profiler.routine_starts().clone()
+    } else {
+        symbols.iter().map(|symbol| symbol.address).collect()
+    }
+}
+
+/// The routine each address belongs to
+fn routine_of(starts: &BTreeSet<u16>, address: u16) -> u16 {
+    starts.range(..=address).next_back().copied().unwrap_or(address)
+}
+
+/// The addresses and routines taking the most cycles, as a table
+pub fn hot_spot_report(profiler: &Profiler, memory: &dyn Memory<MAX_MEMORY>, symbols: &SymbolTable, top: usize) -> String {
+    let total = profiler.total();
+    let mut report = String::new();
+    let _ = writeln!(report, "Profile: {} instructions, {} cycles", total.instructions, total.cycles);
+
+    let mut addresses: Vec<_> = profiler.addresses().collect();
+    addresses.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+
+    let _ = writeln!(report, "\nHot spots:");
+    let _ = writeln!(report, "  {:<8} {:>12} {:>12} {:>7}  Instruction", "Address", "Instructions", "Cycles", "%");
+    for (address, count) in addresses.iter().take(top) {
+        let _ = writeln!(
+            report,
+            "  ${:04X}    {:>12} {:>12} {:>6.2}%  {}",
+            address,
+            count.instructions,
+            count.cycles,
+            percentage(count.cycles, total.cycles),
+            Instruction::decode(memory, *address).with_symbols(symbols),
+        );
+    }
+
+    let mut routines = profiler.routines(&routine_starts(profiler, symbols));
+    routines.sort_by(|a, b| b.count.cycles.cmp(&a.count.cycles).then(a.start.cmp(&b.start)));
+
+    let _ = writeln!(report, "\nRoutines:");
+    let _ = writeln!(report, "  {:<8} {:>12} {:>12} {:>7}", "Routine", "Instructions", "Cycles", "%");
+    for routine in routines.iter().take(top) {
+        let _ = writeln!(
+            report,
+            "  {:<8} {:>12} {:>12} {:>6.2}%",
+            address_name(symbols, routine.start),
+            routine.count.instructions,
+            routine.count.cycles,
+            percentage(routine.count.cycles, total.cycles),
+        );
+    }
+
+    report
+}
+
+/// The counts per address as CSV, in address order
+pub fn to_csv(profiler: &Profiler, memory: &dyn Memory<MAX_MEMORY>, symbols: &SymbolTable) -> String {
+    let starts = &routine_starts(profiler, symbols);
+    let mut csv = String::from("address,instructions,cycles,routine,instruction\n");
+    for (address, count) in profiler.addresses() {
+        let _ = writeln!(
+            csv,
+            "${:04X},{},{},{},\"{}\"",
+            address,
+            count.instructions,
+            count.cycles,
+            address_name(symbols, routine_of(starts, address)),
+            Instruction::decode(memory, address).with_symbols(symbols),
+        );
+    }
+
+    csv
+}
+
+/// The counts per address and per routine as JSON
+pub fn to_json(profiler: &Profiler, memory: &dyn Memory<MAX_MEMORY>, symbols: &SymbolTable) -> Value {
+    let starts = &routine_starts(profiler, symbols);
+    let addresses: Vec<Value> = profiler.addresses()
+        .map(|(address, count)| json!({
+            "address": address,
+            "instruction": Instruction::decode(memory, address).with_symbols(symbols).to_string(),
+            "routine": address_name(symbols, routine_of(starts, address)),
+            "instructions": count.instructions,
+            "cycles": count.cycles,
+        }))
+        .collect();
+
+    let routines: Vec<Value> = profiler.routines(starts).iter()
+        .map(|routine| json!({
+            "start": routine.start,
+            "name": address_name(symbols, routine.start),
+            "instructions": routine.count.instructions,
+            "cycles": routine.count.cycles,
+        }))
+        .collect();
+
+    json!({
+        "instructions": profiler.total().instructions,
+        "cycles": profiler.total().cycles,
+        "addresses": addresses,
+        "routines": routines,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use emulator_6502_core::{BasicMemory, Cpu, Memory, Symbol, SymbolTable, INX_IMPLIED, JMP_ABSOLUTE, JSR_ABSOLUTE, RTS_IMPLIED};
+    use crate::symbols::address_name;
+    use super::{hot_spot_report, to_csv, to_json};
+
+    /// Calls a subroutine at `0x0300` twice
+    fn profiled() -> (Cpu, BasicMemory) {
+        let mut memory = BasicMemory::default();
+        for (offset, byte) in [JSR_ABSOLUTE, 0x00, 0x03, JSR_ABSOLUTE, 0x00, 0x03].iter().enumerate() {
+            memory.write(0x0200 + offset as u16, *byte);
+        }
+        memory.write(0x0300, INX_IMPLIED);
+        memory.write(0x0301, RTS_IMPLIED);
+
+        let mut cpu = Cpu::default();
+        cpu.set_program_counter(0x0200);
+        cpu.enable_profiler();
+        cpu.enable_call_graph(16);
+        for _ in 0..6 {
+            cpu.step(&mut memory);
+        }
+        (cpu, memory)
+    }
+
+    #[test]
+    fn folded_stacks() {
+        let (cpu, _) = profiled();
+        let folded = cpu.call_graph().unwrap().folded(|start| address_name(&SymbolTable::new(), start));
+        assert_eq!(folded, "$0200 12\n$0200;$0300 16\n");
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert(Symbol { name: "increment".to_string(), address: 0x0300, bank: None });
+        let folded = cpu.call_graph().unwrap().folded(|start| address_name(&symbols, start));
+        assert_eq!(folded, "$0200 12\n$0200;increment 16\n");
+    }
+
+    #[test]
+    fn reports() {
+        let (cpu, memory) = profiled();
+        let profiler = cpu.profiler().unwrap();
+
+        let report = hot_spot_report(profiler, &memory, &SymbolTable::new(), 1);
+        assert!(report.starts_with("Profile: 6 instructions, 28 cycles\n"));
+        assert!(report.contains("  $0301               2           12  42.86%  RTS\n"));
+        assert!(!report.contains("$0300               2"));
+        assert!(report.contains("  $0300               4           16  57.14%\n"));
+
+        let csv = to_csv(profiler, &memory, &SymbolTable::new());
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[1], "$0200,1,6,$0200,\"JSR $0300\"");
+        assert_eq!(lines[3], "$0300,2,4,$0300,\"INX\"");
+
+        let json = to_json(profiler, &memory, &SymbolTable::new());
+        assert_eq!(json["cycles"], 28);
+        assert_eq!(json["routines"][1]["name"], "$0300");
+        assert_eq!(json["routines"][1]["instructions"], 4);
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert(Symbol { name: "increment".to_string(), address: 0x0300, bank: None });
+        let csv = to_csv(profiler, &memory, &symbols);
+        assert_eq!(csv.lines().nth(1), Some("$0200,1,6,$0200,\"JSR increment\""));
+        assert_eq!(csv.lines().nth(3), Some("$0300,2,4,increment,\"INX\""));
+        assert!(hot_spot_report(profiler, &memory, &symbols, 2).contains("  increment            4           16  57.14%\n"));
+    }
+    #[test]
+    fn symbol_routines() {
+        // main: INX, JMP loop; loop: INX, JMP loop, where loop is never called with JSR
+        let mut memory = BasicMemory::default();
+        memory.load(0x0200, &[INX_IMPLIED, JMP_ABSOLUTE, 0x00, 0x03], 0).unwrap();
+        memory.load(0x0300, &[INX_IMPLIED, JMP_ABSOLUTE, 0x00, 0x03], 0).unwrap();
+        let mut cpu = Cpu::default();
+        cpu.set_program_counter(0x0200);
+        cpu.enable_profiler();
+        for _ in 0..6 {
+            cpu.step(&mut memory);
+        }
+        let profiler = cpu.profiler().unwrap();
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert(Symbol { name: "main".to_string(), address: 0x0200, bank: None });
+        symbols.insert(Symbol { name: "loop".to_string(), address: 0x0300, bank: None });
+        assert!(hot_spot_report(profiler, &memory, &symbols, 2).contains("  loop                4           10  66.67%\n"));
+        assert_eq!(to_csv(profiler, &memory, &symbols).lines().nth(3), Some("$0300,2,4,loop,\"INX\""));
+        let json = to_json(profiler, &memory, &symbols);
+        assert_eq!((&json["routines"][1]["name"], &json["routines"][1]["cycles"]), (&"loop".into(), &10.into()));
+        assert_eq!(json["addresses"][2]["routine"], "loop");
+    }
+}
\ No newline at end of file
@@ -0,0 +1,269 @@
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use emulator_6502_core::{install_sys, BasicMemory, Bus, Cpu, Exit, ExitDevice, KernalShim, Memory, OutputDevice, Sim65Paravirt, SimDevice, BRK_IMPLIED, SIM_REGISTERS};
+use log::info;
+use structopt::StructOpt;
+use crate::console::ConsoleOpts;
+use crate::coverage::CoverageOpts;
+use crate::dbgfile::load_debug_info;
+use crate::error::{Error, Result};
+use crate::parse::{parse_address, parse_byte};
+use crate::image::{load_image, ImageOpts};
+use crate::profile::ProfileOpts;
+use crate::sim65::SandboxHost;
+use crate::trace::TraceOpts;
+
+#[derive(StructOpt)]
+pub struct RunOpts {
+    #[structopt(flatten)]
+    pub image: ImageOpts,
+    /// Start at the start address record of an Intel HEX or S-record input, or the address the BASIC stub
+    /// of a `.prg` input calls with `SYS`, instead of the reset vector
+    #[structopt(long)]
+    pub use_entry: bool,
+    /// Start the way BASIC's `SYS` does, at the address the BASIC stub of a `.prg` input calls,
+    /// halting when the program returns. Calls to the KERNAL's CHROUT print to stdout
+    #[structopt(long)]
+    pub sys: bool,
+    /// Like `--sys`, but start at this address
+    #[structopt(long, parse(try_from_str = parse_address))]
+    pub sys_address: Option<u16>,
+    /// Run a program built for the llvm-mos `mos-sim` target, starting at the entry point of an ELF input.
+    /// Characters it writes go to stdout, and the status it exits with becomes the exit code
+    #[structopt(long)]
+    pub sim: bool,
+    /// The directory a cc65 sim65 program may open files in. It can't open files outside of it
+    #[structopt(parse(from_os_str), long, default_value = ".")]
+    pub sandbox: PathBuf,
+    /// The arguments passed to a sim65 program after its name
+    #[structopt(last = true)]
+    pub args: Vec<String>,
+    /// End the run when the program writes to this address, e.g. $FFF8, with the byte written as the exit code
+    #[structopt(long, parse(try_from_str = parse_address))]
+    pub exit_address: Option<u16>,
+    /// End the run when the program reaches a `BRK` followed by this signature byte, with the accumulator as the exit code
+    #[structopt(long, parse(try_from_str = parse_byte))]
+    pub exit_brk: Option<u8>,
+    /// Write the bytes the program writes to this address to stdout. Use `--console` for input as well
+    #[structopt(long, parse(try_from_str = parse_address))]
+    pub console_address: Option<u16>,
+    #[structopt(flatten)]
+    pub console: ConsoleOpts,
+    /// Stop after executing this many instructions
+    #[structopt(long)]
+    pub max_instructions: Option<u64>,
+    /// Stop once this many cycles have been executed
+    #[structopt(long)]
+    pub max_cycles: Option<u64>,
+    /// A label file (VICE or ld65 `-Ln`, vasm listing, FCEUX `.nl`, Mesen `.mlb`) naming routines in the profile.
+    /// May be given several times
+    #[structopt(parse(from_os_str), long)]
+    pub symbols: Vec<PathBuf>,
+    /// The debug file written by ld65 with `--dbgfile`, mapping coverage to source lines and naming routines
+    #[structopt(parse(from_os_str), long)]
+    pub dbgfile: Option<PathBuf>,
+    #[structopt(flatten)]
+    pub trace: TraceOpts,
+    #[structopt(flatten)]
+    pub profile: ProfileOpts,
+    #[structopt(flatten)]
+    pub coverage: CoverageOpts,
+}
+
+/// The exit code of a run whose program aborted, like a process killed by `SIGABRT`
+const ABORT_EXIT_CODE: i32 = 134;
+
+/// Why a run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// An instruction jumped to itself, like `JMP *`, so the program can't make progress anymore
+    Halted(u16),
+    InstructionLimit,
+    CycleLimit,
+    /// The user pressed Ctrl-C
+    Interrupted,
+    /// A device reported that the program exited with a status
+    Exited(u8),
+    /// A device reported that the program aborted
+    Aborted,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Halted(address) => write!(f, "Halted at ${:04X}", address),
+            Self::InstructionLimit => write!(f, "Reached the instruction limit"),
+            Self::CycleLimit => write!(f, "Reached the cycle limit"),
+            Self::Interrupted => write!(f, "Interrupted"),
+            Self::Exited(status) => write!(f, "Exited with status {}", status),
+            Self::Aborted => write!(f, "Aborted"),
+        }
+    }
+}
+
+/// Run a memory image until it halts, a limit is reached or the user presses Ctrl-C, then write the requested reports.
+/// Returns the exit code for the process: the status the program exited with, or if it was run with a way to exit,
+/// like `--sim` or `--exit-address`, 1 when it ended any other way, like reaching a limit
+pub fn run(opts: &RunOpts) -> Result<i32> {
+    let image = load_image(&opts.image)?;
+    let (source_map, symbols) = load_debug_info(&image, opts.dbgfile.as_deref(), &opts.symbols)?;
+    let mut cpu = Cpu::default();
+    image.start(&mut cpu, opts.use_entry || opts.sim);
+    let mut memory = image.memory;
+    if opts.sys || opts.sys_address.is_some() {
+        let address = opts.sys_address.or(image.entry).ok_or_else(|| Error::NoSysAddress(opts.image.input.clone()))?;
+        let start = install_sys(&mut memory, address, &image.ranges)
+            .map_err(|error| Error::SysStubOverlap { path: opts.image.input.clone(), error })?;
+        KernalShim::default().install(&mut cpu, |c| print!("{}", c));
+        cpu.set_program_counter(start);
+    }
+    if let Some(header) = &image.sim65 {
+        let args: Vec<String> = std::iter::once(opts.image.input.display().to_string()).chain(opts.args.iter().cloned()).collect();
+        Sim65Paravirt::new(header, &args, SandboxHost::new(&opts.sandbox)).install(&mut cpu);
+    }
+    let mut bus = Bus::new(memory);
+    if opts.sim {
+        bus.map(SIM_REGISTERS, SimDevice::new(write_stdout));
+    }
+    if let Some(address) = opts.exit_address {
+        bus.map(address, ExitDevice::default());
+    }
+    if let Some(address) = opts.console_address {
+        bus.map(address, OutputDevice::new(write_stdout));
+    }
+    let raw_mode = opts.console.install(&mut bus);
+    opts.trace.install(&mut cpu)?;
+    opts.profile.install(&mut cpu);
+    opts.coverage.install(&mut cpu);
+
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_flag = interrupted.clone();
+    ctrlc::set_handler(move || handler_flag.store(true, Ordering::Relaxed))?;
+
+    let stop = execute(&mut cpu, &mut bus, opts, &interrupted);
+    std::io::stdout().flush()?;
+    drop(raw_mode);
+    info!("{} after {} instructions and {} cycles", stop, cpu.instructions(), cpu.cycles());
+
+    opts.profile.write_reports(&cpu, bus.memory(), &symbols)?;
+    opts.coverage.write_report(&cpu, bus.memory(), &opts.image.input, &source_map)?;
+    Ok(match stop {
+        Stop::Exited(status) => status as i32,
+        Stop::Aborted => ABORT_EXIT_CODE,
+        _ if opts.sim || image.sim65.is_some() || opts.exit_address.is_some() || opts.exit_brk.is_some() => 1,
+        _ => 0,
+    })
+}
+
+pub fn write_stdout(c: u8) {
+    let _ = std::io::stdout().write_all(&[c]);
+}
+
+/// Execute instructions until one of the stop conditions is met or a device or trap, like sim65's `exit`, asks to exit
+pub fn execute(cpu: &mut Cpu, bus: &mut Bus<BasicMemory>, opts: &RunOpts, interrupted: &AtomicBool) -> Stop {
+    loop {
+        if interrupted.load(Ordering::Relaxed) {
+            return Stop::Interrupted;
+        }
+        if opts.max_instructions.is_some_and(|max| cpu.instructions() >= max) {
+            return Stop::InstructionLimit;
+        }
+        if opts.max_cycles.is_some_and(|max| cpu.cycles() >= max) {
+            return Stop::CycleLimit;
+        }
+
+        if let Some(signature) = opts.exit_brk {
+            let address = cpu.program_counter();
+            if bus.peek(address) == BRK_IMPLIED && bus.peek(address.wrapping_add(1)) == signature {
+                return Stop::Exited(cpu.registers().accumulator);
+            }
+        }
+        let address = cpu.program_counter();
+        bus.clock(cpu.cycles());
+        cpu.step(bus);
+        match bus.exit().or(cpu.exit()) {
+            Some(Exit::Status(status)) => return Stop::Exited(status),
+            Some(Exit::Abort) => return Stop::Aborted,
+            None => {},
+        }
+        if cpu.program_counter() == address {
+            return Stop::Halted(address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicBool;
+    use emulator_6502_core::{BasicMemory, Bus, Cpu, ExitDevice, Memory, SimDevice, BRK_IMPLIED, INX_IMPLIED, JMP_ABSOLUTE, LDA_IMMEDIATE, SIM_REGISTERS, STA_ABSOLUTE};
+    use structopt::StructOpt;
+    use super::{execute, RunOpts, Stop};
+
+    /// `INX` three times followed by `JMP *`
+    const LOOP: [u8; 6] = [INX_IMPLIED, INX_IMPLIED, INX_IMPLIED, JMP_ABSOLUTE, 0x03, 0x02];
+
+    /// A CPU about to run `program` at `0x0200`
+    fn setup(program: &[u8]) -> (Cpu, Bus<BasicMemory>) {
+        let mut memory = BasicMemory::default();
+        memory.load(0x0200, program, 0).unwrap();
+
+        let mut cpu = Cpu::default();
+        cpu.set_program_counter(0x0200);
+        (cpu, Bus::new(memory))
+    }
+
+    #[test]
+    fn stop_conditions() {
+        let not_interrupted = AtomicBool::new(false);
+
+        let (mut cpu, mut memory) = setup(&LOOP);
+        let opts = RunOpts::from_iter(["run", "-i", "image"]);
+        assert_eq!(execute(&mut cpu, &mut memory, &opts, &not_interrupted), Stop::Halted(0x0203));
+        assert_eq!(cpu.instructions(), 4);
+
+        let (mut cpu, mut memory) = setup(&LOOP);
+        let opts = RunOpts::from_iter(["run", "-i", "image", "--max-instructions", "2"]);
+        assert_eq!(execute(&mut cpu, &mut memory, &opts, &not_interrupted), Stop::InstructionLimit);
+        assert_eq!(cpu.instructions(), 2);
+
+        let (mut cpu, mut memory) = setup(&LOOP);
+        let opts = RunOpts::from_iter(["run", "-i", "image", "--max-cycles", "5"]);
+        assert_eq!(execute(&mut cpu, &mut memory, &opts, &not_interrupted), Stop::CycleLimit);
+        assert_eq!(cpu.cycles(), 6);
+
+        let (mut cpu, mut memory) = setup(&LOOP);
+        assert_eq!(execute(&mut cpu, &mut memory, &opts, &AtomicBool::new(true)), Stop::Interrupted);
+    }
+
+    #[test]
+    fn sim_exit() {
+        let exit = SIM_REGISTERS + 8;
+        let (mut cpu, mut bus) = setup(&[LDA_IMMEDIATE, 0x2A, STA_ABSOLUTE, exit as u8, (exit >> 8) as u8, JMP_ABSOLUTE, 0x00, 0x02]);
+        bus.map(SIM_REGISTERS, SimDevice::new(|_| {}));
+
+        let opts = RunOpts::from_iter(["run", "-i", "image", "--sim"]);
+        assert_eq!(execute(&mut cpu, &mut bus, &opts, &AtomicBool::new(false)), Stop::Exited(0x2A));
+        assert_eq!(cpu.instructions(), 2);
+    }
+
+    #[test]
+    fn exit_protocols() {
+        // Write $2A to $FFF8, then BRK with signature $42 and A = 3
+        let program = [LDA_IMMEDIATE, 0x2A, STA_ABSOLUTE, 0xF8, 0xFF, LDA_IMMEDIATE, 0x03, BRK_IMPLIED, 0x42];
+        let not_interrupted = AtomicBool::new(false);
+
+        let (mut cpu, mut bus) = setup(&program);
+        bus.map(0xFFF8, ExitDevice::default());
+        let opts = RunOpts::from_iter(["run", "-i", "image", "--exit-address", "$FFF8"]);
+        assert_eq!(execute(&mut cpu, &mut bus, &opts, &not_interrupted), Stop::Exited(0x2A));
+
+        let (mut cpu, mut bus) = setup(&program);
+        let opts = RunOpts::from_iter(["run", "-i", "image", "--exit-brk", "$42"]);
+        assert_eq!(execute(&mut cpu, &mut bus, &opts, &not_interrupted), Stop::Exited(0x03));
+        assert_eq!(cpu.program_counter(), 0x0207);
+        assert_eq!(bus.memory().read(0xFFF8), 0x2A);
+    }
+}
\ No newline at end of file
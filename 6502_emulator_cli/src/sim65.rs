@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use emulator_6502_core::{OpenMode, Sim65Host};
+use log::debug;
+
+/// The first file descriptor handed out for opened files, after stdin, stdout and stderr
+const FIRST_FD: u16 = 3;
+
+/// Serves the file access of a sim65 program from the host filesystem, confined to one directory
+pub struct SandboxHost {
+    root: PathBuf,
+    files: Mutex<HashMap<u16, File>>,
+}
+
+impl SandboxHost {
+    pub fn new(root: &Path) -> Self {
+        Self { root: root.to_path_buf(), files: Mutex::new(HashMap::new()) }
+    }
+
+    /// The path `path` names inside the sandbox, or `None` if it would leave it.
+    /// Absolute paths and `..` are refused, as are symbolic links pointing outside and dangling symbolic links
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let relative = Path::new(path);
+        if !relative.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+            return None;
+        }
+        let root = self.root.canonicalize().ok()?;
+        let resolved = root.join(relative);
+        let parent = resolved.parent()?.canonicalize().ok()?;
+        let inside = match resolved.canonicalize() {
+            Ok(target) => target.starts_with(&root),
+            // Nothing is there yet, unless it's a dangling link, which creating the file would follow
+            Err(_) => parent.starts_with(&root) && resolved.symlink_metadata().is_err(),
+        };
+        inside.then_some(resolved)
+    }
+}
+
+impl Sim65Host for SandboxHost {
+    fn open(&self, path: &str, mode: OpenMode) -> Option<u16> {
+        let resolved = match self.resolve(path) {
+            Some(resolved) => resolved,
+            None => {
+                debug!("Refusing to open {:?} outside of {:?}", path, self.root);
+                return None;
+            },
+        };
+        let file = OpenOptions::new()
+            .read(mode.read)
+            .write(mode.write && !mode.append)
+            .append(mode.append)
+            .create(mode.create && !mode.exclusive)
+            .create_new(mode.create && mode.exclusive)
+            .truncate(mode.truncate && mode.write && !mode.append)
+            .open(&resolved)
+            .map_err(|e| debug!("Failed to open {:?}: {}", resolved, e))
+            .ok()?;
+        let mut files = self.files.lock().unwrap();
+        let fd = (FIRST_FD..=u16::MAX).find(|fd| !files.contains_key(fd))?;
+        files.insert(fd, file);
+        Some(fd)
+    }
+
+    fn close(&self, fd: u16) -> bool {
+        fd < FIRST_FD || self.files.lock().unwrap().remove(&fd).is_some()
+    }
+
+    fn read(&self, fd: u16, buffer: &mut [u8]) -> Option<usize> {
+        match fd {
+            0 => std::io::stdin().read(buffer).ok(),
+            _ => self.files.lock().unwrap().get_mut(&fd)?.read(buffer).ok(),
+        }
+    }
+
+    fn write(&self, fd: u16, data: &[u8]) -> Option<usize> {
+        let result = match fd {
+            1 => std::io::stdout().write_all(data),
+            2 => std::io::stderr().write_all(data),
+            _ => self.files.lock().unwrap().get_mut(&fd)?.write_all(data),
+        };
+        result.ok().map(|_| data.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use emulator_6502_core::{OpenMode, Sim65Host};
+    use super::SandboxHost;
+
+    #[test]
+    fn sandbox() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+        let host = SandboxHost::new(&root);
+
+        let write = OpenMode { write: true, create: true, truncate: true, ..Default::default() };
+        let fd = host.open("out.txt", write).unwrap();
+        assert_eq!(fd, 3);
+        assert_eq!(host.write(fd, b"hello"), Some(5));
+        assert!(host.close(fd));
+        assert!(!host.close(fd));
+        assert_eq!(std::fs::read_to_string(root.join("out.txt")).unwrap(), "hello");
+
+        let read = OpenMode { read: true, ..Default::default() };
+        let fd = host.open("./out.txt", read).unwrap();
+        let mut buffer = [0; 16];
+        assert_eq!(host.read(fd, &mut buffer), Some(5));
+        assert_eq!(&buffer[..5], b"hello");
+        assert_eq!(host.open("missing.txt", read), None);
+        assert_eq!(host.open("../secret.txt", read), None);
+        assert_eq!(host.open(dir.path().join("secret.txt").to_str().unwrap(), read), None);
+        assert_eq!(host.open("out.txt", OpenMode { exclusive: true, ..write }), None);
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.path().join("secret.txt"), root.join("link.txt")).unwrap();
+            assert_eq!(host.open("link.txt", read), None);
+
+            // Creating a file through a dangling link would write outside
+            std::os::unix::fs::symlink(dir.path().join("created.txt"), root.join("dangling.txt")).unwrap();
+            assert_eq!(host.open("dangling.txt", write), None);
+            assert!(!dir.path().join("created.txt").exists());
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,542 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use emulator_6502_core::{format_flag_letters, BasicMemory, CallLimits, Cpu, Memory, Registers, BRK_IMPLIED};
+use serde::{Deserialize, Deserializer};
+use structopt::StructOpt;
+use crate::error::{Error, Result};
+use crate::image::{load_image, ImageOpts};
+use crate::parse::{parse_address, parse_flag_letters};
+
+/// The cycles a case may run for if its manifest entry doesn't say otherwise
+const DEFAULT_MAX_CYCLES: u64 = 10_000_000;
+
+#[derive(StructOpt)]
+pub struct TestOpts {
+    /// The TOML manifest listing the cases, each as a `[[case]]` table
+    #[structopt(parse(from_os_str))]
+    pub manifest: PathBuf,
+    /// Write the results as JUnit XML to this file
+    #[structopt(parse(from_os_str), long)]
+    pub junit: Option<PathBuf>,
+    /// Write the results in the Test Anything Protocol to this file
+    #[structopt(parse(from_os_str), long)]
+    pub tap: Option<PathBuf>,
+    /// How many cases to run at once, defaulting to the number of CPUs
+    #[structopt(short, long)]
+    pub jobs: Option<usize>,
+}
+
+/// The cases of a manifest, e.g.
+/// ```toml
+/// [[case]]
+/// name = "multiply"
+/// binary = "multiply.bin"
+/// load_address = 0x0200
+/// stop = "rts"
+/// expect = { a = 0x32, flags_clear = "C", memory = { "$2000" = [0x32, 0x00] } }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(rename = "case", default)]
+    cases: Vec<Case>,
+}
+
+/// A program to run, and what it should leave behind
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Case {
+    pub name: String,
+    /// The program, relative to the manifest, in any of the formats `run` takes
+    pub binary: PathBuf,
+    /// Load the binary as a raw binary at this address
+    #[serde(default, deserialize_with = "address")]
+    pub load_address: Option<u16>,
+    /// Start executing at this address instead of the entry point of the binary or the reset vector
+    #[serde(default, deserialize_with = "address")]
+    pub entry: Option<u16>,
+    #[serde(default)]
+    pub stop: StopCondition,
+    pub max_cycles: Option<u64>,
+    #[serde(default)]
+    pub expect: Expectations,
+}
+
+/// An address in a manifest, either an integer or a string like `"$0200"`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Address {
+    Integer(u16),
+    Text(String),
+}
+
+/// Deserialize an optional [Address]
+fn address<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<u16>, D::Error> {
+    match Option::<Address>::deserialize(deserializer)? {
+        Some(Address::Integer(address)) => Ok(Some(address)),
+        Some(Address::Text(text)) => parse_address(&text).map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// When a case is done and its results are checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub enum StopCondition {
+    /// An instruction jumps to itself, like `JMP *`
+    #[default]
+    Halt,
+    /// The next instruction is a `BRK`, which isn't executed
+    Brk,
+    /// The entry is called as a subroutine, which returns with `RTS`
+    Rts,
+    /// The program counter reaches the address
+    Address(u16),
+}
+
+impl FromStr for StopCondition {
+    type Err = String;
+
+    /// Parse `halt`, `brk`, `rts` or an address
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "halt" => Ok(Self::Halt),
+            "brk" => Ok(Self::Brk),
+            "rts" => Ok(Self::Rts),
+            _ => parse_address(s).map(Self::Address)
+                .map_err(|_| format!("'{}' is not a stop condition, use halt, brk, rts or an address", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for StopCondition {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The registers and memory a case should end with. Anything left out isn't checked
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expectations {
+    pub a: Option<u8>,
+    pub x: Option<u8>,
+    pub y: Option<u8>,
+    pub sp: Option<u8>,
+    #[serde(default, deserialize_with = "address")]
+    pub pc: Option<u16>,
+    /// Flags which have to be set, as letters like `ZC`
+    #[serde(default)]
+    pub flags_set: Flags,
+    /// Flags which have to be clear
+    #[serde(default)]
+    pub flags_clear: Flags,
+    /// Bytes by the address they start at
+    #[serde(default)]
+    pub memory: ExpectedMemory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Flags(pub u8);
+
+impl TryFrom<String> for Flags {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        parse_flag_letters(&s).map(Self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "BTreeMap<String, Vec<u8>>")]
+pub struct ExpectedMemory(pub Vec<(u16, Vec<u8>)>);
+
+impl TryFrom<BTreeMap<String, Vec<u8>>> for ExpectedMemory {
+    type Error = String;
+
+    fn try_from(map: BTreeMap<String, Vec<u8>>) -> std::result::Result<Self, Self::Error> {
+        map.into_iter()
+            .map(|(address, bytes)| Ok((parse_address(&address)?, bytes)))
+            .collect::<std::result::Result<Vec<_>, String>>()
+            .map(Self)
+    }
+}
+
+impl Expectations {
+    /// Describe every difference between the expectations and the registers and memory a case ended with
+    fn check(&self, registers: &Registers, memory: &BasicMemory) -> Vec<String> {
+        let mut differences = Vec::new();
+        let bytes = [("A", self.a, registers.accumulator), ("X", self.x, registers.x), ("Y", self.y, registers.y), ("SP", self.sp, registers.stack_pointer)];
+        for (name, expected, actual) in bytes {
+            if let Some(expected) = expected.filter(|expected| *expected != actual) {
+                differences.push(format!("{}: expected ${:02X}, got ${:02X}", name, expected, actual));
+            }
+        }
+        if let Some(expected) = self.pc.filter(|expected| *expected != registers.program_counter) {
+            differences.push(format!("PC: expected ${:04X}, got ${:04X}", expected, registers.program_counter));
+        }
+
+        let flags = registers.flags.bits();
+        let (set, clear) = (self.flags_set.0 & !flags, self.flags_clear.0 & flags);
+        if set != 0 || clear != 0 {
+            differences.push(format!("flags: expected {} set and {} clear, got {}", letters(self.flags_set.0), letters(self.flags_clear.0), format_flag_letters(flags)));
+        }
+
+        for (start, bytes) in &self.memory.0 {
+            for (offset, expected) in bytes.iter().enumerate() {
+                let address = start.wrapping_add(offset as u16);
+                let actual = memory.read(address);
+                if actual != *expected {
+                    differences.push(format!("${:04X}: expected ${:02X}, got ${:02X}", address, expected, actual));
+                }
+            }
+        }
+        differences
+    }
+}
+
+/// The letters of the flags in `flags`, or `-` for none
+fn letters(flags: u8) -> String {
+    let letters: String = format_flag_letters(flags).chars().filter(char::is_ascii_uppercase).collect();
+    if letters.is_empty() { "-".to_string() } else { letters }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// The case ran, but left the wrong results
+    Failed(Vec<String>),
+    /// The case couldn't be loaded, or didn't stop
+    Error(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct CaseResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub cycles: u64,
+    pub duration: Duration,
+}
+
+/// Run the cases of the manifest, print a summary and write the reports.
+/// Returns the exit code for the process, which is 1 if any case didn't pass
+pub fn run(opts: &TestOpts) -> Result<i32> {
+    let text = std::fs::read_to_string(&opts.manifest)?;
+    let manifest: Manifest = toml::from_str(&text).map_err(|error| Error::InvalidManifest { path: opts.manifest.clone(), error })?;
+    let directory = opts.manifest.parent().unwrap_or_else(|| Path::new("."));
+    let jobs = opts.jobs.unwrap_or_else(|| std::thread::available_parallelism().map(usize::from).unwrap_or(1));
+
+    let start = Instant::now();
+    let results = run_cases(&manifest.cases, directory, jobs);
+    let elapsed = start.elapsed();
+    print!("{}", summary(&results, elapsed));
+
+    let suite = opts.manifest.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    if let Some(path) = &opts.junit {
+        std::fs::write(path, junit(&suite, &results, elapsed))?;
+    }
+    if let Some(path) = &opts.tap {
+        std::fs::write(path, tap(&results))?;
+    }
+
+    Ok(if results.iter().all(|result| result.outcome == Outcome::Passed) { 0 } else { 1 })
+}
+
+/// Run the cases on `jobs` threads, resolving their binaries relative to `directory`. The results are in the order of the cases
+pub fn run_cases(cases: &[Case], directory: &Path, jobs: usize) -> Vec<CaseResult> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; cases.len()]);
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, cases.len().max(1)) {
+            scope.spawn(|| {
+                let mut index = next.fetch_add(1, Ordering::Relaxed);
+                while let Some(case) = cases.get(index) {
+                    let result = run_case(case, directory);
+                    results.lock().unwrap()[index] = Some(result);
+                    index = next.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+    results.into_inner().unwrap().into_iter().flatten().collect()
+}
+
+/// Load and run a single case and check its results. A panic while running it is reported as an error
+fn run_case(case: &Case, directory: &Path) -> CaseResult {
+    let start = Instant::now();
+    let executed = catch_unwind(AssertUnwindSafe(|| execute_case(case, directory)))
+        .unwrap_or_else(|panic| Err((format!("panicked: {}", panic_message(panic.as_ref())), 0)));
+    let (outcome, cycles) = match executed {
+        Ok((registers, memory, cycles)) => {
+            let differences = case.expect.check(&registers, &memory);
+            (if differences.is_empty() { Outcome::Passed } else { Outcome::Failed(differences) }, cycles)
+        },
+        Err((message, cycles)) => (Outcome::Error(message), cycles),
+    };
+    CaseResult { name: case.name.clone(), outcome, cycles, duration: start.elapsed() }
+}
+
+/// The message a panic was raised with
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic.downcast_ref::<&str>().copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Run a case until its stop condition, returning the registers, the memory and the cycles it ended with,
+/// or why it couldn't be run and the cycles it took until then
+fn execute_case(case: &Case, directory: &Path) -> std::result::Result<(Registers, BasicMemory, u64), (String, u64)> {
+    let image_opts = ImageOpts { input: directory.join(&case.binary), load_address: case.load_address, ..ImageOpts::default() };
+    let image = load_image(&image_opts).map_err(|error| (error.to_string(), 0))?;
+    let mut memory = image.memory;
+    let mut cpu = Cpu::default();
+    match case.entry {
+        Some(entry) => cpu.set_program_counter(entry),
+        None => {
+            cpu.load_reset_vector(&memory);
+            if let Some(entry) = image.entry {
+                cpu.set_program_counter(entry);
+            }
+        },
+    }
+    let max_cycles = case.max_cycles.unwrap_or(DEFAULT_MAX_CYCLES);
+
+    if case.stop == StopCondition::Rts {
+        let limits = CallLimits { instructions: u64::MAX, cycles: max_cycles };
+        let address = cpu.program_counter();
+        return match cpu.call_with_limits(&mut memory, address, &cpu.registers(), &limits) {
+            // The program counter after the return is the call's sentinel, so the RTS is reported instead
+            Ok(result) => Ok((Registers { program_counter: result.rts_address, ..result.registers }, memory, result.cycles)),
+            Err(error) => Err((error.to_string(), cpu.cycles())),
+        };
+    }
+
+    loop {
+        let address = cpu.program_counter();
+        let stopped = match case.stop {
+            StopCondition::Brk => memory.read(address) == BRK_IMPLIED,
+            StopCondition::Address(stop) => address == stop,
+            StopCondition::Halt | StopCondition::Rts => false,
+        };
+        if stopped {
+            return Ok((cpu.registers(), memory, cpu.cycles()));
+        }
+        if cpu.cycles() >= max_cycles {
+            return Err((format!("didn't stop within {} cycles, it got to ${:04X}", max_cycles, address), cpu.cycles()));
+        }
+        cpu.step(&mut memory);
+        if case.stop == StopCondition::Halt && cpu.program_counter() == address {
+            return Ok((cpu.registers(), memory, cpu.cycles()));
+        }
+    }
+}
+
+/// A line per case, with the differences of failed cases, followed by the totals
+pub fn summary(results: &[CaseResult], elapsed: Duration) -> String {
+    let mut summary = String::new();
+    for result in results {
+        let _ = match &result.outcome {
+            Outcome::Passed => writeln!(summary, "ok      {} ({} cycles)", result.name, result.cycles),
+            Outcome::Failed(differences) => {
+                let _ = writeln!(summary, "FAILED  {} ({} cycles)", result.name, result.cycles);
+                differences.iter().try_for_each(|difference| writeln!(summary, "        {}", difference))
+            },
+            Outcome::Error(message) => writeln!(summary, "ERROR   {}: {}", result.name, message),
+        };
+    }
+    let count = |matches: fn(&Outcome) -> bool| results.iter().filter(|result| matches(&result.outcome)).count();
+    let _ = writeln!(
+        summary,
+        "\n{} passed, {} failed, {} errors in {:.2}s",
+        count(|outcome| *outcome == Outcome::Passed),
+        count(|outcome| matches!(outcome, Outcome::Failed(_))),
+        count(|outcome| matches!(outcome, Outcome::Error(_))),
+        elapsed.as_secs_f64(),
+    );
+    summary
+}
+
+/// The results as a JUnit XML report with a single test suite
+pub fn junit(suite: &str, results: &[CaseResult], elapsed: Duration) -> String {
+    let failures = results.iter().filter(|result| matches!(result.outcome, Outcome::Failed(_))).count();
+    let errors = results.iter().filter(|result| matches!(result.outcome, Outcome::Error(_))).count();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let totals = format!("tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\"", results.len(), failures, errors, elapsed.as_secs_f64());
+    let _ = writeln!(xml, "<testsuites {}>", totals);
+    let _ = writeln!(xml, "  <testsuite name=\"{}\" {}>", escape_xml(suite), totals);
+    for result in results {
+        let _ = write!(xml, "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"", escape_xml(&result.name), escape_xml(suite), result.duration.as_secs_f64());
+        let _ = match &result.outcome {
+            Outcome::Passed => writeln!(xml, "/>"),
+            Outcome::Failed(differences) => writeln!(
+                xml,
+                ">\n      <failure message=\"{} differences\">{}</failure>\n    </testcase>",
+                differences.len(),
+                escape_xml(&differences.join("\n")),
+            ),
+            Outcome::Error(message) => writeln!(xml, ">\n      <error message=\"{}\"/>\n    </testcase>", escape_xml(message)),
+        };
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+/// The results in the Test Anything Protocol, with the differences of failed cases as diagnostics
+pub fn tap(results: &[CaseResult]) -> String {
+    let mut tap = format!("TAP version 13\n1..{}\n", results.len());
+    for (index, result) in results.iter().enumerate() {
+        let _ = match &result.outcome {
+            Outcome::Passed => writeln!(tap, "ok {} - {}", index + 1, result.name),
+            Outcome::Failed(differences) => {
+                let _ = writeln!(tap, "not ok {} - {}", index + 1, result.name);
+                differences.iter().try_for_each(|difference| writeln!(tap, "# {}", difference))
+            },
+            Outcome::Error(message) => writeln!(tap, "not ok {} - {}\n# {}", index + 1, result.name, message),
+        };
+    }
+    tap
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::time::Duration;
+    use structopt::StructOpt;
+    use super::{junit, panic_message, run, run_cases, summary, tap, Manifest, Outcome, StopCondition, TestOpts};
+
+    /// `LDA #$32`, `STA $2000`, `SEC`, then `BRK`, `RTS` and `JMP *`
+    const PROGRAM: [u8; 11] = [0xA9, 0x32, 0x8D, 0x00, 0x20, 0x38, 0x00, 0x60, 0x4C, 0x08, 0x02];
+
+    const MANIFEST: &str = r#"
+        [[case]]
+        name = "store"
+        binary = "program.bin"
+        load_address = 0x0200
+        entry = 0x0200
+        stop = "brk"
+        expect = { a = 0x32, pc = 0x0206, flags_set = "C", memory = { "$2000" = [0x32] } }
+
+        [[case]]
+        name = "wrong"
+        binary = "program.bin"
+        load_address = "$0200"
+        entry = "0x0200"
+        stop = "$0205"
+        expect = { a = 0x31, flags_set = "C", memory = { "0x1FFF" = [0, 0x33] } }
+
+        [[case]]
+        name = "runaway"
+        binary = "program.bin"
+        load_address = 0x0200
+        entry = 0x0208
+        stop = "$0300"
+        max_cycles = 100
+
+        [[case]]
+        name = "missing"
+        binary = "missing.bin"
+    "#;
+
+    fn write_program(directory: &Path) {
+        std::fs::write(directory.join("program.bin"), PROGRAM).unwrap();
+    }
+
+    #[test]
+    fn stop_conditions() {
+        assert_eq!("halt".parse(), Ok(StopCondition::Halt));
+        assert_eq!("RTS".parse(), Ok(StopCondition::Rts));
+        assert_eq!("$0210".parse(), Ok(StopCondition::Address(0x0210)));
+        assert!("jam".parse::<StopCondition>().is_err());
+    }
+
+    #[test]
+    fn addresses() {
+        let case = |fields: &str| toml::from_str::<Manifest>(&format!("[[case]]\nname = \"a\"\nbinary = \"a.bin\"\n{}", fields));
+        let manifest = case("load_address = \"$C000\"\nentry = 49154\nexpect = { pc = \"0xC010\" }").unwrap();
+        let parsed = &manifest.cases[0];
+        assert_eq!((parsed.load_address, parsed.entry, parsed.expect.pc), (Some(0xC000), Some(0xC002), Some(0xC010)));
+        assert!(case("entry = \"$10000\"").is_err());
+    }
+
+    #[test]
+    fn panics() {
+        let panic = std::panic::catch_unwind(|| panic!("stack {}", "overflow")).unwrap_err();
+        assert_eq!(panic_message(panic.as_ref()), "stack overflow");
+        let panic = std::panic::catch_unwind(|| panic!("halted")).unwrap_err();
+        assert_eq!(panic_message(panic.as_ref()), "halted");
+
+        // `JMP ($FFFF)` reads its target past the end of memory, which panics
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("jump.bin"), [0x6C, 0xFF, 0xFF]).unwrap();
+        let manifest: Manifest = toml::from_str("[[case]]\nname = \"jump\"\nbinary = \"jump.bin\"\nload_address = 0x0200\nentry = 0x0200\n").unwrap();
+        let results = run_cases(&manifest.cases, dir.path(), 1);
+        assert!(matches!(&results[0].outcome, Outcome::Error(message) if message.starts_with("panicked: ")), "{:?}", results[0].outcome);
+    }
+
+    #[test]
+    fn results() {
+        let dir = tempfile::tempdir().unwrap();
+        write_program(dir.path());
+        let manifest: Manifest = toml::from_str(MANIFEST).unwrap();
+        let results = run_cases(&manifest.cases, dir.path(), 2);
+
+        let outcomes: Vec<_> = results.iter().map(|result| (result.name.as_str(), &result.outcome)).collect();
+        assert_eq!(outcomes[0], ("store", &Outcome::Passed));
+        assert_eq!(outcomes[1], ("wrong", &Outcome::Failed(vec![
+            "A: expected $31, got $32".to_string(),
+            "flags: expected C set and - clear, got nvubdizc".to_string(),
+            "$2000: expected $33, got $32".to_string(),
+        ])));
+        assert_eq!(outcomes[2], ("runaway", &Outcome::Error("didn't stop within 100 cycles, it got to $0208".to_string())));
+        assert!(matches!(outcomes[3], ("missing", Outcome::Error(_))));
+        assert_eq!(results[0].cycles, 8);
+
+        let summary = summary(&results, Duration::from_millis(1500));
+        assert!(summary.starts_with("ok      store (8 cycles)\nFAILED  wrong (6 cycles)\n        A: expected $31, got $32\n"), "{}", summary);
+        assert!(summary.ends_with("\n1 passed, 1 failed, 2 errors in 1.50s\n"), "{}", summary);
+
+        let tap = tap(&results);
+        assert!(tap.starts_with("TAP version 13\n1..4\nok 1 - store\nnot ok 2 - wrong\n# A: expected $31, got $32\n"), "{}", tap);
+        assert!(tap.contains("not ok 3 - runaway\n# didn't stop within 100 cycles, it got to $0208\n"), "{}", tap);
+
+        let xml = junit("suite", &results, Duration::from_millis(1500));
+        assert!(xml.contains("<testsuite name=\"suite\" tests=\"4\" failures=\"1\" errors=\"2\" time=\"1.500\">"), "{}", xml);
+        assert!(xml.contains("<failure message=\"3 differences\">A: expected $31, got $32\nflags: expected C set and - clear, got nvubdizc\n$2000: expected $33, got $32</failure>"), "{}", xml);
+        assert!(xml.contains("<error message=\"didn't stop within 100 cycles, it got to $0208\"/>"), "{}", xml);
+    }
+
+    #[test]
+    fn rts_and_exit_code() {
+        let dir = tempfile::tempdir().unwrap();
+        write_program(dir.path());
+        let manifest = dir.path().join("suite.toml");
+        let junit = dir.path().join("junit.xml");
+        let opts = TestOpts::from_iter(["test", manifest.to_str().unwrap(), "--junit", junit.to_str().unwrap()]);
+
+        // Calling the RTS at $0207 returns right away, stopping there
+        std::fs::write(&manifest, "[[case]]\nname = \"call\"\nbinary = \"program.bin\"\nload_address = 0x0200\nentry = 0x0207\nstop = \"rts\"\nexpect = { pc = 0x0207 }\n").unwrap();
+        assert_eq!(run(&opts).unwrap(), 0);
+        assert!(std::fs::read_to_string(&junit).unwrap().contains("<testcase name=\"call\" classname=\"suite\""));
+
+        std::fs::write(&manifest, MANIFEST).unwrap();
+        assert_eq!(run(&opts).unwrap(), 1);
+
+        std::fs::write(&manifest, "[[case]]\nname = \"typo\"\nbinary = \"program.bin\"\nexpect = { flags_set = \"Q\" }\n").unwrap();
+        let error = run(&opts).err().unwrap().to_string();
+        assert!(error.contains("'Q' is not one of the flags"), "{}", error);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,26 @@
+use std::path::PathBuf;
+use emulator_6502_core::SymbolTable;
+use log::info;
+use crate::error::{Error, Result};
+
+/// Load label files into a single symbol table, detecting the format of each file
+pub fn load_symbols(paths: &[PathBuf]) -> Result<SymbolTable> {
+    let mut symbols = SymbolTable::new();
+    for path in paths {
+        let text = std::fs::read_to_string(path)?;
+        let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let added = symbols.load_file(&file_name, &text)
+            .map_err(|error| Error::InvalidSymbols { path: path.clone(), error })?;
+        info!("Loaded {} symbols from {:?}", added, path);
+    }
+
+    Ok(symbols)
+}
+
+/// The name of `address` for reports: the symbol at the address, or else the address in hexadecimal
+pub fn address_name(symbols: &SymbolTable, address: u16) -> String {
+    match symbols.symbol_at(address, None) {
+        Some(symbol) => symbol.to_string(),
+        None => format!("${:04X}", address),
+    }
+}
\ No newline at end of file
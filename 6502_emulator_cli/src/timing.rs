@@ -0,0 +1,122 @@
+use std::path::{Path, PathBuf};
+use emulator_6502_core::{analyze_timing, CallLimits, Cpu, Location, Routine};
+use log::error;
+use structopt::StructOpt;
+use crate::error::{Error, Result};
+use crate::image::{load_image, ImageOpts};
+use crate::parse::{parse_address, parse_location, parse_number};
+
+#[derive(StructOpt)]
+pub struct TimingOpts {
+    #[structopt(flatten)]
+    pub image: ImageOpts,
+    /// The address of the routine, which is called with `JSR` and has to return with `RTS`
+    #[structopt(long, parse(try_from_str = parse_address))]
+    pub routine: u16,
+    /// An input of the routine to vary: `a`, `x`, `y`, `c` for the carry, or a memory address like $80.
+    /// May be given several times
+    #[structopt(long = "vary", parse(try_from_str = parse_location))]
+    pub inputs: Vec<Location>,
+    /// A file with a case per line, holding the value of every input separated by spaces.
+    /// Without it, the routine is run with every combination of input values
+    #[structopt(parse(from_os_str), long)]
+    pub cases: Option<PathBuf>,
+    /// Fail if a case takes more than this many cycles, including the `RTS`
+    #[structopt(long)]
+    pub budget: Option<u64>,
+    /// Fail unless every case takes the same amount of cycles
+    #[structopt(long)]
+    pub constant: bool,
+    /// Give up on a case after this many instructions
+    #[structopt(long, default_value = "1000000")]
+    pub max_instructions: u64,
+}
+
+/// Measure the cycles of the routine over its cases and print the report.
+/// Returns the exit code for the process, which is 1 if the routine misses the budget or isn't constant time as required
+pub fn run(opts: &TimingOpts) -> Result<i32> {
+    let mut image = load_image(&opts.image)?;
+    let cases = opts.cases.as_deref().map(|path| read_cases(path, opts.inputs.len())).transpose()?;
+    let routine = Routine {
+        limits: CallLimits { instructions: opts.max_instructions, cycles: u64::MAX },
+        ..Routine::new(opts.routine, &opts.inputs, &[])
+    };
+
+    let report = analyze_timing(&mut Cpu::default(), &mut image.memory, &routine, cases.as_deref()).map_err(Error::Timing)?;
+    println!("{}", report);
+
+    let mut code = 0;
+    if let Some(budget) = opts.budget {
+        let over = report.over_budget(budget);
+        if over > 0 {
+            error!("{} of {} cases took more than {} cycles", over, report.cases, budget);
+            code = 1;
+        }
+    }
+    if opts.constant && !report.is_constant() {
+        error!("The routine doesn't take the same amount of cycles for every case");
+        code = 1;
+    }
+    Ok(code)
+}
+
+/// Read a file of cases with `inputs` values per line. Empty lines and lines starting with `#` are skipped
+fn read_cases(path: &Path, inputs: usize) -> Result<Vec<Vec<u8>>> {
+    let text = std::fs::read_to_string(path)?;
+    let invalid = |line: usize, message: String| Error::InvalidCases { path: path.to_path_buf(), line: line + 1, message };
+
+    let mut cases = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values = line.split_whitespace()
+            .map(|value| parse_number(value).and_then(|n| u8::try_from(n).ok()).ok_or_else(|| invalid(index, format!("'{}' is not a byte", value))))
+            .collect::<Result<Vec<u8>>>()?;
+        if values.len() != inputs {
+            return Err(invalid(index, format!("expected {} values, one for each input, got {}", inputs, values.len())));
+        }
+        cases.push(values);
+    }
+    Ok(cases)
+}
+
+#[cfg(test)]
+mod test {
+    use emulator_6502_core::{BasicMemory, Location, Memory, MAX_MEMORY};
+    use structopt::StructOpt;
+    use super::{read_cases, run, TimingOpts};
+
+    #[test]
+    fn cases_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cases.txt");
+        std::fs::write(&path, "# A X\n$10 0\n\n255 0x20\n").unwrap();
+        assert_eq!(read_cases(&path, 2).unwrap(), [vec![0x10, 0x00], vec![0xFF, 0x20]]);
+
+        let error = read_cases(&path, 3).err().unwrap().to_string();
+        assert!(error.ends_with("cases.txt: line 2: expected 3 values, one for each input, got 2"), "{}", error);
+        std::fs::write(&path, "256\n").unwrap();
+        let error = read_cases(&path, 1).err().unwrap().to_string();
+        assert!(error.ends_with("cases.txt: line 1: '256' is not a byte"), "{}", error);
+    }
+
+    #[test]
+    fn budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.bin");
+        // BCS +0, RTS
+        let mut memory = vec![0; MAX_MEMORY];
+        memory[0x0200..0x0203].copy_from_slice(&[0xB0, 0x00, 0x60]);
+        std::fs::write(&path, &memory).unwrap();
+        assert_eq!(BasicMemory::from(memory.as_slice()).read(0x0200), 0xB0);
+
+        let opts = |args: &[&str]| TimingOpts::from_iter(["timing", "-i", path.to_str().unwrap(), "--routine", "$0200", "--vary", "c"].iter().chain(args));
+        assert_eq!(opts(&[]).inputs, [Location::Carry]);
+        assert_eq!(run(&opts(&[])).unwrap(), 0);
+        assert_eq!(run(&opts(&["--budget", "9"])).unwrap(), 0);
+        assert_eq!(run(&opts(&["--budget", "8"])).unwrap(), 1);
+        assert_eq!(run(&opts(&["--constant"])).unwrap(), 1);
+    }
+}
\ No newline at end of file
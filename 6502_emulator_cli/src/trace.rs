@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use emulator_6502_core::{trace_line, Cpu, Memory, TraceFilter, Tracer, MAX_MEMORY};
+use log::warn;
+use structopt::StructOpt;
+use crate::error::Result;
+use crate::parse::parse_address_range;
+
+#[derive(StructOpt)]
+pub struct TraceOpts {
+    /// Write a line per executed instruction to this file, in the format of nestest.log
+    #[structopt(parse(from_os_str), long)]
+    pub trace: Option<PathBuf>,
+    /// Only trace instructions in this address range, e.g. $C000-$C0FF
+    #[structopt(long, parse(try_from_str = parse_address_range))]
+    pub trace_range: Option<RangeInclusive<u16>>,
+    /// Only trace instructions starting at or after this cycle
+    #[structopt(long)]
+    pub trace_start_cycle: Option<u64>,
+    /// Stop tracing at this cycle
+    #[structopt(long)]
+    pub trace_stop_cycle: Option<u64>,
+}
+
+impl TraceOpts {
+    /// Install a [FileTracer] on the CPU if tracing was requested
+    pub fn install(&self, cpu: &mut Cpu) -> Result<()> {
+        if let Some(path) = &self.trace {
+            let filter = TraceFilter {
+                address_range: self.trace_range.clone(),
+                start_cycle: self.trace_start_cycle,
+                stop_cycle: self.trace_stop_cycle,
+            };
+            cpu.set_tracer(Some(Box::new(FileTracer::create(path, filter)?)));
+        }
+
+        Ok(())
+    }
+}
+
+/// How many lines are buffered before they are written to the file
+const FLUSH_INTERVAL: u32 = 4096;
+
+/// Writes trace lines to a file
+pub struct FileTracer {
+    writer: Option<BufWriter<File>>,
+    filter: TraceFilter,
+    buffered: u32,
+}
+
+impl FileTracer {
+    pub fn create(path: &Path, filter: TraceFilter) -> Result<Self> {
+        Ok(Self {
+            writer: Some(BufWriter::new(File::create(path)?)),
+            filter,
+            buffered: 0,
+        })
+    }
+}
+
+impl Tracer for FileTracer {
+    fn trace(&mut self, cpu: &Cpu, memory: &dyn Memory<MAX_MEMORY>) {
+        let writer = match self.writer.as_mut() {
+            Some(w) => w,
+            None => return,
+        };
+
+        // Close the file as soon as nothing will be traced anymore, so it's complete while the program keeps running
+        if self.filter.is_finished(cpu.cycles()) {
+            if let Err(e) = writer.flush() {
+                warn!("Failed to write trace: {}", e);
+            }
+            self.writer = None;
+            return;
+        }
+
+        if !self.filter.matches(cpu.program_counter(), cpu.cycles()) {
+            return;
+        }
+
+        let mut result = writeln!(writer, "{}", trace_line(cpu, memory));
+        self.buffered += 1;
+        if result.is_ok() && self.buffered >= FLUSH_INTERVAL {
+            self.buffered = 0;
+            result = writer.flush();
+        }
+
+        if let Err(e) = result {
+            warn!("Failed to write trace, tracing stopped: {}", e);
+            self.writer = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use emulator_6502_core::{BasicMemory, Cpu, Memory, TraceFilter, LDA_IMMEDIATE, NOP_IMPLIED};
+    use tempfile::NamedTempFile;
+    use super::FileTracer;
+
+    #[test]
+    fn writes_filtered_lines() {
+        let mut memory = BasicMemory::default();
+        memory.write(0x0200, LDA_IMMEDIATE);
+        memory.write(0x0201, 0x42);
+        memory.write(0x0202, NOP_IMPLIED);
+        memory.write(0x0203, NOP_IMPLIED);
+        memory.write(0x0204, NOP_IMPLIED);
+
+        let file = NamedTempFile::new().unwrap();
+        let filter = TraceFilter { start_cycle: Some(2), stop_cycle: Some(6), ..TraceFilter::default() };
+
+        let mut cpu = Cpu::default();
+        cpu.set_program_counter(0x0200);
+        cpu.set_tracer(Some(Box::new(FileTracer::create(file.path(), filter).unwrap())));
+        for _ in 0..4 {
+            cpu.step(&mut memory);
+        }
+
+        let trace = std::fs::read_to_string(file.path()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("0202  EA        NOP"));
+        assert!(lines[0].ends_with("A:42 X:00 Y:00 P:20 SP:FF PPU:  0,  0 CYC:2"));
+        assert!(lines[1].ends_with("CYC:4"));
+    }
+}
\ No newline at end of file
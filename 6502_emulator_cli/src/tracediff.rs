@@ -0,0 +1,29 @@
+use std::path::Path;
+use emulator_6502_core::{diff_trace, Cpu, TraceDiff, TraceDiffOptions};
+use log::info;
+use crate::error::{Error, Result};
+use crate::image::{load_image, ImageOpts};
+
+/// Run the program in lockstep with the `reference` trace, printing a report of the first divergence.
+/// Execution starts at `entry`, or at the reset vector if not given
+pub fn run(image: &ImageOpts, reference: &Path, entry: Option<u16>, options: &TraceDiffOptions) -> Result<()> {
+    let mut memory = load_image(image)?.memory;
+    let reference = std::fs::read_to_string(reference)?;
+
+    let mut cpu = Cpu::default();
+    match entry {
+        Some(address) => cpu.set_program_counter(address),
+        None => cpu.load_reset_vector(&memory),
+    }
+
+    match diff_trace(&mut cpu, &mut memory, reference.lines(), options) {
+        TraceDiff::Matched { instructions } => {
+            info!("All {} instructions matched the reference trace", instructions);
+            Ok(())
+        },
+        TraceDiff::Diverged(divergence) => {
+            print!("{}", divergence);
+            Err(Error::TraceDiverged(divergence.instruction))
+        },
+    }
+}
\ No newline at end of file
@@ -0,0 +1,191 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use crate::ops::{BRK_IMPLIED, JSR_ABSOLUTE, RTI_IMPLIED, RTS_IMPLIED};
+
+/// The default limit of [CallGraph::max_depth]
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 256;
+
+/// An entry on the shadow call stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Frame {
+    /// The stack pointer before the call, which is restored by the matching return
+    stack_pointer: u8,
+    /// Whether the frame was entered by an interrupt, and is left with `RTI` instead of `RTS`
+    interrupt: bool,
+    /// The address of the `JSR` or `BRK` which entered the frame
+    call_site: u16,
+}
+
+/// Attributes cycles to call paths, following `JSR` / `RTS` and `BRK` / `RTI` on a shadow call stack.
+/// Enable it with [crate::Cpu::enable_call_graph].
+///
+/// A return is matched to its call by the stack pointer, so returns which don't match a call, like an `RTS` used as a jump,
+/// leave the shadow stack alone, while dropping return addresses from the stack before returning unwinds several frames at once.
+/// Calls nested deeper than the maximum depth are attributed to the deepest tracked routine
+#[derive(Debug, Clone)]
+pub struct CallGraph {
+    max_depth: usize,
+    /// The routines on the call path, starting with the first executed instruction
+    path: Vec<u16>,
+    frames: Vec<Frame>,
+    /// Exclusive cycles per call path
+    paths: BTreeMap<Vec<u16>, u64>,
+}
+
+impl CallGraph {
+    /// Create a call graph tracking calls up to `max_depth` deep
+    pub fn new(max_depth: usize) -> Self {
+        Self {
+            max_depth,
+            path: Vec::new(),
+            frames: Vec::new(),
+            paths: BTreeMap::new(),
+        }
+    }
+
+    /// How deep calls are tracked
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// The routines on the current call path, outermost first
+    pub fn current_path(&self) -> &[u16] {
+        &self.path
+    }
+
+    /// The addresses of the calls on the current path, outermost first.
+    /// The routine at `current_path()[i + 1]` was called from `call_sites()[i]`
+    pub fn call_sites(&self) -> impl DoubleEndedIterator<Item = u16> + '_ {
+        self.frames.iter().map(|f| f.call_site)
+    }
+
+    /// Every call path with the cycles spent in its innermost routine, excluding the routines it called
+    pub fn paths(&self) -> impl Iterator<Item = (&[u16], u64)> {
+        self.paths.iter().map(|(path, cycles)| (path.as_slice(), *cycles))
+    }
+
+    /// The cycles spent in `path`, excluding the routines it called
+    pub fn exclusive_cycles(&self, path: &[u16]) -> u64 {
+        self.paths.get(path).copied().unwrap_or_default()
+    }
+
+    /// The cycles spent in `path`, including the routines it called
+    pub fn inclusive_cycles(&self, path: &[u16]) -> u64 {
+        self.paths.range(path.to_vec()..)
+            .take_while(|(p, _)| p.starts_with(path))
+            .map(|(_, cycles)| cycles)
+            .sum()
+    }
+
+    /// Write the call paths in the folded stack format read by flamegraph tools,
+    /// e.g. `main;update;draw 1234`, naming routines with `name`
+    pub fn folded(&self, name: impl Fn(u16) -> String) -> String {
+        let mut folded = String::new();
+        for (path, cycles) in self.paths() {
+            let names: Vec<String> = path.iter().map(|address| name(*address)).collect();
+            let _ = writeln!(folded, "{} {}", names.join(";"), cycles);
+        }
+
+        folded
+    }
+
+    pub fn clear(&mut self) {
+        self.path.clear();
+        self.frames.clear();
+        self.paths.clear();
+    }
+
+    /// Attribute an instruction which started at `address` to the current path, then follow calls and returns.
+    /// `stack_pointer` is the stack pointer before the instruction, `next` the address and `next_stack_pointer`
+    /// the stack pointer after it
+    pub(crate) fn record(&mut self, address: u16, opcode: u8, cycles: u32, stack_pointer: u8, next: u16, next_stack_pointer: u8) {
+        if self.path.is_empty() {
+            self.path.push(address);
+        }
+
+        match self.paths.get_mut(self.path.as_slice()) {
+            Some(total) => *total += cycles as u64,
+            None => {
+                self.paths.insert(self.path.clone(), cycles as u64);
+            },
+        }
+
+        match opcode {
+            JSR_ABSOLUTE | BRK_IMPLIED if self.frames.len() < self.max_depth => {
+                self.frames.push(Frame { stack_pointer, interrupt: opcode == BRK_IMPLIED, call_site: address });
+                self.path.push(next);
+            },
+            RTS_IMPLIED | RTI_IMPLIED => {
+                let interrupt = opcode == RTI_IMPLIED;
+                let matching = self.frames.iter()
+                    .rposition(|f| f.stack_pointer == next_stack_pointer && f.interrupt == interrupt);
+
+                if let Some(index) = matching {
+                    self.frames.truncate(index);
+                    self.path.truncate(index + 1);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+impl Default for CallGraph {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_CALL_DEPTH)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::format;
+    use alloc::vec::Vec;
+    use crate::ops::*;
+    use super::CallGraph;
+
+    #[test]
+    fn inclusive_and_exclusive() {
+        let mut graph = CallGraph::default();
+        // main at 0x0200 calls 0x0300, which calls 0x0400
+        graph.record(0x0200, JSR_ABSOLUTE, 6, 0xFF, 0x0300, 0x01);
+        graph.record(0x0300, JSR_ABSOLUTE, 6, 0x01, 0x0400, 0x03);
+        graph.record(0x0400, INX_IMPLIED, 2, 0x03, 0x0401, 0x03);
+        assert_eq!(graph.call_sites().collect::<Vec<_>>(), [0x0200, 0x0300]);
+        graph.record(0x0401, RTS_IMPLIED, 6, 0x03, 0x0303, 0x01);
+        graph.record(0x0303, RTS_IMPLIED, 6, 0x01, 0x0203, 0xFF);
+        graph.record(0x0203, NOP_IMPLIED, 2, 0xFF, 0x0204, 0xFF);
+
+        assert_eq!(graph.current_path(), [0x0200]);
+        assert_eq!(graph.exclusive_cycles(&[0x0200]), 8);
+        assert_eq!(graph.inclusive_cycles(&[0x0200]), 28);
+        assert_eq!(graph.exclusive_cycles(&[0x0200, 0x0300]), 12);
+        assert_eq!(graph.inclusive_cycles(&[0x0200, 0x0300]), 20);
+        assert_eq!(graph.inclusive_cycles(&[0x0200, 0x0300, 0x0400]), 8);
+
+        let folded = graph.folded(|address| format!("{:04X}", address));
+        assert_eq!(folded, "0200 8\n0200;0300 12\n0200;0300;0400 8\n");
+    }
+
+    #[test]
+    fn non_standard_flow() {
+        let mut graph = CallGraph::new(2);
+
+        // An RTS used as a jump doesn't match any call
+        graph.record(0x0200, RTS_IMPLIED, 6, 0x02, 0x0300, 0x00);
+        assert_eq!(graph.current_path(), [0x0200]);
+
+        // Calls beyond the maximum depth are not tracked
+        graph.record(0x0300, JSR_ABSOLUTE, 6, 0x00, 0x0400, 0x02);
+        graph.record(0x0400, JSR_ABSOLUTE, 6, 0x02, 0x0500, 0x04);
+        graph.record(0x0500, JSR_ABSOLUTE, 6, 0x04, 0x0600, 0x06);
+        assert_eq!(graph.current_path(), [0x0200, 0x0400, 0x0500]);
+
+        // Dropping a return address and returning unwinds two frames
+        graph.record(0x0600, RTS_IMPLIED, 6, 0x06, 0x0503, 0x04);
+        assert_eq!(graph.current_path(), [0x0200, 0x0400, 0x0500]);
+        graph.record(0x0503, RTS_IMPLIED, 6, 0x02, 0x0303, 0x00);
+        assert_eq!(graph.current_path(), [0x0200]);
+    }
+}
\ No newline at end of file
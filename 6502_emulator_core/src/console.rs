@@ -0,0 +1,175 @@
+use alloc::boxed::Box;
+use crate::device::{Bus, Device};
+use crate::memory::{Memory, MAX_MEMORY};
+
+/// Where the registers of a [ConsoleDevice] are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsoleAddresses {
+    /// Writing this address prints the byte, like `putchar`
+    pub output: u16,
+    /// Reading this address takes the next input byte, like `getchar`
+    pub input: u16,
+}
+
+impl ConsoleAddresses {
+    /// The console of the py65 monitor
+    pub const PY65: Self = Self { output: 0xF001, input: 0xF004 };
+    /// The terminal of the Kowalski simulator, with its I/O area at the default $E000
+    pub const KOWALSKI: Self = Self { output: 0xE001, input: 0xE004 };
+}
+
+/// Character I/O through a pair of registers: bytes written to the output register are passed to the output,
+/// reading the input register takes the next byte of the input, or a status value when none is available.
+/// Both registers are a single byte, so the addresses between them stay memory. They may be the same address
+pub struct ConsoleDevice {
+    addresses: ConsoleAddresses,
+    output: Box<dyn FnMut(u8)>,
+    input: Box<dyn FnMut() -> Option<u8>>,
+    empty: u8,
+}
+
+impl ConsoleDevice {
+    /// A console passing output to `output` and taking input from `input`, which must not block.
+    /// Reading the input register without input available gives 0, as with py65 and Kowalski
+    pub fn new(addresses: ConsoleAddresses, output: impl FnMut(u8) + 'static, input: impl FnMut() -> Option<u8> + 'static) -> Self {
+        Self { addresses, output: Box::new(output), input: Box::new(input), empty: 0 }
+    }
+
+    /// Read `status` from the input register when no input is available, instead of 0
+    pub fn with_empty_status(mut self, status: u8) -> Self {
+        self.empty = status;
+        self
+    }
+
+    pub fn addresses(&self) -> ConsoleAddresses {
+        self.addresses
+    }
+
+    /// Map the registers at their addresses
+    pub fn map<M: Memory<MAX_MEMORY>>(self, bus: &mut Bus<M>) {
+        let input = ConsoleRegister { output: None, input: Some(self.input), empty: self.empty };
+        if self.addresses.output == self.addresses.input {
+            bus.map(self.addresses.input, ConsoleRegister { output: Some(self.output), ..input });
+        } else {
+            bus.map(self.addresses.output, ConsoleRegister { output: Some(self.output), input: None, empty: self.empty });
+            bus.map(self.addresses.input, input);
+        }
+    }
+}
+
+/// One register of a [ConsoleDevice], the output, the input or both
+struct ConsoleRegister {
+    output: Option<Box<dyn FnMut(u8)>>,
+    input: Option<Box<dyn FnMut() -> Option<u8>>>,
+    empty: u8,
+}
+
+impl Device for ConsoleRegister {
+    fn size(&self) -> u16 {
+        1
+    }
+
+    fn read(&mut self, _offset: u16) -> u8 {
+        match self.input.as_mut() {
+            Some(input) => input().unwrap_or(self.empty),
+            None => 0,
+        }
+    }
+
+    /// The next input byte can't be looked at without taking it, so the input register peeks as the empty status
+    fn peek(&self, _offset: u16) -> u8 {
+        match self.input {
+            Some(_) => self.empty,
+            None => 0,
+        }
+    }
+
+    fn write(&mut self, _offset: u16, value: u8) {
+        if let Some(output) = self.output.as_mut() {
+            output(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::collections::VecDeque;
+    use alloc::rc::Rc;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+    use crate::cpu::Cpu;
+    use crate::device::Bus;
+    use crate::memory::{BasicMemory, Memory};
+    use crate::ops::{BEQ_RELATIVE, BRK_IMPLIED, JMP_ABSOLUTE, LDA_ABSOLUTE, STA_ABSOLUTE};
+    use crate::trace::trace_line;
+    use super::*;
+
+    /// A bus with `program` at $0200 and a console at `addresses` reading `input`, and where its output goes
+    fn setup(program: &[u8], addresses: ConsoleAddresses, input: &[u8]) -> (Bus<BasicMemory>, Rc<RefCell<Vec<u8>>>) {
+        let mut memory = BasicMemory::default();
+        memory.load(0x0200, program, 0).unwrap();
+        let mut bus = Bus::new(memory);
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let sink = output.clone();
+        let mut input: VecDeque<u8> = input.iter().copied().collect();
+        ConsoleDevice::new(addresses, move |c| sink.borrow_mut().push(c), move || input.pop_front()).map(&mut bus);
+        (bus, output)
+    }
+
+    #[test]
+    fn echo() {
+        // Echo input until none is available, like py65's getc loop
+        let program = [
+            LDA_ABSOLUTE, 0x04, 0xF0,
+            BEQ_RELATIVE, 0x06,
+            STA_ABSOLUTE, 0x01, 0xF0,
+            JMP_ABSOLUTE, 0x00, 0x02,
+            BRK_IMPLIED,
+        ];
+        let (mut bus, output) = setup(&program, ConsoleAddresses::PY65, b"hi");
+        let mut cpu = Cpu::default();
+        cpu.set_program_counter(0x0200);
+        cpu.enable_history(4);
+        while bus.peek(cpu.program_counter()) != BRK_IMPLIED && cpu.instructions() < 100 {
+            assert!(!trace_line(&cpu, &bus).is_empty());
+            cpu.step(&mut bus);
+        }
+        assert_eq!(*output.borrow(), b"hi");
+        assert_eq!(cpu.program_counter(), 0x020B);
+    }
+
+    #[test]
+    fn registers() {
+        let addresses = ConsoleAddresses { output: 0xD012, input: 0xD010 };
+        let (mut bus, output) = setup(&[STA_ABSOLUTE, 0x10, 0xD0], addresses, b"x");
+        let console = ConsoleDevice::new(addresses, |_| {}, || None).with_empty_status(0xFF);
+        assert_eq!(console.addresses(), addresses);
+
+        // A store to the input register, recorded in the history, doesn't take input
+        let mut cpu = Cpu::default();
+        cpu.set_program_counter(0x0200);
+        cpu.enable_history(1);
+        cpu.step(&mut bus);
+        bus.write(0xD011, 0x42);
+        bus.write(0xD012, b'b');
+        assert_eq!(*output.borrow(), b"b");
+        assert_eq!([bus.peek(0xD010), bus.peek(0xD011), bus.peek(0xD012)], [0x00, 0x42, 0x00]);
+        assert_eq!([bus.read(0xD010), bus.read(0xD010)], [b'x', 0x00]);
+
+        let mut bus = Bus::new(BasicMemory::default());
+        console.map(&mut bus);
+        assert_eq!(bus.read(0xD010), 0xFF);
+    }
+
+    #[test]
+    fn shared_and_distant_registers() {
+        let (mut bus, output) = setup(&[], ConsoleAddresses { output: 0xF000, input: 0xF000 }, b"y");
+        bus.write(0xF000, b'z');
+        assert_eq!((bus.read(0xF000), output.borrow().as_slice()), (b'y', b"z".as_slice()));
+
+        let (mut bus, output) = setup(&[], ConsoleAddresses { output: 0x0000, input: 0xFFFF }, b"");
+        bus.write(0x0000, b'!');
+        bus.write(0x8000, 0x55);
+        assert_eq!((bus.read(0x8000), output.borrow().as_slice()), (0x55, b"!".as_slice()));
+    }
+}
\ No newline at end of file
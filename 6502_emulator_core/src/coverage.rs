@@ -0,0 +1,143 @@
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::memory::MAX_MEMORY;
+
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// How often a branch instruction was taken and not taken
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCount {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// Which instructions were executed, and which way every executed branch went.
+/// Enable it with [crate::Cpu::enable_coverage]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    /// A bit per address, set if an instruction starting there was executed
+    executed: Vec<u64>,
+    branches: BTreeMap<u16, BranchCount>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self {
+            executed: vec![0; MAX_MEMORY / WORD_BITS],
+            branches: BTreeMap::new(),
+        }
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether an instruction starting at `address` was executed
+    pub fn is_executed(&self, address: u16) -> bool {
+        let address = address as usize;
+        self.executed[address / WORD_BITS] & (1 << (address % WORD_BITS)) != 0
+    }
+
+    /// The addresses of all executed instructions, in ascending order
+    pub fn executed(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..MAX_MEMORY)
+            .map(|address| address as u16)
+            .filter(|address| self.is_executed(*address))
+    }
+
+    /// The counts of the branch instruction at `address`, if it was executed
+    pub fn branch(&self, address: u16) -> Option<BranchCount> {
+        self.branches.get(&address).copied()
+    }
+
+    /// All executed branch instructions with their counts, in address order
+    pub fn branches(&self) -> impl Iterator<Item = (u16, BranchCount)> + '_ {
+        self.branches.iter().map(|(address, count)| (*address, *count))
+    }
+
+    /// Add the coverage of another run
+    pub fn merge(&mut self, other: &Coverage) {
+        for (word, other) in self.executed.iter_mut().zip(&other.executed) {
+            *word |= other;
+        }
+
+        for (address, count) in other.branches() {
+            let branch = self.branches.entry(address).or_default();
+            branch.taken += count.taken;
+            branch.not_taken += count.not_taken;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Mark the instruction at `address` as executed, counting which way it went if it's a branch.
+    /// Whether a branch was taken follows from its condition, not where execution continued,
+    /// as a branch with an offset of 0 continues right after itself either way
+    pub(crate) fn record(&mut self, address: u16, branch_taken: Option<bool>) {
+        let index = address as usize;
+        self.executed[index / WORD_BITS] |= 1 << (index % WORD_BITS);
+
+        if let Some(taken) = branch_taken {
+            let branch = self.branches.entry(address).or_default();
+            if taken {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+    use crate::cpu::Cpu;
+    use crate::memory::BasicMemory;
+    use crate::ops::*;
+    use super::{BranchCount, Coverage};
+
+    #[test]
+    fn executed_and_branches() {
+        let mut coverage = Coverage::new();
+        coverage.record(0x0200, None);
+        coverage.record(0x0201, Some(true));
+        coverage.record(0x0201, Some(false));
+        coverage.record(0xFFFF, None);
+
+        assert!(coverage.is_executed(0x0200));
+        assert!(!coverage.is_executed(0x0202));
+        assert_eq!(coverage.executed().collect::<Vec<_>>(), [0x0200, 0x0201, 0xFFFF]);
+        assert_eq!(coverage.branch(0x0201), Some(BranchCount { taken: 1, not_taken: 1 }));
+        assert_eq!(coverage.branch(0x0200), None);
+
+        let mut other = Coverage::new();
+        other.record(0x0300, None);
+        other.record(0x0201, Some(false));
+        coverage.merge(&other);
+
+        assert!(coverage.is_executed(0x0300));
+        assert_eq!(coverage.branch(0x0201), Some(BranchCount { taken: 1, not_taken: 2 }));
+    }
+
+    #[test]
+    fn branch_to_next_instruction() {
+        // SEC, then BCS and BCC with an offset of 0, which continue at the next instruction either way
+        let mut memory = BasicMemory::default();
+        memory.load(0x0200, &[SEC_IMPLIED, BCS_RELATIVE, 0x00, BCC_RELATIVE, 0x00], 0).unwrap();
+        let mut cpu = Cpu::default();
+        cpu.set_program_counter(0x0200);
+        cpu.enable_coverage();
+        for _ in 0..3 {
+            cpu.step(&mut memory);
+        }
+
+        let coverage = cpu.coverage().unwrap();
+        assert_eq!(coverage.branch(0x0201), Some(BranchCount { taken: 1, not_taken: 0 }));
+        assert_eq!(coverage.branch(0x0203), Some(BranchCount { taken: 0, not_taken: 1 }));
+    }
+}
\ No newline at end of file
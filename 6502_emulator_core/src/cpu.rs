@@ -1,7 +1,19 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::fmt;
 use core::num::Wrapping;
 use bitflags::bitflags;
+use crate::callgraph::CallGraph;
+use crate::coverage::Coverage;
+use crate::device::Exit;
+use crate::disassembler::Instruction;
+use crate::history::{History, MemoryWrite};
 use crate::memory::{MAX_MEMORY, Memory};
 use crate::ops::*;
+use crate::profiler::Profiler;
+use crate::provenance::{Provenance, WriteRecord};
+use crate::trace::Tracer;
+use crate::trap::{Trap, TrapMemory, TrapReturn};
 
 #[cfg(test)]
 use log::debug;
@@ -10,6 +22,8 @@ const NEGATIVE_BIT: u8 = 0b1000_0000;
 
 const IRQ_INTERRUPT_VECTOR: u16 = 0xFFFE;
 const RESET_VECTOR: u16 = 0xFFFC;
+/// The return address [Cpu::call] pushes. It's only recognised together with the stack pointer the call started with
+const CALL_RETURN_ADDRESS: u16 = 0xFFFF;
 
 pub struct Cpu {
     program_counter: u16,
@@ -23,6 +37,22 @@ pub struct Cpu {
     flags: CpuStatusFlags,
 
     mode: OperatingMode,
+
+    /// Cycles executed since the CPU was created or reset
+    cycles: u64,
+    /// Instructions executed since the CPU was created or reset
+    instructions: u64,
+    /// The address of the instruction being executed
+    instruction_address: u16,
+    tracer: Option<Box<dyn Tracer>>,
+    history: Option<History>,
+    provenance: Option<Provenance>,
+    profiler: Option<Profiler>,
+    call_graph: Option<CallGraph>,
+    coverage: Option<Coverage>,
+    traps: BTreeMap<u16, Trap>,
+    /// The exit a trap asked for
+    exit: Option<Exit>,
 }
 
 /// This indicates what 6502 'version' to use. This affects certain instructions like `JMP`
@@ -48,6 +78,17 @@ impl Default for Cpu {
             register_y: 0,
             flags: CpuStatusFlags::default(),
             mode: OperatingMode::Wdc,
+            cycles: 0,
+            instructions: 0,
+            instruction_address: RESET_VECTOR,
+            tracer: None,
+            history: None,
+            provenance: None,
+            profiler: None,
+            call_graph: None,
+            coverage: None,
+            traps: BTreeMap::new(),
+            exit: None,
         }
     }
 }
@@ -66,20 +107,261 @@ impl Cpu {
         }
     }
 
-    /// Reset the CPU
+    /// Reset the CPU. An installed [Tracer], the [History], [Provenance], [Profiler], [CallGraph], [Coverage] and traps are kept
     pub fn reset(&mut self) {
         #[cfg(test)]
         debug!("Resetting CPU");
 
+        let tracer = self.tracer.take();
+        let history = self.history.take();
+        let provenance = self.provenance.take();
+        let profiler = self.profiler.take();
+        let call_graph = self.call_graph.take();
+        let coverage = self.coverage.take();
+        let traps = core::mem::take(&mut self.traps);
         *self = Self::default();
+        self.tracer = tracer;
+        self.history = history;
+        self.provenance = provenance;
+        self.profiler = profiler;
+        self.call_graph = call_graph;
+        self.coverage = coverage;
+        self.traps = traps;
+    }
+
+    /// The amount of cycles executed since the CPU was created or reset
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// The amount of instructions executed since the CPU was created or reset
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Install a [Tracer] which is called before every instruction, or remove it with `None`.
+    /// Returns the previously installed tracer
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Tracer>>) -> Option<Box<dyn Tracer>> {
+        core::mem::replace(&mut self.tracer, tracer)
+    }
+
+    /// The exit a trap asked for by returning [TrapReturn::Exit], until the CPU is reset
+    pub fn exit(&self) -> Option<Exit> {
+        self.exit
+    }
+
+    /// Run `trap` instead of the instruction at `address` whenever execution reaches it, then do what it returns.
+    /// A trap counts as one instruction, taking the cycles of the `RTS`, `RTI` or `JMP` it returns with,
+    /// and is recorded in the [History] with its writes, so [Self::step_back] undoes it. It isn't traced.
+    /// A trap can replace itself with [Self::set_trap], but not remove itself: it isn't set while it runs,
+    /// so it's set again afterwards. Returns the trap previously set for the address
+    pub fn set_trap(&mut self, address: u16, trap: Trap) -> Option<Trap> {
+        self.traps.insert(address, trap)
+    }
+
+    /// Remove the trap at `address`, returning it
+    pub fn remove_trap(&mut self, address: u16) -> Option<Trap> {
+        self.traps.remove(&address)
+    }
+
+    /// Start recording the last `capacity` executed instructions, so they can be undone with [Self::step_back].
+    /// Replaces any previously recorded history.
+    /// Stepping back undoes the [Profiler] counts, but not the [Coverage] or the [CallGraph]:
+    /// an undone instruction stays covered, and calls and returns it made stay in the call graph
+    pub fn enable_history(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+    }
+
+    /// Discard the recorded history, but keep recording. Used when memory or registers are changed from outside,
+    /// as stepping back over the change would restore the state before it
+    pub fn clear_history(&mut self) {
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+    }
+
+    /// Stop recording executed instructions and discard the history
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    /// The recorded history, if enabled
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Undo the most recently executed instruction, restoring the registers, cycle count and the memory it wrote.
+    /// Its writes are removed from the [Provenance] and it's no longer counted by the [Profiler].
+    /// Returns `false` if there is nothing to undo
+    pub fn step_back(&mut self, memory: &mut dyn Memory<MAX_MEMORY>) -> bool {
+        let entry = match self.history.as_mut().and_then(History::pop) {
+            Some(e) => e,
+            None => return false,
+        };
+
+        self.instructions = self.instructions.saturating_sub(1);
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.forget(entry.registers.program_counter, self.cycles.saturating_sub(entry.cycles) as u32);
+        }
+        for write in entry.writes.iter().rev() {
+            memory.write(write.address, write.old);
+            if let Some(provenance) = self.provenance.as_mut() {
+                provenance.forget_since(write.address, self.instructions, write.replaced);
+            }
+        }
+        self.set_registers(&entry.registers);
+        self.cycles = entry.cycles;
+        true
+    }
+
+    /// Start recording which instruction wrote every address, keeping the last `depth` writes per address.
+    /// Replaces any previously recorded writes
+    pub fn enable_provenance(&mut self, depth: usize) {
+        self.provenance = Some(Provenance::new(depth));
+    }
+
+    /// Stop recording writes and discard the recorded ones
+    pub fn disable_provenance(&mut self) {
+        self.provenance = None;
+    }
+
+    /// The recorded writes, if enabled
+    pub fn provenance(&self) -> Option<&Provenance> {
+        self.provenance.as_ref()
+    }
+
+    /// Start counting instructions and cycles per address. Replaces any previous counts
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    /// Stop counting and discard the counts
+    pub fn disable_profiler(&mut self) {
+        self.profiler = None;
+    }
+
+    /// The profiler, if enabled
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Start attributing cycles to call paths, tracking calls up to `max_depth` deep. Replaces any previous call graph
+    pub fn enable_call_graph(&mut self, max_depth: usize) {
+        self.call_graph = Some(CallGraph::new(max_depth));
+    }
+
+    /// Stop tracking calls and discard the call graph
+    pub fn disable_call_graph(&mut self) {
+        self.call_graph = None;
+    }
+
+    /// The call graph, if enabled
+    pub fn call_graph(&self) -> Option<&CallGraph> {
+        self.call_graph.as_ref()
+    }
+
+    /// Start recording which instructions are executed and which way branches go. Replaces any previous coverage
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    /// Stop recording coverage and discard it
+    pub fn disable_coverage(&mut self) {
+        self.coverage = None;
+    }
+
+    /// The recorded coverage, if enabled
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Retrieve a snapshot of all registers
+    pub fn registers(&self) -> Registers {
+        Registers {
+            accumulator: self.register_accumulator,
+            x: self.register_x,
+            y: self.register_y,
+            stack_pointer: self.stack_pointer,
+            program_counter: self.program_counter,
+            flags: self.flags,
+        }
+    }
+
+    /// Overwrite all registers with the provided values
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.register_accumulator = registers.accumulator;
+        self.register_x = registers.x;
+        self.register_y = registers.y;
+        self.stack_pointer = registers.stack_pointer;
+        self.program_counter = registers.program_counter;
+        self.flags = registers.flags;
+    }
+
+    /// The address of the next instruction to be executed
+    pub fn program_counter(&self) -> u16 {
+        self.program_counter
+    }
+
+    /// Set the address of the next instruction to be executed
+    pub fn set_program_counter(&mut self, address: u16) {
+        self.program_counter = address;
+    }
+
+    /// Set the program counter to the address stored in the reset vector at `0xFFFC`
+    pub fn load_reset_vector(&mut self, memory: &dyn Memory<MAX_MEMORY>) {
+        let mut cycles = u32::MAX;
+        self.program_counter = Self::read_word(memory, RESET_VECTOR, &mut cycles);
+    }
+
+    /// Call the subroutine at `address` like `JSR` would, with `registers` apart from their program counter,
+    /// and run it until its `RTS` returns from the call. Fails if it doesn't return within the default [CallLimits]
+    pub fn call(&mut self, memory: &mut dyn Memory<MAX_MEMORY>, address: u16, registers: &Registers) -> Result<CallResult, CallError> {
+        self.call_with_limits(memory, address, registers, &CallLimits::default())
+    }
+
+    /// Like [Self::call], failing if the subroutine doesn't return within `limits`
+    pub fn call_with_limits(&mut self, memory: &mut dyn Memory<MAX_MEMORY>, address: u16, registers: &Registers, limits: &CallLimits) -> Result<CallResult, CallError> {
+        self.call_observed(memory, address, registers, limits, &mut |_, _| {})
+    }
+
+    /// Like [Self::call_with_limits], passing the address and cycles of every executed instruction to `observe`
+    pub(crate) fn call_observed(&mut self, memory: &mut dyn Memory<MAX_MEMORY>, address: u16, registers: &Registers, limits: &CallLimits, observe: &mut dyn FnMut(u16, u32)) -> Result<CallResult, CallError> {
+        self.set_registers(&Registers { program_counter: address, ..*registers });
+        // Push the return address like `JSR`, without counting the cycles
+        let mut cycles = u32::MAX;
+        for byte in CALL_RETURN_ADDRESS.to_le_bytes() {
+            self.stack_push(memory, byte, &mut cycles);
+        }
+
+        let (cycles_before, instructions_before) = (self.cycles, self.instructions);
+        let mut rts_address = address;
+        while self.program_counter != CALL_RETURN_ADDRESS || self.stack_pointer != registers.stack_pointer {
+            let instructions = self.instructions - instructions_before;
+            let cycles = self.cycles - cycles_before;
+            if instructions >= limits.instructions {
+                return Err(CallError::InstructionLimit(self.registers()));
+            }
+            if cycles >= limits.cycles {
+                return Err(CallError::CycleLimit(self.registers()));
+            }
+            rts_address = self.program_counter;
+            let cycles = u32::MAX - self.execute_single(memory, u32::MAX);
+            observe(rts_address, cycles);
+        }
+
+        Ok(CallResult {
+            registers: self.registers(),
+            rts_address,
+            cycles: self.cycles - cycles_before,
+            instructions: self.instructions - instructions_before,
+        })
     }
 
     pub fn execute_instructions(&mut self, memory: &mut dyn Memory<MAX_MEMORY>, instructions: u16) {
         #[cfg(test)]
         debug!("Hey!");
         // Set the program counter
-        let mut cycles = u32::MAX;
-        self.program_counter = Self::read_word(memory, RESET_VECTOR, &mut cycles);
+        self.load_reset_vector(memory);
 
         for _ in 0..instructions {
             self.execute_single(memory, u32::MAX);
@@ -92,9 +374,39 @@ impl Cpu {
         }
     }
 
+    /// Execute a single instruction and return the amount of cycles it took
+    pub fn step(&mut self, memory: &mut dyn Memory<MAX_MEMORY>) -> u32 {
+        u32::MAX - self.execute_single(memory, u32::MAX)
+    }
+
     /// Execute instructions
     pub fn execute_single(&mut self, memory: &mut dyn Memory<MAX_MEMORY>, mut cycles: u32) -> u32 {
+        // Begun before any trap runs, so the history entry restores the registers it changes
+        if self.history.is_some() {
+            let instruction = Instruction::decode(memory, self.program_counter);
+            let registers = self.registers();
+            if let Some(history) = self.history.as_mut() {
+                history.begin(instruction, registers, self.cycles);
+            }
+        }
+        self.instruction_address = self.program_counter;
+
+        if !self.traps.is_empty() {
+            if let Some(cycles) = self.run_trap(memory, cycles) {
+                return cycles;
+            }
+        }
+
+        if let Some(mut tracer) = self.tracer.take() {
+            tracer.trace(self, memory);
+            self.tracer = Some(tracer);
+        }
+
+        let stack_pointer_before = self.stack_pointer;
+        let cycles_before = cycles;
         let instruction_byte = self.fetch_byte(memory, &mut cycles);
+        // Whether the instruction is a branch, and if so whether it was taken
+        let mut branch_taken = None;
 
         #[cfg(test)]
         debug!("Execting instruction: {:#04X}", instruction_byte);
@@ -175,55 +487,55 @@ impl Cpu {
             },
             STA_ZERO_PAGE => {
                 let zp_address = self.fetch_byte(memory, &mut cycles);
-                Self::write_byte(memory, zp_address as u16, self.register_accumulator, &mut cycles);
+                self.write_byte(memory, zp_address as u16, self.register_accumulator, &mut cycles);
             },
             STA_ZERO_PAGE_X => {
                 let addr = self.addr_zero_page_x(memory, &mut cycles);
-                Self::write_byte(memory, addr, self.register_accumulator, &mut cycles);
+                self.write_byte(memory, addr, self.register_accumulator, &mut cycles);
             },
             STA_ABSOLUTE => {
                 let address = self.fetch_word(memory, &mut cycles);
-                Self::write_byte(memory, address, self.register_accumulator, &mut cycles);
+                self.write_byte(memory, address, self.register_accumulator, &mut cycles);
             },
             STA_ABSOLUTE_X => {
                 let addr = self.addr_absolute_x_5(memory, &mut cycles);
-                Self::write_byte(memory, addr, self.register_accumulator, &mut cycles);
+                self.write_byte(memory, addr, self.register_accumulator, &mut cycles);
             },
             STA_ABSOLUTE_Y => {
                 let addr = self.addr_absolute_y_5(memory, &mut cycles);
-                Self::write_byte(memory, addr, self.register_accumulator, &mut cycles);
+                self.write_byte(memory, addr, self.register_accumulator, &mut cycles);
             },
             STA_INDIRECT_X => {
                 let addr = self.addr_indirect_x(memory, &mut cycles);
-                Self::write_byte(memory, addr, self.register_accumulator, &mut cycles);
+                self.write_byte(memory, addr, self.register_accumulator, &mut cycles);
             },
             STA_INDIRECT_Y => {
                 let addr = self.addr_indirect_y_5(memory, &mut cycles);
-                Self::write_byte(memory, addr, self.register_accumulator, &mut cycles);
+                self.write_byte(memory, addr, self.register_accumulator, &mut cycles);
             },
             STX_ZERO_PAGE => {
                 let zp_address = self.fetch_byte(memory, &mut cycles);
-                Self::write_byte(memory, zp_address as u16, self.register_x, &mut cycles);
+                self.write_byte(memory, zp_address as u16, self.register_x, &mut cycles);
             },
             STX_ZERO_PAGE_Y => {
                 let addr = self.addr_zero_page_y(memory, &mut cycles);
-                Self::write_byte(memory, addr, self.register_x, &mut cycles);
+                self.write_byte(memory, addr, self.register_x, &mut cycles);
             },
             STX_ABSOLUTE => {
                 let address = self.fetch_word(memory, &mut cycles);
-                Self::write_byte(memory, address, self.register_x, &mut cycles);
+                self.write_byte(memory, address, self.register_x, &mut cycles);
             },
             STY_ZERO_PAGE => {
                 let zp_address = self.fetch_byte(memory, &mut cycles);
-                Self::write_byte(memory, zp_address as u16, self.register_y, &mut cycles);
+                self.write_byte(memory, zp_address as u16, self.register_y, &mut cycles);
             },
             STY_ZERO_PAGE_X => {
                 let addr = self.addr_zero_page_x(memory, &mut cycles);
-                Self::write_byte(memory, addr, self.register_y, &mut cycles);
+                self.write_byte(memory, addr, self.register_y, &mut cycles);
             },
             STY_ABSOLUTE => {
                 let address = self.fetch_word(memory, &mut cycles);
-                Self::write_byte(memory, address, self.register_y, &mut cycles);
+                self.write_byte(memory, address, self.register_y, &mut cycles);
             },
 
             // Register transfers
@@ -690,15 +1002,15 @@ impl Cpu {
                 // so the next instruction is byte 3.
                 // The PC is currently at byte 3, because fetching the instruction
                 // increments it, and fetching a word increments it twice
-                let src_addr = self.program_counter as u16;
+                let src_addr = self.program_counter;
                 let low = (src_addr & 0xFF) as u8;
                 let high = (src_addr >> 8) as u8;
 
                 // The stack runs from 0x0100 - 0x01FF
                 // But the stack pointer stores only the least significant byte
-                Self::write_byte(memory, 0x0100 + (self.stack_pointer as u16), low, &mut cycles);
+                self.write_byte(memory, 0x0100 + (self.stack_pointer as u16), low, &mut cycles);
                 self.stack_pointer = (Wrapping(self.stack_pointer) + Wrapping(1)).0;
-                Self::write_byte(memory, 0x0100 + (self.stack_pointer as u16), high, &mut cycles);
+                self.write_byte(memory, 0x0100 + (self.stack_pointer as u16), high, &mut cycles);
                 self.stack_pointer = (Wrapping(self.stack_pointer) + Wrapping(1)).0;
 
                 self.program_counter = target_addr;
@@ -706,41 +1018,33 @@ impl Cpu {
                 cycles -= 1;
             },
             RTS_IMPLIED => {
-                // The stack is literally a stack of addresses,
-                // the 6502 is little endian, so the LSB gets put on the stack first,
-                // followed by the MSB. This means, that the MSB must be popped first,
-                // followed by the LSB.
-                let ret_high = self.stack_pop(memory, &mut cycles) as u16;
-                let ret_low = self.stack_pop(memory, &mut cycles) as u16;
-                let ret = ret_high << 8 | ret_low;
-                self.program_counter = ret;
-                cycles -= 3;
+                self.return_from_subroutine(memory, &mut cycles);
             },
 
             // Branches
             BCS_RELATIVE => {
-                self.branch(memory, CpuStatusFlags::CARRY, true, &mut cycles);
+                branch_taken = Some(self.branch(memory, CpuStatusFlags::CARRY, true, &mut cycles));
             },
             BCC_RELATIVE => {
-                self.branch(memory, CpuStatusFlags::CARRY, false, &mut cycles);
+                branch_taken = Some(self.branch(memory, CpuStatusFlags::CARRY, false, &mut cycles));
             },
             BEQ_RELATIVE => {
-                self.branch(memory, CpuStatusFlags::ZERO, true, &mut cycles);
+                branch_taken = Some(self.branch(memory, CpuStatusFlags::ZERO, true, &mut cycles));
             },
             BNE_RELATIVE => {
-                self.branch(memory, CpuStatusFlags::ZERO, false, &mut cycles);
+                branch_taken = Some(self.branch(memory, CpuStatusFlags::ZERO, false, &mut cycles));
             },
             BMI_RELATIVE => {
-                self.branch(memory, CpuStatusFlags::NEGATIVE, true, &mut cycles);
+                branch_taken = Some(self.branch(memory, CpuStatusFlags::NEGATIVE, true, &mut cycles));
             },
             BPL_RELATIVE => {
-                self.branch(memory, CpuStatusFlags::NEGATIVE, false, &mut cycles);
+                branch_taken = Some(self.branch(memory, CpuStatusFlags::NEGATIVE, false, &mut cycles));
             },
             BVS_RELATIVE => {
-                self.branch(memory, CpuStatusFlags::OVERFLOW, true, &mut cycles);
+                branch_taken = Some(self.branch(memory, CpuStatusFlags::OVERFLOW, true, &mut cycles));
             },
             BVC_RELATIVE => {
-                self.branch(memory, CpuStatusFlags::OVERFLOW, false, &mut cycles);
+                branch_taken = Some(self.branch(memory, CpuStatusFlags::OVERFLOW, false, &mut cycles));
             },
 
             // Status Flag Changes
@@ -790,28 +1094,129 @@ impl Cpu {
                 cycles -= 1;
             },
             RTI_IMPLIED => {
-                let flag_bits = self.stack_pop(memory, &mut cycles);
-                self.flags = CpuStatusFlags::from_bits_truncate(flag_bits);
+                self.return_from_interrupt(memory, &mut cycles);
+            }
+            _ => {}
+        }
+
+        self.cycles += (cycles_before - cycles) as u64;
+        self.instructions += 1;
 
-                let high_pc = self.stack_pop(memory, &mut cycles) as u16;
-                let low_pc = self.stack_pop(memory, &mut cycles) as u16;
-                self.program_counter = high_pc << 8 | low_pc;
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(self.instruction_address, instruction_byte, cycles_before - cycles, self.program_counter);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(self.instruction_address, branch_taken);
+        }
+        if let Some(call_graph) = self.call_graph.as_mut() {
+            call_graph.record(
+                self.instruction_address,
+                instruction_byte,
+                cycles_before - cycles,
+                stack_pointer_before,
+                self.program_counter,
+                self.stack_pointer,
+            );
+        }
+        cycles
+    }
 
-                self.flags.set(CpuStatusFlags::BREAK_COMMAND, false);
+    /// Run the trap at the program counter, if any, and return the cycles left.
+    /// Returns `None` if the instruction at the program counter should be executed.
+    /// The trap's writes are recorded like an instruction's, and unless it returns [TrapReturn::Execute]
+    /// it's counted by the profiler, coverage and call graph as the `RTS`, `RTI` or `JMP` it returns with
+    fn run_trap(&mut self, memory: &mut dyn Memory<MAX_MEMORY>, mut cycles: u32) -> Option<u32> {
+        let address = self.program_counter;
+        let mut trap = self.traps.remove(&address)?;
+        let mut trap_memory = TrapMemory::new(memory);
+        let action = trap(self, &mut trap_memory);
+        // The trap may have replaced itself, but can't remove itself as it isn't set while it runs
+        self.traps.entry(address).or_insert(trap);
+
+        for mut write in trap_memory.writes {
+            if let Some(provenance) = self.provenance.as_mut() {
+                write.replaced = provenance.record(write.address, WriteRecord {
+                    program_counter: address,
+                    cycles: self.cycles,
+                    instruction: self.instructions,
+                    value: write.new,
+                });
+            }
+            if let Some(history) = self.history.as_mut() {
+                history.record_write(write);
+            }
+        }
 
+        let stack_pointer_before = self.stack_pointer;
+        let cycles_before = cycles;
+        // The cycle of fetching the opcode
+        cycles -= 1;
+        let opcode = match action {
+            TrapReturn::Rts => {
+                self.return_from_subroutine(memory, &mut cycles);
+                RTS_IMPLIED
+            },
+            TrapReturn::Rti => {
+                self.return_from_interrupt(memory, &mut cycles);
+                RTI_IMPLIED
+            },
+            TrapReturn::Jump(target) => {
+                self.program_counter = target;
                 cycles -= 2;
-            }
-            _ => {}
+                JMP_ABSOLUTE
+            },
+            TrapReturn::Execute => return None,
+            TrapReturn::Exit(exit) => {
+                self.exit = Some(exit);
+                cycles -= 2;
+                JMP_ABSOLUTE
+            },
+        };
+        self.cycles += (cycles_before - cycles) as u64;
+        self.instructions += 1;
+
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(address, opcode, cycles_before - cycles, self.program_counter);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(address, None);
+        }
+        if let Some(call_graph) = self.call_graph.as_mut() {
+            call_graph.record(address, opcode, cycles_before - cycles, stack_pointer_before, self.program_counter, self.stack_pointer);
         }
+        Some(cycles)
+    }
 
-        cycles
+    fn return_from_subroutine(&mut self, memory: &dyn Memory<MAX_MEMORY>, cycles: &mut u32) {
+        // The stack is literally a stack of addresses,
+        // the 6502 is little endian, so the LSB gets put on the stack first,
+        // followed by the MSB. This means, that the MSB must be popped first,
+        // followed by the LSB.
+        let ret_high = self.stack_pop(memory, cycles) as u16;
+        let ret_low = self.stack_pop(memory, cycles) as u16;
+        let ret = ret_high << 8 | ret_low;
+        self.program_counter = ret;
+        *cycles -= 3;
+    }
+
+    fn return_from_interrupt(&mut self, memory: &dyn Memory<MAX_MEMORY>, cycles: &mut u32) {
+        let flag_bits = self.stack_pop(memory, cycles);
+        self.flags = CpuStatusFlags::from_bits_truncate(flag_bits);
+
+        let high_pc = self.stack_pop(memory, cycles) as u16;
+        let low_pc = self.stack_pop(memory, cycles) as u16;
+        self.program_counter = high_pc << 8 | low_pc;
+
+        self.flags.set(CpuStatusFlags::BREAK_COMMAND, false);
+
+        *cycles -= 2;
     }
 
     /// Push a value to the stack
     fn stack_push(&mut self, memory: &mut dyn Memory<MAX_MEMORY>, value: u8, cycles: &mut u32) {
         // The stack runs from 0x0100 - 0x01FF
         // But the stack pointer stores only the least significant byte
-        Self::write_byte(memory, 0x0100 + (self.stack_pointer as u16), value, cycles);
+        self.write_byte(memory, 0x0100 + (self.stack_pointer as u16), value, cycles);
         self.stack_pointer = (Wrapping(self.stack_pointer) + Wrapping(1)).0;
     }
 
@@ -849,8 +1254,8 @@ impl Cpu {
 
     /// Branch if the condition is met, i.e. the value of the provided flag is equal to the wanted state.
     /// Takes 1 cycle if the condition is not met. 2 If it is met, or 3 if it is met and the new `program_counter`
-    /// is on a new page.
-    fn branch(&mut self, memory: &dyn Memory<MAX_MEMORY>, flag: CpuStatusFlags, state: bool, cycles: &mut u32) {
+    /// is on a new page. Returns whether the branch was taken
+    fn branch(&mut self, memory: &dyn Memory<MAX_MEMORY>, flag: CpuStatusFlags, state: bool, cycles: &mut u32) -> bool {
         let rel_addr = self.fetch_byte(memory, cycles);
         let status = self.flags.intersects(flag);
 
@@ -875,6 +1280,7 @@ impl Cpu {
 
             self.program_counter = new_pc;
         }
+        status == state
     }
 
     /// Rotate bits in the value at the provided address in memory to the left.
@@ -888,7 +1294,7 @@ impl Cpu {
         self.flags.set(CpuStatusFlags::ZERO, shifted == 0);
         self.flags.set(CpuStatusFlags::NEGATIVE, shifted & 0b1000_0000 != 0);
 
-        Self::write_byte(memory, address, shifted, cycles);
+        self.write_byte(memory, address, shifted, cycles);
         *cycles -= 1;
     }
 
@@ -903,7 +1309,7 @@ impl Cpu {
         self.flags.set(CpuStatusFlags::ZERO, shifted == 0);
         self.flags.set(CpuStatusFlags::NEGATIVE, shifted & 0b1000_0000 != 0);
 
-        Self::write_byte(memory, address, shifted, cycles);
+        self.write_byte(memory, address, shifted, cycles);
         *cycles -= 1;
     }
 
@@ -916,7 +1322,7 @@ impl Cpu {
         self.flags.set(CpuStatusFlags::ZERO, inc == 0);
         self.flags.set(CpuStatusFlags::NEGATIVE, inc & NEGATIVE_BIT != 0);
 
-        Self::write_byte(memory, address, inc, cycles);
+        self.write_byte(memory, address, inc, cycles);
     }
 
     /// Decrement a location in memory
@@ -928,7 +1334,7 @@ impl Cpu {
         self.flags.set(CpuStatusFlags::ZERO, dec == 0);
         self.flags.set(CpuStatusFlags::NEGATIVE, dec & NEGATIVE_BIT != 0);
 
-        Self::write_byte(memory, address, dec, cycles);
+        self.write_byte(memory, address, dec, cycles);
     }
 
     /// Increment a register
@@ -1004,7 +1410,7 @@ impl Cpu {
         let carry = value & 0b1000_0000 != 0;
         let shifted = value << 1;
 
-        Self::write_byte(memory, address, shifted, cycles);
+        self.write_byte(memory, address, shifted, cycles);
         self.flags.set(CpuStatusFlags::CARRY, carry);
         self.flags.set(CpuStatusFlags::ZERO, shifted == 0);
         self.flags.set(CpuStatusFlags::NEGATIVE, shifted & NEGATIVE_BIT != 0);
@@ -1020,7 +1426,7 @@ impl Cpu {
         let carry = value & 0b1 != 0;
         let shifted = value >> 1;
 
-        Self::write_byte(memory, address, shifted, cycles);
+        self.write_byte(memory, address, shifted, cycles);
         self.flags.set(CpuStatusFlags::CARRY, carry);
         self.flags.set(CpuStatusFlags::ZERO, shifted == 0);
         self.flags.set(CpuStatusFlags::NEGATIVE, false);
@@ -1153,7 +1559,7 @@ impl Cpu {
     /// 3 + 1 cycle if page cross
     fn addr_indirect_y(&mut self, memory: &dyn Memory<MAX_MEMORY>, cycles: &mut u32) -> u16 {
         let address = self.fetch_byte(memory, cycles) as u16;
-        let effective_address = Self::read_word(memory, address as u16, cycles);
+        let effective_address = Self::read_word(memory, address, cycles);
         let effective_address_y = effective_address + self.register_y as u16;
 
         if (effective_address ^ effective_address_y) >> 8 != 0 {
@@ -1168,7 +1574,7 @@ impl Cpu {
     /// Always takes 4 cycles
     fn addr_indirect_y_5(&mut self, memory: &dyn Memory<MAX_MEMORY>, cycles: &mut u32) -> u16 {
         let address = self.fetch_byte(memory, cycles) as u16;
-        let effective_address = Self::read_word(memory, address as u16, cycles);
+        let effective_address = Self::read_word(memory, address, cycles);
         let effective_address_y = effective_address + self.register_y as u16;
         *cycles -= 1;
         effective_address_y
@@ -1325,8 +1731,8 @@ impl Cpu {
         byte
     }
 
-    /// Write a byte to memory
-    fn write_byte(memory: &mut dyn Memory<MAX_MEMORY>, address: u16, byte: u8, cycles: &mut u32) {
+    /// Write a byte to memory. Every write made by an instruction goes through here, so it's recorded in the [History] and [Provenance]
+    fn write_byte(&mut self, memory: &mut dyn Memory<MAX_MEMORY>, address: u16, byte: u8, cycles: &mut u32) {
         if address as usize > MAX_MEMORY {
             panic!("Write byte failed: Memory address {} is higher than MAX_MEMORY", address);
         }
@@ -1334,17 +1740,30 @@ impl Cpu {
         #[cfg(test)]
         debug!("Writing byte {:#04X} to memory at {:#06X}", byte, address);
 
+        let replaced = match self.provenance.as_mut() {
+            Some(provenance) => provenance.record(address, WriteRecord {
+                program_counter: self.instruction_address,
+                cycles: self.cycles,
+                instruction: self.instructions,
+                value: byte,
+            }),
+            None => None,
+        };
+        if let Some(history) = self.history.as_mut() {
+            history.record_write(MemoryWrite { address, old: memory.peek(address), new: byte, replaced });
+        }
+
         memory.write(address, byte);
         *cycles -= 1;
     }
 
     /// Write a word to memory
     #[allow(unused)]
-    fn write_word(memory: &mut dyn Memory<MAX_MEMORY>, address: u16, word: u16, cycles: &mut u32) {
+    fn write_word(&mut self, memory: &mut dyn Memory<MAX_MEMORY>, address: u16, word: u16, cycles: &mut u32) {
         let high = (word >> 8) as u8;
         let low = (word & 0xFF) as u8;
-        Self::write_byte(memory, address, low, cycles);
-        Self::write_byte(memory, address + 1, high, cycles);
+        self.write_byte(memory, address, low, cycles);
+        self.write_byte(memory, address + 1, high, cycles);
     }
 }
 
@@ -1367,6 +1786,66 @@ impl Default for CpuStatusFlags {
     }
 }
 
+/// A snapshot of the CPU's registers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers {
+    /// The accumulator register
+    pub accumulator: u8,
+    /// The X register
+    pub x: u8,
+    /// The Y register
+    pub y: u8,
+    /// The stack pointer
+    pub stack_pointer: u8,
+    /// The program counter
+    pub program_counter: u16,
+    /// The processor status flags
+    pub flags: CpuStatusFlags,
+}
+
+/// How long [Cpu::call] lets a subroutine run before giving up on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallLimits {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl Default for CallLimits {
+    /// A million instructions and ten million cycles, more than any well-behaved routine should need
+    fn default() -> Self {
+        Self { instructions: 1_000_000, cycles: 10_000_000 }
+    }
+}
+
+/// The state after a subroutine called with [Cpu::call] returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallResult {
+    /// The registers after the `RTS`. Their program counter is the return address pushed by the call
+    pub registers: Registers,
+    /// The address of the `RTS` that returned from the call
+    pub rts_address: u16,
+    /// The cycles the subroutine took, including its `RTS`
+    pub cycles: u64,
+    /// The instructions the subroutine executed, including its `RTS`
+    pub instructions: u64,
+}
+
+/// Why a subroutine called with [Cpu::call] didn't return. Holds the registers at the time it was stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallError {
+    InstructionLimit(Registers),
+    CycleLimit(Registers),
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InstructionLimit(registers) => write!(f, "the subroutine didn't return within the instruction limit, it got to ${:04X}", registers.program_counter),
+            Self::CycleLimit(registers) => write!(f, "the subroutine didn't return within the cycle limit, it got to ${:04X}", registers.program_counter),
+        }
+    }
+}
+
 /// Represents a register
 #[derive(Clone, Debug)]
 enum Register {
@@ -1395,8 +1874,8 @@ enum LogicalOperation {
 mod test {
     use core::num::Wrapping;
     use log::LevelFilter;
-    use crate::cpu::{Cpu, CpuStatusFlags};
-    use crate::{Memory, OperatingMode};
+    use crate::cpu::{Cpu, CpuStatusFlags, Registers};
+    use crate::{Memory, OperatingMode, MAX_MEMORY};
     use crate::memory::BasicMemory;
     use crate::ops::*;
 
@@ -4738,4 +5217,200 @@ mod test {
         assert!(!cpu.flags.intersects(CpuStatusFlags::BREAK_COMMAND));
         assert_eq!(cpu.program_counter, 0x3040);
     }
+
+    #[test]
+    fn registers_round_trip() {
+        init();
+        let mut cpu = Cpu::default();
+
+        let registers = Registers {
+            accumulator: 0x01,
+            x: 0x02,
+            y: 0x03,
+            stack_pointer: 0x04,
+            program_counter: 0x0506,
+            flags: CpuStatusFlags::CARRY | CpuStatusFlags::NEGATIVE,
+        };
+        cpu.set_registers(&registers);
+        assert_eq!(cpu.registers(), registers);
+        assert_eq!(cpu.program_counter(), 0x0506);
+    }
+
+    #[test]
+    fn step() {
+        init();
+        let mut cpu = Cpu::default();
+        let mut memory = BasicMemory::default();
+
+        memory.write(0xFFFC, 0x00);
+        memory.write(0xFFFD, 0x02);
+        memory.write(0x0200, LDA_ABSOLUTE);
+        memory.write(0x0201, 0x00);
+        memory.write(0x0202, 0x30);
+
+        cpu.load_reset_vector(&memory);
+        assert_eq!(cpu.program_counter(), 0x0200);
+        assert_eq!(cpu.step(&mut memory), 4);
+        assert_eq!(cpu.program_counter(), 0x0203);
+    }
+
+    #[test]
+    fn step_back() {
+        init();
+        let mut cpu = Cpu::default();
+        let mut memory = BasicMemory::default();
+
+        memory.write(0x0200, LDA_IMMEDIATE);
+        memory.write(0x0201, 0x42);
+        memory.write(0x0202, JSR_ABSOLUTE);
+        memory.write(0x0203, 0x00);
+        memory.write(0x0204, 0x03);
+        memory.write(0x0300, STA_ABSOLUTE);
+        memory.write(0x0301, 0x00);
+        memory.write(0x0302, 0x04);
+        memory.write(0x0400, 0x11);
+
+        cpu.enable_history(2);
+        cpu.enable_profiler();
+        cpu.set_program_counter(0x0200);
+        let start = cpu.registers();
+        for _ in 0..3 {
+            cpu.step(&mut memory);
+        }
+
+        let history = cpu.history().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.iter().next().unwrap().writes.len(), 2);
+        assert_eq!(memory.read(0x0400), 0x42);
+
+        assert!(cpu.step_back(&mut memory));
+        assert_eq!(memory.read(0x0400), 0x11);
+        assert_eq!(cpu.program_counter(), 0x0300);
+
+        assert!(cpu.step_back(&mut memory));
+        assert_eq!(cpu.program_counter(), 0x0202);
+        assert_eq!(cpu.cycles(), 2);
+        let profiler = cpu.profiler().unwrap();
+        assert_eq!((profiler.total().instructions, profiler.total().cycles, profiler.count(0x0300).instructions), (1, 2, 0));
+        assert_eq!(memory.read(0x0100 + start.stack_pointer as u16), 0x00);
+
+        // The LDA fell out of the history
+        assert!(!cpu.step_back(&mut memory));
+    }
+
+    #[test]
+    fn provenance() {
+        init();
+        let mut cpu = Cpu::default();
+        let mut memory = BasicMemory::default();
+
+        memory.write(0x0200, LDA_IMMEDIATE);
+        memory.write(0x0201, 0x42);
+        memory.write(0x0202, STA_ZERO_PAGE);
+        memory.write(0x0203, 0x10);
+        memory.write(0x0204, INC_ZERO_PAGE);
+        memory.write(0x0205, 0x10);
+
+        cpu.enable_history(8);
+        cpu.enable_provenance(2);
+        cpu.set_program_counter(0x0200);
+        for _ in 0..3 {
+            cpu.step(&mut memory);
+        }
+        assert_eq!(cpu.instructions(), 3);
+
+        let provenance = cpu.provenance().unwrap();
+        let last = provenance.last_write(0x10).unwrap();
+        assert_eq!((last.program_counter, last.instruction, last.cycles, last.value), (0x0204, 2, 5, 0x43));
+        assert_eq!(provenance.writes(0x10).map(|r| r.program_counter).collect::<alloc::vec::Vec<_>>(), [0x0204, 0x0202]);
+        assert!(provenance.last_write(0x11).is_none());
+
+        assert!(cpu.step_back(&mut memory));
+        assert_eq!(cpu.instructions(), 2);
+        assert_eq!(cpu.provenance().unwrap().last_write(0x10).unwrap().program_counter, 0x0202);
+
+        // Keeping a single write, the one replaced is restored from the history
+        cpu.enable_provenance(1);
+        cpu.step_back(&mut memory);
+        cpu.step(&mut memory);
+        cpu.step(&mut memory);
+        assert!(cpu.step_back(&mut memory));
+        assert_eq!(cpu.provenance().unwrap().last_write(0x10).unwrap().program_counter, 0x0202);
+    }
+
+    #[test]
+    fn cycles_and_tracer() {
+        use alloc::boxed::Box;
+        use alloc::sync::Arc;
+        use core::sync::atomic::{AtomicU64, Ordering};
+        use crate::trace::Tracer;
+
+        /// Records the cycle count of the last traced instruction and the amount of traced instructions
+        struct Recorder(Arc<(AtomicU64, AtomicU64)>);
+        impl Tracer for Recorder {
+            fn trace(&mut self, cpu: &Cpu, _: &dyn Memory<MAX_MEMORY>) {
+                self.0.0.store(cpu.cycles(), Ordering::Relaxed);
+                self.0.1.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        init();
+        let mut cpu = Cpu::default();
+        let mut memory = BasicMemory::default();
+        let traced = Arc::new((AtomicU64::new(0), AtomicU64::new(0)));
+
+        memory.write(0x0200, LDA_IMMEDIATE);
+        memory.write(0x0201, 0x42);
+        memory.write(0x0202, STA_ABSOLUTE);
+        memory.write(0x0203, 0x00);
+        memory.write(0x0204, 0x30);
+
+        cpu.set_program_counter(0x0200);
+        cpu.set_tracer(Some(Box::new(Recorder(traced.clone()))));
+        cpu.step(&mut memory);
+        cpu.step(&mut memory);
+
+        assert_eq!(cpu.cycles(), 6);
+        assert_eq!(traced.0.load(Ordering::Relaxed), 2);
+        assert_eq!(traced.1.load(Ordering::Relaxed), 2);
+
+        cpu.reset();
+        assert_eq!(cpu.cycles(), 0);
+        assert!(cpu.set_tracer(None).is_some());
+    }
+
+    #[test]
+    fn call() {
+        use crate::cpu::{CallError, CallLimits};
+
+        init();
+        let mut cpu = Cpu::default();
+        let mut memory = BasicMemory::default();
+        // A subroutine adding X to A, which calls another one to clear the carry
+        let program = [TXA_IMPLIED, JSR_ABSOLUTE, 0x10, 0x03, ADC_ZERO_PAGE, 0x00, RTS_IMPLIED];
+        for (offset, byte) in program.iter().enumerate() {
+            memory.write(0x0300 + offset as u16, *byte);
+        }
+        memory.write(0x0310, CLC_IMPLIED);
+        memory.write(0x0311, RTS_IMPLIED);
+        memory.write(0x0000, 0x20);
+
+        let registers = Registers { x: 0x12, flags: CpuStatusFlags::CARRY, ..Registers::default() };
+        let result = cpu.call(&mut memory, 0x0300, &registers).unwrap();
+        assert_eq!(result.registers.accumulator, 0x32);
+        assert_eq!(result.registers.stack_pointer, 0x00);
+        assert!(!result.registers.flags.contains(CpuStatusFlags::CARRY));
+        assert_eq!(result.instructions, 6);
+        assert_eq!(result.cycles, 2 + 6 + 2 + 6 + 3 + 6);
+
+        // An endless loop
+        memory.write(0x0400, JMP_ABSOLUTE);
+        memory.write(0x0401, 0x00);
+        memory.write(0x0402, 0x04);
+        let limits = CallLimits { instructions: 100, ..CallLimits::default() };
+        let error = cpu.call_with_limits(&mut memory, 0x0400, &registers, &limits).unwrap_err();
+        assert_eq!(error, CallError::InstructionLimit(Registers { program_counter: 0x0400, stack_pointer: 0x02, ..registers }));
+        let limits = CallLimits { cycles: 10, ..CallLimits::default() };
+        assert!(matches!(cpu.call_with_limits(&mut memory, 0x0400, &registers, &limits), Err(CallError::CycleLimit(_))));
+    }
 }
\ No newline at end of file
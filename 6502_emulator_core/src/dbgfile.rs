@@ -0,0 +1,324 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use crate::debug_info::{SourceLine, SourceMap};
+use crate::symbols::{Symbol, SymbolTable};
+
+/// The major version of the debug file format written by current versions of ld65
+const SUPPORTED_MAJOR_VERSION: u32 = 2;
+
+/// Line types, from cc65's `dbginfo.h`
+const LINE_TYPE_EXTERNAL: u32 = 1;
+const LINE_TYPE_MACRO: u32 = 2;
+
+/// A malformed line in a debug file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbgFileError {
+    /// The line number, starting at 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for DbgFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Debug information of a program built with cc65, read from the file ld65 writes with `--dbgfile`
+#[derive(Debug, Clone, Default)]
+pub struct DbgFile {
+    /// The source lines of the spans of code and data
+    pub source_map: SourceMap,
+    /// Labels, named `scope::label` for labels inside a `.proc` or `.scope`. Cheap local labels are left out
+    pub symbols: SymbolTable,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    segment: u32,
+    start: u32,
+    size: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Line {
+    source: SourceLine,
+    kind: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Scope {
+    name: String,
+    parent: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+struct Label {
+    name: String,
+    scope: Option<u32>,
+    value: u32,
+}
+
+/// The attributes of a record, like `id=0,name="main.s",span=1+2`
+struct Record<'a> {
+    attributes: BTreeMap<&'a str, &'a str>,
+}
+
+impl<'a> Record<'a> {
+    fn parse(attributes: &'a str) -> Result<Self, String> {
+        let mut parsed = BTreeMap::new();
+        let mut rest = attributes.trim();
+
+        while !rest.is_empty() {
+            let (key, value) = rest.split_once('=').ok_or_else(|| format!("expected key=value in '{}'", rest))?;
+            let (value, remaining) = if let Some(quoted) = value.strip_prefix('"') {
+                let end = quoted.find('"').ok_or("unterminated string")?;
+                (&quoted[..end], &quoted[end + 1..])
+            } else {
+                value.split_at(value.find(',').unwrap_or(value.len()))
+            };
+
+            parsed.insert(key.trim(), value);
+            rest = remaining.strip_prefix(',').unwrap_or(remaining).trim_start();
+        }
+
+        Ok(Self { attributes: parsed })
+    }
+
+    fn string(&self, key: &str) -> Result<&'a str, String> {
+        self.attributes.get(key).copied().ok_or_else(|| format!("missing '{}'", key))
+    }
+
+    fn number(&self, key: &str) -> Result<u32, String> {
+        parse_number(self.string(key)?)
+    }
+
+    fn optional_number(&self, key: &str) -> Result<Option<u32>, String> {
+        self.attributes.get(key).map(|value| parse_number(value)).transpose()
+    }
+
+    /// A list of ids joined with `+`, empty if the attribute is missing
+    fn ids(&self, key: &str) -> Result<Vec<u32>, String> {
+        match self.attributes.get(key) {
+            Some(value) => value.split('+').map(parse_number).collect(),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Result<u32, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("'{}' is not a number", s))
+}
+
+/// How much a line type is preferred when several lines cover the same bytes.
+/// Lines of C sources come first, then assembler lines, and lines inside macro bodies last
+fn line_rank(kind: u32) -> u8 {
+    match kind {
+        LINE_TYPE_EXTERNAL => 2,
+        LINE_TYPE_MACRO => 0,
+        _ => 1,
+    }
+}
+
+/// Collects the records of a debug file, which may refer to records further down the file
+#[derive(Default)]
+struct Builder {
+    dbg: DbgFile,
+    /// Indices in the source map by file id
+    files: BTreeMap<u32, usize>,
+    /// Start addresses by segment id
+    segments: BTreeMap<u32, u32>,
+    spans: BTreeMap<u32, Span>,
+    scopes: BTreeMap<u32, Scope>,
+    labels: Vec<Label>,
+    /// The lines covering every span, with the line number of their record
+    span_lines: BTreeMap<u32, Vec<(usize, Line)>>,
+}
+
+impl Builder {
+    fn record(&mut self, line_number: usize, kind: &str, record: &Record) -> Result<(), String> {
+        match kind {
+            "version" => {
+                let major = record.number("major")?;
+                if major != SUPPORTED_MAJOR_VERSION {
+                    return Err(format!("unsupported debug file version {}", major));
+                }
+            },
+            "file" => {
+                let file = self.dbg.source_map.add_file(record.string("name")?);
+                self.files.insert(record.number("id")?, file);
+            },
+            "seg" => {
+                self.segments.insert(record.number("id")?, record.number("start")?);
+            },
+            "span" => {
+                let span = Span { segment: record.number("seg")?, start: record.number("start")?, size: record.number("size")? };
+                self.spans.insert(record.number("id")?, span);
+            },
+            "line" => {
+                let file = record.number("file")?;
+                let file = *self.files.get(&file).ok_or_else(|| format!("unknown file {}", file))?;
+                let line = Line {
+                    source: SourceLine { file, line: record.number("line")? },
+                    kind: record.optional_number("type")?.unwrap_or_default(),
+                };
+                for span in record.ids("span")? {
+                    self.span_lines.entry(span).or_default().push((line_number, line));
+                }
+            },
+            "scope" => {
+                let scope = Scope { name: record.string("name")?.to_string(), parent: record.optional_number("parent")? };
+                self.scopes.insert(record.number("id")?, scope);
+            },
+            "sym" => {
+                let name = record.string("name")?;
+                let value = record.optional_number("val")?;
+                if let (Ok("lab"), Some(value), false) = (record.string("type"), value, name.starts_with('@')) {
+                    self.labels.push(Label { name: name.to_string(), scope: record.optional_number("scope")?, value });
+                }
+            },
+            _ => {},
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<DbgFile, DbgFileError> {
+        // Pick a single line for every byte, letting better ranked lines replace the spans of worse ones
+        let mut placed = Vec::new();
+        for (id, lines) in &self.span_lines {
+            let (line_number, best) = lines.iter().max_by_key(|(_, l)| line_rank(l.kind)).expect("spans are only added with a line");
+            let error = |message: String| DbgFileError { line: *line_number, message };
+            let span = self.spans.get(id).ok_or_else(|| error(format!("unknown span {}", id)))?;
+            let segment = self.segments.get(&span.segment).ok_or_else(|| error(format!("unknown segment {}", span.segment)))?;
+            if span.size > 0 {
+                placed.push((line_rank(best.kind), segment + span.start, span.size, best.source));
+            }
+        }
+        placed.sort_by_key(|(rank, address, _, _)| (*rank, *address));
+
+        let mut bytes: BTreeMap<u32, (u32, SourceLine)> = BTreeMap::new();
+        for (_, address, size, source) in placed {
+            let covered: Vec<u32> = bytes.range(..address + size)
+                .filter(|(start, (length, _))| **start + *length > address)
+                .map(|(start, _)| *start)
+                .collect();
+            for start in covered {
+                bytes.remove(&start);
+            }
+            bytes.insert(address, (size, source));
+        }
+
+        for (address, (size, source)) in bytes {
+            if let Ok(address) = u16::try_from(address) {
+                self.dbg.source_map.add_span(address, u16::try_from(size).unwrap_or(u16::MAX), source);
+            }
+        }
+
+        for label in self.labels {
+            let Ok(address) = u16::try_from(label.value) else { continue };
+            let mut name = label.name;
+            let mut scope = label.scope;
+            while let Some(s) = scope.and_then(|id| self.scopes.get(&id)) {
+                if s.name.is_empty() {
+                    break;
+                }
+                name = format!("{}::{}", s.name, name);
+                scope = s.parent;
+            }
+            self.dbg.symbols.insert(Symbol { name, address, bank: None });
+        }
+
+        Ok(self.dbg)
+    }
+}
+
+impl DbgFile {
+    /// Parse the contents of a debug file
+    pub fn parse(text: &str) -> Result<Self, DbgFileError> {
+        let mut builder = Builder::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| DbgFileError { line: index + 1, message };
+            let (kind, attributes) = match line.trim().split_once(char::is_whitespace) {
+                Some(split) => split,
+                None if line.trim().is_empty() => continue,
+                None => return Err(error(format!("expected attributes after '{}'", line.trim()))),
+            };
+
+            let record = Record::parse(attributes).map_err(error)?;
+            builder.record(index + 1, kind, &record).map_err(error)?;
+        }
+
+        builder.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DBG: &str = "\
+version\tmajor=2,minor=0
+info\tcsym=0,file=2,lib=0,line=5,mod=1,scope=2,seg=1,span=4,sym=4,type=0
+file\tid=0,name=\"src/main.s\",size=120,mtime=0x5F000000,mod=0
+file\tid=1,name=\"src/macros.inc\",size=40,mtime=0x5F000000,mod=0
+line\tid=0,file=0,line=3,span=0
+line\tid=1,file=0,line=4,span=1
+line\tid=2,file=0,line=8,span=2
+line\tid=3,file=1,line=2,type=2,count=1,span=2
+line\tid=4,file=0,line=9,span=3
+mod\tid=0,name=\"main.o\",file=0
+seg\tid=0,name=\"CODE\",start=0x000200,size=0x000008,addrsize=absolute,type=ro,oname=\"main.bin\",ooffs=0
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=3
+span\tid=2,seg=0,start=5,size=1
+span\tid=3,seg=0,start=6,size=2
+scope\tid=0,name=\"\",mod=0,size=8,span=0+1+2+3
+scope\tid=1,name=\"print\",mod=0,type=scope,size=3,parent=0,sym=1,span=2+3
+sym\tid=0,name=\"start\",addrsize=absolute,scope=0,def=0,ref=1,val=0x200,seg=0,type=lab
+sym\tid=1,name=\"print\",addrsize=absolute,scope=0,def=2,val=0x205,seg=0,type=lab
+sym\tid=2,name=\"loop\",addrsize=absolute,scope=1,def=4,val=0x206,seg=0,type=lab
+sym\tid=3,name=\"WIDTH\",addrsize=zeropage,scope=0,def=1,val=0x28,type=equ
+";
+
+    #[test]
+    fn lines_and_labels() {
+        let dbg = DbgFile::parse(DBG).unwrap();
+        let map = &dbg.source_map;
+        assert_eq!(map.files(), ["src/main.s", "src/macros.inc"]);
+        assert_eq!(map.line_at(0x0200), Some(SourceLine { file: 0, line: 3 }));
+        assert_eq!(map.line_at(0x0204), Some(SourceLine { file: 0, line: 4 }));
+        // The line invoking a macro is preferred over the line in its body
+        assert_eq!(map.line_at(0x0205), Some(SourceLine { file: 0, line: 8 }));
+        assert_eq!(map.line_at(0x0208), None);
+
+        assert_eq!(dbg.symbols.address_of("start"), Some(0x0200));
+        assert_eq!(dbg.symbols.address_of("print::loop"), Some(0x0206));
+        assert_eq!(dbg.symbols.address_of("WIDTH"), None);
+    }
+
+    #[test]
+    fn external_lines_replace_assembler_lines() {
+        let text = format!("{}file\tid=2,name=\"main.c\",size=1,mtime=0,mod=0\nspan\tid=4,seg=0,start=0,size=5\nline\tid=5,file=2,line=12,type=1,span=4\n", DBG);
+        let dbg = DbgFile::parse(&text).unwrap();
+        assert_eq!(dbg.source_map.line_at(0x0203), Some(SourceLine { file: 2, line: 12 }));
+        assert!(!dbg.source_map.is_line_start(0x0202));
+        assert_eq!(dbg.source_map.line_at(0x0205), Some(SourceLine { file: 0, line: 8 }));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(DbgFile::parse("version\tmajor=1,minor=0\n").unwrap_err().line, 1);
+        assert!(DbgFile::parse("version\tmajor=2,minor=0\nline\tid=0,file=3,line=1\n").is_err());
+        assert!(DbgFile::parse("file\tid=0,name=\"unterminated\n").is_err());
+        assert_eq!(DbgFile::parse("file\tid=0,name=\"a.s\"\nline\tid=0,file=0,line=1,span=7\n").unwrap_err().line, 2);
+    }
+}
\ No newline at end of file
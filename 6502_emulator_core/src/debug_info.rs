@@ -0,0 +1,154 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// A location in a source file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourceLine {
+    /// Index of the file in [SourceMap::files]
+    pub file: usize,
+    /// The line number, starting at 1
+    pub line: u32,
+}
+
+/// A range of addresses generated from a single source line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Span {
+    /// Number of bytes, at least 1
+    length: u16,
+    source: SourceLine,
+}
+
+/// Maps addresses to source lines and back.
+/// Filled by loaders of debug information, used by debuggers to express locations in source lines.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<String>,
+    /// Spans keyed by their start address
+    spans: BTreeMap<u16, Span>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether no address is mapped to a line
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    /// Register a source file, returning its index. Registering a file twice returns the same index
+    pub fn add_file(&mut self, name: &str) -> usize {
+        match self.files.iter().position(|f| f == name) {
+            Some(index) => index,
+            None => {
+                self.files.push(name.to_string());
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// The names of all registered files, indexed by [SourceLine::file]
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Find the index of a file. Debuggers often use a different path to the same file
+    /// than the debug information, so if there is no exact match, a file whose path is a suffix of `path`
+    /// (or the other way around) on a path separator boundary is returned
+    pub fn find_file(&self, path: &str) -> Option<usize> {
+        if let Some(index) = self.files.iter().position(|f| f == path) {
+            return Some(index);
+        }
+
+        let normalized = path.replace('\\', "/");
+        self.files.iter().position(|f| {
+            let f = f.replace('\\', "/");
+            is_path_suffix(&normalized, &f) || is_path_suffix(&f, &normalized)
+        })
+    }
+
+    /// Map `length` bytes starting at `address` to a line
+    pub fn add_span(&mut self, address: u16, length: u16, source: SourceLine) {
+        self.spans.insert(address, Span { length: length.max(1), source });
+    }
+
+    /// The line which generated the byte at `address`
+    pub fn line_at(&self, address: u16) -> Option<SourceLine> {
+        let (start, span) = self.spans.range(..=address).next_back()?;
+        if (address - start) < span.length {
+            Some(span.source)
+        } else {
+            None
+        }
+    }
+
+    /// Whether `address` is the first byte generated from a line
+    pub fn is_line_start(&self, address: u16) -> bool {
+        self.spans.contains_key(&address)
+    }
+
+    /// All spans as the start address, number of bytes and line, in ascending address order
+    pub fn spans(&self) -> impl Iterator<Item = (u16, u16, SourceLine)> + '_ {
+        self.spans.iter().map(|(address, span)| (*address, span.length, span.source))
+    }
+
+    /// The start addresses of all spans generated from a line, in ascending order
+    pub fn addresses_of(&self, source: SourceLine) -> Vec<u16> {
+        self.spans.iter()
+            .filter(|(_, span)| span.source == source)
+            .map(|(address, _)| *address)
+            .collect()
+    }
+
+    /// The nearest line at or after `line` in `file` which generated code, used to place breakpoints
+    /// on lines without code, like comments
+    pub fn nearest_line(&self, file: usize, line: u32) -> Option<SourceLine> {
+        self.spans.values()
+            .map(|span| span.source)
+            .filter(|s| s.file == file && s.line >= line)
+            .min_by_key(|s| s.line)
+    }
+}
+
+/// Whether `suffix` is a suffix of `path` starting at a path component
+fn is_path_suffix(path: &str, suffix: &str) -> bool {
+    path.ends_with(suffix) && (path.len() == suffix.len() || path[..path.len() - suffix.len()].ends_with('/'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn line_lookup() {
+        let mut map = SourceMap::new();
+        let file = map.add_file("src/main.s");
+        assert_eq!(map.add_file("src/main.s"), file);
+
+        map.add_span(0x0200, 2, SourceLine { file, line: 3 });
+        map.add_span(0x0202, 3, SourceLine { file, line: 5 });
+
+        assert_eq!(map.line_at(0x0201), Some(SourceLine { file, line: 3 }));
+        assert_eq!(map.line_at(0x0204), Some(SourceLine { file, line: 5 }));
+        assert_eq!(map.line_at(0x0205), None);
+        assert_eq!(map.line_at(0x01FF), None);
+
+        assert!(map.is_line_start(0x0202));
+        assert!(!map.is_line_start(0x0203));
+        assert_eq!(map.addresses_of(SourceLine { file, line: 5 }), [0x0202]);
+        assert_eq!(map.nearest_line(file, 4), Some(SourceLine { file, line: 5 }));
+        assert_eq!(map.nearest_line(file, 6), None);
+    }
+
+    #[test]
+    fn find_file_by_suffix() {
+        let mut map = SourceMap::new();
+        let file = map.add_file("src/main.s");
+
+        assert_eq!(map.find_file("/home/user/project/src/main.s"), Some(file));
+        assert_eq!(map.find_file("C:\\project\\src\\main.s"), Some(file));
+        assert_eq!(map.find_file("/home/user/project/src/notmain.s"), None);
+    }
+}
\ No newline at end of file
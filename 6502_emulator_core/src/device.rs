@@ -0,0 +1,261 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use crate::memory::{MAX_MEMORY, Memory};
+
+/// A request from a device to end the run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// The program exited with a status, like `exit(status)`
+    Status(u8),
+    /// The program aborted, like `abort()`
+    Abort,
+}
+
+/// A memory-mapped peripheral, occupying [Device::size] consecutive addresses of a [Bus]
+pub trait Device {
+    /// The number of addresses the registers of the device occupy
+    fn size(&self) -> u16;
+
+    /// Read the register at `offset` from the start of the device. Reads may have side effects, like latching a counter
+    fn read(&mut self, offset: u16) -> u8;
+
+    /// Read the register at `offset` without any side effects, for inspecting it from outside the CPU
+    fn peek(&self, offset: u16) -> u8;
+
+    /// Write the register at `offset` from the start of the device
+    fn write(&mut self, offset: u16, value: u8);
+
+    /// Told the number of cycles the CPU executed before each instruction, by whoever runs the CPU
+    fn clock(&mut self, _cycles: u64) {}
+
+    /// Whether the device asks to end the run
+    fn exit(&self) -> Option<Exit> {
+        None
+    }
+
+    /// Reset the device along with the memory
+    fn reset(&mut self) {}
+}
+
+/// A single register ending the run with the status written to it, for programs run headless, e.g. in CI
+#[derive(Debug, Default)]
+pub struct ExitDevice {
+    exit: Option<Exit>,
+}
+
+impl Device for ExitDevice {
+    fn size(&self) -> u16 {
+        1
+    }
+
+    fn read(&mut self, _offset: u16) -> u8 {
+        0
+    }
+
+    fn peek(&self, _offset: u16) -> u8 {
+        0
+    }
+
+    fn write(&mut self, _offset: u16, value: u8) {
+        self.exit = Some(Exit::Status(value));
+    }
+
+    fn exit(&self) -> Option<Exit> {
+        self.exit
+    }
+
+    fn reset(&mut self) {
+        self.exit = None;
+    }
+}
+
+/// A single register passing every byte written to it on, like a character to print
+pub struct OutputDevice {
+    output: Box<dyn FnMut(u8)>,
+}
+
+impl OutputDevice {
+    pub fn new(output: impl FnMut(u8) + 'static) -> Self {
+        Self { output: Box::new(output) }
+    }
+}
+
+impl Device for OutputDevice {
+    fn size(&self) -> u16 {
+        1
+    }
+
+    fn read(&mut self, _offset: u16) -> u8 {
+        0
+    }
+
+    fn peek(&self, _offset: u16) -> u8 {
+        0
+    }
+
+    fn write(&mut self, _offset: u16, value: u8) {
+        (self.output)(value);
+    }
+}
+
+struct Mapping {
+    start: u16,
+    end: u16,
+    device: RefCell<Box<dyn Device>>,
+}
+
+/// Memory with devices mapped over some of its addresses. Accesses to other addresses go to the memory
+pub struct Bus<M> {
+    memory: M,
+    devices: Vec<Mapping>,
+}
+
+impl<M: Memory<MAX_MEMORY>> Bus<M> {
+    pub fn new(memory: M) -> Self {
+        Self { memory, devices: Vec::new() }
+    }
+
+    /// Map `device` at `start`. A device mapped later takes precedence where it overlaps an earlier one
+    ///
+    /// # Panics
+    ///
+    /// If the device doesn't fit between `start` and the end of memory, or has no registers
+    pub fn map(&mut self, start: u16, device: impl Device + 'static) {
+        let end = device.size().checked_sub(1).and_then(|last| start.checked_add(last)).expect("Device doesn't fit in memory");
+        self.devices.push(Mapping { start, end, device: RefCell::new(Box::new(device)) });
+    }
+
+    /// The memory behind the devices
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    pub fn into_memory(self) -> M {
+        self.memory
+    }
+
+    /// Tell all devices the number of cycles the CPU executed. Call this before each instruction
+    pub fn clock(&mut self, cycles: u64) {
+        for mapping in &mut self.devices {
+            mapping.device.get_mut().clock(cycles);
+        }
+    }
+
+    /// The first request of a device to end the run
+    pub fn exit(&self) -> Option<Exit> {
+        self.devices.iter().find_map(|mapping| mapping.device.borrow().exit())
+    }
+
+    fn device_at(&self, address: u16) -> Option<&Mapping> {
+        self.devices.iter().rev().find(|mapping| (mapping.start..=mapping.end).contains(&address))
+    }
+}
+
+impl<M: Memory<MAX_MEMORY>> Memory<MAX_MEMORY> for Bus<M> {
+    fn reset(&mut self) {
+        self.memory.reset();
+        for mapping in &mut self.devices {
+            mapping.device.get_mut().reset();
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match self.device_at(address) {
+            Some(mapping) => mapping.device.borrow_mut().write(address - mapping.start, value),
+            None => self.memory.write(address, value),
+        }
+    }
+
+    fn read(&self, address: u16) -> u8 {
+        match self.device_at(address) {
+            Some(mapping) => mapping.device.borrow_mut().read(address - mapping.start),
+            None => self.memory.read(address),
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match self.device_at(address) {
+            Some(mapping) => mapping.device.borrow().peek(address - mapping.start),
+            None => self.memory.peek(address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::rc::Rc;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+    use crate::memory::BasicMemory;
+    use super::*;
+
+    /// Two registers which record writes and read back their offset, exiting once 0xFF is written
+    struct Recorder(Rc<RefCell<Vec<(u16, u8)>>>);
+
+    impl Device for Recorder {
+        fn size(&self) -> u16 {
+            2
+        }
+
+        fn read(&mut self, offset: u16) -> u8 {
+            self.peek(offset)
+        }
+
+        fn peek(&self, offset: u16) -> u8 {
+            offset as u8 + 0x10
+        }
+
+        fn write(&mut self, offset: u16, value: u8) {
+            self.0.borrow_mut().push((offset, value));
+        }
+
+        fn exit(&self) -> Option<Exit> {
+            self.0.borrow().iter().any(|(_, value)| *value == 0xFF).then_some(Exit::Status(3))
+        }
+    }
+
+    #[test]
+    fn mapping() {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let mut bus = Bus::new(BasicMemory::default());
+        bus.map(0xD000, Recorder(writes.clone()));
+        bus.map(0xD001, Recorder(writes.clone()));
+
+        bus.write(0xCFFF, 0x01);
+        bus.write(0xD000, 0x02);
+        bus.write(0xD002, 0x03);
+        assert_eq!(*writes.borrow(), vec![(0, 0x02), (1, 0x03)]);
+        assert_eq!(bus.memory().read(0xCFFF), 0x01);
+        assert_eq!(bus.memory().read(0xD000), 0x00);
+        assert_eq!([bus.read(0xD000), bus.read(0xD001), bus.read(0xD002), bus.read(0xD003)], [0x10, 0x10, 0x11, 0x00]);
+        assert_eq!([bus.peek(0xD000), bus.peek(0xD002), bus.peek(0xD003)], [0x10, 0x11, 0x00]);
+
+        assert_eq!(bus.exit(), None);
+        bus.write(0xD001, 0xFF);
+        assert_eq!(bus.exit(), Some(Exit::Status(3)));
+    }
+
+    #[test]
+    fn exit_and_output() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let sink = output.clone();
+        let mut bus = Bus::new(BasicMemory::default());
+        bus.map(0xFFF8, ExitDevice::default());
+        bus.map(0xFFF9, OutputDevice::new(move |c| sink.borrow_mut().push(c)));
+
+        bus.write(0xFFF9, b'o');
+        bus.write(0xFFF9, b'k');
+        assert_eq!(*output.borrow(), b"ok");
+        assert_eq!(bus.exit(), None);
+        bus.write(0xFFF8, 0);
+        assert_eq!(bus.exit(), Some(Exit::Status(0)));
+        bus.reset();
+        assert_eq!(bus.exit(), None);
+    }
+}
\ No newline at end of file
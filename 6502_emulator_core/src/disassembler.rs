@@ -0,0 +1,386 @@
+use core::fmt;
+use crate::memory::{MAX_MEMORY, Memory};
+use crate::ops::*;
+use crate::symbols::{Symbol, SymbolTable};
+
+/// How an instruction finds its operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressingMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+impl AddressingMode {
+    /// The number of operand bytes following the opcode
+    pub fn operand_length(&self) -> u16 {
+        match self {
+            Self::Implied | Self::Accumulator => 0,
+            Self::Immediate | Self::ZeroPage | Self::ZeroPageX | Self::ZeroPageY
+                | Self::IndirectX | Self::IndirectY | Self::Relative => 1,
+            Self::Absolute | Self::AbsoluteX | Self::AbsoluteY | Self::Indirect => 2,
+        }
+    }
+}
+
+/// A decoded instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    /// The address of the opcode
+    pub address: u16,
+    pub opcode: u8,
+    /// The mnemonic, or `???` if the opcode is not supported
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    /// The operand as it is encoded. A single byte for one byte operands
+    pub operand: u16,
+}
+
+impl Instruction {
+    /// Decode the instruction at `address`
+    pub fn decode(memory: &dyn Memory<MAX_MEMORY>, address: u16) -> Self {
+        let opcode = memory.peek(address);
+        let (mnemonic, mode) = decode_opcode(opcode).unwrap_or(("???", AddressingMode::Implied));
+
+        let operand = match mode.operand_length() {
+            0 => 0,
+            1 => memory.peek(address.wrapping_add(1)) as u16,
+            _ => u16::from_le_bytes([memory.peek(address.wrapping_add(1)), memory.peek(address.wrapping_add(2))]),
+        };
+
+        Self { address, opcode, mnemonic, mode, operand }
+    }
+
+    /// Whether the opcode is supported by the CPU
+    pub fn is_known(&self) -> bool {
+        decode_opcode(self.opcode).is_some()
+    }
+
+    /// The length of the instruction in bytes, including the opcode
+    pub fn length(&self) -> u16 {
+        1 + self.mode.operand_length()
+    }
+
+    /// The encoded instruction. Only the first [Self::length] bytes are meaningful
+    pub fn bytes(&self) -> [u8; 3] {
+        let [low, high] = self.operand.to_le_bytes();
+        [self.opcode, low, high]
+    }
+
+    /// The address of the next instruction in memory
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.length())
+    }
+
+    /// The destination of a branch, `JMP` or `JSR`. `None` for indirect jumps and other instructions
+    pub fn target(&self) -> Option<u16> {
+        match (self.mode, self.mnemonic) {
+            (AddressingMode::Relative, _) => Some(self.next_address().wrapping_add(self.operand as u8 as i8 as u16)),
+            (AddressingMode::Absolute, "JMP" | "JSR") => Some(self.operand),
+            _ => None,
+        }
+    }
+}
+
+impl Instruction {
+    /// Format the instruction with operand addresses replaced by their names, like `JSR print` or `LDA table,X`.
+    /// Addresses without a symbol are shown as usual
+    pub fn with_symbols<'a>(&'a self, symbols: &'a SymbolTable) -> Symbolic<'a> {
+        Symbolic { instruction: self, symbols }
+    }
+
+    fn format<'a>(&self, f: &mut fmt::Formatter<'_>, name: impl Fn(u16) -> Option<&'a Symbol>) -> fmt::Result {
+        let m = self.mnemonic;
+        let operand = self.operand;
+        if let Some(symbol) = self.operand_address().and_then(name) {
+            return match self.mode {
+                AddressingMode::ZeroPageX | AddressingMode::AbsoluteX => write!(f, "{} {},X", m, symbol),
+                AddressingMode::ZeroPageY | AddressingMode::AbsoluteY => write!(f, "{} {},Y", m, symbol),
+                AddressingMode::Indirect => write!(f, "{} ({})", m, symbol),
+                AddressingMode::IndirectX => write!(f, "{} ({},X)", m, symbol),
+                AddressingMode::IndirectY => write!(f, "{} ({}),Y", m, symbol),
+                _ => write!(f, "{} {}", m, symbol),
+            };
+        }
+
+        match self.mode {
+            AddressingMode::Implied => write!(f, "{}", m),
+            AddressingMode::Accumulator => write!(f, "{} A", m),
+            AddressingMode::Immediate => write!(f, "{} #${:02X}", m, operand),
+            AddressingMode::ZeroPage => write!(f, "{} ${:02X}", m, operand),
+            AddressingMode::ZeroPageX => write!(f, "{} ${:02X},X", m, operand),
+            AddressingMode::ZeroPageY => write!(f, "{} ${:02X},Y", m, operand),
+            AddressingMode::Absolute => write!(f, "{} ${:04X}", m, operand),
+            AddressingMode::AbsoluteX => write!(f, "{} ${:04X},X", m, operand),
+            AddressingMode::AbsoluteY => write!(f, "{} ${:04X},Y", m, operand),
+            AddressingMode::Indirect => write!(f, "{} (${:04X})", m, operand),
+            AddressingMode::IndirectX => write!(f, "{} (${:02X},X)", m, operand),
+            AddressingMode::IndirectY => write!(f, "{} (${:02X}),Y", m, operand),
+            AddressingMode::Relative => write!(f, "{} ${:04X}", m, self.target().unwrap_or_default()),
+        }
+    }
+
+    /// The address written in the operand, which a symbol can stand for. The branch target for relative branches
+    fn operand_address(&self) -> Option<u16> {
+        match self.mode {
+            AddressingMode::Implied | AddressingMode::Accumulator | AddressingMode::Immediate => None,
+            AddressingMode::Relative => self.target(),
+            _ => Some(self.operand),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.format(f, |_| None)
+    }
+}
+
+/// An [Instruction] formatted with symbols, see [Instruction::with_symbols]
+pub struct Symbolic<'a> {
+    instruction: &'a Instruction,
+    symbols: &'a SymbolTable,
+}
+
+impl fmt::Display for Symbolic<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.instruction.format(f, |address| self.symbols.symbol_at(address, None))
+    }
+}
+
+/// The mnemonic and addressing mode of an opcode, `None` if the opcode is not supported
+pub fn decode_opcode(opcode: u8) -> Option<(&'static str, AddressingMode)> {
+    let decoded = match opcode {
+        LDA_IMMEDIATE => ("LDA", AddressingMode::Immediate),
+        LDA_ZERO_PAGE => ("LDA", AddressingMode::ZeroPage),
+        LDA_ZERO_PAGE_X => ("LDA", AddressingMode::ZeroPageX),
+        LDA_ABSOLUTE => ("LDA", AddressingMode::Absolute),
+        LDA_ABSOLUTE_X => ("LDA", AddressingMode::AbsoluteX),
+        LDA_ABSOLUTE_Y => ("LDA", AddressingMode::AbsoluteY),
+        LDA_INDIRECT_X => ("LDA", AddressingMode::IndirectX),
+        LDA_INDIRECT_Y => ("LDA", AddressingMode::IndirectY),
+        LDX_IMMEDIATE => ("LDX", AddressingMode::Immediate),
+        LDX_ZERO_PAGE => ("LDX", AddressingMode::ZeroPage),
+        LDX_ZERO_PAGE_Y => ("LDX", AddressingMode::ZeroPageY),
+        LDX_ABSOLUTE => ("LDX", AddressingMode::Absolute),
+        LDX_ABSOLUTE_Y => ("LDX", AddressingMode::AbsoluteY),
+        LDY_IMMEDIATE => ("LDY", AddressingMode::Immediate),
+        LDY_ZERO_PAGE => ("LDY", AddressingMode::ZeroPage),
+        LDY_ZERO_PAGE_X => ("LDY", AddressingMode::ZeroPageX),
+        LDY_ABSOLUTE => ("LDY", AddressingMode::Absolute),
+        LDY_ABSOLUTE_X => ("LDY", AddressingMode::AbsoluteX),
+        STA_ZERO_PAGE => ("STA", AddressingMode::ZeroPage),
+        STA_ZERO_PAGE_X => ("STA", AddressingMode::ZeroPageX),
+        STA_ABSOLUTE => ("STA", AddressingMode::Absolute),
+        STA_ABSOLUTE_X => ("STA", AddressingMode::AbsoluteX),
+        STA_ABSOLUTE_Y => ("STA", AddressingMode::AbsoluteY),
+        STA_INDIRECT_X => ("STA", AddressingMode::IndirectX),
+        STA_INDIRECT_Y => ("STA", AddressingMode::IndirectY),
+        STX_ZERO_PAGE => ("STX", AddressingMode::ZeroPage),
+        STX_ZERO_PAGE_Y => ("STX", AddressingMode::ZeroPageY),
+        STX_ABSOLUTE => ("STX", AddressingMode::Absolute),
+        STY_ZERO_PAGE => ("STY", AddressingMode::ZeroPage),
+        STY_ZERO_PAGE_X => ("STY", AddressingMode::ZeroPageX),
+        STY_ABSOLUTE => ("STY", AddressingMode::Absolute),
+        TAX_IMPLIED => ("TAX", AddressingMode::Implied),
+        TAY_IMPLIED => ("TAY", AddressingMode::Implied),
+        TXA_IMPLIED => ("TXA", AddressingMode::Implied),
+        TYA_IMPLIED => ("TYA", AddressingMode::Implied),
+        TSX_IMPLIED => ("TSX", AddressingMode::Implied),
+        TXS_IMPLIED => ("TXS", AddressingMode::Implied),
+        PHA_IMPLIED => ("PHA", AddressingMode::Implied),
+        PHP_IMPLIED => ("PHP", AddressingMode::Implied),
+        PLA_IMPLIED => ("PLA", AddressingMode::Implied),
+        PLP_IMPLIED => ("PLP", AddressingMode::Implied),
+        AND_IMMEDIATE => ("AND", AddressingMode::Immediate),
+        AND_ZERO_PAGE => ("AND", AddressingMode::ZeroPage),
+        AND_ZERO_PAGE_X => ("AND", AddressingMode::ZeroPageX),
+        AND_ABSOLUTE => ("AND", AddressingMode::Absolute),
+        AND_ABSOLUTE_X => ("AND", AddressingMode::AbsoluteX),
+        AND_ABSOLUTE_Y => ("AND", AddressingMode::AbsoluteY),
+        AND_INDIRECT_X => ("AND", AddressingMode::IndirectX),
+        AND_INDIRECT_Y => ("AND", AddressingMode::IndirectY),
+        EOR_IMMEDIATE => ("EOR", AddressingMode::Immediate),
+        EOR_ZERO_PAGE => ("EOR", AddressingMode::ZeroPage),
+        EOR_ZERO_PAGE_X => ("EOR", AddressingMode::ZeroPageX),
+        EOR_ABSOLUTE => ("EOR", AddressingMode::Absolute),
+        EOR_ABSOLUTE_X => ("EOR", AddressingMode::AbsoluteX),
+        EOR_ABSOLUTE_Y => ("EOR", AddressingMode::AbsoluteY),
+        EOR_INDIRECT_X => ("EOR", AddressingMode::IndirectX),
+        EOR_INDIRECT_Y => ("EOR", AddressingMode::IndirectY),
+        ORA_IMMEDIATE => ("ORA", AddressingMode::Immediate),
+        ORA_ZERO_PAGE => ("ORA", AddressingMode::ZeroPage),
+        ORA_ZERO_PAGE_X => ("ORA", AddressingMode::ZeroPageX),
+        ORA_ABSOLUTE => ("ORA", AddressingMode::Absolute),
+        ORA_ABSOLUTE_X => ("ORA", AddressingMode::AbsoluteX),
+        ORA_ABSOLUTE_Y => ("ORA", AddressingMode::AbsoluteY),
+        ORA_INDIRECT_X => ("ORA", AddressingMode::IndirectX),
+        ORA_INDIRECT_Y => ("ORA", AddressingMode::IndirectY),
+        BIT_ZERO_PAGE => ("BIT", AddressingMode::ZeroPage),
+        BIT_ABSOLUTE => ("BIT", AddressingMode::Absolute),
+        ADC_IMMEDIATE => ("ADC", AddressingMode::Immediate),
+        ADC_ZERO_PAGE => ("ADC", AddressingMode::ZeroPage),
+        ADC_ZERO_PAGE_X => ("ADC", AddressingMode::ZeroPageX),
+        ADC_ABSOLUTE => ("ADC", AddressingMode::Absolute),
+        ADC_ABSOLUTE_X => ("ADC", AddressingMode::AbsoluteX),
+        ADC_ABSOLUTE_Y => ("ADC", AddressingMode::AbsoluteY),
+        ADC_INDIRECT_X => ("ADC", AddressingMode::IndirectX),
+        ADC_INDIRECT_Y => ("ADC", AddressingMode::IndirectY),
+        SBC_IMMEDIATE => ("SBC", AddressingMode::Immediate),
+        SBC_ZERO_PAGE => ("SBC", AddressingMode::ZeroPage),
+        SBC_ZERO_PAGE_X => ("SBC", AddressingMode::ZeroPageX),
+        SBC_ABSOLUTE => ("SBC", AddressingMode::Absolute),
+        SBC_ABSOLUTE_X => ("SBC", AddressingMode::AbsoluteX),
+        SBC_ABSOLUTE_Y => ("SBC", AddressingMode::AbsoluteY),
+        SBC_INDIRECT_X => ("SBC", AddressingMode::IndirectX),
+        SBC_INDIRECT_Y => ("SBC", AddressingMode::IndirectY),
+        CMP_IMMEDIATE => ("CMP", AddressingMode::Immediate),
+        CMP_ZERO_PAGE => ("CMP", AddressingMode::ZeroPage),
+        CMP_ZERO_PAGE_X => ("CMP", AddressingMode::ZeroPageX),
+        CMP_ABSOLUTE => ("CMP", AddressingMode::Absolute),
+        CMP_ABSOLUTE_X => ("CMP", AddressingMode::AbsoluteX),
+        CMP_ABSOLUTE_Y => ("CMP", AddressingMode::AbsoluteY),
+        CMP_INDIRECT_X => ("CMP", AddressingMode::IndirectX),
+        CMP_INDIRECT_Y => ("CMP", AddressingMode::IndirectY),
+        CPX_IMMEDIATE => ("CPX", AddressingMode::Immediate),
+        CPX_ZERO_PAGE => ("CPX", AddressingMode::ZeroPage),
+        CPX_ABSOLUTE => ("CPX", AddressingMode::Absolute),
+        CPY_IMMEDIATE => ("CPY", AddressingMode::Immediate),
+        CPY_ZERO_PAGE => ("CPY", AddressingMode::ZeroPage),
+        CPY_ABSOLUTE => ("CPY", AddressingMode::Absolute),
+        INC_ZERO_PAGE => ("INC", AddressingMode::ZeroPage),
+        INC_ZERO_PAGE_X => ("INC", AddressingMode::ZeroPageX),
+        INC_ABSOLUTE => ("INC", AddressingMode::Absolute),
+        INC_ABSOLUTE_X => ("INC", AddressingMode::AbsoluteX),
+        INX_IMPLIED => ("INX", AddressingMode::Implied),
+        INY_IMPLIED => ("INY", AddressingMode::Implied),
+        DEC_ZERO_PAGE => ("DEC", AddressingMode::ZeroPage),
+        DEC_ZERO_PAGE_X => ("DEC", AddressingMode::ZeroPageX),
+        DEC_ABSOLUTE => ("DEC", AddressingMode::Absolute),
+        DEC_ABSOLUTE_X => ("DEC", AddressingMode::AbsoluteX),
+        DEX_IMPLIED => ("DEX", AddressingMode::Implied),
+        DEY_IMPLIED => ("DEY", AddressingMode::Implied),
+        ASL_ACCUMULATOR => ("ASL", AddressingMode::Accumulator),
+        ASL_ZERO_PAGE => ("ASL", AddressingMode::ZeroPage),
+        ASL_ZERO_PAGE_X => ("ASL", AddressingMode::ZeroPageX),
+        ASL_ABSOLUTE => ("ASL", AddressingMode::Absolute),
+        ASL_ABSOLUTE_X => ("ASL", AddressingMode::AbsoluteX),
+        LSR_ACCUMULATOR => ("LSR", AddressingMode::Accumulator),
+        LSR_ZERO_PAGE => ("LSR", AddressingMode::ZeroPage),
+        LSR_ZERO_PAGE_X => ("LSR", AddressingMode::ZeroPageX),
+        LSR_ABSOLUTE => ("LSR", AddressingMode::Absolute),
+        LSR_ABSOLUTE_X => ("LSR", AddressingMode::AbsoluteX),
+        ROL_ACCUMULATOR => ("ROL", AddressingMode::Accumulator),
+        ROL_ZERO_PAGE => ("ROL", AddressingMode::ZeroPage),
+        ROL_ZERO_PAGE_X => ("ROL", AddressingMode::ZeroPageX),
+        ROL_ABSOLUTE => ("ROL", AddressingMode::Absolute),
+        ROL_ABSOLUTE_X => ("ROL", AddressingMode::AbsoluteX),
+        ROR_ACCUMULATOR => ("ROR", AddressingMode::Accumulator),
+        ROR_ZERO_PAGE => ("ROR", AddressingMode::ZeroPage),
+        ROR_ZERO_PAGE_X => ("ROR", AddressingMode::ZeroPageX),
+        ROR_ABSOLUTE => ("ROR", AddressingMode::Absolute),
+        ROR_ABSOLUTE_X => ("ROR", AddressingMode::AbsoluteX),
+        JMP_ABSOLUTE => ("JMP", AddressingMode::Absolute),
+        JMP_INDIRECT => ("JMP", AddressingMode::Indirect),
+        JSR_ABSOLUTE => ("JSR", AddressingMode::Absolute),
+        RTS_IMPLIED => ("RTS", AddressingMode::Implied),
+        BCC_RELATIVE => ("BCC", AddressingMode::Relative),
+        BCS_RELATIVE => ("BCS", AddressingMode::Relative),
+        BEQ_RELATIVE => ("BEQ", AddressingMode::Relative),
+        BMI_RELATIVE => ("BMI", AddressingMode::Relative),
+        BNE_RELATIVE => ("BNE", AddressingMode::Relative),
+        BPL_RELATIVE => ("BPL", AddressingMode::Relative),
+        BVC_RELATIVE => ("BVC", AddressingMode::Relative),
+        BVS_RELATIVE => ("BVS", AddressingMode::Relative),
+        CLC_IMPLIED => ("CLC", AddressingMode::Implied),
+        CLD_IMPLIED => ("CLD", AddressingMode::Implied),
+        CLI_IMPLIED => ("CLI", AddressingMode::Implied),
+        CLV_IMPLIED => ("CLV", AddressingMode::Implied),
+        SEC_IMPLIED => ("SEC", AddressingMode::Implied),
+        SED_IMPLIED => ("SED", AddressingMode::Implied),
+        SEI_IMPLIED => ("SEI", AddressingMode::Implied),
+        BRK_IMPLIED => ("BRK", AddressingMode::Implied),
+        NOP_IMPLIED => ("NOP", AddressingMode::Implied),
+        RTI_IMPLIED => ("RTI", AddressingMode::Implied),
+        _ => return None,
+    };
+
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+    use crate::memory::{BasicMemory, Memory};
+    use crate::ops::*;
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Instruction {
+        let mut memory = BasicMemory::default();
+        for (offset, byte) in bytes.iter().enumerate() {
+            memory.write(0xC000 + offset as u16, *byte);
+        }
+        Instruction::decode(&memory, 0xC000)
+    }
+
+    #[test]
+    fn formatting() {
+        assert_eq!(decode(&[LDA_IMMEDIATE, 0x42]).to_string(), "LDA #$42");
+        assert_eq!(decode(&[STX_ZERO_PAGE_Y, 0x10]).to_string(), "STX $10,Y");
+        assert_eq!(decode(&[JMP_ABSOLUTE, 0xF5, 0xC5]).to_string(), "JMP $C5F5");
+        assert_eq!(decode(&[JMP_INDIRECT, 0x00, 0x02]).to_string(), "JMP ($0200)");
+        assert_eq!(decode(&[LDA_INDIRECT_X, 0x80]).to_string(), "LDA ($80,X)");
+        assert_eq!(decode(&[LDA_INDIRECT_Y, 0x89]).to_string(), "LDA ($89),Y");
+        assert_eq!(decode(&[LSR_ACCUMULATOR]).to_string(), "LSR A");
+        assert_eq!(decode(&[CLC_IMPLIED]).to_string(), "CLC");
+        assert_eq!(decode(&[0xFF]).to_string(), "???");
+    }
+
+    #[test]
+    fn branch_targets() {
+        let forward = decode(&[BNE_RELATIVE, 0x04]);
+        assert_eq!(forward.target(), Some(0xC006));
+        assert_eq!(forward.to_string(), "BNE $C006");
+
+        let backward = decode(&[BCS_RELATIVE, 0xFE]);
+        assert_eq!(backward.target(), Some(0xC000));
+        assert_eq!(decode(&[JSR_ABSOLUTE, 0x34, 0x12]).target(), Some(0x1234));
+        assert_eq!(decode(&[JMP_INDIRECT, 0x34, 0x12]).target(), None);
+    }
+
+    #[test]
+    fn symbols() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(Symbol { name: "print".to_string(), address: 0x1234, bank: None });
+        symbols.insert(Symbol { name: "counter".to_string(), address: 0x0080, bank: None });
+        symbols.insert(Symbol { name: "loop".to_string(), address: 0xC000, bank: None });
+
+        let named = |bytes: &[u8]| decode(bytes).with_symbols(&symbols).to_string();
+        assert_eq!(named(&[JSR_ABSOLUTE, 0x34, 0x12]), "JSR print");
+        assert_eq!(named(&[LDA_ZERO_PAGE_X, 0x80]), "LDA counter,X");
+        assert_eq!(named(&[LDA_INDIRECT_Y, 0x80]), "LDA (counter),Y");
+        assert_eq!(named(&[BCS_RELATIVE, 0xFE]), "BCS loop");
+        assert_eq!(named(&[LDA_IMMEDIATE, 0x80]), "LDA #$80");
+        assert_eq!(named(&[STA_ABSOLUTE, 0x35, 0x12]), "STA $1235");
+    }
+
+    #[test]
+    fn lengths() {
+        assert_eq!(decode(&[NOP_IMPLIED]).length(), 1);
+        assert_eq!(decode(&[LDA_ZERO_PAGE, 0x00]).length(), 2);
+        assert_eq!(decode(&[STA_ABSOLUTE_Y, 0x00, 0x02]).length(), 3);
+        assert_eq!(decode(&[STA_ABSOLUTE_Y, 0x00, 0x02]).bytes(), [0x99, 0x00, 0x02]);
+        assert!(!decode(&[0x02]).is_known());
+        assert_eq!((0..=255u8).filter(|op| decode_opcode(*op).is_some()).count(), 151);
+    }
+}
\ No newline at end of file
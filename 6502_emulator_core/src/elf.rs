@@ -0,0 +1,680 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::debug_info::{SourceLine, SourceMap};
+use crate::hexfile::Program;
+use crate::memory::MAX_MEMORY;
+use crate::symbols::{Symbol, SymbolTable};
+
+/// The ELF machine type of 6502 executables, as registered by llvm-mos
+const EM_MOS: u16 = 6502;
+const ELF_HEADER_SIZE: usize = 52;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const SHN_UNDEF: u16 = 0;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const SYMBOL_SIZE: usize = 16;
+
+/// Line number program opcodes, from the DWARF 5 standard
+const DW_LNS_COPY: u8 = 0x01;
+const DW_LNS_ADVANCE_PC: u8 = 0x02;
+const DW_LNS_ADVANCE_LINE: u8 = 0x03;
+const DW_LNS_SET_FILE: u8 = 0x04;
+const DW_LNS_CONST_ADD_PC: u8 = 0x08;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 0x09;
+const DW_LNE_END_SEQUENCE: u8 = 0x01;
+const DW_LNE_SET_ADDRESS: u8 = 0x02;
+
+/// Attribute forms which appear in DWARF 5 line table headers
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0B;
+const DW_FORM_STRP: u64 = 0x0E;
+const DW_FORM_UDATA: u64 = 0x0F;
+const DW_FORM_DATA16: u64 = 0x1E;
+const DW_FORM_LINE_STRP: u64 = 0x1F;
+const DW_LNCT_PATH: u64 = 0x1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 0x2;
+
+/// Why an ELF file couldn't be loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfError {
+    pub message: String,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+fn error(message: impl Into<String>) -> ElfError {
+    ElfError { message: message.into() }
+}
+
+/// A 6502 executable built by llvm-mos: a 32-bit little-endian ELF file
+#[derive(Debug, Clone, Default)]
+pub struct ElfFile {
+    /// The loadable segments and the entry point. Only the bytes stored in the file are loaded,
+    /// zeroing `.bss` and the like is left to the startup code
+    pub program: Program,
+    /// The defined symbols of the symbol table
+    pub symbols: SymbolTable,
+    /// The source lines of the DWARF line tables, if the file has any
+    pub source_map: SourceMap,
+}
+
+/// Little-endian reads from a byte slice, returning `None` past the end
+#[derive(Debug, Clone)]
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self::at(data, 0)
+    }
+
+    fn at(data: &'a [u8], offset: usize) -> Self {
+        Self { data, offset }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.offset >= self.data.len()
+    }
+
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.offset..self.offset.checked_add(length)?)?;
+        self.offset += length;
+        Some(bytes)
+    }
+
+    /// An unsigned number of `size` bytes, up to 8
+    fn uint(&mut self, size: usize) -> Option<u64> {
+        Some(self.bytes(size)?.iter().rev().fold(0, |acc, b| acc << 8 | *b as u64))
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(self.uint(2)? as u16)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(self.uint(4)? as u32)
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7F) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let (mut value, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7F) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= !0 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+
+    /// A zero terminated UTF-8 string
+    fn cstr(&mut self) -> Option<&'a str> {
+        let rest = self.data.get(self.offset..)?;
+        let length = rest.iter().position(|b| *b == 0)?;
+        self.offset += length + 1;
+        core::str::from_utf8(&rest[..length]).ok()
+    }
+}
+
+/// The fields of the ELF header needed to find the segments and sections
+struct Header {
+    entry: u32,
+    program_headers: usize,
+    program_header_size: usize,
+    program_header_count: usize,
+    section_headers: usize,
+    section_header_size: usize,
+    section_header_count: usize,
+    section_names: usize,
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut header = Reader::at(bytes, 24);
+        let entry = header.u32()?;
+        let program_headers = header.u32()? as usize;
+        let section_headers = header.u32()? as usize;
+        header.bytes(6)?;
+        let program_header_size = header.u16()? as usize;
+        let program_header_count = header.u16()? as usize;
+        let section_header_size = header.u16()? as usize;
+        let section_header_count = header.u16()? as usize;
+        let section_names = header.u16()? as usize;
+        Some(Self {
+            entry,
+            program_headers,
+            program_header_size,
+            program_header_count,
+            section_headers,
+            section_header_size,
+            section_header_count,
+            section_names,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Section<'a> {
+    name: &'a str,
+    kind: u32,
+    data: &'a [u8],
+    link: usize,
+}
+
+impl ElfFile {
+    /// Parse the contents of an ELF file
+    pub fn parse(bytes: &[u8]) -> Result<Self, ElfError> {
+        if !bytes.starts_with(b"\x7fELF") {
+            return Err(error("not an ELF file"));
+        }
+        if bytes.len() < ELF_HEADER_SIZE || bytes[4] != 1 || bytes[5] != 1 {
+            return Err(error("only 32-bit little-endian ELF files are supported"));
+        }
+        let machine = Reader::at(bytes, 18).u16().unwrap_or_default();
+        if machine != EM_MOS {
+            return Err(error(format!("not a 6502 executable, the machine type is {}", machine)));
+        }
+
+        let header = Header::parse(bytes).ok_or_else(|| error("the ELF header is truncated"))?;
+        let program = Self::segments(bytes, &header)?;
+        let sections = Self::sections(bytes, &header).ok_or_else(|| error("the section headers are malformed"))?;
+        let symbols = Self::symbols(&sections).ok_or_else(|| error("the symbol table is malformed"))?;
+
+        let section = |name: &str| sections.iter().find(|s| s.name == name).map(|s| s.data).unwrap_or_default();
+        let strings = Strings { line: section(".debug_line_str"), str: section(".debug_str") };
+        let mut source_map = SourceMap::new();
+        line_tables(section(".debug_line"), &strings, &mut source_map)
+            .ok_or_else(|| error("the DWARF line table is malformed or uses an unsupported feature"))?;
+
+        Ok(Self { program, symbols, source_map })
+    }
+
+    fn segments(bytes: &[u8], header: &Header) -> Result<Program, ElfError> {
+        let mut program = Program { entry: u16::try_from(header.entry).ok().filter(|entry| *entry != 0), ..Program::default() };
+        for index in 0..header.program_header_count {
+            let mut segment = Reader::at(bytes, header.program_headers + index * header.program_header_size);
+            let fields = (|| Some((segment.u32()?, segment.u32()? as usize, segment.u32()?, segment.u32()?, segment.u32()? as usize)))();
+            let (kind, offset, address, _, length) = fields.ok_or_else(|| error("the program headers are truncated"))?;
+            if kind != PT_LOAD || length == 0 {
+                continue;
+            }
+
+            let data = bytes.get(offset..offset + length)
+                .ok_or_else(|| error(format!("the segment at ${:04X} is truncated", address)))?;
+            if address as usize + length > MAX_MEMORY {
+                return Err(error(format!("the segment at ${:04X} is outside of the 64 KiB address space", address)));
+            }
+            program.push(address, data);
+        }
+        Ok(program)
+    }
+
+    fn sections<'a>(bytes: &'a [u8], header: &Header) -> Option<Vec<Section<'a>>> {
+        let mut sections = Vec::with_capacity(header.section_header_count);
+        for index in 0..header.section_header_count {
+            let mut section = Reader::at(bytes, header.section_headers + index * header.section_header_size);
+            let (name, kind) = (section.u32()?, section.u32()?);
+            section.bytes(8)?;
+            let (offset, size, link) = (section.u32()? as usize, section.u32()? as usize, section.u32()? as usize);
+            let data = if kind == SHT_NOBITS { &[][..] } else { bytes.get(offset..offset.checked_add(size)?)? };
+            sections.push((name as usize, Section { name: "", kind, data, link }));
+        }
+
+        let names = match sections.get(header.section_names) {
+            Some((_, names)) => names.data,
+            None if sections.is_empty() => &[],
+            None => return None,
+        };
+        sections.into_iter()
+            .map(|(name, section)| Some(Section { name: Reader::at(names, name).cstr()?, ..section }))
+            .collect()
+    }
+
+    fn symbols(sections: &[Section]) -> Option<SymbolTable> {
+        let mut symbols = SymbolTable::new();
+        for table in sections.iter().filter(|section| section.kind == SHT_SYMTAB) {
+            let names = sections.get(table.link)?.data;
+            // The first entry is always the undefined symbol
+            for entry in table.data.chunks_exact(SYMBOL_SIZE).skip(1) {
+                let mut entry = Reader::new(entry);
+                let (name, value) = (entry.u32()? as usize, entry.u32()?);
+                entry.u32()?;
+                let (info, _, index) = (entry.u8()?, entry.u8()?, entry.u16()?);
+
+                let name = Reader::at(names, name).cstr()?;
+                if name.is_empty() || index == SHN_UNDEF || matches!(info & 0xF, STT_SECTION | STT_FILE) {
+                    continue;
+                }
+                if let Ok(address) = u16::try_from(value) {
+                    symbols.insert(Symbol { name: name.to_string(), address, bank: None });
+                }
+            }
+        }
+        Some(symbols)
+    }
+}
+
+/// The string sections DWARF 5 line table headers refer to
+struct Strings<'a> {
+    line: &'a [u8],
+    str: &'a [u8],
+}
+
+/// A value of a line table header entry. Only strings and numbers are of interest
+enum Value<'a> {
+    String(&'a str),
+    Number(u64),
+    Other,
+}
+
+fn read_value<'a>(reader: &mut Reader<'a>, form: u64, offset_size: usize, strings: &Strings<'a>) -> Option<Value<'a>> {
+    Some(match form {
+        DW_FORM_STRING => Value::String(reader.cstr()?),
+        DW_FORM_LINE_STRP => Value::String(Reader::at(strings.line, reader.uint(offset_size)? as usize).cstr()?),
+        DW_FORM_STRP => Value::String(Reader::at(strings.str, reader.uint(offset_size)? as usize).cstr()?),
+        DW_FORM_UDATA => Value::Number(reader.uleb()?),
+        DW_FORM_DATA1 => Value::Number(reader.uint(1)?),
+        DW_FORM_DATA2 => Value::Number(reader.uint(2)?),
+        DW_FORM_DATA4 => Value::Number(reader.uint(4)?),
+        DW_FORM_DATA8 => Value::Number(reader.uint(8)?),
+        DW_FORM_DATA16 => {
+            reader.bytes(16)?;
+            Value::Other
+        },
+        DW_FORM_BLOCK => {
+            let length = reader.uleb()? as usize;
+            reader.bytes(length)?;
+            Value::Other
+        },
+        _ => return None,
+    })
+}
+
+/// Read the directory or file name entries of a DWARF 5 line table header as paths and directory indices
+fn read_entries<'a>(reader: &mut Reader<'a>, offset_size: usize, strings: &Strings<'a>) -> Option<Vec<(&'a str, usize)>> {
+    let format_count = reader.u8()?;
+    let formats = (0..format_count).map(|_| Some((reader.uleb()?, reader.uleb()?))).collect::<Option<Vec<_>>>()?;
+    let count = reader.uleb()?;
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let (mut path, mut directory) = ("", 0);
+        for (content, form) in &formats {
+            match (*content, read_value(reader, *form, offset_size, strings)?) {
+                (DW_LNCT_PATH, Value::String(s)) => path = s,
+                (DW_LNCT_DIRECTORY_INDEX, Value::Number(n)) => directory = n as usize,
+                _ => {},
+            }
+        }
+        entries.push((path, directory));
+    }
+    Some(entries)
+}
+
+fn join(directory: &str, name: &str) -> String {
+    if directory.is_empty() || name.starts_with('/') || name.get(1..2) == Some(":") {
+        name.to_string()
+    } else {
+        format!("{}/{}", directory.trim_end_matches('/'), name)
+    }
+}
+
+/// Turns the rows of a line number program into spans, each running up to the next row
+struct Rows<'a> {
+    source_map: &'a mut SourceMap,
+    /// The source map index of each file number
+    files: Vec<Option<usize>>,
+    previous: Option<(u64, u64, i64)>,
+}
+
+impl Rows<'_> {
+    fn row(&mut self, address: u64, file: u64, line: i64, end_sequence: bool) {
+        if let Some((start, file, line)) = self.previous.take() {
+            let file = self.files.get(file as usize).copied().flatten();
+            let fits = address > start && address as usize <= MAX_MEMORY;
+            if let (Some(file), Ok(line), true) = (file, u32::try_from(line), fits) {
+                if line != 0 {
+                    self.source_map.add_span(start as u16, (address - start).min(u16::MAX as u64) as u16, SourceLine { file, line });
+                }
+            }
+        }
+        if !end_sequence {
+            self.previous = Some((address, file, line));
+        }
+    }
+}
+
+/// Add the lines of all units in a `.debug_line` section to `source_map`
+fn line_tables(section: &[u8], strings: &Strings, source_map: &mut SourceMap) -> Option<()> {
+    let mut units = Reader::new(section);
+    while !units.is_empty() {
+        let (length, offset_size) = match units.u32()? {
+            0xFFFF_FFFF => (units.uint(8)? as usize, 8),
+            length => (length as usize, 4),
+        };
+        line_table(units.bytes(length)?, offset_size, strings, source_map)?;
+    }
+    Some(())
+}
+
+fn line_table(unit: &[u8], offset_size: usize, strings: &Strings, source_map: &mut SourceMap) -> Option<()> {
+    let mut header = Reader::new(unit);
+    let version = header.u16()?;
+    if !(2..=5).contains(&version) {
+        return None;
+    }
+    if version >= 5 {
+        // Address and segment selector sizes
+        header.bytes(2)?;
+    }
+    let header_length = header.uint(offset_size)? as usize;
+    let program = header.offset.checked_add(header_length)?;
+    let minimum_length = header.u8()? as u64;
+    if version >= 4 {
+        // Maximum operations per instruction, only used by VLIW machines
+        header.u8()?;
+    }
+    let _default_is_stmt = header.u8()?;
+    let line_base = header.u8()? as i8 as i64;
+    let line_range = header.u8()?;
+    let opcode_base = header.u8()?;
+    let opcode_lengths = header.bytes(opcode_base.checked_sub(1)? as usize)?;
+    if line_range == 0 {
+        return None;
+    }
+
+    let mut files = Vec::new();
+    if version >= 5 {
+        let directories = read_entries(&mut header, offset_size, strings)?;
+        for (name, directory) in read_entries(&mut header, offset_size, strings)? {
+            let directory = directories.get(directory).map(|d| d.0).unwrap_or_default();
+            files.push(Some(source_map.add_file(&join(directory, name))));
+        }
+    } else {
+        // Before DWARF 5, directory 0 is the compilation directory, which isn't in the line table, and file numbers start at 1
+        let mut directories = vec![""];
+        loop {
+            match header.cstr()? {
+                "" => break,
+                directory => directories.push(directory),
+            }
+        }
+        files.push(None);
+        loop {
+            let name = header.cstr()?;
+            if name.is_empty() {
+                break;
+            }
+            let directory = directories.get(header.uleb()? as usize).copied().unwrap_or_default();
+            header.uleb()?;
+            header.uleb()?;
+            files.push(Some(source_map.add_file(&join(directory, name))));
+        }
+    }
+
+    let mut rows = Rows { source_map, files, previous: None };
+    let mut reader = Reader::at(unit, program);
+    let (mut address, mut file, mut line) = (0u64, 1u64, 1i64);
+    while !reader.is_empty() {
+        let opcode = reader.u8()?;
+        if opcode >= opcode_base {
+            let adjusted = opcode - opcode_base;
+            address += (adjusted / line_range) as u64 * minimum_length;
+            line += line_base + (adjusted % line_range) as i64;
+            rows.row(address, file, line, false);
+            continue;
+        }
+
+        match opcode {
+            0 => {
+                let length = reader.uleb()? as usize;
+                let mut extended = Reader::new(reader.bytes(length)?);
+                match extended.u8()? {
+                    DW_LNE_END_SEQUENCE => {
+                        rows.row(address, file, line, true);
+                        (address, file, line) = (0, 1, 1);
+                    },
+                    DW_LNE_SET_ADDRESS => address = extended.uint(length - 1)?,
+                    _ => {},
+                }
+            },
+            DW_LNS_COPY => rows.row(address, file, line, false),
+            DW_LNS_ADVANCE_PC => address += reader.uleb()? * minimum_length,
+            DW_LNS_ADVANCE_LINE => line += reader.sleb()?,
+            DW_LNS_SET_FILE => file = reader.uleb()?,
+            DW_LNS_CONST_ADD_PC => address += ((255 - opcode_base) / line_range) as u64 * minimum_length,
+            DW_LNS_FIXED_ADVANCE_PC => address += reader.u16()? as u64,
+            // Everything else only changes registers which don't matter for mapping addresses to lines
+            _ => {
+                for _ in 0..opcode_lengths[opcode as usize - 1] {
+                    reader.uleb()?;
+                }
+            },
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use crate::debug_info::SourceLine;
+    use super::*;
+
+    const SHT_PROGBITS: u32 = 1;
+    const SHT_STRTAB: u32 = 3;
+
+    /// Build an ELF file with one loadable segment holding `code` at `address`, and `sections`
+    /// of name, type, data and link following the null section
+    fn elf(address: u16, code: &[u8], entry: u16, sections: &[(&str, u32, Vec<u8>, u32)]) -> Vec<u8> {
+        let mut names = vec![0u8];
+        let mut headers = vec![[0u32; 10]];
+        let mut data = Vec::new();
+        let data_start = ELF_HEADER_SIZE + 32 + code.len();
+        let shstrtab = [(".shstrtab", SHT_STRTAB, Vec::new(), 0)];
+        for (index, (name, kind, contents, link)) in sections.iter().chain(shstrtab.iter()).enumerate() {
+            let name_offset = names.len() as u32;
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+            let contents = if index == sections.len() { &names } else { contents };
+            headers.push([name_offset, *kind, 0, 0, (data_start + data.len()) as u32, contents.len() as u32, *link, 0, 1, 0]);
+            data.extend_from_slice(contents);
+        }
+
+        let mut bytes = b"\x7fELF\x01\x01\x01".to_vec();
+        bytes.resize(16, 0);
+        let section_headers = (data_start + data.len()) as u32;
+        for half in [2u16, EM_MOS] {
+            bytes.extend_from_slice(&half.to_le_bytes());
+        }
+        for word in [1, entry as u32, ELF_HEADER_SIZE as u32, section_headers, 0] {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        for half in [ELF_HEADER_SIZE as u16, 32, 1, 40, headers.len() as u16, headers.len() as u16 - 1] {
+            bytes.extend_from_slice(&half.to_le_bytes());
+        }
+        let code_offset = (ELF_HEADER_SIZE + 32) as u32;
+        for word in [PT_LOAD, code_offset, address as u32, address as u32, code.len() as u32, code.len() as u32 + 4, 5, 1] {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(code);
+        bytes.extend_from_slice(&data);
+        for header in headers {
+            for word in header {
+                bytes.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// A line table unit with the usual opcode base of 13
+    fn line_unit(version: u16, file_names: &[u8], program: &[u8]) -> Vec<u8> {
+        let mut header = vec![1, 1, 1, (-5i8) as u8, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+        if version < 4 {
+            header.remove(1);
+        }
+        header.extend_from_slice(file_names);
+
+        let mut unit = version.to_le_bytes().to_vec();
+        if version >= 5 {
+            unit.extend_from_slice(&[2, 0]);
+        }
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend_from_slice(&header);
+        unit.extend_from_slice(program);
+
+        let mut bytes = (unit.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&unit);
+        bytes
+    }
+
+    fn symbol(name: u32, value: u32, info: u8, section: u16) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for word in [name, value, 0] {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(&[info, 0]);
+        bytes.extend_from_slice(&section.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn segments_and_symbols() {
+        let names = b"\0main\0__rc2\0puts\0main.c\0".to_vec();
+        let symbols = [
+            symbol(0, 0, 0, 0),
+            symbol(1, 0x0200, 0x12, 1),
+            symbol(6, 0x0004, 0x10, 0xFFF1),
+            symbol(12, 0, 0x10, SHN_UNDEF),
+            symbol(17, 0, STT_FILE, 0xFFF1),
+        ].concat();
+        let bytes = elf(0x0200, &[0xA9, 0x01, 0x60], 0x0200, &[
+            (".symtab", SHT_SYMTAB, symbols, 2),
+            (".strtab", SHT_STRTAB, names, 0),
+        ]);
+
+        let elf = ElfFile::parse(&bytes).unwrap();
+        assert_eq!(elf.program.entry, Some(0x0200));
+        assert_eq!(elf.program.segments.len(), 1);
+        assert_eq!(elf.program.segments[0].address, 0x0200);
+        assert_eq!(elf.program.segments[0].data, [0xA9, 0x01, 0x60]);
+        assert_eq!(elf.symbols.len(), 2);
+        assert_eq!(elf.symbols.address_of("main"), Some(0x0200));
+        assert_eq!(elf.symbols.address_of("__rc2"), Some(0x0004));
+        assert!(elf.source_map.is_empty());
+    }
+
+    #[test]
+    fn dwarf_lines() {
+        // DWARF 4: main.c in src, lines 5, 6 and 8 at $0200, $0202 and $0205 up to $0206
+        let dwarf4 = line_unit(4, b"src\0\0main.c\0\x01\0\0\0", &[
+            0x00, 0x03, DW_LNE_SET_ADDRESS, 0x00, 0x02,
+            DW_LNS_ADVANCE_LINE, 4,
+            DW_LNS_COPY,
+            (1 + 5) + 14 * 2 + 13,
+            (2 + 5) + 14 * 3 + 13,
+            DW_LNS_ADVANCE_PC, 1,
+            0x00, 0x01, DW_LNE_END_SEQUENCE,
+        ]);
+        // DWARF 5: util.c in /work, named in .debug_line_str and with an MD5 checksum, line 10 at $0300 up to $0304
+        let mut file_names = vec![1, DW_LNCT_PATH as u8, DW_FORM_STRING as u8, 1];
+        file_names.extend_from_slice(b"/work\0");
+        file_names.extend_from_slice(&[3, DW_LNCT_PATH as u8, DW_FORM_LINE_STRP as u8, DW_LNCT_DIRECTORY_INDEX as u8, DW_FORM_UDATA as u8, 5, DW_FORM_DATA16 as u8, 1]);
+        file_names.extend_from_slice(&[1, 0, 0, 0, 0]);
+        file_names.extend_from_slice(&[0xAA; 16]);
+        let dwarf5 = line_unit(5, &file_names, &[
+            0x00, 0x03, DW_LNE_SET_ADDRESS, 0x00, 0x03,
+            DW_LNS_SET_FILE, 0,
+            DW_LNS_ADVANCE_LINE, 9,
+            DW_LNS_COPY,
+            DW_LNS_ADVANCE_PC, 4,
+            0x00, 0x01, DW_LNE_END_SEQUENCE,
+        ]);
+
+        let bytes = elf(0x0200, &[0xEA; 7], 0, &[
+            (".debug_line", SHT_PROGBITS, [dwarf4, dwarf5].concat(), 0),
+            (".debug_line_str", SHT_PROGBITS, b"\0util.c\0".to_vec(), 0),
+        ]);
+        let elf = ElfFile::parse(&bytes).unwrap();
+        assert_eq!(elf.program.entry, None);
+
+        let map = &elf.source_map;
+        assert_eq!(map.files(), ["src/main.c", "/work/util.c"]);
+        assert_eq!(map.line_at(0x0201), Some(SourceLine { file: 0, line: 5 }));
+        assert_eq!(map.line_at(0x0204), Some(SourceLine { file: 0, line: 6 }));
+        assert!(map.is_line_start(0x0205));
+        assert_eq!(map.line_at(0x0205), Some(SourceLine { file: 0, line: 8 }));
+        assert_eq!(map.line_at(0x0206), None);
+        assert_eq!(map.line_at(0x0303), Some(SourceLine { file: 1, line: 10 }));
+        assert_eq!(map.line_at(0x0304), None);
+    }
+
+    #[test]
+    fn header_length_overflow() {
+        // A 64-bit DWARF 4 unit whose header claims to be longer than memory
+        let mut section = [0xFF; 4].to_vec();
+        section.extend_from_slice(&10u64.to_le_bytes());
+        section.extend_from_slice(&4u16.to_le_bytes());
+        section.extend_from_slice(&u64::MAX.to_le_bytes());
+        let strings = Strings { line: &[], str: &[] };
+        let mut map = SourceMap::default();
+        assert_eq!(line_tables(&section, &strings, &mut map), None);
+    }
+
+    #[test]
+    fn errors() {
+        let message = |bytes: &[u8]| ElfFile::parse(bytes).unwrap_err().message;
+        assert_eq!(message(b"\x01\x08\xA9\x01"), "not an ELF file");
+
+        let mut bytes = elf(0x0200, &[0x60], 0x0200, &[]);
+        bytes[18] = 0x03;
+        bytes[19] = 0x00;
+        assert_eq!(message(&bytes), "not a 6502 executable, the machine type is 3");
+        bytes[4] = 2;
+        assert_eq!(message(&bytes), "only 32-bit little-endian ELF files are supported");
+
+        let bytes = elf(0xFFFF, &[0x60, 0x60], 0, &[]);
+        assert_eq!(message(&bytes), "the segment at $FFFF is outside of the 64 KiB address space");
+        let bytes = elf(0x0200, &[0x60], 0, &[(".debug_line", SHT_PROGBITS, line_unit(6, b"", &[]), 0)]);
+        assert_eq!(message(&bytes), "the DWARF line table is malformed or uses an unsupported feature");
+    }
+}
\ No newline at end of file
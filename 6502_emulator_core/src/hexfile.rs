@@ -0,0 +1,368 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+use core::ops::RangeInclusive;
+use crate::memory::{MAX_MEMORY, Memory};
+
+/// Data bytes per record written by [to_intel_hex] and [to_srec]
+const BYTES_PER_RECORD: usize = 16;
+
+/// Bytes at consecutive addresses
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u16,
+    pub data: Vec<u8>,
+}
+
+/// A program read from a file which places its data at addresses, like Intel HEX or Motorola S-records
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    /// The data in the order it appears in the file. Adjacent records are merged into one segment
+    pub segments: Vec<Segment>,
+    /// The address to start executing at, if the file has a start address record
+    pub entry: Option<u16>,
+}
+
+impl Program {
+    /// Write every segment to memory at its address
+    pub fn load_into(&self, memory: &mut dyn Memory<MAX_MEMORY>) {
+        for segment in &self.segments {
+            for (offset, byte) in segment.data.iter().enumerate() {
+                memory.write(segment.address.wrapping_add(offset as u16), *byte);
+            }
+        }
+    }
+
+    /// Add `data` at `address`, which the caller checked to fit in the address space
+    pub(crate) fn push(&mut self, address: u32, data: &[u8]) {
+        match self.segments.last_mut() {
+            Some(last) if last.address as u32 + last.data.len() as u32 == address => last.data.extend_from_slice(data),
+            _ => self.segments.push(Segment { address: address as u16, data: data.to_vec() }),
+        }
+    }
+}
+
+/// A malformed record in an Intel HEX or S-record file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HexFileError {
+    /// The line number, starting at 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for HexFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Decode the hexadecimal digits of a record, which start after the `prefix_length` characters of the record type
+fn decode_hex(digits: &str, prefix_length: usize) -> Result<Vec<u8>, String> {
+    if let Some((column, c)) = digits.char_indices().find(|(_, c)| !c.is_ascii_hexdigit()) {
+        return Err(format!("'{}' at column {} is not a hexadecimal digit", c, prefix_length + column + 1));
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err("odd number of hexadecimal digits".into());
+    }
+
+    Ok((0..digits.len()).step_by(2).map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap_or_default()).collect())
+}
+
+/// Check that `length` bytes at `address` fit in the address space
+fn check_range(address: u32, length: usize) -> Result<(), String> {
+    let end = address as usize + length;
+    if end > MAX_MEMORY {
+        return Err(format!("data at ${:04X}-${:04X} is outside of the 64 KiB address space", address, end - 1));
+    }
+    Ok(())
+}
+
+fn be_value(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, b| acc << 8 | *b as u32)
+}
+
+/// The checksum of an Intel HEX record: the two's complement of the sum of all other bytes
+fn intel_hex_checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)).wrapping_neg()
+}
+
+/// The checksum of an S-record: the ones' complement of the sum of the count, address and data bytes
+fn srec_checksum(bytes: &[u8]) -> u8 {
+    !bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+/// Parse an Intel HEX file. Extended segment and linear address records are supported as long as
+/// the data stays within 64 KiB. Either kind of start address record sets the entry point
+pub fn parse_intel_hex(text: &str) -> Result<Program, HexFileError> {
+    let mut program = Program::default();
+    let mut base = 0u32;
+    let mut last_line = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        last_line = index + 1;
+        if line.is_empty() {
+            continue;
+        }
+
+        let error = |message: String| HexFileError { line: index + 1, message };
+        let digits = line.strip_prefix(':').ok_or_else(|| error("records must start with ':'".into()))?;
+        let bytes = decode_hex(digits, 1).map_err(error)?;
+        if bytes.len() < 5 {
+            return Err(error("record is too short".into()));
+        }
+
+        let length = bytes[0] as usize;
+        if bytes.len() != length + 5 {
+            return Err(error(format!("record should have {} data bytes, but has {}", length, bytes.len() - 5)));
+        }
+        let (record, checksum) = bytes.split_at(bytes.len() - 1);
+        let expected = intel_hex_checksum(record);
+        if checksum[0] != expected {
+            return Err(error(format!("checksum is {:02X}, but should be {:02X}", checksum[0], expected)));
+        }
+
+        let address = be_value(&record[1..3]);
+        let data = &record[4..];
+        let data_length = |expected: usize| if data.len() == expected {
+            Ok(be_value(data))
+        } else {
+            Err(error(format!("record type {:02X} should have {} data bytes, but has {}", record[3], expected, data.len())))
+        };
+
+        match record[3] {
+            0x00 => {
+                check_range(base + address, data.len()).map_err(error)?;
+                program.push(base + address, data);
+            },
+            0x01 => return Ok(program),
+            0x02 => base = data_length(2)? << 4,
+            0x04 => base = data_length(2)? << 16,
+            0x03 | 0x05 => {
+                let value = data_length(4)?;
+                let entry = if record[3] == 0x03 { (value >> 16) * 16 + (value & 0xFFFF) } else { value };
+                let entry = u16::try_from(entry).map_err(|_| error(format!("start address ${:X} is outside of the 64 KiB address space", entry)))?;
+                program.entry = Some(entry);
+            },
+            kind => return Err(error(format!("unknown record type {:02X}", kind))),
+        }
+    }
+
+    Err(HexFileError { line: last_line, message: "missing end of file record (:00000001FF)".into() })
+}
+
+/// Parse a Motorola S-record file with 16, 24 or 32 bit addresses, as long as the data stays within 64 KiB.
+/// A termination record with a non-zero address sets the entry point, and count records are verified
+pub fn parse_srec(text: &str) -> Result<Program, HexFileError> {
+    let mut program = Program::default();
+    let mut data_records = 0u32;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let error = |message: String| HexFileError { line: index + 1, message };
+        let kind = line.strip_prefix('S')
+            .and_then(|rest| rest.chars().next())
+            .and_then(|c| c.to_digit(10))
+            .ok_or_else(|| error("records must start with 'S' and the record type".into()))?;
+        let bytes = decode_hex(&line[2..], 2).map_err(error)?;
+
+        let count = *bytes.first().ok_or_else(|| error("record is too short".into()))? as usize;
+        if bytes.len() != count + 1 {
+            return Err(error(format!("record should have {} bytes after the count, but has {}", count, bytes.len() - 1)));
+        }
+        let (record, checksum) = bytes.split_at(bytes.len() - 1);
+        let expected = srec_checksum(record);
+        if checksum[0] != expected {
+            return Err(error(format!("checksum is {:02X}, but should be {:02X}", checksum[0], expected)));
+        }
+
+        let address_length = match kind {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => return Err(error(format!("unknown record type S{}", kind))),
+        };
+        if record.len() < address_length + 1 {
+            return Err(error("record is too short for its address".into()));
+        }
+        let address = be_value(&record[1..=address_length]);
+        let data = &record[address_length + 1..];
+
+        match kind {
+            1..=3 => {
+                check_range(address, data.len()).map_err(error)?;
+                program.push(address, data);
+                data_records += 1;
+            },
+            5 | 6 if address != data_records => {
+                return Err(error(format!("count record says there are {} data records, but there are {}", address, data_records)));
+            },
+            7..=9 => {
+                if address != 0 {
+                    let entry = u16::try_from(address).map_err(|_| error(format!("start address ${:X} is outside of the 64 KiB address space", address)))?;
+                    program.entry = Some(entry);
+                }
+                break;
+            },
+            _ => {},
+        }
+    }
+
+    Ok(program)
+}
+
+/// Split `ranges` of memory into chunks of at most [BYTES_PER_RECORD] bytes
+fn chunks<'a>(memory: &'a dyn Memory<MAX_MEMORY>, ranges: &'a [RangeInclusive<u16>]) -> impl Iterator<Item = (u16, Vec<u8>)> + 'a {
+    ranges.iter().flat_map(move |range| {
+        let (start, end) = (*range.start() as usize, *range.end() as usize);
+        (start..=end).step_by(BYTES_PER_RECORD).map(move |address| {
+            let last = (address + BYTES_PER_RECORD - 1).min(end);
+            (address as u16, (address..=last).map(|a| memory.peek(a as u16)).collect())
+        })
+    })
+}
+
+fn push_record(out: &mut String, prefix: &str, bytes: &[u8], checksum: u8) {
+    out.push_str(prefix);
+    for byte in bytes {
+        let _ = write!(out, "{:02X}", byte);
+    }
+    let _ = writeln!(out, "{:02X}", checksum);
+}
+
+/// Write memory `ranges` as Intel HEX, with a start linear address record if an `entry` is given
+pub fn to_intel_hex(memory: &dyn Memory<MAX_MEMORY>, ranges: &[RangeInclusive<u16>], entry: Option<u16>) -> String {
+    let mut out = String::new();
+    let mut write = |kind: u8, address: u16, data: &[u8]| {
+        let mut record = Vec::with_capacity(data.len() + 4);
+        record.push(data.len() as u8);
+        record.extend_from_slice(&address.to_be_bytes());
+        record.push(kind);
+        record.extend_from_slice(data);
+        push_record(&mut out, ":", &record, intel_hex_checksum(&record));
+    };
+
+    for (address, data) in chunks(memory, ranges) {
+        write(0x00, address, &data);
+    }
+    if let Some(entry) = entry {
+        write(0x05, 0, &(entry as u32).to_be_bytes());
+    }
+    write(0x01, 0, &[]);
+    out
+}
+
+/// Write memory `ranges` as S-records with 16 bit addresses, followed by a count record
+/// and a termination record holding the `entry`, or zero if there is none
+pub fn to_srec(memory: &dyn Memory<MAX_MEMORY>, ranges: &[RangeInclusive<u16>], entry: Option<u16>) -> String {
+    let mut out = String::new();
+    let mut write = |kind: &str, address: u16, data: &[u8]| {
+        let mut record = Vec::with_capacity(data.len() + 3);
+        record.push(data.len() as u8 + 3);
+        record.extend_from_slice(&address.to_be_bytes());
+        record.extend_from_slice(data);
+        push_record(&mut out, kind, &record, srec_checksum(&record));
+    };
+
+    write("S0", 0, &[]);
+    let mut records = 0u16;
+    for (address, data) in chunks(memory, ranges) {
+        write("S1", address, &data);
+        records += 1;
+    }
+    write("S5", records, &[]);
+    write("S9", entry.unwrap_or_default(), &[]);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+    use crate::memory::BasicMemory;
+    use super::*;
+
+    #[test]
+    fn intel_hex_records() {
+        let text = ":10010000214601360121470136007EFE09D2190140\n:0400000500000100F6\n:00000001FF\n";
+        let program = parse_intel_hex(text).unwrap();
+        assert_eq!(program.segments.len(), 1);
+        assert_eq!(program.segments[0].address, 0x0100);
+        assert_eq!(program.segments[0].data[..4], [0x21, 0x46, 0x01, 0x36]);
+        assert_eq!(program.entry, Some(0x0100));
+
+        let mut memory = BasicMemory::default();
+        program.load_into(&mut memory);
+        assert_eq!(memory.read(0x010F), 0x01);
+    }
+
+    #[test]
+    fn intel_hex_errors() {
+        let error = parse_intel_hex(":00000001FF\n").map(|_| ());
+        assert_eq!(error, Ok(()));
+
+        let error = parse_intel_hex(":10010000214601360121470136007EFE09D2190141\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 1: checksum is 41, but should be 40");
+        let error = parse_intel_hex(":0300000001FC\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 1: record should have 3 data bytes, but has 1");
+        assert_eq!(parse_intel_hex("\n:01000000AA55\n").unwrap_err().to_string(), "line 2: missing end of file record (:00000001FF)");
+        assert!(parse_intel_hex(":01000000AG55\n").unwrap_err().message.contains("'G' at column 11"));
+
+        // Extended linear addresses beyond 64 KiB
+        let error = parse_intel_hex(":020000040001F9\n:01000000AA55\n:00000001FF\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: data at $10000-$10000 is outside of the 64 KiB address space");
+    }
+
+    #[test]
+    fn srec_records() {
+        let text = "S00600004844521B\nS1137AF00A0A0D0000000000000000000000000061\nS2080000107A7B7C7DF9\nS5030002FA\nS9037AF092\n";
+        let program = parse_srec(text).unwrap();
+        assert_eq!(program.segments.len(), 2);
+        assert_eq!(program.segments[0].address, 0x7AF0);
+        assert_eq!(program.segments[0].data[..3], [0x0A, 0x0A, 0x0D]);
+        assert_eq!(program.segments[1].address, 0x0010);
+        assert_eq!(program.segments[1].data, [0x7A, 0x7B, 0x7C, 0x7D]);
+        assert_eq!(program.entry, Some(0x7AF0));
+
+        let error = parse_srec("S1137AF00A0A0D0000000000000000000000000062\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 1: checksum is 62, but should be 61");
+        let error = parse_srec("S1137AF00A0A0D0000000000000000000000000061\nS5030002FA\n").unwrap_err();
+        assert_eq!(error.message, "count record says there are 2 data records, but there are 1");
+        assert!(parse_srec("S1047AF00A\n").is_err());
+        assert_eq!(parse_srec("S1047AF00G\n").unwrap_err().message, "'G' at column 10 is not a hexadecimal digit");
+        assert_eq!(parse_srec("S9030000FC\n").unwrap().entry, None);
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let mut memory = BasicMemory::default();
+        for address in 0xC000..0xC020u16 {
+            memory.write(address, address as u8);
+        }
+        memory.write(0xFFFC, 0x00);
+        memory.write(0xFFFD, 0xC0);
+        let ranges = [0xC000..=0xC011, 0xFFFC..=0xFFFD];
+
+        let hex = to_intel_hex(&memory, &ranges, Some(0xC000));
+        assert!(hex.starts_with(":10C00000000102030405060708090A0B0C0D0E0FB8\n"));
+        assert!(hex.ends_with(":040000050000C00037\n:00000001FF\n"));
+        let program = parse_intel_hex(&hex).unwrap();
+        assert_eq!(program.entry, Some(0xC000));
+        assert_eq!(program.segments, [
+            Segment { address: 0xC000, data: (0..0x12).collect() },
+            Segment { address: 0xFFFC, data: [0x00, 0xC0].to_vec() },
+        ]);
+
+        let srec = to_srec(&memory, &ranges, Some(0xC000));
+        assert!(srec.starts_with("S0030000FC\nS113C000000102030405060708090A0B0C0D0E0FB4\n"));
+        let program = parse_srec(&srec).unwrap();
+        assert_eq!(program.entry, Some(0xC000));
+        assert_eq!(program.segments.len(), 2);
+        assert_eq!(program.segments[0].data.len(), 0x12);
+    }
+}
\ No newline at end of file
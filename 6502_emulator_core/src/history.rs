@@ -0,0 +1,168 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::cpu::Registers;
+use crate::disassembler::Instruction;
+use crate::provenance::WriteRecord;
+use crate::trace::UNUSED_FLAG_BIT;
+
+/// A byte written by an instruction, with the value it replaced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub address: u16,
+    /// The value before the write
+    pub old: u8,
+    /// The value written
+    pub new: u8,
+    /// The [crate::Provenance] record the write replaced, restored when it's undone
+    pub replaced: Option<WriteRecord>,
+}
+
+/// An executed instruction, with everything needed to undo it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub instruction: Instruction,
+    /// The registers before the instruction was executed
+    pub registers: Registers,
+    /// The cycle count before the instruction was executed
+    pub cycles: u64,
+    /// The writes made by the instruction, in the order they were made
+    pub writes: Vec<MemoryWrite>,
+}
+
+impl fmt::Display for HistoryEntry {
+    /// A trace-like line, e.g. `0200  STA $0300         A:42 X:00 Y:00 P:24 SP:FD CYC:7  $0300: 00 -> 42`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let registers = &self.registers;
+        write!(
+            f,
+            "{:04X}  {:<16}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            registers.program_counter,
+            alloc::format!("{}", self.instruction),
+            registers.accumulator,
+            registers.x,
+            registers.y,
+            registers.flags.bits() | UNUSED_FLAG_BIT,
+            registers.stack_pointer,
+            self.cycles,
+        )?;
+
+        for write in &self.writes {
+            write!(f, "  ${:04X}: {:02X} -> {:02X}", write.address, write.old, write.new)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A bounded record of the last executed instructions, used to step backwards.
+/// Enable it with [crate::Cpu::enable_history]. Only writes made by instructions are recorded,
+/// so memory changed from outside the CPU, e.g. by a debugger, is not restored when stepping back
+#[derive(Debug, Clone)]
+pub struct History {
+    capacity: usize,
+    entries: VecDeque<HistoryEntry>,
+}
+
+impl History {
+    /// Create a history keeping the last `capacity` instructions
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity.min(4096)),
+        }
+    }
+
+    /// The maximum number of instructions kept
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterate over the entries, oldest first
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &HistoryEntry> + ExactSizeIterator {
+        self.entries.iter()
+    }
+
+    /// The most recently executed instruction
+    pub fn last(&self) -> Option<&HistoryEntry> {
+        self.entries.back()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Start recording an instruction, dropping the oldest entry if the history is full
+    pub(crate) fn begin(&mut self, instruction: Instruction, registers: Registers, cycles: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back(HistoryEntry { instruction, registers, cycles, writes: Vec::new() });
+    }
+
+    /// Record a write made by the instruction being executed
+    pub(crate) fn record_write(&mut self, write: MemoryWrite) {
+        if let Some(entry) = self.entries.back_mut() {
+            entry.writes.push(write);
+        }
+    }
+
+    /// Remove the most recently executed instruction, to undo it
+    pub(crate) fn pop(&mut self) -> Option<HistoryEntry> {
+        self.entries.pop_back()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+    use crate::cpu::Registers;
+    use crate::disassembler::Instruction;
+    use crate::memory::{BasicMemory, Memory};
+    use crate::ops::*;
+    use super::{History, MemoryWrite};
+
+    #[test]
+    fn bounded() {
+        let memory = BasicMemory::default();
+        let mut history = History::new(2);
+        for cycles in 0..3 {
+            history.begin(Instruction::decode(&memory, 0), Registers::default(), cycles);
+        }
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.iter().map(|e| e.cycles).collect::<alloc::vec::Vec<_>>(), [1, 2]);
+        assert_eq!(history.pop().map(|e| e.cycles), Some(2));
+        assert_eq!(history.last().map(|e| e.cycles), Some(1));
+    }
+
+    #[test]
+    fn display() {
+        let mut memory = BasicMemory::default();
+        memory.write(0x0200, STA_ABSOLUTE);
+        memory.write(0x0201, 0x00);
+        memory.write(0x0202, 0x03);
+
+        let mut history = History::new(1);
+        let registers = Registers { accumulator: 0x42, stack_pointer: 0xFD, program_counter: 0x0200, ..Registers::default() };
+        history.begin(Instruction::decode(&memory, 0x0200), registers, 7);
+        history.record_write(MemoryWrite { address: 0x0300, old: 0x00, new: 0x42, replaced: None });
+
+        assert_eq!(
+            history.last().unwrap().to_string(),
+            "0200  STA $0300         A:42 X:00 Y:00 P:20 SP:FD CYC:7  $0300: 00 -> 42",
+        );
+    }
+}
\ No newline at end of file
@@ -1,8 +1,52 @@
 #![no_std]
 
+extern crate alloc;
+
+mod callgraph;
+pub use callgraph::*;
+mod console;
+pub use console::*;
+mod coverage;
+pub use coverage::*;
 mod cpu;
 pub use cpu::*;
+mod dbgfile;
+pub use dbgfile::*;
+mod debug_info;
+pub use debug_info::*;
+mod device;
+pub use device::*;
+mod disassembler;
+pub use disassembler::*;
+mod elf;
+pub use elf::*;
+mod hexfile;
+pub use hexfile::*;
+mod history;
+pub use history::*;
 mod memory;
 pub use memory::*;
 mod ops;
-pub use ops::*;
\ No newline at end of file
+pub use ops::*;
+mod prg;
+pub use prg::*;
+mod profiler;
+pub use profiler::*;
+mod provenance;
+pub use provenance::*;
+mod sim;
+pub use sim::*;
+mod sim65;
+pub use sim65::*;
+mod symbols;
+pub use symbols::*;
+mod timing;
+pub use timing::*;
+mod trace;
+pub use trace::*;
+mod tracediff;
+pub use tracediff::*;
+mod trap;
+pub use trap::*;
+mod verify;
+pub use verify::*;
\ No newline at end of file
@@ -1,12 +1,58 @@
+use core::fmt;
 #[cfg(test)]
 use log::debug;
 
 pub const MAX_MEMORY: usize = 1024 * 64;
 
+/// The words at the top of memory the CPU reads the addresses of its interrupt handlers and start from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vector {
+    Nmi,
+    Reset,
+    Irq,
+}
+
+impl Vector {
+    /// The address of the low byte of the vector
+    pub fn address(self) -> u16 {
+        match self {
+            Self::Nmi => 0xFFFA,
+            Self::Reset => 0xFFFC,
+            Self::Irq => 0xFFFE,
+        }
+    }
+}
+
+/// Why a raw binary couldn't be loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// The header to skip is longer than the binary
+    HeaderTooLong { header: usize, length: usize },
+    /// The binary runs past the end of memory
+    TooLong { address: u16, length: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HeaderTooLong { header, length } => write!(f, "the {} byte header is longer than the {} byte binary", header, length),
+            Self::TooLong { address, length } => {
+                write!(f, "{} bytes at ${:04X} don't fit in memory, only {} bytes are left", length, address, MAX_MEMORY - *address as usize)
+            },
+        }
+    }
+}
+
 pub trait Memory<const N: usize> {
     fn reset(&mut self);
     fn write(&mut self, address: u16, value: u8);
     fn read(&self, address: u16) -> u8;
+
+    /// Read a byte without the side effects a read by the CPU may have, like a device taking input.
+    /// Use it to inspect memory, e.g. to disassemble, trace or show it
+    fn peek(&self, address: u16) -> u8 {
+        self.read(address)
+    }
 }
 
 pub struct BasicMemory {
@@ -22,6 +68,39 @@ impl Default for BasicMemory {
     }
 }
 
+impl BasicMemory {
+    /// Memory filled with a repeating `pattern`, e.g. `[0xEA]` for NOPs. An empty pattern fills with zeros
+    pub fn filled(pattern: &[u8]) -> Self {
+        let mut memory = Self::default();
+        if !pattern.is_empty() {
+            for (byte, value) in memory.data.iter_mut().zip(pattern.iter().cycle()) {
+                *byte = *value;
+            }
+        }
+        memory
+    }
+
+    /// Copy a raw binary of any length to `address`, leaving out a header of `skip` bytes.
+    /// Returns the number of bytes copied
+    pub fn load(&mut self, address: u16, bytes: &[u8], skip: usize) -> Result<usize, LoadError> {
+        let data = bytes.get(skip..).ok_or(LoadError::HeaderTooLong { header: skip, length: bytes.len() })?;
+        let start = address as usize;
+        if start + data.len() > MAX_MEMORY {
+            return Err(LoadError::TooLong { address, length: data.len() });
+        }
+
+        self.data[start..start + data.len()].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    /// Point a `vector` at `target`
+    pub fn write_vector(&mut self, vector: Vector, target: u16) {
+        let [low, high] = target.to_le_bytes();
+        self.write(vector.address(), low);
+        self.write(vector.address() + 1, high);
+    }
+}
+
 impl From<&[u8]> for BasicMemory {
     fn from(i: &[u8]) -> Self {
         Self { data: i.try_into().expect("Invalid length") }
@@ -61,4 +140,27 @@ impl Memory<MAX_MEMORY> for BasicMemory {
         }
         self.data[address as usize]
     }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+    use super::*;
+
+    #[test]
+    fn raw_binaries() {
+        let mut memory = BasicMemory::filled(&[0x00, 0xFF]);
+        assert_eq!((memory.read(0x1000), memory.read(0x1001)), (0x00, 0xFF));
+
+        assert_eq!(memory.load(0xC000, &[0x4E, 0x45, 0x53, 0xA9, 0x01], 3), Ok(2));
+        assert_eq!(memory.read(0xC000), 0xA9);
+        assert_eq!(memory.read(0xC002), 0x00);
+        assert_eq!(memory.load(0xFFFE, &[1, 2], 0), Ok(2));
+        assert_eq!(memory.load(0xFFFF, &[1, 2], 0), Err(LoadError::TooLong { address: 0xFFFF, length: 2 }));
+        assert_eq!(memory.load(0, &[1, 2], 3).unwrap_err().to_string(), "the 3 byte header is longer than the 2 byte binary");
+
+        memory.write_vector(Vector::Reset, 0xC000);
+        assert_eq!((memory.read(0xFFFC), memory.read(0xFFFD)), (0x00, 0xC0));
+        assert_eq!(BasicMemory::filled(&[]).read(0x1234), 0);
+    }
 }
\ No newline at end of file
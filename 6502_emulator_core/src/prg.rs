@@ -0,0 +1,208 @@
+use alloc::boxed::Box;
+use core::fmt;
+use core::ops::RangeInclusive;
+use crate::cpu::Cpu;
+use crate::memory::{LoadError, MAX_MEMORY, Memory};
+use crate::ops::{JMP_ABSOLUTE, JSR_ABSOLUTE};
+use crate::trap::TrapReturn;
+
+/// The token BASIC V2 stores for the `SYS` keyword
+const SYS_TOKEN: u8 = 0x9E;
+/// The KERNAL routine printing the character in the accumulator
+pub const CHROUT: u16 = 0xFFD2;
+/// BASIC's `READY.` prompt, which a program started with `SYS` returns to
+pub const BASIC_READY: u16 = 0xA474;
+
+/// A Commodore program file: a little-endian load address followed by the data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Prg<'a> {
+    pub load_address: u16,
+    pub data: &'a [u8],
+}
+
+impl<'a> Prg<'a> {
+    /// Split the load address off the contents of a PRG file
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, LoadError> {
+        match bytes {
+            [low, high, data @ ..] => Ok(Self { load_address: u16::from_le_bytes([*low, *high]), data }),
+            _ => Err(LoadError::HeaderTooLong { header: 2, length: bytes.len() }),
+        }
+    }
+
+    /// The address given to the first `SYS` in the BASIC stub at the start of the program, like `10 SYS 2061`
+    pub fn sys_address(&self) -> Option<u16> {
+        let mut offset = 0;
+        loop {
+            // Each line is the address of the next line, the line number, then tokens up to a zero byte
+            let next = u16::from_le_bytes([*self.data.get(offset)?, *self.data.get(offset + 1)?]);
+            if next == 0 {
+                return None;
+            }
+            let line = self.data.get(offset + 4..)?;
+            let line = &line[..line.iter().position(|b| *b == 0)?];
+
+            if let Some(sys) = line.iter().position(|b| *b == SYS_TOKEN) {
+                let start = sys + 1 + line[sys + 1..].iter().take_while(|b| **b == b' ' || **b == b'(').count();
+                let length = line[start..].iter().take_while(|b| b.is_ascii_digit()).count();
+                return core::str::from_utf8(&line[start..start + length]).ok()?.parse().ok();
+            }
+
+            let next_offset = next.checked_sub(self.load_address)? as usize;
+            if next_offset <= offset {
+                return None;
+            }
+            offset = next_offset;
+        }
+    }
+}
+
+/// The program overlaps the stub [install_sys] writes in place of BASIC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SysStubOverlap {
+    pub stub: RangeInclusive<u16>,
+    /// The part of the program which overlaps it
+    pub program: RangeInclusive<u16>,
+}
+
+impl fmt::Display for SysStubOverlap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the program at ${:04X}-${:04X} overlaps ${:04X}-${:04X}, where BASIC's SYS is emulated",
+            self.program.start(), self.program.end(), self.stub.start(), self.stub.end(),
+        )
+    }
+}
+
+/// Prepare `memory` to start `address` the way BASIC's `SYS` does, as a subroutine returning to [BASIC_READY].
+/// There, a `JMP *` stands in for the prompt, so a run halts once the program returns.
+/// Returns the address to start executing at, which holds the `JSR` right before [BASIC_READY],
+/// or an error if that would overwrite any of the `program` ranges
+pub fn install_sys(memory: &mut dyn Memory<MAX_MEMORY>, address: u16, program: &[RangeInclusive<u16>]) -> Result<u16, SysStubOverlap> {
+    let start = BASIC_READY - 3;
+    let stub = start..=BASIC_READY + 2;
+    if let Some(range) = program.iter().find(|range| range.start() <= stub.end() && stub.start() <= range.end()) {
+        return Err(SysStubOverlap { stub, program: range.clone() });
+    }
+
+    let [low, high] = address.to_le_bytes();
+    let [ready_low, ready_high] = BASIC_READY.to_le_bytes();
+    for (offset, byte) in [JSR_ABSOLUTE, low, high, JMP_ABSOLUTE, ready_low, ready_high].iter().enumerate() {
+        memory.write(start + offset as u16, *byte);
+    }
+    Ok(start)
+}
+
+/// Converts PETSCII to text, following the switches between the uppercase and lowercase character sets.
+/// Cursor movement, colours and graphics characters are dropped
+#[derive(Debug, Clone, Default)]
+pub struct Petscii {
+    lowercase: bool,
+}
+
+impl Petscii {
+    /// The character for a PETSCII code, if it has one
+    pub fn decode(&mut self, code: u8) -> Option<char> {
+        match code {
+            0x0D | 0x8D => Some('\n'),
+            0x0E => {
+                self.lowercase = true;
+                None
+            },
+            0x8E => {
+                self.lowercase = false;
+                None
+            },
+            0x41..=0x5A if self.lowercase => Some(code.to_ascii_lowercase() as char),
+            0x5C => Some('£'),
+            0x5E => Some('↑'),
+            0x5F => Some('←'),
+            0x20..=0x5D => Some(code as char),
+            0x61..=0x7A => Some((code - 0x20) as char),
+            0xC1..=0xDA => Some((code - 0x80) as char),
+            0xA0 => Some(' '),
+            _ => None,
+        }
+    }
+}
+
+/// Traps calls to KERNAL routines in a machine without a KERNAL ROM. Only `CHROUT` is supported
+#[derive(Debug, Clone, Default)]
+pub struct KernalShim {
+    petscii: Petscii,
+}
+
+impl KernalShim {
+    /// Set a trap on each supported routine of `cpu`, returning like `RTS` once it has run.
+    /// The characters printed with `CHROUT` are passed to `output`
+    pub fn install(mut self, cpu: &mut Cpu, mut output: impl FnMut(char) + Send + 'static) {
+        cpu.set_trap(CHROUT, Box::new(move |cpu, _| {
+            if let Some(c) = self.petscii.decode(cpu.registers().accumulator) {
+                output(c);
+            }
+            TrapReturn::Rts
+        }));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use alloc::string::{String, ToString};
+    use alloc::sync::Arc;
+    use std::sync::Mutex;
+    use crate::memory::BasicMemory;
+    use crate::ops::{LDA_IMMEDIATE, LDX_IMMEDIATE, RTS_IMPLIED};
+    use super::*;
+
+    /// `10 SYS2061` followed by the machine code at 2061, like most C64 assemblers write it
+    const PROGRAM: [u8; 14] = [0x01, 0x08, 0x0B, 0x08, 0x0A, 0x00, SYS_TOKEN, b'2', b'0', b'6', b'1', 0x00, 0x00, 0x00];
+
+    #[test]
+    fn basic_stub() {
+        let prg = Prg::parse(&PROGRAM).unwrap();
+        assert_eq!(prg.load_address, 0x0801);
+        assert_eq!(prg.data.len(), 12);
+        assert_eq!(prg.sys_address(), Some(2061));
+
+        // A REM line before the SYS line, with a space and parentheses
+        let data = [0x07, 0x08, 0x0A, 0x00, 0x8F, 0x00, 0x15, 0x08, 0x14, 0x00, SYS_TOKEN, b' ', b'(', b'4', b'9', b'1', b'5', b'2', b')', 0x00, 0x00, 0x00];
+        assert_eq!(Prg { load_address: 0x0801, data: &data }.sys_address(), Some(49152));
+        assert_eq!(Prg { load_address: 0x0801, data: &data[..12] }.sys_address(), None);
+        assert_eq!(Prg { load_address: 0xC000, data: &[0xA9, 0x01, 0x60] }.sys_address(), None);
+        assert_eq!(Prg::parse(&[0x01]), Err(LoadError::HeaderTooLong { header: 2, length: 1 }));
+    }
+
+    #[test]
+    fn petscii() {
+        let mut petscii = Petscii::default();
+        let text: String = b"HELLO, \x0eHELLO \xc3\x36\x34!\x0d\x8eOK\x93".iter().filter_map(|c| petscii.decode(*c)).collect();
+        assert_eq!(text, "HELLO, hello C64!\nOK");
+    }
+
+    #[test]
+    fn sys_with_chrout() {
+        let mut memory = BasicMemory::default();
+        let program = [LDA_IMMEDIATE, 0x48, JSR_ABSOLUTE, 0xD2, 0xFF, LDX_IMMEDIATE, 0x01, RTS_IMPLIED];
+        for (offset, byte) in program.iter().enumerate() {
+            memory.write(0xC000 + offset as u16, *byte);
+        }
+        let mut cpu = Cpu::default();
+        let output = Arc::new(Mutex::new(String::new()));
+        let printed = output.clone();
+        KernalShim::default().install(&mut cpu, move |c| printed.lock().unwrap().push(c));
+
+        cpu.set_program_counter(install_sys(&mut memory, 0xC000, &[0xC000..=0xC007]).unwrap());
+        while cpu.program_counter() != BASIC_READY {
+            cpu.step(&mut memory);
+        }
+        assert_eq!(output.lock().unwrap().as_str(), "H");
+        assert_eq!(cpu.registers().x, 0x01);
+
+        // A program loaded over BASIC can't be started this way
+        let overlap = install_sys(&mut memory, 0xA000, &[0x0801..=0x080C, 0xA000..=0xA471]).unwrap_err();
+        assert_eq!(overlap, SysStubOverlap { stub: 0xA471..=0xA476, program: 0xA000..=0xA471 });
+        assert_eq!(overlap.to_string(), "the program at $A000-$A471 overlaps $A471-$A476, where BASIC's SYS is emulated");
+    }
+}
\ No newline at end of file
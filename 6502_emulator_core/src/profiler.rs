@@ -0,0 +1,167 @@
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::memory::MAX_MEMORY;
+use crate::ops::{BRK_IMPLIED, JSR_ABSOLUTE};
+
+/// Executions counted for a single address or routine
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProfileCount {
+    /// Instructions executed
+    pub instructions: u64,
+    /// Cycles spent in those instructions
+    pub cycles: u64,
+}
+
+impl ProfileCount {
+    fn add(&mut self, other: &ProfileCount) {
+        self.instructions += other.instructions;
+        self.cycles += other.cycles;
+    }
+
+    fn subtract(&mut self, other: &ProfileCount) {
+        self.instructions = self.instructions.saturating_sub(other.instructions);
+        self.cycles = self.cycles.saturating_sub(other.cycles);
+    }
+}
+
+/// Counts for all instructions from `start` up to the next routine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoutineProfile {
+    pub start: u16,
+    pub count: ProfileCount,
+}
+
+/// Counts the instructions executed and cycles spent at every address.
+/// Enable it with [crate::Cpu::enable_profiler]
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    counts: Vec<ProfileCount>,
+    /// Addresses at which routines start: `JSR` targets, interrupt handlers and the first executed instruction
+    routine_starts: BTreeSet<u16>,
+    total: ProfileCount,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self {
+            counts: vec![ProfileCount::default(); MAX_MEMORY],
+            routine_starts: BTreeSet::new(),
+            total: ProfileCount::default(),
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The counts for the instruction at `address`
+    pub fn count(&self, address: u16) -> ProfileCount {
+        self.counts[address as usize]
+    }
+
+    /// The counts over all addresses
+    pub fn total(&self) -> ProfileCount {
+        self.total
+    }
+
+    /// All addresses at which an instruction was executed, with their counts, in address order
+    pub fn addresses(&self) -> impl Iterator<Item = (u16, ProfileCount)> + '_ {
+        self.counts.iter()
+            .enumerate()
+            .filter(|(_, count)| count.instructions > 0)
+            .map(|(address, count)| (address as u16, *count))
+    }
+
+    /// The addresses at which routines were found to start
+    pub fn routine_starts(&self) -> &BTreeSet<u16> {
+        &self.routine_starts
+    }
+
+    /// Aggregate the counts per routine, in address order. An instruction belongs to the closest routine starting at or before it.
+    /// Routines start at the given `starts`, e.g. from a symbol table, or at the starts found during execution if it is empty
+    pub fn routines(&self, starts: &BTreeSet<u16>) -> Vec<RoutineProfile> {
+        let starts = if starts.is_empty() { &self.routine_starts } else { starts };
+
+        let mut routines: Vec<RoutineProfile> = Vec::new();
+        for (address, count) in self.addresses() {
+            let start = match starts.range(..=address).next_back() {
+                Some(start) => *start,
+                // Code before the first routine is attributed to its own address
+                None => address,
+            };
+
+            match routines.last_mut() {
+                Some(routine) if routine.start == start => routine.count.add(&count),
+                _ => routines.push(RoutineProfile { start, count }),
+            }
+        }
+
+        routines
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Count an instruction which started at `address` and continued execution at `next`
+    pub(crate) fn record(&mut self, address: u16, opcode: u8, cycles: u32, next: u16) {
+        if self.total.instructions == 0 {
+            self.routine_starts.insert(address);
+        }
+        if matches!(opcode, JSR_ABSOLUTE | BRK_IMPLIED) {
+            self.routine_starts.insert(next);
+        }
+
+        let count = ProfileCount { instructions: 1, cycles: cycles as u64 };
+        self.counts[address as usize].add(&count);
+        self.total.add(&count);
+    }
+
+    /// Remove the count of an instruction at `address` taking `cycles`, after it was undone.
+    /// The routine it may have started is kept
+    pub(crate) fn forget(&mut self, address: u16, cycles: u32) {
+        let count = ProfileCount { instructions: 1, cycles: cycles as u64 };
+        self.counts[address as usize].subtract(&count);
+        self.total.subtract(&count);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::collections::BTreeSet;
+    use crate::ops::*;
+    use super::{ProfileCount, Profiler, RoutineProfile};
+
+    #[test]
+    fn routines() {
+        let mut profiler = Profiler::new();
+        profiler.record(0x0200, JSR_ABSOLUTE, 6, 0x0300);
+        profiler.record(0x0300, INX_IMPLIED, 2, 0x0301);
+        profiler.record(0x0301, RTS_IMPLIED, 6, 0x0203);
+        profiler.record(0x0203, NOP_IMPLIED, 2, 0x0204);
+
+        assert_eq!(profiler.total(), ProfileCount { instructions: 4, cycles: 16 });
+        assert_eq!(profiler.count(0x0300), ProfileCount { instructions: 1, cycles: 2 });
+        assert_eq!(profiler.addresses().count(), 4);
+        assert_eq!(profiler.routine_starts().iter().copied().collect::<alloc::vec::Vec<_>>(), [0x0200, 0x0300]);
+
+        assert_eq!(profiler.routines(&BTreeSet::new()), [
+            RoutineProfile { start: 0x0200, count: ProfileCount { instructions: 2, cycles: 8 } },
+            RoutineProfile { start: 0x0300, count: ProfileCount { instructions: 2, cycles: 8 } },
+        ]);
+
+        let symbols = [0x0201, 0x0301].into_iter().collect();
+        assert_eq!(profiler.routines(&symbols), [
+            RoutineProfile { start: 0x0200, count: ProfileCount { instructions: 1, cycles: 6 } },
+            RoutineProfile { start: 0x0201, count: ProfileCount { instructions: 2, cycles: 4 } },
+            RoutineProfile { start: 0x0301, count: ProfileCount { instructions: 1, cycles: 6 } },
+        ]);
+
+        profiler.forget(0x0203, 2);
+        assert_eq!(profiler.total(), ProfileCount { instructions: 3, cycles: 14 });
+        assert_eq!(profiler.count(0x0203), ProfileCount::default());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,140 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::memory::MAX_MEMORY;
+
+/// A byte written by an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteRecord {
+    /// The address of the instruction which made the write
+    pub program_counter: u16,
+    /// The cycle count at which the instruction started
+    pub cycles: u64,
+    /// The index of the instruction, as counted by [crate::Cpu::instructions]
+    pub instruction: u64,
+    /// The value written
+    pub value: u8,
+}
+
+impl fmt::Display for WriteRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "${:02X} written by ${:04X} at cycle {} (instruction {})",
+            self.value, self.program_counter, self.cycles, self.instruction,
+        )
+    }
+}
+
+/// A shadow map recording which instruction last wrote every address, and optionally the writes before.
+/// Enable it with [crate::Cpu::enable_provenance]
+#[derive(Debug, Clone)]
+pub struct Provenance {
+    /// How many writes are kept per address
+    depth: usize,
+    last: Vec<Option<WriteRecord>>,
+    /// Writes before the last one, newest first. Only used if more than one write is kept per address
+    earlier: BTreeMap<u16, VecDeque<WriteRecord>>,
+}
+
+impl Provenance {
+    /// Create a map keeping the last `depth` writes to every address. A depth of 0 is treated as 1
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth: depth.max(1),
+            last: vec![None; MAX_MEMORY],
+            earlier: BTreeMap::new(),
+        }
+    }
+
+    /// How many writes are kept per address
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The last write to `address`, or `None` if it was never written
+    pub fn last_write(&self, address: u16) -> Option<&WriteRecord> {
+        self.last[address as usize].as_ref()
+    }
+
+    /// The writes to `address` which are kept, newest first
+    pub fn writes(&self, address: u16) -> impl Iterator<Item = &WriteRecord> {
+        self.last_write(address)
+            .into_iter()
+            .chain(self.earlier.get(&address).into_iter().flatten())
+    }
+
+    pub fn clear(&mut self) {
+        self.last.iter_mut().for_each(|r| *r = None);
+        self.earlier.clear();
+    }
+
+    /// Record a write to `address`, returning the last write it replaces
+    pub(crate) fn record(&mut self, address: u16, record: WriteRecord) -> Option<WriteRecord> {
+        let previous = self.last[address as usize].replace(record);
+
+        if let (Some(previous), true) = (previous, self.depth > 1) {
+            let earlier = self.earlier.entry(address).or_default();
+            earlier.push_front(previous);
+            earlier.truncate(self.depth - 1);
+        }
+        previous
+    }
+
+    /// Drop the last write to `address` if it was made by instruction `instruction` or later, after it was undone,
+    /// making `replaced`, the write it replaced, the last one again
+    pub(crate) fn forget_since(&mut self, address: u16, instruction: u64, replaced: Option<WriteRecord>) {
+        if self.last_write(address).is_some_and(|r| r.instruction >= instruction) {
+            self.last[address as usize] = replaced;
+            if let Some(earlier) = self.earlier.get_mut(&address) {
+                earlier.pop_front();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+    use super::{Provenance, WriteRecord};
+
+    fn record(instruction: u64, value: u8) -> WriteRecord {
+        WriteRecord { program_counter: 0x0200, cycles: instruction * 2, instruction, value }
+    }
+
+    #[test]
+    fn keeps_last_writes() {
+        let mut provenance = Provenance::new(2);
+        assert!(provenance.last_write(0x10).is_none());
+
+        for instruction in 0..3 {
+            provenance.record(0x10, record(instruction, instruction as u8));
+        }
+
+        assert_eq!(provenance.last_write(0x10), Some(&record(2, 2)));
+        assert_eq!(provenance.writes(0x10).map(|r| r.instruction).collect::<Vec<_>>(), [2, 1]);
+
+        provenance.forget_since(0x10, 2, Some(record(1, 1)));
+        assert_eq!(provenance.writes(0x10).map(|r| r.instruction).collect::<Vec<_>>(), [1]);
+        provenance.forget_since(0x10, 1, Some(record(0, 0)));
+        assert_eq!(provenance.writes(0x10).map(|r| r.instruction).collect::<Vec<_>>(), [0]);
+        provenance.forget_since(0x10, 0, None);
+        assert!(provenance.last_write(0x10).is_none());
+    }
+
+    #[test]
+    fn single_write() {
+        let mut provenance = Provenance::new(1);
+        assert_eq!(provenance.record(0x10, record(0, 1)), None);
+        let replaced = provenance.record(0x10, record(1, 2));
+        assert_eq!(provenance.writes(0x10).count(), 1);
+        assert_eq!(provenance.last_write(0x10).unwrap().value, 2);
+
+        // Undoing the last write restores the one it replaced
+        provenance.forget_since(0x10, 1, replaced);
+        assert_eq!(provenance.last_write(0x10), Some(&record(0, 1)));
+        provenance.forget_since(0x10, 1, None);
+        assert_eq!(provenance.last_write(0x10), Some(&record(0, 1)));
+    }
+}
\ No newline at end of file
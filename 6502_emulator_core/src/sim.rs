@@ -0,0 +1,148 @@
+use alloc::boxed::Box;
+use crate::device::{Device, Exit};
+
+/// The address the registers of the llvm-mos `mos-sim` simulator are mapped at
+pub const SIM_REGISTERS: u16 = 0xFFF0;
+
+/// Register offsets, from `sim-io.h` of the llvm-mos SDK
+const CLOCK: u16 = 0x0;
+const CLOCK_SIZE: u16 = 4;
+const ABORT: u16 = 0x7;
+const EXIT: u16 = 0x8;
+const PUTCHAR: u16 = 0x9;
+
+/// The registers of the llvm-mos `mos-sim` target, which programs built with `mos-sim-clang` expect at [SIM_REGISTERS]:
+/// a 32-bit cycle counter, abort, exit with a status and character output.
+/// Reading the low byte of the counter latches it, so the following reads of the upper bytes match
+pub struct SimDevice {
+    output: Box<dyn FnMut(u8)>,
+    cycles: u64,
+    latched: u32,
+    exit: Option<Exit>,
+}
+
+impl SimDevice {
+    /// A device passing every character the program writes to `output`
+    pub fn new(output: impl FnMut(u8) + 'static) -> Self {
+        Self { output: Box::new(output), cycles: 0, latched: 0, exit: None }
+    }
+}
+
+impl Device for SimDevice {
+    fn size(&self) -> u16 {
+        PUTCHAR + 1
+    }
+
+    fn read(&mut self, offset: u16) -> u8 {
+        if offset == CLOCK {
+            self.latched = self.cycles as u32;
+        }
+        self.peek(offset)
+    }
+
+    /// The low byte of the counter reads the current cycles without latching them, the upper bytes the latched ones
+    fn peek(&self, offset: u16) -> u8 {
+        match offset {
+            CLOCK => self.cycles as u8,
+            _ if offset < CLOCK + CLOCK_SIZE => (self.latched >> (8 * (offset - CLOCK))) as u8,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset {
+            ABORT => self.exit = Some(Exit::Abort),
+            EXIT => self.exit = Some(Exit::Status(value)),
+            PUTCHAR => (self.output)(value),
+            _ => {},
+        }
+    }
+
+    fn clock(&mut self, cycles: u64) {
+        self.cycles = cycles;
+    }
+
+    fn exit(&self) -> Option<Exit> {
+        self.exit
+    }
+
+    fn reset(&mut self) {
+        self.exit = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::rc::Rc;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+    use crate::cpu::Cpu;
+    use crate::device::Bus;
+    use crate::memory::{BasicMemory, Memory};
+    use crate::trace::trace_line;
+    use crate::ops::{JMP_ABSOLUTE, LDA_ABSOLUTE, LDA_IMMEDIATE, STA_ABSOLUTE};
+    use super::*;
+
+    #[test]
+    fn registers() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let sink = output.clone();
+        let mut memory = BasicMemory::default();
+
+        // Print "Hi", read the clock, then exit with status 7
+        let program = [
+            LDA_IMMEDIATE, b'H', STA_ABSOLUTE, 0xF9, 0xFF,
+            LDA_IMMEDIATE, b'i', STA_ABSOLUTE, 0xF9, 0xFF,
+            LDA_ABSOLUTE, 0xF0, 0xFF,
+            LDA_IMMEDIATE, 7, STA_ABSOLUTE, 0xF8, 0xFF,
+            JMP_ABSOLUTE, 0x12, 0x02,
+        ];
+        memory.load(0x0200, &program, 0).unwrap();
+        let mut bus = Bus::new(memory);
+        bus.map(SIM_REGISTERS, SimDevice::new(move |c| sink.borrow_mut().push(c)));
+
+        let mut cpu = Cpu::default();
+        cpu.set_program_counter(0x0200);
+        while bus.exit().is_none() {
+            bus.clock(cpu.cycles());
+            cpu.step(&mut bus);
+            if cpu.program_counter() == 0x020D {
+                assert_eq!(cpu.registers().accumulator, 12);
+            }
+        }
+        assert_eq!(*output.borrow(), b"Hi");
+        assert_eq!(bus.exit(), Some(Exit::Status(7)));
+        assert_eq!(cpu.program_counter(), 0x0212);
+    }
+
+    #[test]
+    fn clock_latch() {
+        let mut sim = SimDevice::new(|_| {});
+        sim.clock(0x0102_0304_0506);
+        assert_eq!(sim.read(CLOCK + 1), 0x00);
+        assert_eq!(sim.peek(CLOCK), 0x06);
+        assert_eq!(sim.peek(CLOCK + 1), 0x00);
+        assert_eq!(sim.read(CLOCK), 0x06);
+        sim.clock(0xFFFF_FFFF);
+        assert_eq!([sim.read(CLOCK + 1), sim.read(CLOCK + 2), sim.read(CLOCK + 3)], [0x05, 0x04, 0x03]);
+
+        sim.write(ABORT, 0);
+        assert_eq!(sim.exit(), Some(Exit::Abort));
+        sim.reset();
+        assert_eq!(sim.exit(), None);
+    }
+
+    #[test]
+    fn tracing_doesnt_latch() {
+        let mut memory = BasicMemory::default();
+        memory.load(0x0200, &[LDA_ABSOLUTE, 0xF0, 0xFF], 0).unwrap();
+        let mut bus = Bus::new(memory);
+        bus.map(SIM_REGISTERS, SimDevice::new(|_| {}));
+        let mut cpu = Cpu::default();
+        cpu.set_program_counter(0x0200);
+
+        bus.clock(0x1234);
+        assert!(trace_line(&cpu, &bus).contains("LDA $FFF0 = 34"));
+        assert_eq!(bus.read(SIM_REGISTERS + 1), 0x00);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,394 @@
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::cpu::Cpu;
+use crate::device::Exit;
+use crate::memory::{Memory, MAX_MEMORY};
+use crate::trap::TrapReturn;
+
+/// The magic bytes at the start of a binary built with `cl65 -t sim6502`
+pub const SIM65_MAGIC: &[u8] = b"sim65";
+/// The address of the first host service a sim65 program calls. The services follow in the order of [Sim65Call]
+pub const SIM65_PARAVIRT: u16 = 0xFFF4;
+const HEADER_VERSION: u8 = 2;
+const CPU_6502: u8 = 0;
+
+/// A host service of the sim65 paravirtualization ABI, called with `JSR` at its address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sim65Call {
+    Open,
+    Close,
+    Read,
+    Write,
+    Args,
+    Exit,
+}
+
+impl Sim65Call {
+    const ALL: [Self; 6] = [Self::Open, Self::Close, Self::Read, Self::Write, Self::Args, Self::Exit];
+
+    /// The call made by jumping to `address`, if any
+    pub fn at(address: u16) -> Option<Self> {
+        Self::ALL.get(address.wrapping_sub(SIM65_PARAVIRT) as usize).copied()
+    }
+
+    pub fn address(self) -> u16 {
+        SIM65_PARAVIRT + self as u16
+    }
+}
+
+/// Why a sim65 header couldn't be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sim65Error {
+    /// The binary doesn't start with [SIM65_MAGIC]
+    BadMagic,
+    /// The binary ends inside the header
+    Truncated(usize),
+    UnsupportedVersion(u8),
+    /// The binary was built for a CPU other than the 6502, like the 65C02
+    UnsupportedCpu(u8),
+}
+
+impl fmt::Display for Sim65Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a sim65 binary"),
+            Self::Truncated(length) => write!(f, "the {} byte binary ends inside the {} byte sim65 header", length, Sim65Header::SIZE),
+            Self::UnsupportedVersion(version) => write!(f, "sim65 header version {} isn't supported, only version {}", version, HEADER_VERSION),
+            Self::UnsupportedCpu(cpu) => write!(f, "the binary is built for CPU type {}, only the 6502 (0) is supported", cpu),
+        }
+    }
+}
+
+/// The header of a binary built with `cl65 -t sim6502`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sim65Header {
+    /// The zero page address of the C parameter stack pointer
+    pub stack_pointer: u8,
+    /// Where the code after the header is loaded
+    pub load_address: u16,
+    /// Where execution starts
+    pub reset_address: u16,
+}
+
+impl Sim65Header {
+    /// The size of the header, and so the offset of the code in the binary
+    pub const SIZE: usize = 12;
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, Sim65Error> {
+        if !bytes.starts_with(SIM65_MAGIC) {
+            return Err(Sim65Error::BadMagic);
+        }
+        if bytes.len() < Self::SIZE {
+            return Err(Sim65Error::Truncated(bytes.len()));
+        }
+        if bytes[5] != HEADER_VERSION {
+            return Err(Sim65Error::UnsupportedVersion(bytes[5]));
+        }
+        if bytes[6] != CPU_6502 {
+            return Err(Sim65Error::UnsupportedCpu(bytes[6]));
+        }
+        Ok(Self {
+            stack_pointer: bytes[7],
+            load_address: u16::from_le_bytes([bytes[8], bytes[9]]),
+            reset_address: u16::from_le_bytes([bytes[10], bytes[11]]),
+        })
+    }
+}
+
+/// How a program asked to open a file, decoded from the flags cc65's `open` takes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenMode {
+    pub read: bool,
+    pub write: bool,
+    pub create: bool,
+    pub truncate: bool,
+    pub append: bool,
+    /// Fail if the file already exists
+    pub exclusive: bool,
+}
+
+impl OpenMode {
+    fn from_flags(flags: u16) -> Self {
+        Self {
+            read: flags & 0x01 != 0,
+            write: flags & 0x02 != 0,
+            create: flags & 0x10 != 0,
+            truncate: flags & 0x20 != 0,
+            append: flags & 0x40 != 0,
+            exclusive: flags & 0x80 != 0,
+        }
+    }
+}
+
+/// The file access behind the sim65 host services. File descriptors 0, 1 and 2 are stdin, stdout and stderr.
+/// The traps of all services share the host, so it's called through `&self` and must be `Sync`
+pub trait Sim65Host: Send + Sync {
+    /// Open the file at `path`, returning its file descriptor
+    fn open(&self, path: &str, mode: OpenMode) -> Option<u16>;
+    /// Close a file descriptor, returning whether it was open
+    fn close(&self, fd: u16) -> bool;
+    /// Read up to `buffer.len()` bytes, returning how many were read
+    fn read(&self, fd: u16, buffer: &mut [u8]) -> Option<usize>;
+    /// Write `data`, returning how many bytes were written
+    fn write(&self, fd: u16, data: &[u8]) -> Option<usize>;
+}
+
+/// Runs the host services of a sim65 program, the way `sim65` does
+pub struct Sim65Paravirt {
+    stack_pointer: u8,
+    args: Vec<String>,
+    host: Box<dyn Sim65Host>,
+}
+
+impl Sim65Paravirt {
+    /// Serve the program with `header` from `host`, passing it `args` including the program name
+    pub fn new(header: &Sim65Header, args: &[String], host: impl Sim65Host + 'static) -> Self {
+        Self { stack_pointer: header.stack_pointer, args: args.to_owned(), host: Box::new(host) }
+    }
+
+    /// Set a trap on each host service of `cpu`. A call returns like `RTS` once the service has run,
+    /// except `exit`, which ends the run with [Exit::Status]
+    pub fn install(self, cpu: &mut Cpu) {
+        let paravirt = Arc::new(self);
+        for call in Sim65Call::ALL {
+            let paravirt = paravirt.clone();
+            cpu.set_trap(call.address(), Box::new(move |cpu, memory| paravirt.call(call, cpu, memory)));
+        }
+    }
+
+    /// Run a host service, taking its arguments from the CPU and the C parameter stack
+    fn call(&self, call: Sim65Call, cpu: &mut Cpu, memory: &mut dyn Memory<MAX_MEMORY>) -> TrapReturn {
+        let mut registers = cpu.registers();
+        let ax = u16::from_le_bytes([registers.accumulator, registers.x]);
+        let result = match call {
+            Sim65Call::Open => {
+                // `open` is variadic, Y holds the size of its arguments. The mode is ignored like sim65 does
+                let _mode = self.pop(memory, registers.y.wrapping_sub(4) as u16);
+                let flags = self.pop(memory, 2);
+                let name = self.pop(memory, 2);
+                let path = read_string(memory, name);
+                self.host.open(&String::from_utf8_lossy(&path), OpenMode::from_flags(flags))
+            },
+            Sim65Call::Close => self.host.close(ax).then_some(0),
+            Sim65Call::Read => {
+                let buffer = self.pop(memory, 2);
+                let fd = self.pop(memory, 2);
+                let mut data = vec![0; ax as usize];
+                let count = self.host.read(fd, &mut data);
+                for (offset, byte) in data.iter().take(count.unwrap_or(0)).enumerate() {
+                    memory.write(buffer.wrapping_add(offset as u16), *byte);
+                }
+                count.map(|count| count as u16)
+            },
+            Sim65Call::Write => {
+                let buffer = self.pop(memory, 2);
+                let fd = self.pop(memory, 2);
+                let data: Vec<u8> = (0..ax).map(|offset| memory.peek(buffer.wrapping_add(offset))).collect();
+                self.host.write(fd, &data).map(|count| count as u16)
+            },
+            Sim65Call::Args => Some(self.write_args(memory, ax)),
+            Sim65Call::Exit => return TrapReturn::Exit(Exit::Status(registers.accumulator)),
+        };
+
+        let [low, high] = result.unwrap_or(0xFFFF).to_le_bytes();
+        registers.accumulator = low;
+        registers.x = high;
+        cpu.set_registers(&registers);
+        TrapReturn::Rts
+    }
+
+    /// Pop a word off the C parameter stack, moving the stack pointer by `increment` bytes
+    fn pop(&self, memory: &mut dyn Memory<MAX_MEMORY>, increment: u16) -> u16 {
+        let stack = read_word(memory, self.stack_pointer as u16);
+        let value = read_word(memory, stack);
+        write_word(memory, self.stack_pointer as u16, stack.wrapping_add(increment));
+        value
+    }
+
+    /// Copy the arguments below the C parameter stack, store the address of their `argv` array at `argv`
+    /// and return `argc`
+    fn write_args(&self, memory: &mut dyn Memory<MAX_MEMORY>, argv: u16) -> u16 {
+        let count = self.args.len() as u16;
+        let mut array = read_word(memory, self.stack_pointer as u16).wrapping_sub((count + 1) * 2);
+        write_word(memory, argv, array);
+
+        let mut stack = array;
+        for arg in &self.args {
+            stack = stack.wrapping_sub(arg.len() as u16 + 1);
+            for (offset, byte) in arg.bytes().chain([0]).enumerate() {
+                memory.write(stack.wrapping_add(offset as u16), byte);
+            }
+            write_word(memory, array, stack);
+            array = array.wrapping_add(2);
+        }
+        write_word(memory, array, 0);
+        write_word(memory, self.stack_pointer as u16, stack);
+        count
+    }
+}
+
+fn read_word(memory: &dyn Memory<MAX_MEMORY>, address: u16) -> u16 {
+    u16::from_le_bytes([memory.peek(address), memory.peek(address.wrapping_add(1))])
+}
+
+fn write_word(memory: &mut dyn Memory<MAX_MEMORY>, address: u16, value: u16) {
+    let [low, high] = value.to_le_bytes();
+    memory.write(address, low);
+    memory.write(address.wrapping_add(1), high);
+}
+
+/// Read a NUL terminated string of at most 1024 bytes, like sim65 does
+fn read_string(memory: &dyn Memory<MAX_MEMORY>, address: u16) -> Vec<u8> {
+    (0..1024u16).map(|offset| memory.peek(address.wrapping_add(offset))).take_while(|byte| *byte != 0).collect()
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use alloc::string::ToString;
+    use std::sync::Mutex;
+    use crate::memory::BasicMemory;
+    use crate::ops::{JSR_ABSOLUTE, LDA_IMMEDIATE, LDX_IMMEDIATE, LDY_IMMEDIATE};
+    use super::*;
+
+    /// Records what the program wrote, and hands out file descriptor 3 for `input.txt` only
+    #[derive(Default, Clone)]
+    struct TestHost {
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Sim65Host for TestHost {
+        fn open(&self, path: &str, mode: OpenMode) -> Option<u16> {
+            (path == "input.txt" && mode == OpenMode { read: true, ..Default::default() }).then_some(3)
+        }
+
+        fn close(&self, fd: u16) -> bool {
+            fd == 3
+        }
+
+        fn read(&self, fd: u16, buffer: &mut [u8]) -> Option<usize> {
+            (fd == 3).then(|| {
+                buffer[..2].copy_from_slice(b"OK");
+                2
+            })
+        }
+
+        fn write(&self, fd: u16, data: &[u8]) -> Option<usize> {
+            (fd == 1).then(|| self.output.lock().unwrap().extend_from_slice(data)).map(|_| data.len())
+        }
+    }
+
+    const HEADER: Sim65Header = Sim65Header { stack_pointer: 0x02, load_address: 0x0200, reset_address: 0x0200 };
+
+    fn setup(program: &[u8]) -> (Cpu, BasicMemory, TestHost) {
+        let mut memory = BasicMemory::default();
+        memory.load(0x0200, program, 0).unwrap();
+        write_word(&mut memory, 0x02, 0xC000);
+        let host = TestHost::default();
+        let mut cpu = Cpu::default();
+        Sim65Paravirt::new(&HEADER, &["test".to_string(), "-v".to_string()], host.clone()).install(&mut cpu);
+        cpu.set_program_counter(0x0200);
+        (cpu, memory, host)
+    }
+
+    /// Run until the program calls `exit`
+    fn run(cpu: &mut Cpu, memory: &mut BasicMemory) -> u8 {
+        loop {
+            cpu.step(memory);
+            if let Some(Exit::Status(status)) = cpu.exit() {
+                return status;
+            }
+        }
+    }
+
+    #[test]
+    fn header() {
+        let mut bytes = *b"sim65\x02\x00\x80\x00\x02\x10\x02";
+        assert_eq!(Sim65Header::parse(&bytes), Ok(Sim65Header { stack_pointer: 0x80, load_address: 0x0200, reset_address: 0x0210 }));
+        assert_eq!(Sim65Header::parse(&bytes[..8]), Err(Sim65Error::Truncated(8)));
+        assert_eq!(Sim65Header::parse(b"sim66"), Err(Sim65Error::BadMagic));
+        bytes[6] = 1;
+        assert_eq!(Sim65Header::parse(&bytes), Err(Sim65Error::UnsupportedCpu(1)));
+        bytes[5] = 1;
+        assert_eq!(Sim65Header::parse(&bytes), Err(Sim65Error::UnsupportedVersion(1)));
+        assert_eq!(Sim65Call::at(0xFFF7), Some(Sim65Call::Write));
+        assert_eq!(Sim65Call::at(0xFFFA), None);
+    }
+
+    #[test]
+    fn write_and_exit() {
+        // write(1, $0300, 2), then exit(7)
+        let (mut cpu, mut memory, host) = setup(&[
+            LDA_IMMEDIATE, 0x02, LDX_IMMEDIATE, 0x00, JSR_ABSOLUTE, 0xF7, 0xFF,
+            LDA_IMMEDIATE, 0x07, JSR_ABSOLUTE, 0xF9, 0xFF,
+        ]);
+        write_word(&mut memory, 0x02, 0xBFFC);
+        write_word(&mut memory, 0xBFFC, 0x0300);
+        write_word(&mut memory, 0xBFFE, 1);
+        memory.write(0x0300, b'H');
+        memory.write(0x0301, b'i');
+
+        assert_eq!(run(&mut cpu, &mut memory), 7);
+        assert_eq!(host.output.lock().unwrap().as_slice(), b"Hi");
+        assert_eq!(read_word(&memory, 0x02), 0xC000);
+        assert_eq!(cpu.program_counter(), 0xFFF9);
+    }
+
+    #[test]
+    fn open_and_read() {
+        // open("input.txt", O_RDONLY) with no mode, then read(fd, $0300, 16) and exit
+        let (mut cpu, mut memory, _) = setup(&[
+            LDY_IMMEDIATE, 0x04, JSR_ABSOLUTE, 0xF4, 0xFF, JSR_ABSOLUTE, 0xF9, 0xFF,
+        ]);
+        write_word(&mut memory, 0x02, 0xBFFC);
+        write_word(&mut memory, 0xBFFC, 0x0001);
+        write_word(&mut memory, 0xBFFE, 0x0300);
+        for (offset, byte) in b"input.txt\0".iter().enumerate() {
+            memory.write(0x0300 + offset as u16, *byte);
+        }
+        run(&mut cpu, &mut memory);
+        assert_eq!((cpu.registers().accumulator, cpu.registers().x), (3, 0));
+        assert_eq!(read_word(&memory, 0x02), 0xC000);
+
+        cpu.set_program_counter(Sim65Call::Read.address());
+        write_word(&mut memory, 0x02, 0xBFFC);
+        write_word(&mut memory, 0xBFFC, 0x0400);
+        write_word(&mut memory, 0xBFFE, 3);
+        let mut registers = cpu.registers();
+        registers.accumulator = 16;
+        cpu.set_registers(&registers);
+        // The call returns to after the `JSR` to `exit`, which is still on the stack
+        cpu.step(&mut memory);
+        assert_eq!(cpu.program_counter(), 0x0208);
+        assert_eq!((cpu.registers().accumulator, memory.read(0x0400), memory.read(0x0401)), (2, b'O', b'K'));
+
+        // Closing a descriptor that isn't open returns -1
+        cpu.set_program_counter(Sim65Call::Close.address());
+        cpu.step(&mut memory);
+        assert_eq!((cpu.registers().accumulator, cpu.registers().x), (0xFF, 0xFF));
+    }
+
+    #[test]
+    fn args() {
+        let (mut cpu, mut memory, _) = setup(&[]);
+        cpu.set_program_counter(Sim65Call::Args.address());
+        let mut registers = cpu.registers();
+        registers.accumulator = 0x10;
+        cpu.set_registers(&registers);
+        cpu.step(&mut memory);
+
+        assert_eq!(cpu.registers().accumulator, 2);
+        let argv = read_word(&memory, 0x0010);
+        assert_eq!(argv, 0xC000 - 6);
+        assert_eq!(read_string(&memory, read_word(&memory, argv)), b"test");
+        assert_eq!(read_string(&memory, read_word(&memory, argv + 2)), b"-v");
+        assert_eq!(read_word(&memory, argv + 4), 0);
+        assert_eq!(read_word(&memory, 0x02), read_word(&memory, argv + 2));
+    }
+}
\ No newline at end of file
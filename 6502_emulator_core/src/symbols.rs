@@ -0,0 +1,476 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// A named address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u16,
+    /// The ROM bank the symbol lives in, for code in a bank switched window. `None` if the address is not banked
+    pub bank: Option<u16>,
+}
+
+impl fmt::Display for Symbol {
+    /// The name, qualified with the bank if there is one, like `02:reset`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{}", bank, self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// The label file formats [SymbolTable::load] understands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolFormat {
+    /// VICE monitor labels, `al C:1234 .name`. ld65 writes this format with `-Ln`
+    Vice,
+    /// The symbol tables at the end of a vasm listing, written with `-L`
+    Vasm,
+    /// FCEUX name lists, `$1234#name#comment`. The bank is part of the file name, like `game.nes.2.nl`
+    Fceux,
+    /// Mesen label files, `P:1234:name:comment`. PRG ROM offsets are mapped to CPU addresses with the [MesenLayout]
+    Mesen(MesenLayout),
+}
+
+/// How the PRG ROM offsets of Mesen labels map to CPU addresses: the ROM is split in banks of `bank_size` bytes,
+/// and the bank holding a label is switched in at `base`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MesenLayout {
+    pub bank_size: u32,
+    pub base: u16,
+}
+
+impl Default for MesenLayout {
+    /// 16 KiB banks at `$8000`, like the switchable bank of UxROM and MMC1 boards
+    fn default() -> Self {
+        Self { bank_size: 0x4000, base: 0x8000 }
+    }
+}
+
+impl SymbolFormat {
+    /// Guess the format of a label file from its name, or else from its contents
+    pub fn detect(file_name: &str, text: &str) -> Option<Self> {
+        let lower = file_name.to_ascii_lowercase();
+        if lower.ends_with(".nl") {
+            return Some(Self::Fceux);
+        }
+        if lower.ends_with(".mlb") {
+            return Some(Self::Mesen(MesenLayout::default()));
+        }
+        if lower.ends_with(".lbl") {
+            return Some(Self::Vice);
+        }
+        if text.lines().any(|l| l.starts_with("Symbols by name:") || l.starts_with("Symbols by value:")) {
+            return Some(Self::Vasm);
+        }
+
+        let first = text.lines().map(str::trim).find(|l| !l.is_empty())?;
+        if first.starts_with("al ") {
+            Some(Self::Vice)
+        } else if first.starts_with('$') && first.contains('#') {
+            Some(Self::Fceux)
+        } else if parse_mesen(first, MesenLayout::default()).is_ok() {
+            Some(Self::Mesen(MesenLayout::default()))
+        } else {
+            None
+        }
+    }
+}
+
+/// A malformed line in a label file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolError {
+    /// The line number, starting at 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Names for addresses, loaded from the label files of assemblers and other emulators.
+/// Used by the disassembler and debuggers for lookups in both directions.
+/// The same name may be defined in several banks, and several names may share an address
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    /// Indices into `symbols`, in the order they were added
+    by_address: BTreeMap<u16, Vec<usize>>,
+    by_name: BTreeMap<String, Vec<usize>>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// All symbols, in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    /// Add a symbol. Returns `false` if the exact same symbol was already present
+    pub fn insert(&mut self, symbol: Symbol) -> bool {
+        if self.symbols_at(symbol.address).any(|s| *s == symbol) {
+            return false;
+        }
+
+        let index = self.symbols.len();
+        self.by_address.entry(symbol.address).or_default().push(index);
+        self.by_name.entry(symbol.name.clone()).or_default().push(index);
+        self.symbols.push(symbol);
+        true
+    }
+
+    /// All symbols at `address`, in any bank
+    pub fn symbols_at(&self, address: u16) -> impl Iterator<Item = &Symbol> {
+        self.by_address.get(&address).into_iter().flatten().map(|i| &self.symbols[*i])
+    }
+
+    /// The symbol naming `address` in `bank`. Symbols without a bank match any bank.
+    /// Without a bank, a symbol without a bank is preferred, but one from any bank is returned otherwise
+    pub fn symbol_at(&self, address: u16, bank: Option<u16>) -> Option<&Symbol> {
+        let mut symbols = self.symbols_at(address);
+        match bank {
+            Some(bank) => symbols.find(|s| s.bank == Some(bank))
+                .or_else(|| self.symbols_at(address).find(|s| s.bank.is_none())),
+            None => symbols.find(|s| s.bank.is_none())
+                .or_else(|| self.symbols_at(address).next()),
+        }
+    }
+
+    /// The closest symbol at or before `address` and the distance to it, for names like `print+3`
+    pub fn nearest(&self, address: u16) -> Option<(&Symbol, u16)> {
+        let (start, _) = self.by_address.range(..=address).next_back()?;
+        self.symbol_at(*start, None).map(|s| (s, address - start))
+    }
+
+    /// All definitions of `name`, in any bank
+    pub fn find(&self, name: &str) -> impl Iterator<Item = &Symbol> {
+        self.by_name.get(name).into_iter().flatten().map(|i| &self.symbols[*i])
+    }
+
+    /// The address of a symbol, given as `name` or qualified with a hexadecimal bank as `bank:name`.
+    /// An unqualified name defined in several banks resolves to the first definition
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        if let Some((bank, unqualified)) = split_bank(name) {
+            if let Some(symbol) = self.find(unqualified).find(|s| s.bank == Some(bank)) {
+                return Some(symbol.address);
+            }
+        }
+
+        self.find(name).next().map(|s| s.address)
+    }
+
+    /// Load the symbols of a label file in `format`, placing them in `bank` unless the format specifies banks itself.
+    /// Returns the number of symbols added
+    pub fn load(&mut self, format: SymbolFormat, text: &str, bank: Option<u16>) -> Result<usize, SymbolError> {
+        let mut added = 0;
+        let mut vasm_section = VasmSection::None;
+
+        for (index, line) in text.lines().enumerate() {
+            let parsed = match format {
+                SymbolFormat::Vice => parse_vice(line),
+                SymbolFormat::Vasm => parse_vasm(line, &mut vasm_section),
+                SymbolFormat::Fceux => parse_fceux(line),
+                SymbolFormat::Mesen(layout) => parse_mesen(line, layout),
+            };
+
+            match parsed {
+                Ok(Some(mut symbol)) => {
+                    symbol.bank = symbol.bank.or(bank);
+                    if self.insert(symbol) {
+                        added += 1;
+                    }
+                },
+                Ok(None) => {},
+                Err(message) => return Err(SymbolError { line: index + 1, message }),
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// Load a label file, detecting its format with [SymbolFormat::detect].
+    /// The bank of an FCEUX name list is taken from its file name, and Mesen labels are mapped with the default [MesenLayout]
+    pub fn load_file(&mut self, file_name: &str, text: &str) -> Result<usize, SymbolError> {
+        let format = SymbolFormat::detect(file_name, text).ok_or_else(|| SymbolError {
+            line: 1,
+            message: "not a VICE, ld65, vasm, FCEUX or Mesen label file".to_string(),
+        })?;
+
+        let bank = match format {
+            SymbolFormat::Fceux => fceux_bank(file_name),
+            _ => None,
+        };
+        self.load(format, text, bank)
+    }
+}
+
+impl Extend<Symbol> for SymbolTable {
+    fn extend<T: IntoIterator<Item = Symbol>>(&mut self, symbols: T) {
+        for symbol in symbols {
+            self.insert(symbol);
+        }
+    }
+}
+
+/// Split a bank qualified name like `02:reset`. Scoped names like `main::loop` are not split
+fn split_bank(name: &str) -> Option<(u16, &str)> {
+    let (bank, unqualified) = name.split_once(':')?;
+    if unqualified.is_empty() || unqualified.starts_with(':') {
+        return None;
+    }
+
+    u16::from_str_radix(bank, 16).ok().map(|bank| (bank, unqualified))
+}
+
+/// Parse a hexadecimal address, which some formats pad to more than four digits
+fn parse_hex_address(s: &str) -> Result<u16, String> {
+    let value = u32::from_str_radix(s, 16).map_err(|_| format!("'{}' is not a hexadecimal address", s))?;
+    u16::try_from(value).map_err(|_| format!("${:X} is not an address between $0000 and $FFFF", value))
+}
+
+fn symbol(name: &str, address: u16, bank: Option<u16>) -> Option<Symbol> {
+    Some(Symbol { name: name.to_string(), address, bank })
+}
+
+/// `al C:1234 .name`, where the memory space and the dot are optional. Other monitor commands are skipped
+fn parse_vice(line: &str) -> Result<Option<Symbol>, String> {
+    let mut words = line.split_whitespace();
+    if words.next() != Some("al") {
+        return Ok(None);
+    }
+
+    let (address, name) = match (words.next(), words.next()) {
+        (Some(address), Some(name)) => (address, name),
+        _ => return Err("expected 'al <address> .<name>'".to_string()),
+    };
+    let address = address.rsplit(':').next().unwrap_or(address);
+    Ok(symbol(name.strip_prefix('.').unwrap_or(name), parse_hex_address(address)?, None))
+}
+
+/// Which table of a vasm listing is being read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VasmSection {
+    None,
+    /// `name   A:1234` lines
+    ByName,
+    /// `1234 name` lines
+    ByValue,
+}
+
+/// The symbol tables at the end of a vasm listing. Values which don't fit in 16 bits are constants and are skipped
+fn parse_vasm(line: &str, section: &mut VasmSection) -> Result<Option<Symbol>, String> {
+    if line.starts_with("Symbols by name:") {
+        *section = VasmSection::ByName;
+        return Ok(None);
+    }
+    if line.starts_with("Symbols by value:") {
+        *section = VasmSection::ByValue;
+        return Ok(None);
+    }
+    if line.trim().is_empty() {
+        *section = VasmSection::None;
+        return Ok(None);
+    }
+
+    let mut words = line.split_whitespace();
+    let (name, value) = match (*section, words.next(), words.next()) {
+        (VasmSection::None, _, _) => return Ok(None),
+        (VasmSection::ByName, Some(name), Some(value)) => (name, value.rsplit(':').next().unwrap_or(value)),
+        (VasmSection::ByValue, Some(value), Some(name)) => (name, value),
+        _ => return Err("expected a symbol and its value".to_string()),
+    };
+
+    let value = u32::from_str_radix(value, 16).map_err(|_| format!("'{}' is not a hexadecimal value", value))?;
+    Ok(u16::try_from(value).ok().and_then(|address| symbol(name, address, None)))
+}
+
+/// `$1234#name#comment`, or `$1234/10#name#` for arrays. Lines without a name only carry a comment
+fn parse_fceux(line: &str) -> Result<Option<Symbol>, String> {
+    let line = match line.trim().strip_prefix('$') {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let mut fields = line.split('#');
+    let address = fields.next().unwrap_or_default();
+    let address = address.split('/').next().unwrap_or(address);
+    let address = parse_hex_address(address)?;
+
+    match fields.next().map(str::trim) {
+        Some(name) if !name.is_empty() => Ok(symbol(name, address, None)),
+        _ => Ok(None),
+    }
+}
+
+/// The bank of an FCEUX name list, from file names like `game.nes.2.nl`. RAM name lists (`game.nes.ram.nl`) have no bank
+fn fceux_bank(file_name: &str) -> Option<u16> {
+    let stem = file_name.strip_suffix(".nl").or_else(|| file_name.strip_suffix(".NL"))?;
+    let (_, bank) = stem.rsplit_once('.')?;
+    u16::from_str_radix(bank, 16).ok()
+}
+
+/// `P:1234:name:comment` or `R:0010-001F:name`, also with the memory type names of Mesen 2 like `NesPrgRom`.
+/// Labels in memory types without a fixed CPU address, like CHR ROM, are skipped.
+/// PRG ROM labels which `layout` maps outside of the address space are errors
+fn parse_mesen(line: &str, layout: MesenLayout) -> Result<Option<Symbol>, String> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    let mut fields = line.splitn(4, ':');
+    let (kind, offset, name) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(kind), Some(offset), Some(name)) => (kind, offset, name.trim()),
+        _ => return Err("expected '<type>:<address>:<name>'".to_string()),
+    };
+    let offset = offset.split('-').next().unwrap_or(offset);
+    let offset = u32::from_str_radix(offset, 16).map_err(|_| format!("'{}' is not a hexadecimal address", offset))?;
+    if name.is_empty() {
+        return Ok(None);
+    }
+
+    let (address, bank) = match kind {
+        "P" | "NesPrgRom" => {
+            if layout.bank_size == 0 {
+                return Err("the PRG ROM bank size is 0".to_string());
+            }
+            (layout.base as u32 + offset % layout.bank_size, Some(offset / layout.bank_size))
+        },
+        "S" | "W" | "NesSaveRam" | "NesWorkRam" => (0x6000 + offset, None),
+        "R" | "G" | "NesInternalRam" | "NesMemory" => (offset, None),
+        _ => return Ok(None),
+    };
+
+    let address = u16::try_from(address).map_err(|_| format!("${:X} is outside of the address space", address))?;
+    let bank = bank.map(|b| u16::try_from(b).map_err(|_| format!("bank {:X} is out of range", b))).transpose()?;
+    Ok(symbol(name, address, bank))
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+    use super::*;
+
+    #[test]
+    fn vice_and_ld65() {
+        let mut symbols = SymbolTable::new();
+        let text = "al C:0200 .start\nal 00C000 .reset\nbreak 0200\nal 0300 print\n";
+        assert_eq!(symbols.load_file("game.lbl", text), Ok(3));
+        assert_eq!(symbols.address_of("start"), Some(0x0200));
+        assert_eq!(symbols.address_of("reset"), Some(0xC000));
+        assert_eq!(symbols.symbol_at(0x0300, None).unwrap().name, "print");
+
+        let error = symbols.load(SymbolFormat::Vice, "al C:0200 .a\nal 10000 .b", None).unwrap_err();
+        assert_eq!(error.line, 2);
+    }
+
+    #[test]
+    fn vasm_listing() {
+        let text = "\
+Sections:
+00: \"seg200\" (200-206)
+
+Source: \"test.s\"
+                        \t     1: loop: inx
+
+Symbols by name:
+loop                             A:0200
+size                             E:00012345
+
+Symbols by value:
+0200 loop
+";
+        assert_eq!(SymbolFormat::detect("test.lst", text), Some(SymbolFormat::Vasm));
+
+        let mut symbols = SymbolTable::new();
+        assert_eq!(symbols.load_file("test.lst", text), Ok(1));
+        assert_eq!(symbols.address_of("loop"), Some(0x0200));
+        assert_eq!(symbols.address_of("size"), None);
+    }
+
+    #[test]
+    fn fceux_name_lists() {
+        let mut symbols = SymbolTable::new();
+        let text = "$C000#Reset#Entry point\n$0300/10#buffer#\n$C010##just a comment\n";
+        assert_eq!(symbols.load_file("game.nes.2.nl", text), Ok(2));
+        assert_eq!(symbols.load_file("game.nes.ram.nl", "$0010#counter#\n"), Ok(1));
+
+        let reset = symbols.find("Reset").next().unwrap();
+        assert_eq!((reset.address, reset.bank), (0xC000, Some(2)));
+        assert_eq!(reset.to_string(), "02:Reset");
+        assert_eq!(symbols.symbol_at(0x0010, Some(2)).unwrap().name, "counter");
+    }
+
+    #[test]
+    fn mesen_labels() {
+        let text = "P:4010:Reset:Entry point\nR:0010-001F:buffer\nP:0000::comment only\nC:0000:tiles\nNesMemory:2000:PPUCTRL\n";
+        assert_eq!(SymbolFormat::detect("game", text), Some(SymbolFormat::Mesen(MesenLayout::default())));
+
+        let mut symbols = SymbolTable::new();
+        assert_eq!(symbols.load_file("game.mlb", text), Ok(3));
+        let reset = symbols.find("Reset").next().unwrap();
+        assert_eq!((reset.address, reset.bank), (0x8010, Some(1)));
+        assert_eq!(symbols.address_of("buffer"), Some(0x0010));
+        assert_eq!(symbols.address_of("PPUCTRL"), Some(0x2000));
+
+        // 8 KiB banks at $A000, like the second switchable bank of MMC3 boards
+        let mut symbols = SymbolTable::new();
+        let layout = MesenLayout { bank_size: 0x2000, base: 0xA000 };
+        assert_eq!(symbols.load(SymbolFormat::Mesen(layout), text, None), Ok(3));
+        let reset = symbols.find("Reset").next().unwrap();
+        assert_eq!((reset.address, reset.bank), (0xA010, Some(2)));
+
+        // A 32 KiB bank doesn't fit at $C000
+        let layout = MesenLayout { bank_size: 0x8000, base: 0xC000 };
+        let error = SymbolTable::new().load(SymbolFormat::Mesen(layout), text, None).unwrap_err();
+        assert_eq!(error, SymbolError { line: 1, message: "$10010 is outside of the address space".to_string() });
+    }
+
+    #[test]
+    fn banked_lookups() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(Symbol { name: "handler".to_string(), address: 0x8000, bank: Some(0) });
+        symbols.insert(Symbol { name: "handler".to_string(), address: 0x8100, bank: Some(1) });
+        symbols.insert(Symbol { name: "other".to_string(), address: 0x8000, bank: Some(1) });
+        assert!(!symbols.insert(Symbol { name: "other".to_string(), address: 0x8000, bank: Some(1) }));
+
+        assert_eq!(symbols.address_of("handler"), Some(0x8000));
+        assert_eq!(symbols.address_of("01:handler"), Some(0x8100));
+        assert_eq!(symbols.address_of("02:handler"), None);
+        assert_eq!(symbols.symbol_at(0x8000, Some(1)).unwrap().name, "other");
+        assert_eq!(symbols.symbol_at(0x8000, None).unwrap().name, "handler");
+        assert_eq!(symbols.symbols_at(0x8000).count(), 2);
+
+        let (nearest, offset) = symbols.nearest(0x8105).unwrap();
+        assert_eq!((nearest.name.as_str(), offset), ("handler", 5));
+        assert!(symbols.nearest(0x7FFF).is_none());
+        assert_eq!(symbols.iter().map(|s| s.address).collect::<Vec<_>>(), [0x8000, 0x8100, 0x8000]);
+    }
+
+    #[test]
+    fn scoped_names() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(Symbol { name: "add::loop".to_string(), address: 0x0200, bank: None });
+        assert_eq!(symbols.address_of("add::loop"), Some(0x0200));
+        assert!(SymbolFormat::detect("unknown.txt", "hello").is_none());
+    }
+}
\ No newline at end of file
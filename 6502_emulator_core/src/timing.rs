@@ -0,0 +1,237 @@
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::cpu::{CallError, Cpu};
+use crate::disassembler::{AddressingMode, Instruction};
+use crate::memory::{Memory, MAX_MEMORY};
+use crate::verify::{next_inputs, write_values, Journal, Location, Routine};
+
+/// Why an instruction didn't always take the same amount of cycles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VarianceCause {
+    /// A branch takes a cycle more when it's taken, and another one when it's taken to another page
+    Branch,
+    /// An indexed read takes a cycle more when the index carries into the next page
+    PageCrossing,
+}
+
+impl fmt::Display for VarianceCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Branch => write!(f, "branching"),
+            Self::PageCrossing => write!(f, "page crossing"),
+        }
+    }
+}
+
+/// An instruction which took a varying amount of cycles
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VariableInstruction {
+    pub instruction: Instruction,
+    pub cause: VarianceCause,
+    /// How many times the instruction took each amount of cycles, over all cases
+    pub cycles: BTreeMap<u32, u64>,
+}
+
+/// The cycles a routine took over a set of inputs, as measured by [analyze_timing]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimingReport {
+    pub inputs: Vec<Location>,
+    /// The amount of input combinations run
+    pub cases: u64,
+    /// How many cases took each amount of cycles, including the `RTS`
+    pub histogram: BTreeMap<u64, u64>,
+    /// The inputs of the first case taking the fewest cycles
+    pub fastest: Vec<u8>,
+    /// The inputs of the first case taking the most cycles
+    pub slowest: Vec<u8>,
+    /// The instructions causing the variance, by address
+    pub variable: Vec<VariableInstruction>,
+}
+
+impl TimingReport {
+    /// The fewest cycles a case took
+    pub fn min(&self) -> u64 {
+        self.histogram.keys().next().copied().unwrap_or_default()
+    }
+
+    /// The most cycles a case took
+    pub fn max(&self) -> u64 {
+        self.histogram.keys().next_back().copied().unwrap_or_default()
+    }
+
+    /// Whether every case took the same amount of cycles
+    pub fn is_constant(&self) -> bool {
+        self.histogram.len() <= 1
+    }
+
+    /// The amount of cases taking more than `budget` cycles
+    pub fn over_budget(&self, budget: u64) -> u64 {
+        self.histogram.range(budget + 1..).map(|(_, cases)| cases).sum()
+    }
+}
+
+impl fmt::Display for TimingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_constant() {
+            return write!(f, "{} cases all took {} cycles", self.cases, self.min());
+        }
+        write!(f, "{} cases took {} to {} cycles", self.cases, self.min(), self.max())?;
+        for (cycles, cases) in &self.histogram {
+            write!(f, "\n  {} cycles: {} cases", cycles, cases)?;
+        }
+        write!(f, "\nfastest with ")?;
+        write_values(f, &self.inputs, &self.fastest)?;
+        write!(f, ", slowest with ")?;
+        write_values(f, &self.inputs, &self.slowest)?;
+        for variable in &self.variable {
+            write!(f, "\n${:04X} {} varies by {}:", variable.instruction.address, variable.instruction, variable.cause)?;
+            for (index, (cycles, count)) in variable.cycles.iter().enumerate() {
+                write!(f, "{} {} cycles {} times", if index == 0 { "" } else { "," }, cycles, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Why [analyze_timing] couldn't time a routine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimingError {
+    /// The values of [Routine::inputs] the routine didn't return for
+    pub inputs: Vec<u8>,
+    pub error: CallError,
+}
+
+impl fmt::Display for TimingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, with input values {:02X?}", self.error, self.inputs)
+    }
+}
+
+/// Call `routine` with each of `cases`, holding values for its inputs, or with every combination of values if `None`.
+/// Counts the cycles of every case and of every instruction, finding the instructions whose cycles vary.
+/// Memory is restored after every call. Fails if the routine doesn't return for some input
+pub fn analyze_timing(cpu: &mut Cpu, memory: &mut dyn Memory<MAX_MEMORY>, routine: &Routine, cases: Option<&[Vec<u8>]>) -> Result<TimingReport, TimingError> {
+    let mut report = TimingReport {
+        inputs: routine.inputs.clone(),
+        cases: 0,
+        histogram: BTreeMap::new(),
+        fastest: Vec::new(),
+        slowest: Vec::new(),
+        variable: Vec::new(),
+    };
+    let mut journal = Journal::new(memory);
+    let mut instructions: BTreeMap<u16, BTreeMap<u32, u64>> = BTreeMap::new();
+    let mut run = |inputs: &[u8]| -> Result<(), TimingError> {
+        let mut registers = routine.registers;
+        for (location, value) in routine.inputs.iter().zip(inputs) {
+            location.set(&mut registers, &mut journal, *value);
+        }
+        let result = cpu.call_observed(&mut journal, routine.address, &registers, &routine.limits, &mut |address, cycles| {
+            *instructions.entry(address).or_default().entry(cycles).or_default() += 1;
+        });
+        journal.undo();
+        let result = result.map_err(|error| TimingError { inputs: inputs.to_vec(), error })?;
+
+        if report.cases == 0 || result.cycles < report.min() {
+            report.fastest = inputs.to_vec();
+        }
+        if report.cases == 0 || result.cycles > report.max() {
+            report.slowest = inputs.to_vec();
+        }
+        report.cases += 1;
+        *report.histogram.entry(result.cycles).or_default() += 1;
+        Ok(())
+    };
+
+    match cases {
+        Some(cases) => cases.iter().try_for_each(|inputs| run(inputs))?,
+        None => {
+            let mut inputs = vec![0u8; routine.inputs.len()];
+            loop {
+                run(&inputs)?;
+                if !next_inputs(&routine.inputs, &mut inputs) {
+                    break;
+                }
+            }
+        },
+    }
+
+    report.variable = instructions.into_iter()
+        .filter(|(_, cycles)| cycles.len() > 1)
+        .map(|(address, cycles)| {
+            let instruction = Instruction::decode(memory, address);
+            let cause = match instruction.mode {
+                AddressingMode::Relative => VarianceCause::Branch,
+                _ => VarianceCause::PageCrossing,
+            };
+            VariableInstruction { instruction, cause, cycles }
+        })
+        .collect();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::string::ToString;
+    use crate::cpu::CallLimits;
+    use crate::memory::BasicMemory;
+    use crate::ops::*;
+    use super::*;
+
+    fn setup(program: &[u8]) -> BasicMemory {
+        let mut memory = BasicMemory::default();
+        memory.load(0x0200, program, 0).unwrap();
+        memory
+    }
+
+    #[test]
+    fn constant_time() {
+        // Load from a table indexed by X, which never crosses a page for a table at the start of one
+        let mut memory = setup(&[LDA_ABSOLUTE_X, 0x00, 0x30, RTS_IMPLIED]);
+        let routine = Routine::new(0x0200, &[Location::X], &[]);
+        let report = analyze_timing(&mut Cpu::default(), &mut memory, &routine, None).unwrap();
+        assert!(report.is_constant());
+        assert_eq!(report.cases, 256);
+        assert_eq!(report.to_string(), "256 cases all took 10 cycles");
+    }
+
+    #[test]
+    fn variance() {
+        // Add one to A unless the carry is set, then load from a table crossing a page at $30F0 + X
+        let mut memory = setup(&[BCS_RELATIVE, 0x02, ADC_IMMEDIATE, 0x01, LDA_ABSOLUTE_X, 0xF0, 0x30, RTS_IMPLIED]);
+        let routine = Routine::new(0x0200, &[Location::Carry, Location::X], &[]);
+        let cases = [vec![0, 0x00], vec![1, 0x00], vec![0, 0x10]];
+        let report = analyze_timing(&mut Cpu::default(), &mut memory, &routine, Some(&cases)).unwrap();
+        assert_eq!((report.cases, report.min(), report.max()), (3, 13, 15));
+        assert_eq!((report.fastest.as_slice(), report.slowest.as_slice()), ([1, 0x00].as_slice(), [0, 0x10].as_slice()));
+        assert_eq!(report.over_budget(13), 2);
+        assert_eq!(report.variable.len(), 2);
+        assert_eq!(report.variable[0].cause, VarianceCause::Branch);
+        assert_eq!(report.variable[1].cause, VarianceCause::PageCrossing);
+        assert_eq!(
+            report.to_string(),
+            "3 cases took 13 to 15 cycles\n  13 cycles: 1 cases\n  14 cycles: 1 cases\n  15 cycles: 1 cases\n\
+             fastest with C=1 X=$00, slowest with C=0 X=$10\n\
+             $0200 BCS $0204 varies by branching: 2 cycles 2 times, 3 cycles 1 times\n\
+             $0204 LDA $30F0,X varies by page crossing: 4 cycles 2 times, 5 cycles 1 times",
+        );
+
+        let all = analyze_timing(&mut Cpu::default(), &mut memory, &routine, None).unwrap();
+        assert_eq!(all.cases, 512);
+        assert_eq!(all.histogram.values().sum::<u64>(), 512);
+    }
+
+    #[test]
+    fn runaway() {
+        let mut memory = setup(&[BCS_RELATIVE, 0xFE, RTS_IMPLIED]);
+        let routine = Routine {
+            limits: CallLimits { instructions: 10, ..CallLimits::default() },
+            ..Routine::new(0x0200, &[Location::Carry], &[])
+        };
+        let error = analyze_timing(&mut Cpu::default(), &mut memory, &routine, None).unwrap_err();
+        assert_eq!(error.inputs, [1]);
+        assert_eq!(error.to_string(), "the subroutine didn't return within the instruction limit, it got to $0200, with input values [01]");
+    }
+}
\ No newline at end of file
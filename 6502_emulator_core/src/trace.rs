@@ -0,0 +1,202 @@
+use alloc::format;
+use alloc::string::String;
+use core::ops::RangeInclusive;
+use crate::cpu::Cpu;
+use crate::disassembler::{AddressingMode, Instruction};
+use crate::memory::{MAX_MEMORY, Memory};
+
+/// The unused bit of the status register, which reads as 1 on real hardware
+pub(crate) const UNUSED_FLAG_BIT: u8 = 0b0010_0000;
+
+/// Receives the CPU state before every instruction executed by [Cpu::execute_single].
+/// Install one with [Cpu::set_tracer]. Tracers must be `Send` so the CPU can be moved between threads
+pub trait Tracer: Send {
+    fn trace(&mut self, cpu: &Cpu, memory: &dyn Memory<MAX_MEMORY>);
+}
+
+/// Selects which instructions are traced
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// Only trace instructions located in this range
+    pub address_range: Option<RangeInclusive<u16>>,
+    /// Only trace instructions starting at or after this cycle
+    pub start_cycle: Option<u64>,
+    /// Only trace instructions starting before this cycle
+    pub stop_cycle: Option<u64>,
+}
+
+impl TraceFilter {
+    /// Whether an instruction at `address` starting at `cycle` should be traced
+    pub fn matches(&self, address: u16, cycle: u64) -> bool {
+        self.address_range.as_ref().is_none_or(|r| r.contains(&address))
+            && self.start_cycle.is_none_or(|start| cycle >= start)
+            && self.stop_cycle.is_none_or(|stop| cycle < stop)
+    }
+
+    /// Whether no instruction at or after `cycle` will be traced anymore
+    pub fn is_finished(&self, cycle: u64) -> bool {
+        self.stop_cycle.is_some_and(|stop| cycle >= stop)
+    }
+}
+
+/// Format the instruction about to be executed in the column layout of `nestest.log`:
+/// ```text
+/// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:7
+/// ```
+/// Memory operands are annotated with their effective address and current value, like `STA $0300,X @ 0305 = 00`.
+/// As this emulator has no PPU, the `PPU` column is always `  0,  0`, keeping the columns of the reference log.
+/// The unused bit 5 of `P` is always shown as set, as it is on real hardware.
+pub fn trace_line(cpu: &Cpu, memory: &dyn Memory<MAX_MEMORY>) -> String {
+    let registers = cpu.registers();
+    let instruction = Instruction::decode(memory, registers.program_counter);
+
+    let bytes = instruction.bytes();
+    let hex = match instruction.length() {
+        1 => format!("{:02X}", bytes[0]),
+        2 => format!("{:02X} {:02X}", bytes[0], bytes[1]),
+        _ => format!("{:02X} {:02X} {:02X}", bytes[0], bytes[1], bytes[2]),
+    };
+
+    format!(
+        "{:04X}  {:<9} {:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:  0,  0 CYC:{}",
+        registers.program_counter,
+        hex,
+        annotated(&instruction, cpu, memory),
+        registers.accumulator,
+        registers.x,
+        registers.y,
+        registers.flags.bits() | UNUSED_FLAG_BIT,
+        registers.stack_pointer,
+        cpu.cycles(),
+    )
+}
+
+/// The disassembly with the effective address and value of memory operands, as `nestest.log` shows them
+fn annotated(instruction: &Instruction, cpu: &Cpu, memory: &dyn Memory<MAX_MEMORY>) -> String {
+    let registers = cpu.registers();
+    let operand = instruction.operand;
+    let zero_page_word = |address: u8| u16::from_le_bytes([memory.peek(address as u16), memory.peek(address.wrapping_add(1) as u16)]);
+
+    match instruction.mode {
+        AddressingMode::ZeroPage => format!("{} = {:02X}", instruction, memory.peek(operand)),
+        AddressingMode::Absolute if !matches!(instruction.mnemonic, "JMP" | "JSR") => {
+            format!("{} = {:02X}", instruction, memory.peek(operand))
+        },
+        AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+            let index = if instruction.mode == AddressingMode::ZeroPageX { registers.x } else { registers.y };
+            let address = (operand as u8).wrapping_add(index) as u16;
+            format!("{} @ {:02X} = {:02X}", instruction, address, memory.peek(address))
+        },
+        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+            let index = if instruction.mode == AddressingMode::AbsoluteX { registers.x } else { registers.y };
+            let address = operand.wrapping_add(index as u16);
+            format!("{} @ {:04X} = {:02X}", instruction, address, memory.peek(address))
+        },
+        AddressingMode::Indirect => {
+            let target = u16::from_le_bytes([memory.peek(operand), memory.peek(operand.wrapping_add(1))]);
+            format!("{} = {:04X}", instruction, target)
+        },
+        AddressingMode::IndirectX => {
+            let pointer = (operand as u8).wrapping_add(registers.x);
+            let address = zero_page_word(pointer);
+            format!("{} @ {:02X} = {:04X} = {:02X}", instruction, pointer, address, memory.peek(address))
+        },
+        AddressingMode::IndirectY => {
+            let base = zero_page_word(operand as u8);
+            let address = base.wrapping_add(registers.y as u16);
+            format!("{} = {:04X} @ {:04X} = {:02X}", instruction, base, address, memory.peek(address))
+        },
+        _ => format!("{}", instruction),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu::{Cpu, CpuStatusFlags, Registers};
+    use crate::memory::{BasicMemory, Memory};
+    use crate::ops::*;
+    use super::*;
+
+    fn cpu_at(program_counter: u16) -> Cpu {
+        let mut cpu = Cpu::default();
+        cpu.set_registers(&Registers {
+            stack_pointer: 0xFD,
+            program_counter,
+            flags: CpuStatusFlags::IRQ_DISABLE,
+            ..Registers::default()
+        });
+        cpu
+    }
+
+    #[test]
+    fn nestest_layout() {
+        let mut memory = BasicMemory::default();
+        memory.write(0xC000, JMP_ABSOLUTE);
+        memory.write(0xC001, 0xF5);
+        memory.write(0xC002, 0xC5);
+
+        assert_eq!(
+            trace_line(&cpu_at(0xC000), &memory),
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
+        );
+
+        memory.write(0xC5F5, LDX_IMMEDIATE);
+        memory.write(0xC5F6, 0x00);
+        assert_eq!(
+            trace_line(&cpu_at(0xC5F5), &memory),
+            "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
+        );
+    }
+
+    #[test]
+    fn memory_annotations() {
+        let mut memory = BasicMemory::default();
+        memory.write(0x0300, STA_ABSOLUTE_X);
+        memory.write(0x0301, 0x00);
+        memory.write(0x0302, 0x06);
+        memory.write(0x0610, 0x5A);
+
+        memory.write(0x0303, LDA_INDIRECT_Y);
+        memory.write(0x0304, 0x89);
+        memory.write(0x0089, 0x00);
+        memory.write(0x008A, 0x06);
+
+        memory.write(0x0305, LDA_INDIRECT_X);
+        memory.write(0x0306, 0xFF);
+        memory.write(0x000F, 0x10);
+        memory.write(0x0010, 0x06);
+
+        let mut cpu = cpu_at(0x0300);
+        let mut registers = cpu.registers();
+        registers.x = 0x10;
+        registers.y = 0x10;
+        cpu.set_registers(&registers);
+
+        assert!(trace_line(&cpu, &memory).contains("STA $0600,X @ 0610 = 5A       "));
+
+        registers.program_counter = 0x0303;
+        cpu.set_registers(&registers);
+        assert!(trace_line(&cpu, &memory).contains("LDA ($89),Y = 0600 @ 0610 = 5A  "));
+
+        registers.program_counter = 0x0305;
+        cpu.set_registers(&registers);
+        assert!(trace_line(&cpu, &memory).contains("LDA ($FF,X) @ 0F = 0610 = 5A    "));
+    }
+
+    #[test]
+    fn filter() {
+        let filter = TraceFilter {
+            address_range: Some(0xC000..=0xC0FF),
+            start_cycle: Some(10),
+            stop_cycle: Some(20),
+        };
+
+        assert!(filter.matches(0xC000, 10));
+        assert!(!filter.matches(0xC100, 10));
+        assert!(!filter.matches(0xC000, 9));
+        assert!(!filter.matches(0xC000, 20));
+        assert!(!filter.is_finished(19));
+        assert!(filter.is_finished(20));
+        assert!(TraceFilter::default().matches(0xFFFF, u64::MAX));
+    }
+}
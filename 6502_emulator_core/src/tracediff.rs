@@ -0,0 +1,482 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::cpu::Cpu;
+use crate::memory::{MAX_MEMORY, Memory};
+use crate::trace::{UNUSED_FLAG_BIT, trace_line};
+
+/// Status register bits in the order they are written as letters, e.g. `nvUbdIzc`
+const FLAG_LETTERS: [char; 8] = ['N', 'V', 'U', 'B', 'D', 'I', 'Z', 'C'];
+
+/// A column of a trace line which can be compared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TraceField {
+    ProgramCounter,
+    Accumulator,
+    X,
+    Y,
+    Flags,
+    StackPointer,
+    /// Cycles since the first line of the trace
+    Cycles,
+}
+
+impl TraceField {
+    pub const ALL: [TraceField; 7] = [
+        Self::ProgramCounter,
+        Self::Accumulator,
+        Self::X,
+        Self::Y,
+        Self::Flags,
+        Self::StackPointer,
+        Self::Cycles,
+    ];
+
+    /// The short name used for the field, e.g. `PC` or `SP`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ProgramCounter => "PC",
+            Self::Accumulator => "A",
+            Self::X => "X",
+            Self::Y => "Y",
+            Self::Flags => "P",
+            Self::StackPointer => "SP",
+            Self::Cycles => "CYC",
+        }
+    }
+
+    /// Parse a field name, case insensitive. `S` and `CYCLES` are accepted as well
+    pub fn from_name(name: &str) -> Option<Self> {
+        let field = match name.to_ascii_uppercase().as_str() {
+            "PC" => Self::ProgramCounter,
+            "A" => Self::Accumulator,
+            "X" => Self::X,
+            "Y" => Self::Y,
+            "P" => Self::Flags,
+            "SP" | "S" => Self::StackPointer,
+            "CYC" | "CYCLES" => Self::Cycles,
+            _ => return None,
+        };
+
+        Some(field)
+    }
+}
+
+/// The CPU state at the start of an instruction, as found in a trace line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub program_counter: u16,
+    pub accumulator: u8,
+    pub x: u8,
+    pub y: u8,
+    pub flags: u8,
+    pub stack_pointer: u8,
+    /// Not every trace format contains a cycle count
+    pub cycles: Option<u64>,
+}
+
+impl TraceRecord {
+    /// Capture the state of the CPU
+    pub fn capture(cpu: &Cpu) -> Self {
+        let registers = cpu.registers();
+        Self {
+            program_counter: registers.program_counter,
+            accumulator: registers.accumulator,
+            x: registers.x,
+            y: registers.y,
+            flags: registers.flags.bits(),
+            stack_pointer: registers.stack_pointer,
+            cycles: Some(cpu.cycles()),
+        }
+    }
+
+    /// Parse a trace line in the format of `nestest.log` or a similar format.
+    /// The line must start with the program counter, optionally prefixed with `$` and followed by `:`.
+    /// Registers are found as `A:`, `X:`, `Y:`, `P:` and `SP:` or `S:` tokens, the cycle count as `CYC:`.
+    /// `P` may be hexadecimal or a string of flag letters like `nvUbdIzc`, where uppercase means set.
+    /// Returns `None` for lines which are not instruction lines, like blank lines and comments
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut tokens = line.split_whitespace();
+        let pc = tokens.next()?.trim_start_matches('$').split(':').next()?;
+        if pc.len() != 4 {
+            return None;
+        }
+        let program_counter = u16::from_str_radix(pc, 16).ok()?;
+
+        let (mut accumulator, mut x, mut y, mut flags, mut stack_pointer, mut cycles) = (None, None, None, None, None, None);
+        for token in line.split_whitespace() {
+            let (name, value) = match token.split_once(':') {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let hex = || u8::from_str_radix(value, 16).ok();
+            match name {
+                "A" => accumulator = hex(),
+                "X" => x = hex(),
+                "Y" => y = hex(),
+                "SP" | "S" => stack_pointer = hex(),
+                "P" => flags = hex().or_else(|| parse_flag_letters(value)),
+                "CYC" => cycles = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            program_counter,
+            accumulator: accumulator?,
+            x: x?,
+            y: y?,
+            flags: flags?,
+            stack_pointer: stack_pointer?,
+            cycles,
+        })
+    }
+}
+
+/// Parse flags written as eight letters, e.g. `nvUbdIzc`
+fn parse_flag_letters(value: &str) -> Option<u8> {
+    if value.chars().count() != FLAG_LETTERS.len() {
+        return None;
+    }
+
+    value.chars().zip(FLAG_LETTERS.iter()).enumerate().try_fold(0u8, |acc, (bit, (c, letter))| {
+        if c.to_ascii_uppercase() != *letter && c != '-' && c != '.' {
+            return None;
+        }
+        let set = c.is_ascii_uppercase();
+        Some(acc | (set as u8) << (7 - bit))
+    })
+}
+
+/// Write flags as letters, uppercase if set, e.g. `nvUbdIzc`
+pub fn format_flag_letters(flags: u8) -> String {
+    FLAG_LETTERS.iter().enumerate()
+        .map(|(bit, letter)| if flags & (1 << (7 - bit)) != 0 { *letter } else { letter.to_ascii_lowercase() })
+        .collect()
+}
+
+/// Mask of the status register bit written as `letter`, one of `NV-BDIZC`
+pub fn flag_bit(letter: char) -> Option<u8> {
+    FLAG_LETTERS.iter()
+        .position(|l| *l == letter.to_ascii_uppercase() && *l != 'U')
+        .map(|bit| 1 << (7 - bit))
+}
+
+/// What to compare
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceDiffOptions {
+    /// Fields which are not compared
+    pub ignored_fields: Vec<TraceField>,
+    /// Status register bits which are not compared. The unused bit 5 is never compared
+    pub ignored_flags: u8,
+    /// How many instructions to show before and after the divergence
+    pub context: usize,
+}
+
+impl Default for TraceDiffOptions {
+    fn default() -> Self {
+        Self {
+            ignored_fields: Vec::new(),
+            ignored_flags: 0,
+            context: 5,
+        }
+    }
+}
+
+/// A line of the reference trace next to the same instruction traced by the emulator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TracePair {
+    /// Line number in the reference trace, starting at 1
+    pub line: usize,
+    pub reference: String,
+    pub emulator: String,
+}
+
+/// The first instruction at which the emulator and the reference disagree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the instruction, starting at 0
+    pub instruction: usize,
+    pub expected: TraceRecord,
+    pub actual: TraceRecord,
+    /// The fields which differ
+    pub fields: Vec<TraceField>,
+    /// Instructions before the divergence, all of which matched
+    pub before: Vec<TracePair>,
+    /// The diverging instruction
+    pub diverged: TracePair,
+    /// Reference lines following the divergence
+    pub reference_after: Vec<String>,
+    /// Emulator trace lines following the divergence
+    pub emulator_after: Vec<String>,
+}
+
+/// The result of [diff_trace]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceDiff {
+    /// Every instruction in the reference matched
+    Matched { instructions: usize },
+    Diverged(Divergence),
+}
+
+/// Run the CPU in lockstep with a reference trace, until the reference ends or the first diverging instruction.
+/// Lines which [TraceRecord::parse] does not recognize are skipped.
+/// Cycle counts are compared relative to the first line, as emulators differ in what they count during reset
+pub fn diff_trace<'a, I>(cpu: &mut Cpu, memory: &mut dyn Memory<MAX_MEMORY>, reference: I, options: &TraceDiffOptions) -> TraceDiff
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut lines = reference.into_iter()
+        .enumerate()
+        .filter_map(|(index, line)| TraceRecord::parse(line).map(|record| (index + 1, line, record)));
+
+    let mut before = VecDeque::with_capacity(options.context + 1);
+    let mut cycle_base = None;
+    let mut instructions = 0;
+
+    while let Some((line, reference_line, expected)) = lines.next() {
+        let actual = TraceRecord::capture(cpu);
+        let emulator_line = trace_line(cpu, memory);
+
+        let base = *cycle_base.get_or_insert((expected.cycles, cpu.cycles()));
+        let fields = differing_fields(&expected, &actual, base, options);
+
+        let pair = TracePair { line, reference: reference_line.into(), emulator: emulator_line };
+        if !fields.is_empty() {
+            let reference_after = lines.by_ref()
+                .take(options.context)
+                .map(|(_, l, _)| l.into())
+                .collect();
+
+            let emulator_after = (0..options.context)
+                .map(|_| {
+                    cpu.step(memory);
+                    trace_line(cpu, memory)
+                })
+                .collect();
+
+            return TraceDiff::Diverged(Divergence {
+                instruction: instructions,
+                expected,
+                actual,
+                fields,
+                before: before.into_iter().collect(),
+                diverged: pair,
+                reference_after,
+                emulator_after,
+            });
+        }
+
+        if options.context > 0 {
+            if before.len() == options.context {
+                before.pop_front();
+            }
+            before.push_back(pair);
+        }
+
+        cpu.step(memory);
+        instructions += 1;
+    }
+
+    TraceDiff::Matched { instructions }
+}
+
+/// Compare two records. `cycle_base` holds the cycle counts of the first reference line and emulator instruction
+fn differing_fields(expected: &TraceRecord, actual: &TraceRecord, cycle_base: (Option<u64>, u64), options: &TraceDiffOptions) -> Vec<TraceField> {
+    let flag_mask = !(options.ignored_flags | UNUSED_FLAG_BIT);
+
+    TraceField::ALL.iter()
+        .copied()
+        .filter(|field| !options.ignored_fields.contains(field))
+        .filter(|field| match field {
+            TraceField::ProgramCounter => expected.program_counter != actual.program_counter,
+            TraceField::Accumulator => expected.accumulator != actual.accumulator,
+            TraceField::X => expected.x != actual.x,
+            TraceField::Y => expected.y != actual.y,
+            TraceField::Flags => expected.flags & flag_mask != actual.flags & flag_mask,
+            TraceField::StackPointer => expected.stack_pointer != actual.stack_pointer,
+            TraceField::Cycles => match (expected.cycles, cycle_base.0, actual.cycles) {
+                (Some(expected), Some(expected_base), Some(actual)) => {
+                    expected.wrapping_sub(expected_base) != actual.wrapping_sub(cycle_base.1)
+                },
+                _ => false,
+            },
+        })
+        .collect()
+}
+
+impl fmt::Display for Divergence {
+    /// A report showing the context of the divergence, with the differing fields marked
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Diverged at instruction {} (reference line {})", self.instruction, self.diverged.line)?;
+        writeln!(f)?;
+
+        for pair in &self.before {
+            writeln!(f, "  {:>6}  {}", pair.line, pair.emulator)?;
+        }
+
+        writeln!(f, "> {:>6}  {}", "ref", self.diverged.reference)?;
+        writeln!(f, "> {:>6}  {}", "emu", self.diverged.emulator)?;
+        writeln!(f, "  {:>6}  {}", "", markers(&self.diverged.emulator, &self.fields))?;
+
+        for field in &self.fields {
+            let (expected, actual) = match field {
+                TraceField::ProgramCounter => (alloc::format!("{:04X}", self.expected.program_counter), alloc::format!("{:04X}", self.actual.program_counter)),
+                TraceField::Accumulator => (alloc::format!("{:02X}", self.expected.accumulator), alloc::format!("{:02X}", self.actual.accumulator)),
+                TraceField::X => (alloc::format!("{:02X}", self.expected.x), alloc::format!("{:02X}", self.actual.x)),
+                TraceField::Y => (alloc::format!("{:02X}", self.expected.y), alloc::format!("{:02X}", self.actual.y)),
+                TraceField::Flags => (
+                    alloc::format!("{:02X} ({})", self.expected.flags, format_flag_letters(self.expected.flags)),
+                    alloc::format!("{:02X} ({})", self.actual.flags, format_flag_letters(self.actual.flags)),
+                ),
+                TraceField::StackPointer => (alloc::format!("{:02X}", self.expected.stack_pointer), alloc::format!("{:02X}", self.actual.stack_pointer)),
+                TraceField::Cycles => (alloc::format!("{}", self.expected.cycles.unwrap_or_default()), alloc::format!("{}", self.actual.cycles.unwrap_or_default())),
+            };
+            writeln!(f, "  {}: expected {}, got {}", field.name(), expected, actual)?;
+        }
+
+        if !self.reference_after.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Reference continues with:")?;
+            for line in &self.reference_after {
+                writeln!(f, "  {}", line)?;
+            }
+        }
+
+        if !self.emulator_after.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Emulator continues with:")?;
+            for line in &self.emulator_after {
+                writeln!(f, "  {}", line)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A line of `^` markers under the values of `fields` in an emulator trace line
+fn markers(line: &str, fields: &[TraceField]) -> String {
+    let mut markers: Vec<u8> = alloc::vec![b' '; line.len()];
+    for field in fields {
+        let span = match field {
+            TraceField::ProgramCounter => Some((0, 4)),
+            TraceField::Cycles => line.find(" CYC:").map(|start| (start + 5, line.len() - start - 5)),
+            field => line.find(&alloc::format!(" {}:", field.name())).map(|start| (start + field.name().len() + 2, 2)),
+        };
+
+        if let Some((start, length)) = span {
+            markers[start..start + length].fill(b'^');
+        }
+    }
+
+    String::from_utf8(markers).unwrap_or_default().trim_end().into()
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::format;
+    use alloc::string::ToString;
+    use alloc::vec;
+    use crate::cpu::{Cpu, CpuStatusFlags, Registers};
+    use crate::memory::{BasicMemory, Memory};
+    use crate::ops::*;
+    use super::*;
+
+    #[test]
+    fn parse_nestest_line() {
+        let record = TraceRecord::parse("C000  4C F5 C5  JMP $C5F5                       A:00 X:01 Y:02 P:24 SP:FD PPU:  0, 21 CYC:7").unwrap();
+        assert_eq!(record, TraceRecord {
+            program_counter: 0xC000,
+            accumulator: 0x00,
+            x: 0x01,
+            y: 0x02,
+            flags: 0x24,
+            stack_pointer: 0xFD,
+            cycles: Some(7),
+        });
+
+        assert!(TraceRecord::parse("").is_none());
+        assert!(TraceRecord::parse("; comment").is_none());
+        assert!(TraceRecord::parse("C000  4C F5 C5  JMP $C5F5").is_none());
+    }
+
+    #[test]
+    fn parse_flag_letter_line() {
+        let record = TraceRecord::parse("$C000:4C F5 C5  JMP $C5F5  A:00 X:00 Y:00 S:FD P:nvUbdIzC").unwrap();
+        assert_eq!(record.flags, 0b0010_0101);
+        assert_eq!(record.stack_pointer, 0xFD);
+        assert_eq!(record.cycles, None);
+        assert_eq!(format_flag_letters(0b0010_0101), "nvUbdIzC");
+        assert_eq!(flag_bit('b'), Some(0b0001_0000));
+        assert_eq!(flag_bit('U'), None);
+    }
+
+    /// `LDA #$42`, `LDX #$01`, `INX` at `0x0200`
+    fn setup() -> (Cpu, BasicMemory) {
+        let mut memory = BasicMemory::default();
+        for (offset, byte) in [LDA_IMMEDIATE, 0x42, LDX_IMMEDIATE, 0x01, INX_IMPLIED, NOP_IMPLIED, NOP_IMPLIED].iter().enumerate() {
+            memory.write(0x0200 + offset as u16, *byte);
+        }
+
+        let mut cpu = Cpu::default();
+        cpu.set_registers(&Registers {
+            program_counter: 0x0200,
+            stack_pointer: 0xFD,
+            flags: CpuStatusFlags::IRQ_DISABLE,
+            ..Registers::default()
+        });
+        (cpu, memory)
+    }
+
+    const REFERENCE: &str = "\
+0200  A9 42     LDA #$42                        A:00 X:00 Y:00 P:24 SP:FD CYC:7
+0202  A2 01     LDX #$01                        A:42 X:00 Y:00 P:24 SP:FD CYC:9
+0204  E8        INX                             A:42 X:01 Y:00 P:24 SP:FD CYC:11
+0205  EA        NOP                             A:42 X:02 Y:00 P:24 SP:FD CYC:13
+";
+
+    #[test]
+    fn matching_trace() {
+        let (mut cpu, mut memory) = setup();
+        let result = diff_trace(&mut cpu, &mut memory, REFERENCE.lines(), &TraceDiffOptions::default());
+        assert_eq!(result, TraceDiff::Matched { instructions: 4 });
+    }
+
+    #[test]
+    fn diverging_trace() {
+        let reference = REFERENCE.replace("A:42 X:02", "A:42 X:03").replace("CYC:11", "CYC:12");
+        let (mut cpu, mut memory) = setup();
+        let options = TraceDiffOptions { ignored_fields: vec![TraceField::Cycles], context: 2, ..TraceDiffOptions::default() };
+
+        let divergence = match diff_trace(&mut cpu, &mut memory, reference.lines(), &options) {
+            TraceDiff::Diverged(d) => d,
+            r => panic!("Expected a divergence, got {:?}", r),
+        };
+
+        assert_eq!(divergence.instruction, 3);
+        assert_eq!(divergence.fields, [TraceField::X]);
+        assert_eq!(divergence.diverged.line, 4);
+        assert_eq!(divergence.before.len(), 2);
+        assert_eq!(divergence.emulator_after.len(), 2);
+
+        let report = divergence.to_string();
+        assert!(report.contains("  X: expected 03, got 02"));
+        assert!(report.contains(&format!("{}^^", " ".repeat(8 + 50))));
+    }
+
+    #[test]
+    fn ignored_flags() {
+        let reference = REFERENCE.replace("P:24", "P:34");
+        let (mut cpu, mut memory) = setup();
+        let result = diff_trace(&mut cpu, &mut memory, reference.lines(), &TraceDiffOptions::default());
+        assert!(matches!(result, TraceDiff::Diverged(ref d) if d.fields == [TraceField::Flags]));
+
+        let (mut cpu, mut memory) = setup();
+        let options = TraceDiffOptions { ignored_flags: CpuStatusFlags::BREAK_COMMAND.bits(), ..TraceDiffOptions::default() };
+        let result = diff_trace(&mut cpu, &mut memory, reference.lines(), &options);
+        assert_eq!(result, TraceDiff::Matched { instructions: 4 });
+    }
+}
\ No newline at end of file
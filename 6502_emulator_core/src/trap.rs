@@ -0,0 +1,152 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use crate::cpu::Cpu;
+use crate::device::Exit;
+use crate::history::MemoryWrite;
+use crate::memory::{Memory, MAX_MEMORY};
+
+/// A routine implemented by the host, run instead of the code at an address. See [Cpu::set_trap]
+pub type Trap = Box<dyn FnMut(&mut Cpu, &mut dyn Memory<MAX_MEMORY>) -> TrapReturn + Send>;
+
+/// What the CPU does once a [Trap] has run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapReturn {
+    /// Return to the caller like `RTS`, so a trap can stand in for a subroutine called with `JSR`
+    Rts,
+    /// Return from an interrupt like `RTI`
+    Rti,
+    /// Continue at an address like `JMP`
+    Jump(u16),
+    /// Execute the instruction at the trapped address as if there was no trap
+    Execute,
+    /// End the run: stay at the trapped address like `JMP *`, and report the exit with [Cpu::exit]
+    Exit(Exit),
+}
+
+/// The memory a trap runs with, keeping its writes so they can be recorded like an instruction's
+pub(crate) struct TrapMemory<'a> {
+    memory: &'a mut dyn Memory<MAX_MEMORY>,
+    pub(crate) writes: Vec<MemoryWrite>,
+}
+
+impl<'a> TrapMemory<'a> {
+    pub(crate) fn new(memory: &'a mut dyn Memory<MAX_MEMORY>) -> Self {
+        Self { memory, writes: Vec::new() }
+    }
+}
+
+impl Memory<MAX_MEMORY> for TrapMemory<'_> {
+    fn reset(&mut self) {
+        self.memory.reset();
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.writes.push(MemoryWrite { address, old: self.memory.peek(address), new: value, replaced: None });
+        self.memory.write(address, value);
+    }
+
+    fn read(&self, address: u16) -> u8 {
+        self.memory.read(address)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.memory.peek(address)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::boxed::Box;
+    use crate::cpu::Cpu;
+    use crate::memory::{BasicMemory, Memory};
+    use crate::ops::{INX_IMPLIED, JSR_ABSOLUTE, LDA_IMMEDIATE, NOP_IMPLIED};
+    use super::TrapReturn;
+
+    const CHROUT: u16 = 0xFFD2;
+
+    fn setup(program: &[u8]) -> (Cpu, BasicMemory) {
+        let mut memory = BasicMemory::default();
+        memory.load(0x0200, program, 0).unwrap();
+        let mut cpu = Cpu::default();
+        cpu.set_program_counter(0x0200);
+        (cpu, memory)
+    }
+
+    #[test]
+    fn chrout() {
+        let (mut cpu, mut memory) = setup(&[LDA_IMMEDIATE, b'A', JSR_ABSOLUTE, 0xD2, 0xFF, INX_IMPLIED]);
+        // Print to a screen at $0400
+        let mut cursor = 0x0400;
+        cpu.set_trap(CHROUT, Box::new(move |cpu, memory| {
+            memory.write(cursor, cpu.registers().accumulator);
+            cursor += 1;
+            TrapReturn::Rts
+        }));
+
+        cpu.step(&mut memory);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.program_counter(), CHROUT);
+        assert_eq!(cpu.step(&mut memory), 6);
+        assert_eq!(cpu.program_counter(), 0x0205);
+        assert_eq!(cpu.instructions(), 3);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.registers().x, 1);
+        assert_eq!((memory.read(0x0400), memory.read(0x0401)), (b'A', 0));
+
+        // Traps are kept across a reset, and can be removed again
+        cpu.reset();
+        assert!(cpu.remove_trap(CHROUT).is_some());
+        assert!(cpu.remove_trap(CHROUT).is_none());
+    }
+
+    #[test]
+    fn return_actions() {
+        let (mut cpu, mut memory) = setup(&[NOP_IMPLIED, INX_IMPLIED]);
+        cpu.set_trap(0x0200, Box::new(|cpu, memory| {
+            memory.write(0x0300, 0x42);
+            let mut registers = cpu.registers();
+            registers.y = 7;
+            cpu.set_registers(&registers);
+            TrapReturn::Execute
+        }));
+        cpu.enable_history(2);
+        assert_eq!(cpu.step(&mut memory), 2);
+        assert_eq!((cpu.program_counter(), cpu.registers().y, memory.read(0x0300)), (0x0201, 7, 0x42));
+
+        // The trap's changes are undone with the instruction it ran before
+        assert!(cpu.step_back(&mut memory));
+        assert_eq!((cpu.program_counter(), cpu.registers().y, memory.read(0x0300)), (0x0200, 0, 0x00));
+        cpu.step(&mut memory);
+
+        cpu.set_trap(0x0201, Box::new(|_, _| TrapReturn::Jump(0x0200)));
+        assert_eq!(cpu.step(&mut memory), 3);
+        assert_eq!((cpu.program_counter(), cpu.registers().x), (0x0200, 0));
+    }
+
+    #[test]
+    fn recorded() {
+        let (mut cpu, mut memory) = setup(&[JSR_ABSOLUTE, 0xD2, 0xFF, INX_IMPLIED]);
+        cpu.set_trap(CHROUT, Box::new(|cpu, memory| {
+            memory.write(0x0400, cpu.registers().accumulator.wrapping_add(1));
+            TrapReturn::Rts
+        }));
+        cpu.enable_history(4);
+        cpu.enable_profiler();
+        cpu.enable_coverage();
+        cpu.enable_call_graph(8);
+
+        cpu.step(&mut memory);
+        assert_eq!(cpu.call_graph().unwrap().current_path(), [0x0200, CHROUT]);
+        cpu.step(&mut memory);
+        assert_eq!(cpu.call_graph().unwrap().current_path(), [0x0200]);
+        assert_eq!(cpu.call_graph().unwrap().inclusive_cycles(&[0x0200, CHROUT]), 6);
+        let profiler = cpu.profiler().unwrap();
+        assert_eq!((profiler.count(CHROUT).cycles, profiler.total().instructions, profiler.total().cycles), (6, 2, 12));
+        assert!(cpu.coverage().unwrap().is_executed(CHROUT));
+
+        let entry = cpu.history().unwrap().iter().last().unwrap().clone();
+        assert_eq!((entry.registers.program_counter, entry.writes.len()), (CHROUT, 1));
+        assert!(cpu.step_back(&mut memory));
+        assert_eq!((cpu.program_counter(), memory.read(0x0400)), (CHROUT, 0x00));
+    }
+}
\ No newline at end of file
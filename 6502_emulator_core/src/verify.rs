@@ -0,0 +1,378 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::cpu::{CallError, CallLimits, Cpu, CpuStatusFlags, Registers};
+use crate::memory::{Memory, MAX_MEMORY};
+
+/// A register, flag or memory byte a routine takes as input or leaves its result in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Accumulator,
+    X,
+    Y,
+    /// The carry flag, 0 or 1
+    Carry,
+    Memory(u16),
+}
+
+impl Location {
+    /// The highest value the location can hold
+    fn max(self) -> u8 {
+        match self {
+            Self::Carry => 1,
+            _ => 0xFF,
+        }
+    }
+
+    pub(crate) fn set(self, registers: &mut Registers, memory: &mut dyn Memory<MAX_MEMORY>, value: u8) {
+        match self {
+            Self::Accumulator => registers.accumulator = value,
+            Self::X => registers.x = value,
+            Self::Y => registers.y = value,
+            Self::Carry => registers.flags.set(CpuStatusFlags::CARRY, value != 0),
+            Self::Memory(address) => memory.write(address, value),
+        }
+    }
+
+    pub(crate) fn get(self, registers: &Registers, memory: &dyn Memory<MAX_MEMORY>) -> u8 {
+        match self {
+            Self::Accumulator => registers.accumulator,
+            Self::X => registers.x,
+            Self::Y => registers.y,
+            Self::Carry => registers.flags.contains(CpuStatusFlags::CARRY) as u8,
+            Self::Memory(address) => memory.peek(address),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Accumulator => write!(f, "A"),
+            Self::X => write!(f, "X"),
+            Self::Y => write!(f, "Y"),
+            Self::Carry => write!(f, "C"),
+            Self::Memory(address) => write!(f, "${:04X}", address),
+        }
+    }
+}
+
+/// A routine to verify with [verify_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Routine {
+    /// The address the routine is called at with `JSR`
+    pub address: u16,
+    /// The locations whose every combination of values the routine is run with
+    pub inputs: Vec<Location>,
+    /// The locations which are compared with the expected results
+    pub outputs: Vec<Location>,
+    /// The registers the routine is called with, apart from the inputs
+    pub registers: Registers,
+    pub limits: CallLimits,
+    /// How many of the simplest failing inputs to report
+    pub counterexamples: usize,
+}
+
+impl Routine {
+    pub fn new(address: u16, inputs: &[Location], outputs: &[Location]) -> Self {
+        Self {
+            address,
+            inputs: inputs.to_vec(),
+            outputs: outputs.to_vec(),
+            registers: Registers::default(),
+            limits: CallLimits::default(),
+            counterexamples: 10,
+        }
+    }
+}
+
+/// Input values the routine got wrong
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counterexample {
+    /// The values of [Routine::inputs]
+    pub inputs: Vec<u8>,
+    /// The values of [Routine::outputs] the reference model expects
+    pub expected: Vec<u8>,
+    /// The values of [Routine::outputs] the routine left, or why it didn't return
+    pub actual: Result<Vec<u8>, CallError>,
+}
+
+impl Counterexample {
+    /// Inputs with fewer non-zero values are simpler, then those with a smaller sum
+    fn complexity(&self) -> (usize, u32, &[u8]) {
+        let non_zero = self.inputs.iter().filter(|value| **value != 0).count();
+        (non_zero, self.inputs.iter().map(|value| *value as u32).sum(), &self.inputs)
+    }
+}
+
+/// The outcome of [verify_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    pub inputs: Vec<Location>,
+    pub outputs: Vec<Location>,
+    /// The amount of input combinations run
+    pub cases: u64,
+    /// The amount of input combinations with wrong results
+    pub failures: u64,
+    /// The simplest failing inputs, simplest first
+    pub counterexamples: Vec<Counterexample>,
+}
+
+impl Verification {
+    pub fn passed(&self) -> bool {
+        self.failures == 0
+    }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.passed() {
+            return write!(f, "all {} cases passed", self.cases);
+        }
+        write!(f, "{} of {} cases failed", self.failures, self.cases)?;
+        for counterexample in &self.counterexamples {
+            write!(f, "\n  ")?;
+            write_values(f, &self.inputs, &counterexample.inputs)?;
+            write!(f, ": expected ")?;
+            write_values(f, &self.outputs, &counterexample.expected)?;
+            match &counterexample.actual {
+                Ok(actual) => {
+                    write!(f, ", got ")?;
+                    write_values(f, &self.outputs, actual)?;
+                },
+                Err(error) => write!(f, ", but {}", error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn write_values(f: &mut fmt::Formatter<'_>, locations: &[Location], values: &[u8]) -> fmt::Result {
+    for (index, (location, value)) in locations.iter().zip(values).enumerate() {
+        if index > 0 {
+            write!(f, " ")?;
+        }
+        match location {
+            Location::Carry => write!(f, "{}={}", location, value)?,
+            _ => write!(f, "{}=${:02X}", location, value)?,
+        }
+    }
+    Ok(())
+}
+
+/// Memory which remembers the old value of every write, so the writes can be undone
+pub(crate) struct Journal<'a> {
+    memory: &'a mut dyn Memory<MAX_MEMORY>,
+    writes: Vec<(u16, u8)>,
+}
+
+impl<'a> Journal<'a> {
+    pub(crate) fn new(memory: &'a mut dyn Memory<MAX_MEMORY>) -> Self {
+        Self { memory, writes: Vec::new() }
+    }
+
+    pub(crate) fn undo(&mut self) {
+        for (address, old) in self.writes.drain(..).rev() {
+            self.memory.write(address, old);
+        }
+    }
+}
+
+impl Memory<MAX_MEMORY> for Journal<'_> {
+    fn reset(&mut self) {
+        self.memory.reset();
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.writes.push((address, self.memory.peek(address)));
+        self.memory.write(address, value);
+    }
+
+    fn read(&self, address: u16) -> u8 {
+        self.memory.read(address)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.memory.peek(address)
+    }
+}
+
+/// Call `routine` with every combination of values of its inputs and compare its outputs with what `expected`
+/// returns for the same inputs. Memory is restored after every call, so each one starts from the same state
+pub fn verify_exhaustive(cpu: &mut Cpu, memory: &mut dyn Memory<MAX_MEMORY>, routine: &Routine, mut expected: impl FnMut(&[u8]) -> Vec<u8>) -> Verification {
+    let mut verification = Verification {
+        inputs: routine.inputs.clone(),
+        outputs: routine.outputs.clone(),
+        cases: 0,
+        failures: 0,
+        counterexamples: Vec::new(),
+    };
+    let mut journal = Journal::new(memory);
+    let mut inputs = vec![0u8; routine.inputs.len()];
+
+    loop {
+        let mut registers = routine.registers;
+        for (location, value) in routine.inputs.iter().zip(&inputs) {
+            location.set(&mut registers, &mut journal, *value);
+        }
+        let actual = cpu.call_with_limits(&mut journal, routine.address, &registers, &routine.limits)
+            .map(|result| routine.outputs.iter().map(|location| location.get(&result.registers, &journal)).collect::<Vec<_>>());
+        journal.undo();
+
+        verification.cases += 1;
+        let expected = expected(&inputs);
+        if actual.as_ref() != Ok(&expected) {
+            verification.failures += 1;
+            let counterexample = Counterexample { inputs: inputs.clone(), expected, actual };
+            let position = verification.counterexamples.partition_point(|other| other.complexity() <= counterexample.complexity());
+            if position < routine.counterexamples {
+                verification.counterexamples.insert(position, counterexample);
+                verification.counterexamples.truncate(routine.counterexamples);
+            }
+        }
+
+        if !next_inputs(&routine.inputs, &mut inputs) {
+            return verification;
+        }
+    }
+}
+
+/// Move on to the next combination of values of `locations`, counting up like an odometer with the first location
+/// turning fastest. Returns `false` once all combinations have been visited
+pub(crate) fn next_inputs(locations: &[Location], values: &mut [u8]) -> bool {
+    locations.iter().zip(values.iter_mut()).any(|(location, value)| {
+        if *value == location.max() {
+            *value = 0;
+            false
+        } else {
+            *value += 1;
+            true
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::rc::Rc;
+    use alloc::string::ToString;
+    use core::cell::Cell;
+    use crate::device::{Bus, Device};
+    use crate::memory::BasicMemory;
+    use crate::ops::*;
+    use super::*;
+
+    /// Multiply A by X into A (low) and Y (high), using $00 and $01 as scratch space.
+    /// With `bug`, the carry into the high byte is dropped
+    fn multiply(bug: bool) -> BasicMemory {
+        let mut memory = BasicMemory::default();
+        let program = [
+            STA_ZERO_PAGE, 0x00, // multiplicand
+            STX_ZERO_PAGE, 0x01, // multiplier
+            LDA_IMMEDIATE, 0x00,
+            TAY_IMPLIED,         // high byte
+            LDX_IMMEDIATE, 0x08,
+            // loop:
+            ASL_ACCUMULATOR,
+            if bug { NOP_IMPLIED } else { PHA_IMPLIED },
+            TYA_IMPLIED,
+            ROL_ACCUMULATOR,
+            TAY_IMPLIED,
+            if bug { NOP_IMPLIED } else { PLA_IMPLIED },
+            ASL_ZERO_PAGE, 0x01,
+            BCC_RELATIVE, 0x06,
+            CLC_IMPLIED,
+            ADC_ZERO_PAGE, 0x00,
+            BCC_RELATIVE, 0x01,
+            INY_IMPLIED,
+            // skip:
+            DEX_IMPLIED,
+            BNE_RELATIVE, 0xED,
+            RTS_IMPLIED,
+        ];
+        memory.load(0x0200, &program, 0).unwrap();
+        memory
+    }
+
+    /// Multiplying by 10, the multiplier in X
+    fn routine() -> Routine {
+        let registers = Registers { x: 10, ..Registers::default() };
+        Routine { registers, ..Routine::new(0x0200, &[Location::Accumulator], &[Location::Accumulator, Location::Y]) }
+    }
+
+    fn expected_product(inputs: &[u8]) -> Vec<u8> {
+        let product = inputs[0] as u16 * 10;
+        vec![product as u8, (product >> 8) as u8]
+    }
+
+    #[test]
+    fn multiplication() {
+        let mut memory = multiply(false);
+        let verification = verify_exhaustive(&mut Cpu::default(), &mut memory, &routine(), expected_product);
+        assert!(verification.passed(), "{}", verification);
+        assert_eq!(verification.to_string(), "all 256 cases passed");
+        assert_eq!(memory.read(0x0000), 0x00);
+    }
+
+    #[test]
+    fn counterexamples() {
+        let routine = Routine { counterexamples: 2, ..routine() };
+        let verification = verify_exhaustive(&mut Cpu::default(), &mut multiply(true), &routine, expected_product);
+        assert!(!verification.passed());
+        assert_eq!(verification.counterexamples.len(), 2);
+        assert_eq!(
+            verification.to_string(),
+            "255 of 256 cases failed\n  A=$01: expected A=$0A Y=$00, got A=$00 Y=$00\n  A=$02: expected A=$14 Y=$00, got A=$00 Y=$00",
+        );
+    }
+
+    #[test]
+    fn runaway() {
+        let mut memory = BasicMemory::default();
+        memory.load(0x0200, &[BCS_RELATIVE, 0xFE, RTS_IMPLIED], 0).unwrap();
+        let routine = Routine {
+            limits: CallLimits { instructions: 10, ..CallLimits::default() },
+            ..Routine::new(0x0200, &[Location::Carry], &[Location::Carry])
+        };
+        let verification = verify_exhaustive(&mut Cpu::default(), &mut memory, &routine, |inputs| inputs.to_vec());
+        assert_eq!((verification.cases, verification.failures), (2, 1));
+        assert_eq!(verification.to_string(), "1 of 2 cases failed\n  C=1: expected C=1, but the subroutine didn't return within the instruction limit, it got to $0200");
+    }
+    /// A register which reads as the last value written, and is cleared by reading it
+    struct Latch(Rc<Cell<(u8, usize)>>);
+
+    impl Device for Latch {
+        fn size(&self) -> u16 {
+            1
+        }
+
+        fn read(&mut self, _offset: u16) -> u8 {
+            let (value, reads) = self.0.get();
+            self.0.set((0, reads + 1));
+            value
+        }
+
+        fn peek(&self, _offset: u16) -> u8 {
+            self.0.get().0
+        }
+
+        fn write(&mut self, _offset: u16, value: u8) {
+            self.0.set((value, self.0.get().1));
+        }
+    }
+
+    #[test]
+    fn device_outputs() {
+        // STA $D000, RTS
+        let mut memory = BasicMemory::default();
+        memory.load(0x0200, &[STA_ABSOLUTE, 0x00, 0xD0, RTS_IMPLIED], 0).unwrap();
+        let mut bus = Bus::new(memory);
+        let latch = Rc::new(Cell::new((0, 0)));
+        bus.map(0xD000, Latch(latch.clone()));
+
+        // Saving the old value and checking the output don't read the register
+        let routine = Routine::new(0x0200, &[Location::Accumulator], &[Location::Memory(0xD000)]);
+        let verification = verify_exhaustive(&mut Cpu::default(), &mut bus, &routine, |inputs| inputs.to_vec());
+        assert!(verification.passed(), "{}", verification);
+        assert_eq!(latch.get(), (0, 0));
+    }
+}
\ No newline at end of file
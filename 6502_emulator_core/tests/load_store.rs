@@ -1,4 +1,4 @@
-use emulator_6502_core::{BasicMemory, Cpu, Memory};
+use emulator_6502_core::{BasicMemory, Cpu, Memory, Registers};
 use crate::common::init;
 
 mod common;
@@ -11,7 +11,7 @@ fn load_store() {
     let mut memory = BasicMemory::from(bin.as_slice());
     let mut cpu = Cpu::default();
 
-    cpu.execute_instructions(&mut memory, 9);
+    cpu.call(&mut memory, 0x0000, &Registers::default()).unwrap();
     assert_eq!(memory.read(0x2000), 0x32);
     assert_eq!(memory.read(0x2032), 0x32);
     assert_eq!(memory.read(0x2001), 0x32);
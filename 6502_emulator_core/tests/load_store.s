@@ -13,6 +13,8 @@
 
     sty $2002
 
+    rts
+
     .org $FFFC
     .word $0000
     .word $0000
@@ -13,6 +13,8 @@
 	tay
 	sty $8002
 
+	rts
+
 	.org $FFFC
 	.word 0000
 	.word 0000
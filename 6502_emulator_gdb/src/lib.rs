@@ -0,0 +1,11 @@
+//! A GDB remote serial protocol stub for the 6502 emulator.
+//!
+//! The stub serves a single client over any [Connection], usually a TCP socket or a Unix socket.
+//! GDB does not know the 6502, so the registers are described through [TARGET_XML].
+
+mod error;
+pub use error::*;
+mod packet;
+pub use packet::{Connection, Incoming, PacketStream};
+mod stub;
+pub use stub::*;
\ No newline at end of file
@@ -0,0 +1,297 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use log::trace;
+
+/// Byte sent by the client outside of a packet to interrupt a running target (Ctrl-C)
+const INTERRUPT: u8 = 0x03;
+
+/// A byte stream a GDB client is connected over
+pub trait Connection: Read + Write {
+    /// Switch reads between blocking and non-blocking mode
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Something received from the client
+#[derive(Debug, PartialEq, Eq)]
+pub enum Incoming {
+    /// A packet with a valid checksum, without the framing
+    Packet(Vec<u8>),
+    /// The client requested the target to halt
+    Interrupt,
+}
+
+/// Frames and unframes remote serial protocol packets on a [Connection]
+pub struct PacketStream<C> {
+    connection: C,
+    /// Whether packets are acknowledged with `+` / `-`. GDB can disable this with `QStartNoAckMode`
+    ack: bool,
+    /// Bytes read while polling for an interrupt, to be received before the connection is read again
+    pending: VecDeque<u8>,
+}
+
+impl<C: Connection> PacketStream<C> {
+    pub fn new(connection: C) -> Self {
+        Self {
+            connection,
+            ack: true,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Stop sending and expecting acknowledgements
+    pub fn disable_ack(&mut self) {
+        self.ack = false;
+    }
+
+    /// Receive the next packet or interrupt. Returns `None` if the client disconnected
+    pub fn receive(&mut self) -> io::Result<Option<Incoming>> {
+        loop {
+            let byte = match self.read_byte() {
+                Ok(b) => b,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            };
+
+            match byte {
+                INTERRUPT => return Ok(Some(Incoming::Interrupt)),
+                b'$' => {
+                    if let Some(packet) = self.read_packet_body()? {
+                        trace!("<- {}", String::from_utf8_lossy(&packet));
+                        return Ok(Some(Incoming::Packet(packet)));
+                    }
+                },
+                // Acknowledgements and line noise
+                _ => {}
+            }
+        }
+    }
+
+    /// Send a packet, waiting for the client to acknowledge it if acknowledgements are enabled
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        trace!("-> {}", String::from_utf8_lossy(data));
+
+        let mut framed = Vec::with_capacity(data.len() + 4);
+        framed.push(b'$');
+        framed.extend_from_slice(data);
+        framed.push(b'#');
+        framed.extend_from_slice(format!("{:02x}", checksum(data)).as_bytes());
+
+        loop {
+            self.connection.write_all(&framed)?;
+            self.connection.flush()?;
+
+            if !self.ack {
+                return Ok(());
+            }
+
+            // Wait for the acknowledgement, resending on a NACK
+            loop {
+                match self.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Check, without blocking, whether the client sent an interrupt.
+    /// Anything else it sent is kept for [Self::receive]. Fails with [ErrorKind::UnexpectedEof] if the client disconnected
+    pub fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.connection.set_nonblocking(true)?;
+        let mut buf = [0u8; 64];
+        let result = self.connection.read(&mut buf);
+        self.connection.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Err(io::Error::new(ErrorKind::UnexpectedEof, "the client disconnected")),
+            Ok(length) => {
+                let mut interrupted = false;
+                for &byte in &buf[..length] {
+                    match byte {
+                        INTERRUPT => interrupted = true,
+                        _ => self.pending.push_back(byte),
+                    }
+                }
+                Ok(interrupted)
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the packet contents and checksum following a `$`.
+    /// Returns `None` if the checksum did not match, in which case the client is asked to resend
+    fn read_packet_body(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut data = Vec::new();
+        loop {
+            match self.read_byte()? {
+                b'#' => break,
+                b => data.push(b),
+            }
+        }
+
+        let checksum_hex = [self.read_byte()?, self.read_byte()?];
+        let expected = core::str::from_utf8(&checksum_hex)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok());
+
+        if expected != Some(checksum(&data)) {
+            if self.ack {
+                self.connection.write_all(b"-")?;
+                self.connection.flush()?;
+            }
+            return Ok(None);
+        }
+
+        if self.ack {
+            self.connection.write_all(b"+")?;
+            self.connection.flush()?;
+        }
+
+        Ok(Some(data))
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(byte);
+        }
+
+        let mut buf = [0u8; 1];
+        self.connection.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+}
+
+/// The modulo 256 sum of all bytes in a packet
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+/// Encode bytes as lowercase hexadecimal
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode a string of hexadecimal byte pairs
+pub fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    hex.chunks(2)
+        .map(|pair| {
+            core::str::from_utf8(pair)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+        })
+        .collect()
+}
+
+/// Parse a big-endian hexadecimal number, as used for addresses and lengths
+pub fn parse_hex(hex: &[u8]) -> Option<u32> {
+    core::str::from_utf8(hex)
+        .ok()
+        .and_then(|s| u32::from_str_radix(s, 16).ok())
+}
+
+/// Undo the escaping applied to binary data in `X` packets
+pub fn unescape_binary(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut iter = data.iter();
+    while let Some(&b) = iter.next() {
+        if b == b'}' {
+            if let Some(&escaped) = iter.next() {
+                out.push(escaped ^ 0x20);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checksum_wraps() {
+        assert_eq!(checksum(b"OK"), 0x9a);
+        assert_eq!(checksum(b"qSupported:multiprocess+"), 0xc6);
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(encode_hex(&[0x00, 0xAB, 0x10]), "00ab10");
+        assert_eq!(decode_hex(b"00ab10"), Some(vec![0x00, 0xAB, 0x10]));
+        assert_eq!(decode_hex(b"0"), None);
+        assert_eq!(decode_hex(b"zz"), None);
+    }
+
+    /// A connection reading `input`, which would block once it's empty unless the client disconnected
+    struct Scripted {
+        input: VecDeque<u8>,
+        disconnected: bool,
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.input.is_empty() && !self.disconnected {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Scripted {
+        fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn poll_interrupt() {
+        let input = b"$g#67".iter().copied().chain([INTERRUPT]).collect();
+        let mut stream = PacketStream::new(Scripted { input, disconnected: false });
+        assert!(stream.poll_interrupt().unwrap());
+        assert!(!stream.poll_interrupt().unwrap());
+        assert_eq!(stream.receive().unwrap(), Some(Incoming::Packet(b"g".to_vec())));
+
+        stream.connection.disconnected = true;
+        assert_eq!(stream.poll_interrupt().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(stream.receive().unwrap(), None);
+    }
+
+    #[test]
+    fn unescape() {
+        assert_eq!(unescape_binary(&[0x01, b'}', b'#' ^ 0x20, 0x02]), vec![0x01, b'#', 0x02]);
+    }
+}
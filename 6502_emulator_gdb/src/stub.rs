@@ -0,0 +1,762 @@
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use emulator_6502_core::{Cpu, CpuStatusFlags, Instruction, Memory, Registers, SourceLine, SourceMap, SymbolTable, MAX_MEMORY};
+use log::{debug, info};
+use crate::error::Result;
+use crate::packet::{decode_hex, encode_hex, parse_hex, unescape_binary, Connection, Incoming, PacketStream};
+
+/// Target description advertised to GDB through `qXfer:features:read`.
+/// The register numbers used by `p` / `P` packets follow the order in this file.
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.6502.core">
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="x" bitsize="8" type="uint8" regnum="1"/>
+    <reg name="y" bitsize="8" type="uint8" regnum="2"/>
+    <reg name="sp" bitsize="8" type="uint8" regnum="3"/>
+    <reg name="pc" bitsize="16" type="code_ptr" regnum="4"/>
+    <reg name="p" bitsize="8" type="uint8" regnum="5"/>
+  </feature>
+</target>
+"#;
+
+/// How many instructions are executed between checks for a Ctrl-C from the client while continuing
+const INTERRUPT_POLL_INTERVAL: u32 = 4096;
+
+/// Signal numbers used in stop replies
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// Output of `monitor help`
+const MONITOR_HELP: &str = "\
+history [count]     Show the last executed instructions, oldest first (default 20)
+whowrote <address>  Show which instructions last wrote the byte at the address, newest first
+symbol <name|addr>  Show the address of a symbol, or the symbol at an address
+break <location>    Set a breakpoint at a source line (file.s:42), symbol or address
+delete <location>   Remove the breakpoint at a source line, symbol or address
+where               Show the calls leading to the current instruction, with their source lines
+list [count]        Disassemble from the current instruction, with the source lines (default 10)
+";
+
+/// Instructions shown by `monitor list` without a count
+const DEFAULT_LIST_COUNT: usize = 10;
+
+/// The kind of memory access a watchpoint triggers on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+impl WatchKind {
+    /// The name GDB expects in a stop reply
+    fn stop_reason(&self) -> &'static str {
+        match self {
+            Self::Write => "watch",
+            Self::Read => "rwatch",
+            Self::Access => "awatch",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Watchpoint {
+    kind: WatchKind,
+    address: u16,
+    length: u16,
+}
+
+impl Watchpoint {
+    fn covers(&self, address: u16) -> bool {
+        address.wrapping_sub(self.address) < self.length
+    }
+}
+
+/// Why execution stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StopReason {
+    /// A single step completed or a breakpoint was reached
+    Trap,
+    /// The client sent Ctrl-C
+    Interrupt,
+    /// A watchpoint was triggered by an access to the address
+    Watch(WatchKind, u16),
+    /// Reverse execution reached the oldest instruction in the history
+    HistoryBegin,
+}
+
+impl StopReason {
+    fn reply(&self) -> String {
+        match self {
+            Self::Trap => format!("S{:02x}", SIGTRAP),
+            Self::Interrupt => format!("S{:02x}", SIGINT),
+            Self::Watch(kind, address) => format!("T{:02x}{}:{:04x};", SIGTRAP, kind.stop_reason(), address),
+            Self::HistoryBegin => format!("T{:02x}replaylog:begin;", SIGTRAP),
+        }
+    }
+}
+
+/// What to do after a packet has been handled
+enum Action {
+    /// Send the reply and wait for the next packet
+    Reply(Vec<u8>),
+    /// The reply has already been sent
+    Handled,
+    /// Send the reply and end the session
+    ReplyAndClose(Vec<u8>),
+    /// End the session without replying
+    Close,
+}
+
+/// Memory wrapper which records the first data access matching a watchpoint
+struct WatchedMemory<'a, M> {
+    inner: &'a mut M,
+    watchpoints: &'a [Watchpoint],
+    hit: Cell<Option<(WatchKind, u16)>>,
+    /// The address of the next instruction byte to be fetched and how many are left,
+    /// as fetching the instruction isn't a data access
+    fetch: Cell<(u16, u16)>,
+}
+
+impl<'a, M: Memory<MAX_MEMORY>> WatchedMemory<'a, M> {
+    fn check(&self, address: u16, write: bool) {
+        if self.hit.get().is_some() {
+            return;
+        }
+
+        let (next, left) = self.fetch.get();
+        if !write && left > 0 && address == next {
+            self.fetch.set((next.wrapping_add(1), left - 1));
+            return;
+        }
+
+        let hit = self.watchpoints.iter()
+            .filter(|w| w.covers(address))
+            .find(|w| match w.kind {
+                WatchKind::Write => write,
+                WatchKind::Read => !write,
+                WatchKind::Access => true,
+            });
+
+        if let Some(watchpoint) = hit {
+            self.hit.set(Some((watchpoint.kind, address)));
+        }
+    }
+}
+
+impl<'a, M: Memory<MAX_MEMORY>> Memory<MAX_MEMORY> for WatchedMemory<'a, M> {
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.check(address, true);
+        self.inner.write(address, value);
+    }
+
+    fn read(&self, address: u16) -> u8 {
+        self.check(address, false);
+        self.inner.read(address)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.inner.peek(address)
+    }
+}
+
+/// A GDB remote serial protocol stub controlling a [Cpu] and its memory
+pub struct GdbStub<M> {
+    cpu: Cpu,
+    memory: M,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+    symbols: SymbolTable,
+    source_map: SourceMap,
+}
+
+impl<M: Memory<MAX_MEMORY>> GdbStub<M> {
+    /// Create a stub. The CPU is expected to be ready to execute, e.g. after [Cpu::load_reset_vector]
+    pub fn new(cpu: Cpu, memory: M) -> Self {
+        Self {
+            cpu,
+            memory,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            symbols: SymbolTable::new(),
+            source_map: SourceMap::new(),
+        }
+    }
+
+    /// Use these symbols in monitor commands, both to name addresses and in place of addresses
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    /// Use these source lines in monitor commands, like `break main.s:42`.
+    /// Tracebacks with `monitor where` need the call graph to be enabled on the CPU
+    pub fn set_source_map(&mut self, source_map: SourceMap) {
+        self.source_map = source_map;
+    }
+
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    /// Take back the CPU and memory
+    pub fn into_parts(self) -> (Cpu, M) {
+        (self.cpu, self.memory)
+    }
+
+    /// Serve a single client until it detaches, kills the target or disconnects
+    pub fn serve<C: Connection>(&mut self, connection: C) -> Result<()> {
+        let mut stream = PacketStream::new(connection);
+
+        while let Some(incoming) = stream.receive()? {
+            let packet = match incoming {
+                Incoming::Packet(p) => p,
+                // The target is already halted
+                Incoming::Interrupt => {
+                    stream.send(StopReason::Interrupt.reply().as_bytes())?;
+                    continue;
+                }
+            };
+
+            match self.handle(&packet, &mut stream)? {
+                Action::Reply(reply) => stream.send(&reply)?,
+                Action::Handled => {},
+                Action::ReplyAndClose(reply) => {
+                    stream.send(&reply)?;
+                    break;
+                },
+                Action::Close => break,
+            }
+        }
+
+        info!("GDB client disconnected");
+        Ok(())
+    }
+
+    fn handle<C: Connection>(&mut self, packet: &[u8], stream: &mut PacketStream<C>) -> Result<Action> {
+        let (command, args) = match packet.split_first() {
+            Some((c, a)) => (*c, a),
+            None => return Ok(Action::Reply(Vec::new())),
+        };
+
+        let reply = match command {
+            b'?' => StopReason::Trap.reply().into_bytes(),
+            b'g' => encode_hex(&self.register_bytes()).into_bytes(),
+            b'G' => self.write_registers(args),
+            b'p' => self.read_register(args),
+            b'P' => self.write_register(args),
+            b'm' => self.read_memory(args),
+            b'M' => self.write_memory(args, false),
+            b'X' => self.write_memory(args, true),
+            b's' | b'c' => {
+                if !args.is_empty() {
+                    match parse_hex(args) {
+                        Some(address) => self.cpu.set_program_counter(address as u16),
+                        None => return Ok(Action::Reply(b"E01".to_vec())),
+                    }
+                }
+
+                let stop = self.resume(stream, command == b's')?;
+                debug!("Stopped at {:#06X}: {:?}", self.cpu.program_counter(), stop);
+                stop.reply().into_bytes()
+            },
+            b'b' if self.cpu.history().is_none() => b"E01".to_vec(),
+            b'b' if args == b"s" || args == b"c" => {
+                let stop = self.reverse(stream, args == b"s")?;
+                debug!("Stopped at {:#06X} running backwards: {:?}", self.cpu.program_counter(), stop);
+                stop.reply().into_bytes()
+            },
+            b'Z' => self.insert_point(args),
+            b'z' => self.remove_point(args),
+            b'H' | b'T' => b"OK".to_vec(),
+            b'q' => self.query(args),
+            b'Q' => {
+                if args == b"StartNoAckMode" {
+                    stream.send(b"OK")?;
+                    stream.disable_ack();
+                    return Ok(Action::Handled);
+                }
+                Vec::new()
+            },
+            b'D' => return Ok(Action::ReplyAndClose(b"OK".to_vec())),
+            b'k' => return Ok(Action::Close),
+            _ => Vec::new(),
+        };
+
+        Ok(Action::Reply(reply))
+    }
+
+    /// Run until a breakpoint, watchpoint or interrupt. Executes exactly one instruction if `single_step` is set
+    fn resume<C: Connection>(&mut self, stream: &mut PacketStream<C>, single_step: bool) -> Result<StopReason> {
+        let mut executed = 0u32;
+        loop {
+            if let Some((kind, address)) = self.step_watched() {
+                return Ok(StopReason::Watch(kind, address));
+            }
+
+            executed = executed.wrapping_add(1);
+            if single_step || self.breakpoints.contains(&self.cpu.program_counter()) {
+                return Ok(StopReason::Trap);
+            }
+
+            if executed.is_multiple_of(INTERRUPT_POLL_INTERVAL) && stream.poll_interrupt()? {
+                return Ok(StopReason::Interrupt);
+            }
+        }
+    }
+
+    /// Undo instructions from the history until a breakpoint, an interrupt or the start of the history.
+    /// Undoes exactly one instruction if `single_step` is set. Watchpoints are not checked while running backwards
+    fn reverse<C: Connection>(&mut self, stream: &mut PacketStream<C>, single_step: bool) -> Result<StopReason> {
+        let mut undone = 0u32;
+        loop {
+            if !self.cpu.step_back(&mut self.memory) {
+                return Ok(StopReason::HistoryBegin);
+            }
+
+            undone = undone.wrapping_add(1);
+            if single_step || self.breakpoints.contains(&self.cpu.program_counter()) {
+                return Ok(StopReason::Trap);
+            }
+
+            if undone.is_multiple_of(INTERRUPT_POLL_INTERVAL) && stream.poll_interrupt()? {
+                return Ok(StopReason::Interrupt);
+            }
+        }
+    }
+
+    /// Execute a single instruction, returning the first watchpoint it triggered
+    fn step_watched(&mut self) -> Option<(WatchKind, u16)> {
+        if self.watchpoints.is_empty() {
+            self.cpu.step(&mut self.memory);
+            return None;
+        }
+
+        let pc = self.cpu.program_counter();
+        let length = Instruction::decode(&self.memory, pc).length();
+        let mut watched = WatchedMemory {
+            inner: &mut self.memory,
+            watchpoints: &self.watchpoints,
+            hit: Cell::new(None),
+            fetch: Cell::new((pc, length)),
+        };
+        self.cpu.step(&mut watched);
+        watched.hit.get()
+    }
+
+    /// All registers, in target description order. The program counter is little-endian
+    fn register_bytes(&self) -> [u8; 7] {
+        let r = self.cpu.registers();
+        let [pc_low, pc_high] = r.program_counter.to_le_bytes();
+        [r.accumulator, r.x, r.y, r.stack_pointer, pc_low, pc_high, r.flags.bits()]
+    }
+
+    fn write_registers(&mut self, args: &[u8]) -> Vec<u8> {
+        match decode_hex(args) {
+            Some(bytes) if bytes.len() == 7 => {
+                self.cpu.set_registers(&Registers {
+                    accumulator: bytes[0],
+                    x: bytes[1],
+                    y: bytes[2],
+                    stack_pointer: bytes[3],
+                    program_counter: u16::from_le_bytes([bytes[4], bytes[5]]),
+                    flags: CpuStatusFlags::from_bits_truncate(bytes[6]),
+                });
+                self.cpu.clear_history();
+                b"OK".to_vec()
+            },
+            _ => b"E01".to_vec(),
+        }
+    }
+
+    fn read_register(&self, args: &[u8]) -> Vec<u8> {
+        let bytes = self.register_bytes();
+        match parse_hex(args) {
+            Some(n @ 0..=3) => encode_hex(&bytes[n as usize..n as usize + 1]).into_bytes(),
+            Some(4) => encode_hex(&bytes[4..6]).into_bytes(),
+            Some(5) => encode_hex(&bytes[6..7]).into_bytes(),
+            _ => b"E01".to_vec(),
+        }
+    }
+
+    fn write_register(&mut self, args: &[u8]) -> Vec<u8> {
+        let mut parts = args.splitn(2, |b| *b == b'=');
+        let number = parts.next().and_then(parse_hex);
+        let value = parts.next().and_then(decode_hex);
+
+        let (number, value) = match (number, value) {
+            (Some(n), Some(v)) => (n, v),
+            _ => return b"E01".to_vec(),
+        };
+
+        let mut r = self.cpu.registers();
+        match (number, value.as_slice()) {
+            (0, [v]) => r.accumulator = *v,
+            (1, [v]) => r.x = *v,
+            (2, [v]) => r.y = *v,
+            (3, [v]) => r.stack_pointer = *v,
+            (4, [low, high]) => r.program_counter = u16::from_le_bytes([*low, *high]),
+            (5, [v]) => r.flags = CpuStatusFlags::from_bits_truncate(*v),
+            _ => return b"E01".to_vec(),
+        }
+
+        self.cpu.set_registers(&r);
+        self.cpu.clear_history();
+        b"OK".to_vec()
+    }
+
+    fn read_memory(&self, args: &[u8]) -> Vec<u8> {
+        let (address, length) = match parse_address_length(args) {
+            Some(v) => v,
+            None => return b"E01".to_vec(),
+        };
+
+        let bytes: Vec<u8> = (0..length)
+            .map(|offset| self.memory.peek((address + offset) as u16))
+            .collect();
+        encode_hex(&bytes).into_bytes()
+    }
+
+    /// Handle `M` (hex encoded) and `X` (binary) memory writes
+    fn write_memory(&mut self, args: &[u8], binary: bool) -> Vec<u8> {
+        let colon = match args.iter().position(|b| *b == b':') {
+            Some(c) => c,
+            None => return b"E01".to_vec(),
+        };
+
+        let (address, length) = match parse_address_length(&args[..colon]) {
+            Some(v) => v,
+            None => return b"E01".to_vec(),
+        };
+
+        let data = if binary {
+            Some(unescape_binary(&args[colon + 1..]))
+        } else {
+            decode_hex(&args[colon + 1..])
+        };
+
+        match data {
+            Some(data) if data.len() as u32 == length => {
+                for (offset, byte) in data.into_iter().enumerate() {
+                    self.memory.write((address + offset as u32) as u16, byte);
+                }
+                self.cpu.clear_history();
+                b"OK".to_vec()
+            },
+            _ => b"E01".to_vec(),
+        }
+    }
+
+    fn insert_point(&mut self, args: &[u8]) -> Vec<u8> {
+        match parse_point(args) {
+            Some(Point::Breakpoint(address)) => {
+                self.breakpoints.insert(address);
+            },
+            Some(Point::Watchpoint(watchpoint)) => {
+                if !self.watchpoints.contains(&watchpoint) {
+                    self.watchpoints.push(watchpoint);
+                }
+            },
+            None => return b"E01".to_vec(),
+        }
+
+        b"OK".to_vec()
+    }
+
+    fn remove_point(&mut self, args: &[u8]) -> Vec<u8> {
+        match parse_point(args) {
+            Some(Point::Breakpoint(address)) => {
+                self.breakpoints.remove(&address);
+            },
+            Some(Point::Watchpoint(watchpoint)) => {
+                self.watchpoints.retain(|w| *w != watchpoint);
+            },
+            None => return b"E01".to_vec(),
+        }
+
+        b"OK".to_vec()
+    }
+
+    fn query(&mut self, args: &[u8]) -> Vec<u8> {
+        if args.starts_with(b"Supported") {
+            let mut features = b"PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_vec();
+            if self.cpu.history().is_some() {
+                features.extend_from_slice(b";ReverseStep+;ReverseContinue+");
+            }
+            return features;
+        }
+
+        if let Some(command) = args.strip_prefix(b"Rcmd,") {
+            return match decode_hex(command) {
+                Some(command) => encode_hex(self.monitor(&String::from_utf8_lossy(&command)).as_bytes()).into_bytes(),
+                None => b"E01".to_vec(),
+            };
+        }
+
+        if let Some(annex) = args.strip_prefix(b"Xfer:features:read:target.xml:") {
+            return match parse_pair(annex) {
+                Some((offset, length)) => transfer_chunk(TARGET_XML.as_bytes(), offset as usize, length as usize),
+                None => b"E01".to_vec(),
+            };
+        }
+
+        match args {
+            b"Attached" => b"1".to_vec(),
+            b"C" => b"QC1".to_vec(),
+            b"fThreadInfo" => b"m1".to_vec(),
+            b"sThreadInfo" => b"l".to_vec(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Run a `monitor` command, returning its output
+    fn monitor(&mut self, command: &str) -> String {
+        let mut words = command.split_whitespace();
+        match words.next() {
+            Some("history") => {
+                let count = match words.next().map(str::parse) {
+                    None => 20,
+                    Some(Ok(count)) => count,
+                    Some(Err(_)) => return "Usage: history [count]\n".to_string(),
+                };
+
+                match self.cpu.history() {
+                    Some(history) => history.iter()
+                        .skip(history.len().saturating_sub(count))
+                        .map(|entry| format!("{}\n", entry))
+                        .collect(),
+                    None => "History is not enabled\n".to_string(),
+                }
+            },
+            Some("whowrote") => {
+                let address = match words.next().and_then(|w| self.monitor_address(w)) {
+                    Some(address) => address,
+                    None => return "Usage: whowrote <address>\n".to_string(),
+                };
+
+                let provenance = match self.cpu.provenance() {
+                    Some(p) => p,
+                    None => return "Write provenance is not enabled\n".to_string(),
+                };
+
+                let writes: String = provenance.writes(address)
+                    .map(|record| {
+                        let instruction = Instruction::decode(&self.memory, record.program_counter);
+                        format!("{}  {}\n", record, instruction.with_symbols(&self.symbols))
+                    })
+                    .collect();
+                if writes.is_empty() {
+                    format!("${:04X} was not written\n", address)
+                } else {
+                    writes
+                }
+            },
+            Some("symbol") => {
+                let argument = match words.next() {
+                    Some(argument) => argument,
+                    None => return "Usage: symbol <name|addr>\n".to_string(),
+                };
+
+                if let Some(address) = parse_monitor_address(argument) {
+                    match self.symbols.nearest(address) {
+                        Some((symbol, 0)) => format!("${:04X} is {}\n", address, symbol),
+                        Some((symbol, offset)) => format!("${:04X} is {}+{}\n", address, symbol, offset),
+                        None => format!("No symbol at or before ${:04X}\n", address),
+                    }
+                } else {
+                    // An unqualified name may be defined in several banks
+                    let definitions: String = self.symbols.find(argument)
+                        .map(|symbol| format!("{} is ${:04X}\n", symbol, symbol.address))
+                        .collect();
+                    match self.symbols.address_of(argument) {
+                        None => format!("Unknown symbol '{}'\n", argument),
+                        Some(address) if definitions.is_empty() => format!("{} is ${:04X}\n", argument, address),
+                        Some(_) => definitions,
+                    }
+                }
+            },
+            Some(command @ ("break" | "delete")) => {
+                let location = words.collect::<Vec<_>>().join(" ");
+                if location.is_empty() {
+                    return format!("Usage: {} <file:line|symbol|address>\n", command);
+                }
+                let (addresses, source) = match self.locate(&location) {
+                    Ok(located) => located,
+                    Err(message) => return message,
+                };
+
+                for address in &addresses {
+                    if command == "break" {
+                        self.breakpoints.insert(*address);
+                    } else {
+                        self.breakpoints.remove(address);
+                    }
+                }
+
+                let addresses: Vec<String> = addresses.iter().map(|a| format!("${:04X}", a)).collect();
+                let verb = if command == "break" { "Breakpoint set at" } else { "Breakpoint removed from" };
+                match source {
+                    Some(source) => format!("{} {} ({})\n", verb, addresses.join(", "), self.source_location(source)),
+                    None => format!("{} {}\n", verb, addresses.join(", ")),
+                }
+            },
+            Some("where") => {
+                let pc = self.cpu.program_counter();
+                let call_sites: Vec<u16> = self.cpu.call_graph().map(|c| c.call_sites().rev().collect()).unwrap_or_default();
+                core::iter::once(pc).chain(call_sites)
+                    .enumerate()
+                    .map(|(depth, address)| format!("#{:<2} {}\n", depth, self.describe(address)))
+                    .collect()
+            },
+            Some("list") => {
+                let count = match words.next().map(str::parse) {
+                    None => DEFAULT_LIST_COUNT,
+                    Some(Ok(count)) => count,
+                    Some(Err(_)) => return "Usage: list [count]\n".to_string(),
+                };
+
+                let pc = self.cpu.program_counter();
+                let mut address = pc;
+                let mut listing = String::new();
+                for _ in 0..count {
+                    if let Some(source) = self.source_map.line_at(address).filter(|_| self.source_map.is_line_start(address)) {
+                        listing.push_str(&format!("   {}\n", self.source_location(source)));
+                    }
+
+                    let instruction = Instruction::decode(&self.memory, address);
+                    let marker = if address == pc { "=>" } else { "  " };
+                    listing.push_str(&format!("{} {:04X}  {}\n", marker, address, instruction.with_symbols(&self.symbols)));
+                    address = instruction.next_address();
+                }
+                listing
+            },
+            Some("help") | None => MONITOR_HELP.to_string(),
+            Some(other) => format!("Unknown monitor command '{}', try 'monitor help'\n", other),
+        }
+    }
+
+    /// Parse an address typed in a monitor command, written as `0x10`, `$10` or `16`, or the name of a symbol
+    fn monitor_address(&self, s: &str) -> Option<u16> {
+        parse_monitor_address(s).or_else(|| self.symbols.address_of(s))
+    }
+
+    /// Resolve a location typed in a monitor command, a source line written as `file.s:42` or an address.
+    /// A source line may have generated code at several addresses, like a macro invocation.
+    /// On failure, the message to show is returned
+    fn locate(&self, location: &str) -> std::result::Result<(Vec<u16>, Option<SourceLine>), String> {
+        if let Some((path, line)) = location.rsplit_once(':') {
+            if let (Some(file), Ok(line)) = (self.source_map.find_file(path), line.parse()) {
+                return match self.source_map.nearest_line(file, line) {
+                    Some(source) => Ok((self.source_map.addresses_of(source), Some(source))),
+                    None => Err(format!("No code at or after line {} of {}\n", line, path)),
+                };
+            }
+        }
+
+        match self.monitor_address(location) {
+            Some(address) => Ok((vec![address], self.source_map.line_at(address))),
+            None => Err(format!("Unknown location '{}'\n", location)),
+        }
+    }
+
+    /// An address with its symbol and source line, like `$0206 in print+1 at src/main.s:9: inx`
+    fn describe(&self, address: u16) -> String {
+        let mut description = format!("${:04X}", address);
+        match self.symbols.nearest(address) {
+            Some((symbol, 0)) => description.push_str(&format!(" in {}", symbol)),
+            Some((symbol, offset)) => description.push_str(&format!(" in {}+{}", symbol, offset)),
+            None => {},
+        }
+        if let Some(source) = self.source_map.line_at(address) {
+            description.push_str(&format!(" at {}", self.source_location(source)));
+        }
+        description
+    }
+
+    /// A source line as `file:line`, followed by the text of the line if the file can be read
+    fn source_location(&self, source: SourceLine) -> String {
+        let path = &self.source_map.files()[source.file];
+        let text = std::fs::read_to_string(path).ok()
+            .and_then(|contents| contents.lines().nth(source.line.saturating_sub(1) as usize).map(|l| l.trim().to_string()));
+
+        match text {
+            Some(text) => format!("{}:{}: {}", path, source.line, text),
+            None => format!("{}:{}", path, source.line),
+        }
+    }
+}
+
+/// Parse an address typed in a monitor command, written as `0x10`, `$10` or `16`
+fn parse_monitor_address(s: &str) -> Option<u16> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix('$')) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+enum Point {
+    Breakpoint(u16),
+    Watchpoint(Watchpoint),
+}
+
+/// Parse the `type,addr,kind` arguments of `Z` and `z` packets
+fn parse_point(args: &[u8]) -> Option<Point> {
+    let mut parts = args.split(|b| *b == b',');
+    let kind = parse_hex(parts.next()?)?;
+    let address = parse_hex(parts.next()?)? as u16;
+    let length = parse_hex(parts.next()?)? as u16;
+
+    let watch = |kind| Some(Point::Watchpoint(Watchpoint { kind, address, length: length.max(1) }));
+    match kind {
+        // Software and hardware breakpoints are handled the same, memory is never patched
+        0 | 1 => Some(Point::Breakpoint(address)),
+        2 => watch(WatchKind::Write),
+        3 => watch(WatchKind::Read),
+        4 => watch(WatchKind::Access),
+        _ => None,
+    }
+}
+
+/// Parse two comma separated hexadecimal numbers
+fn parse_pair(args: &[u8]) -> Option<(u32, u32)> {
+    let comma = args.iter().position(|b| *b == b',')?;
+    Some((parse_hex(&args[..comma])?, parse_hex(&args[comma + 1..])?))
+}
+
+/// Parse `addr,length`, making sure the range lies within memory
+fn parse_address_length(args: &[u8]) -> Option<(u32, u32)> {
+    let (address, length) = parse_pair(args)?;
+    if address as usize + length as usize > MAX_MEMORY {
+        return None;
+    }
+
+    Some((address, length))
+}
+
+/// Reply to a `qXfer` read with the requested part of `data`
+fn transfer_chunk(data: &[u8], offset: usize, length: usize) -> Vec<u8> {
+    let start = offset.min(data.len());
+    let end = (start + length).min(data.len());
+    let marker = if end == data.len() { b'l' } else { b'm' };
+
+    let mut reply = vec![marker];
+    reply.extend_from_slice(&data[start..end]);
+    reply
+}
@@ -0,0 +1,114 @@
+use std::io::{Read, Write};
+use std::thread::JoinHandle;
+use emulator_6502_core::{BasicMemory, Cpu, Memory, SourceMap, SymbolTable};
+use emulator_6502_gdb::{Connection, GdbStub};
+
+/// Assembled at `0x0200`:
+/// ```text
+/// 0200  LDA #$42
+/// 0202  STA $10
+/// 0204  INX
+/// 0205  JMP $0204
+/// ```
+#[allow(unused)]
+pub const PROGRAM: [u8; 8] = [0xA9, 0x42, 0x85, 0x10, 0xE8, 0x4C, 0x04, 0x02];
+
+/// Memory with [PROGRAM] loaded at `0x0200` and the reset vector pointing to it
+#[allow(unused)]
+pub fn memory() -> BasicMemory {
+    let mut memory = BasicMemory::default();
+    for (offset, byte) in PROGRAM.iter().enumerate() {
+        memory.write(0x0200 + offset as u16, *byte);
+    }
+    memory.write(0xFFFC, 0x00);
+    memory.write(0xFFFD, 0x02);
+    memory
+}
+
+/// Serve a single client on a background thread
+#[allow(unused)]
+pub fn spawn_stub<C: Connection + Send + 'static>(connection: C) -> JoinHandle<(Cpu, BasicMemory)> {
+    spawn_stub_with(connection, |_| {})
+}
+
+/// Serve a single client on a background thread, after configuring the CPU
+#[allow(unused)]
+pub fn spawn_stub_with<C, F>(connection: C, configure: F) -> JoinHandle<(Cpu, BasicMemory)>
+where
+    C: Connection + Send + 'static,
+    F: FnOnce(&mut Cpu) + Send + 'static,
+{
+    spawn_stub_with_debug_info(connection, configure, SymbolTable::new(), SourceMap::new())
+}
+
+/// Serve a single client on a background thread, with symbols and source lines for monitor commands
+#[allow(unused)]
+pub fn spawn_stub_with_debug_info<C, F>(connection: C, configure: F, symbols: SymbolTable, source_map: SourceMap) -> JoinHandle<(Cpu, BasicMemory)>
+where
+    C: Connection + Send + 'static,
+    F: FnOnce(&mut Cpu) + Send + 'static,
+{
+    std::thread::spawn(move || {
+        let memory = memory();
+        let mut cpu = Cpu::default();
+        cpu.load_reset_vector(&memory);
+        configure(&mut cpu);
+
+        let mut stub = GdbStub::new(cpu, memory);
+        stub.set_symbols(symbols);
+        stub.set_source_map(source_map);
+        stub.serve(connection).expect("Serving client");
+        stub.into_parts()
+    })
+}
+
+/// A minimal client speaking the remote serial protocol with acknowledgements
+pub struct Client<C> {
+    connection: C,
+}
+
+#[allow(unused)]
+impl<C: Read + Write> Client<C> {
+    pub fn new(connection: C) -> Self {
+        Self { connection }
+    }
+
+    /// Send a packet and return the reply
+    pub fn request(&mut self, packet: &str) -> String {
+        self.send(packet);
+        self.receive()
+    }
+
+    pub fn send(&mut self, packet: &str) {
+        let checksum = packet.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        write!(self.connection, "${}#{:02x}", packet, checksum).expect("Writing packet");
+        assert_eq!(self.read_byte(), b'+', "Packet was not acknowledged");
+    }
+
+    pub fn send_raw(&mut self, bytes: &[u8]) {
+        self.connection.write_all(bytes).expect("Writing bytes");
+    }
+
+    pub fn receive(&mut self) -> String {
+        while self.read_byte() != b'$' {}
+
+        let mut data = Vec::new();
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                b => data.push(b),
+            }
+        }
+        self.read_byte();
+        self.read_byte();
+        self.connection.write_all(b"+").expect("Writing ack");
+
+        String::from_utf8(data).expect("Reply is not UTF-8")
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let mut buf = [0u8; 1];
+        self.connection.read_exact(&mut buf).expect("Reading from stub");
+        buf[0]
+    }
+}
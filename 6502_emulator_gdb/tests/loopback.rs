@@ -0,0 +1,271 @@
+use std::net::{TcpListener, TcpStream};
+use emulator_6502_core::{BasicMemory, Cpu, SourceLine, SourceMap, SymbolFormat, SymbolTable};
+use crate::common::{spawn_stub, spawn_stub_with, spawn_stub_with_debug_info, Client};
+
+mod common;
+
+type Handle = std::thread::JoinHandle<(Cpu, BasicMemory)>;
+
+fn connect() -> (Client<TcpStream>, Handle) {
+    connect_with(|_| {})
+}
+
+fn connect_with<F: FnOnce(&mut Cpu) + Send + 'static>(configure: F) -> (Client<TcpStream>, Handle) {
+    let (client, server) = socket_pair();
+    (Client::new(client), spawn_stub_with(server, configure))
+}
+
+fn socket_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Binding listener");
+    let client = TcpStream::connect(listener.local_addr().unwrap()).expect("Connecting");
+    let (server, _) = listener.accept().expect("Accepting client");
+    client.set_nodelay(true).unwrap();
+    server.set_nodelay(true).unwrap();
+    (client, server)
+}
+
+#[test]
+fn registers_and_memory() {
+    let (mut client, handle) = connect();
+
+    assert_eq!(client.request("?"), "S05");
+    assert_eq!(client.request("g"), "000000ff000200");
+    assert_eq!(client.request("P0=7f"), "OK");
+    assert_eq!(client.request("p0"), "7f");
+    assert_eq!(client.request("P4=0002"), "OK");
+    assert_eq!(client.request("p4"), "0002");
+
+    assert_eq!(client.request("m200,4"), "a9428510");
+    assert_eq!(client.request("M300,2:abcd"), "OK");
+    assert_eq!(client.request("m300,2"), "abcd");
+    assert_eq!(client.request("mffff,2"), "E01");
+
+    assert!(client.request("qSupported:xmlRegisters=i386").contains("qXfer:features:read+"));
+    assert!(client.request("qXfer:features:read:target.xml:0,fff").starts_with("l<?xml"));
+
+    assert_eq!(client.request("D"), "OK");
+    handle.join().unwrap();
+}
+
+#[test]
+fn step_and_breakpoint() {
+    let (mut client, handle) = connect();
+
+    assert_eq!(client.request("s"), "S05");
+    assert_eq!(client.request("p4"), "0202");
+    assert_eq!(client.request("p0"), "42");
+
+    assert_eq!(client.request("Z0,205,1"), "OK");
+    assert_eq!(client.request("c"), "S05");
+    assert_eq!(client.request("p4"), "0502");
+
+    // Continuing from a breakpoint executes the instruction before stopping at it again
+    assert_eq!(client.request("c"), "S05");
+    assert_eq!(client.request("p4"), "0502");
+    assert_eq!(client.request("p1"), "02");
+
+    assert_eq!(client.request("z0,205,1"), "OK");
+    client.send("k");
+
+    let (cpu, _) = handle.join().unwrap();
+    assert_eq!(cpu.program_counter(), 0x0205);
+}
+
+#[test]
+fn watchpoint() {
+    let (mut client, handle) = connect();
+
+    assert_eq!(client.request("Z2,10,1"), "OK");
+    assert_eq!(client.request("c"), "T05watch:0010;");
+    assert_eq!(client.request("p4"), "0402");
+    assert_eq!(client.request("m10,1"), "42");
+
+    // Fetching an instruction doesn't trigger a read watchpoint on it
+    assert_eq!(client.request("z2,10,1"), "OK");
+    assert_eq!(client.request("Z3,203,1"), "OK");
+    assert_eq!(client.request("Z4,10,1"), "OK");
+    assert_eq!(client.request("P4=0002"), "OK");
+    assert_eq!(client.request("c"), "T05awatch:0010;");
+
+    client.send("k");
+    handle.join().unwrap();
+}
+
+#[test]
+fn interrupt() {
+    let (mut client, handle) = connect();
+
+    client.send("c");
+    client.send_raw(&[0x03]);
+    assert_eq!(client.receive(), "S02");
+
+    client.send("k");
+    handle.join().unwrap();
+}
+
+#[test]
+fn reverse_execution() {
+    let (mut client, handle) = connect_with(|cpu| cpu.enable_history(16));
+
+    assert!(client.request("qSupported").contains("ReverseStep+;ReverseContinue+"));
+    for _ in 0..3 {
+        assert_eq!(client.request("s"), "S05");
+    }
+
+    let output = client.request(&format!("qRcmd,{}", hex("history 2")));
+    let output = String::from_utf8(unhex(&output)).unwrap();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("0202  STA $10") && lines[0].ends_with("$0010: 00 -> 42"));
+    assert!(lines[1].starts_with("0204  INX"));
+
+    assert_eq!(client.request("bs"), "S05");
+    assert_eq!(client.request("p4"), "0402");
+    assert_eq!(client.request("p1"), "00");
+
+    assert_eq!(client.request("Z0,202,1"), "OK");
+    assert_eq!(client.request("bc"), "S05");
+    assert_eq!(client.request("p4"), "0202");
+    assert_eq!(client.request("m10,1"), "00");
+
+    assert_eq!(client.request("bc"), "T05replaylog:begin;");
+    assert_eq!(client.request("p4"), "0002");
+
+    client.send("k");
+    handle.join().unwrap();
+}
+
+#[test]
+fn writes_clear_history() {
+    let (mut client, handle) = connect_with(|cpu| cpu.enable_history(16));
+
+    // Stepping back over a write from the debugger would undo it
+    assert_eq!(client.request("s"), "S05");
+    assert_eq!(client.request("M10,1:07"), "OK");
+    assert_eq!(client.request("bs"), "T05replaylog:begin;");
+    assert_eq!(client.request("m10,1"), "07");
+
+    assert_eq!(client.request("s"), "S05");
+    assert_eq!(client.request("G000000FF040200"), "OK");
+    assert_eq!(client.request("bs"), "T05replaylog:begin;");
+    assert_eq!(client.request("p4"), "0402");
+
+    client.send("k");
+    handle.join().unwrap();
+}
+
+#[test]
+fn who_wrote() {
+    let (mut client, handle) = connect_with(|cpu| cpu.enable_provenance(1));
+
+    assert_eq!(client.request("Z0,204,1"), "OK");
+    assert_eq!(client.request("c"), "S05");
+
+    let output = client.request(&format!("qRcmd,{}", hex("whowrote 0x10")));
+    let output = String::from_utf8(unhex(&output)).unwrap();
+    assert_eq!(output, "$42 written by $0202 at cycle 2 (instruction 1)  STA $10\n");
+
+    let output = client.request(&format!("qRcmd,{}", hex("whowrote $11")));
+    assert_eq!(String::from_utf8(unhex(&output)).unwrap(), "$0011 was not written\n");
+
+    client.send("k");
+    handle.join().unwrap();
+}
+
+#[test]
+fn reverse_without_history() {
+    let (mut client, handle) = connect();
+
+    assert!(!client.request("qSupported").contains("ReverseStep"));
+    assert_eq!(client.request("bs"), "E01");
+
+    client.send("k");
+    handle.join().unwrap();
+}
+
+#[test]
+fn symbols() {
+    let mut symbols = SymbolTable::new();
+    symbols.load(SymbolFormat::Vice, "al C:0200 .start\nal C:0204 .loop\nal C:0010 .result\n", None).unwrap();
+    symbols.load(SymbolFormat::Fceux, "$0204#loop#\n", Some(1)).unwrap();
+    let (client, server) = socket_pair();
+    let handle = spawn_stub_with_debug_info(server, |_| {}, symbols, SourceMap::new());
+    let mut client = Client::new(client);
+
+    assert_eq!(monitor(&mut client, "symbol start"), "start is $0200\n");
+    assert_eq!(monitor(&mut client, "symbol loop"), "loop is $0204\n01:loop is $0204\n");
+    assert_eq!(monitor(&mut client, "symbol 01:loop"), "01:loop is $0204\n");
+    assert_eq!(monitor(&mut client, "symbol $0206"), "$0206 is loop+2\n");
+    assert_eq!(monitor(&mut client, "symbol $0010"), "$0010 is result\n");
+    assert_eq!(monitor(&mut client, "symbol nothing"), "Unknown symbol 'nothing'\n");
+    assert_eq!(monitor(&mut client, "whowrote result"), "Write provenance is not enabled\n");
+
+    client.send("k");
+    handle.join().unwrap();
+}
+
+#[test]
+fn source_lines() {
+    let source = std::env::temp_dir().join(format!("gdb-loopback-{}.s", std::process::id()));
+    std::fs::write(&source, "start:  lda #$42\n        sta result\n\nloop:   inx\n        jmp loop\n").unwrap();
+
+    let mut source_map = SourceMap::new();
+    let file = source_map.add_file(&source.to_string_lossy());
+    for (address, length, line) in [(0x0200, 2, 1), (0x0202, 2, 2), (0x0204, 1, 4), (0x0205, 3, 5)] {
+        source_map.add_span(address, length, SourceLine { file, line });
+    }
+    let mut symbols = SymbolTable::new();
+    symbols.load(SymbolFormat::Vice, "al C:0200 .start\nal C:0204 .loop\nal C:0010 .result\n", None).unwrap();
+
+    let (client, server) = socket_pair();
+    let handle = spawn_stub_with_debug_info(server, |cpu| cpu.enable_call_graph(16), symbols, source_map);
+    let mut client = Client::new(client);
+    let path = source.to_string_lossy().to_string();
+
+    let output = monitor(&mut client, &format!("break {}:3", path));
+    assert_eq!(output, format!("Breakpoint set at $0204 ({}:4: loop:   inx)\n", path));
+    assert_eq!(client.request("c"), "S05");
+    assert_eq!(client.request("p4"), "0402");
+
+    assert_eq!(monitor(&mut client, "where"), format!("#0  $0204 in loop at {}:4: loop:   inx\n", path));
+    let listing = monitor(&mut client, "list 2");
+    assert_eq!(listing, format!("   {}:4: loop:   inx\n=> 0204  INX\n   {}:5: jmp loop\n   0205  JMP loop\n", path, path));
+
+    assert_eq!(monitor(&mut client, "delete loop"), format!("Breakpoint removed from $0204 ({}:4: loop:   inx)\n", path));
+    assert_eq!(monitor(&mut client, "break other.s:1"), "Unknown location 'other.s:1'\n");
+    assert_eq!(monitor(&mut client, "break"), "Usage: break <file:line|symbol|address>\n");
+
+    client.send("k");
+    handle.join().unwrap();
+    std::fs::remove_file(source).unwrap();
+}
+
+/// Run a monitor command and return its output
+fn monitor(client: &mut Client<TcpStream>, command: &str) -> String {
+    String::from_utf8(unhex(&client.request(&format!("qRcmd,{}", hex(command))))).unwrap()
+}
+
+fn hex(s: &str) -> String {
+    s.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Vec<u8> {
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_socket() {
+    use std::os::unix::net::UnixStream;
+
+    let (client, server) = UnixStream::pair().expect("Creating socket pair");
+    let handle = spawn_stub(server);
+    let mut client = Client::new(client);
+
+    assert_eq!(client.request("QStartNoAckMode"), "OK");
+    client.send_raw(b"$g#67");
+    assert_eq!(client.receive(), "000000ff000200");
+
+    drop(client);
+    handle.join().unwrap();
+}
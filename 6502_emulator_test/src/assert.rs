@@ -0,0 +1,145 @@
+use std::fmt::Write;
+use emulator_6502_core::{format_flag_letters, trace_line, CpuStatusFlags, Memory};
+use crate::machine::{Machine, CONTEXT};
+
+/// A register or status flag to check with [Machine::assert_registers]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    StackPointer,
+    ProgramCounter,
+    /// A status flag, expected to be 0 or 1
+    Flag(CpuStatusFlags),
+}
+
+impl Register {
+    fn name(&self) -> String {
+        match self {
+            Self::A => "A".to_string(),
+            Self::X => "X".to_string(),
+            Self::Y => "Y".to_string(),
+            Self::StackPointer => "SP".to_string(),
+            Self::ProgramCounter => "PC".to_string(),
+            Self::Flag(flag) => format!("flag {}", format_flag_letters(flag.bits()).replace(char::is_lowercase, "")),
+        }
+    }
+
+    fn format(&self, value: u16) -> String {
+        match self {
+            Self::ProgramCounter => format!("${:04X}", value),
+            Self::Flag(_) => value.to_string(),
+            _ => format!("${:02X}", value),
+        }
+    }
+}
+
+impl Machine {
+    /// The last executed instructions, followed by the next one, as shown by failed assertions
+    pub fn context(&self) -> String {
+        let mut context = String::new();
+        if let Some(history) = self.cpu.history() {
+            for entry in history.iter().skip(history.len().saturating_sub(CONTEXT)) {
+                let _ = writeln!(context, "  {}", entry);
+            }
+        }
+        let _ = write!(context, "> {}", trace_line(&self.cpu, &self.memory));
+        context
+    }
+
+    /// Check the value of each register in `expected`
+    ///
+    /// # Panics
+    /// If any register differs, listing each checked register and the context
+    pub fn assert_registers(&self, expected: &[(Register, u16)]) {
+        let registers = self.cpu.registers();
+        let mut failed = false;
+        let mut report = String::new();
+        for (register, value) in expected {
+            let actual = match register {
+                Register::A => registers.accumulator as u16,
+                Register::X => registers.x as u16,
+                Register::Y => registers.y as u16,
+                Register::StackPointer => registers.stack_pointer as u16,
+                Register::ProgramCounter => registers.program_counter,
+                Register::Flag(flag) => registers.flags.contains(*flag) as u16,
+            };
+            if actual == *value {
+                let _ = writeln!(report, "  {:<8} {}", register.name(), register.format(actual));
+            } else {
+                failed = true;
+                let _ = writeln!(report, "  {:<8} expected {}, got {}", register.name(), register.format(*value), register.format(actual));
+            }
+        }
+        if failed {
+            panic!("registers differ from the expected values:\n{}{}", report, self.context());
+        }
+    }
+
+    /// Check that memory holds `expected` from `address` on
+    ///
+    /// # Panics
+    /// If any byte differs, listing the differing bytes and the context
+    pub fn assert_memory(&self, address: u16, expected: &[u8]) {
+        let mut report = String::new();
+        for (offset, value) in expected.iter().enumerate() {
+            let address = address.wrapping_add(offset as u16);
+            let actual = self.memory.read(address);
+            if actual != *value {
+                let _ = writeln!(report, "  ${:04X}  expected ${:02X}, got ${:02X}", address, value, actual);
+            }
+        }
+        if !report.is_empty() {
+            let end = address.wrapping_add(expected.len().saturating_sub(1) as u16);
+            panic!("memory at ${:04X}-${:04X} differs from the expected values:\n{}{}", address, end, report, self.context());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use emulator_6502_core::{CpuStatusFlags, BRK_IMPLIED, LDA_IMMEDIATE, SEC_IMPLIED, STA_ABSOLUTE};
+    use crate::machine::Machine;
+    use super::Register;
+
+    fn machine() -> Machine {
+        let mut machine = Machine::builder()
+            .load(0x0200, &[LDA_IMMEDIATE, 0x32, STA_ABSOLUTE, 0x00, 0x20, SEC_IMPLIED, BRK_IMPLIED])
+            .start(0x0200)
+            .build();
+        machine.run_until_brk();
+        machine
+    }
+
+    fn panic_message(f: impl FnOnce()) -> String {
+        let error = catch_unwind(AssertUnwindSafe(f)).unwrap_err();
+        error.downcast_ref::<String>().cloned().unwrap()
+    }
+
+    #[test]
+    fn registers() {
+        let machine = machine();
+        machine.assert_registers(&[(Register::A, 0x32), (Register::ProgramCounter, 0x0206), (Register::Flag(CpuStatusFlags::CARRY), 1)]);
+
+        let message = panic_message(|| machine.assert_registers(&[(Register::A, 0x33), (Register::Flag(CpuStatusFlags::ZERO), 0)]));
+        let expected = "registers differ from the expected values:\n  \
+            A        expected $33, got $32\n  \
+            flag Z   0\n  \
+            0200  LDA #$32          A:00 X:00 Y:00 P:20 SP:00 CYC:0\n  \
+            0202  STA $2000         A:32 X:00 Y:00 P:20 SP:00 CYC:2  $2000: 00 -> 32\n  \
+            0205  SEC               A:32 X:00 Y:00 P:20 SP:00 CYC:6\n\
+            > 0206  00        BRK";
+        assert!(message.starts_with(expected), "{}", message);
+    }
+
+    #[test]
+    fn memory() {
+        let machine = machine();
+        machine.assert_memory(0x2000, &[0x32, 0x00]);
+
+        let message = panic_message(|| machine.assert_memory(0x1FFF, &[0x00, 0x31, 0x00]));
+        assert!(message.starts_with("memory at $1FFF-$2001 differs from the expected values:\n  $2000  expected $31, got $32\n  0200  LDA"), "{}", message);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,10 @@
+//! Helpers for testing 6502 programs with the 6502 emulator.
+//!
+//! A [Machine] is set up with a [MachineBuilder], run with the run helpers until it reaches an address,
+//! a `BRK` or the end of a subroutine, and checked with [Machine::assert_registers] and [Machine::assert_memory],
+//! which show what differs together with the last executed instructions.
+
+mod assert;
+pub use assert::*;
+mod machine;
+pub use machine::*;
\ No newline at end of file
@@ -0,0 +1,216 @@
+use emulator_6502_core::{BasicMemory, CallLimits, CallResult, Cpu, CpuStatusFlags, Memory, Registers, Vector, BRK_IMPLIED, MAX_MEMORY};
+
+/// How many of the last executed instructions a failure shows
+pub(crate) const CONTEXT: usize = 5;
+/// The cycles a run may take by default before it's considered runaway
+const DEFAULT_CYCLE_LIMIT: u64 = 10_000_000;
+
+/// A CPU with its memory, set up with [MachineBuilder]
+pub struct Machine {
+    pub cpu: Cpu,
+    pub memory: BasicMemory,
+    cycle_limit: u64,
+}
+
+/// Sets up a [Machine]: the memory, the vectors and the registers it starts with
+pub struct MachineBuilder {
+    memory: BasicMemory,
+    registers: Registers,
+    start: Option<u16>,
+    cycle_limit: u64,
+}
+
+impl Default for MachineBuilder {
+    fn default() -> Self {
+        Self {
+            memory: BasicMemory::default(),
+            registers: Registers::default(),
+            start: None,
+            cycle_limit: DEFAULT_CYCLE_LIMIT,
+        }
+    }
+}
+
+impl MachineBuilder {
+    /// Use a full 64 KiB memory image, like the binaries vasm writes with `-Fbin` for code starting at `.org $0000`
+    ///
+    /// # Panics
+    /// If the image isn't exactly 64 KiB
+    pub fn image(mut self, image: &[u8]) -> Self {
+        assert_eq!(image.len(), MAX_MEMORY, "memory images must be exactly 64 KiB");
+        self.memory = BasicMemory::from(image);
+        self
+    }
+
+    /// Copy `bytes` into memory at `address`
+    ///
+    /// # Panics
+    /// If the bytes don't fit into memory
+    pub fn load(mut self, address: u16, bytes: &[u8]) -> Self {
+        if let Err(error) = self.memory.load(address, bytes, 0) {
+            panic!("{}", error);
+        }
+        self
+    }
+
+    /// Point `vector` at `target`
+    pub fn vector(mut self, vector: Vector, target: u16) -> Self {
+        self.memory.write_vector(vector, target);
+        self
+    }
+
+    /// Start with these registers, apart from the program counter set with [Self::start]
+    pub fn registers(mut self, registers: Registers) -> Self {
+        self.registers = registers;
+        self
+    }
+
+    pub fn accumulator(mut self, value: u8) -> Self {
+        self.registers.accumulator = value;
+        self
+    }
+
+    pub fn x(mut self, value: u8) -> Self {
+        self.registers.x = value;
+        self
+    }
+
+    pub fn y(mut self, value: u8) -> Self {
+        self.registers.y = value;
+        self
+    }
+
+    pub fn stack_pointer(mut self, value: u8) -> Self {
+        self.registers.stack_pointer = value;
+        self
+    }
+
+    pub fn flags(mut self, flags: CpuStatusFlags) -> Self {
+        self.registers.flags = flags;
+        self
+    }
+
+    /// Start executing at `address` instead of the reset vector
+    pub fn start(mut self, address: u16) -> Self {
+        self.start = Some(address);
+        self
+    }
+
+    /// Fail a run which takes more than this many cycles, instead of the default of ten million
+    pub fn cycle_limit(mut self, cycles: u64) -> Self {
+        self.cycle_limit = cycles;
+        self
+    }
+
+    pub fn build(self) -> Machine {
+        let mut cpu = Cpu::default();
+        let program_counter = self.start.unwrap_or_else(|| {
+            cpu.load_reset_vector(&self.memory);
+            cpu.program_counter()
+        });
+        cpu.set_registers(&Registers { program_counter, ..self.registers });
+        cpu.enable_history(CONTEXT);
+        Machine { cpu, memory: self.memory, cycle_limit: self.cycle_limit }
+    }
+}
+
+impl Machine {
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder::default()
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    /// Execute a single instruction and return the cycles it took
+    pub fn step(&mut self) -> u32 {
+        self.cpu.step(&mut self.memory)
+    }
+
+    /// Run until the program counter reaches `address`, returning the cycles it took
+    ///
+    /// # Panics
+    /// If the cycle limit is reached first
+    pub fn run_until(&mut self, address: u16) -> u64 {
+        self.run_while(|cpu, _| cpu.program_counter() != address, &format!("reach ${:04X}", address))
+    }
+
+    /// Run until the next instruction is a `BRK`, returning the cycles it took. The `BRK` isn't executed
+    ///
+    /// # Panics
+    /// If the cycle limit is reached first
+    pub fn run_until_brk(&mut self) -> u64 {
+        self.run_while(|cpu, memory| memory.read(cpu.program_counter()) != BRK_IMPLIED, "reach a BRK")
+    }
+
+    /// Call the subroutine at `address` with the current registers and run it until it returns
+    ///
+    /// # Panics
+    /// If the subroutine doesn't return within the cycle limit
+    pub fn call(&mut self, address: u16) -> CallResult {
+        let limits = CallLimits { instructions: u64::MAX, cycles: self.cycle_limit };
+        let registers = self.cpu.registers();
+        match self.cpu.call_with_limits(&mut self.memory, address, &registers, &limits) {
+            Ok(result) => result,
+            Err(error) => panic!("calling ${:04X} failed: {}\n{}", address, error, self.context()),
+        }
+    }
+
+    fn run_while(&mut self, running: impl Fn(&Cpu, &BasicMemory) -> bool, goal: &str) -> u64 {
+        let start = self.cpu.cycles();
+        while running(&self.cpu, &self.memory) {
+            if self.cpu.cycles() - start >= self.cycle_limit {
+                panic!("the program didn't {} within {} cycles\n{}", goal, self.cycle_limit, self.context());
+            }
+            self.cpu.step(&mut self.memory);
+        }
+        self.cpu.cycles() - start
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use emulator_6502_core::{CpuStatusFlags, Memory, Vector, BRK_IMPLIED, INX_IMPLIED, JMP_ABSOLUTE, LDA_IMMEDIATE, RTS_IMPLIED, STA_ABSOLUTE};
+    use super::Machine;
+
+    const PROGRAM: [u8; 7] = [LDA_IMMEDIATE, 0x32, STA_ABSOLUTE, 0x00, 0x20, INX_IMPLIED, BRK_IMPLIED];
+
+    #[test]
+    fn builder() {
+        let machine = Machine::builder()
+            .load(0x0200, &PROGRAM)
+            .vector(Vector::Reset, 0x0200)
+            .x(0x10)
+            .flags(CpuStatusFlags::CARRY)
+            .build();
+        assert_eq!(machine.memory.read(0x0201), 0x32);
+        assert_eq!(machine.registers().program_counter, 0x0200);
+        assert_eq!(machine.registers().x, 0x10);
+        assert!(machine.registers().flags.contains(CpuStatusFlags::CARRY));
+
+        let machine = Machine::builder().load(0x0200, &PROGRAM).start(0x0205).build();
+        assert_eq!(machine.registers().program_counter, 0x0205);
+    }
+
+    #[test]
+    fn run_helpers() {
+        let mut machine = Machine::builder().load(0x0200, &PROGRAM).start(0x0200).build();
+        assert_eq!(machine.run_until(0x0205), 6);
+        assert_eq!(machine.memory.read(0x2000), 0x32);
+        assert_eq!(machine.run_until_brk(), 2);
+        assert_eq!(machine.registers().program_counter, 0x0206);
+
+        let mut machine = Machine::builder().load(0x0200, &PROGRAM[..6]).load(0x0206, &[RTS_IMPLIED]).build();
+        let result = machine.call(0x0200);
+        assert_eq!((result.registers.accumulator, result.registers.x), (0x32, 0x01));
+        assert_eq!(result.cycles, 14);
+    }
+
+    #[test]
+    #[should_panic(expected = "the program didn't reach $0300 within 100 cycles")]
+    fn cycle_limit() {
+        let mut machine = Machine::builder().load(0x0200, &[JMP_ABSOLUTE, 0x00, 0x02]).start(0x0200).cycle_limit(100).build();
+        machine.run_until(0x0300);
+    }
+}
\ No newline at end of file